@@ -0,0 +1,534 @@
+use std::cmp::Reverse;
+use std::fs::{
+  self,
+  Metadata,
+};
+use std::io;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::sync::Mutex;
+
+use anyhow::Context as _;
+use hashbrown::{
+  HashMap,
+  HashSet,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use super::{
+  sorted_entries,
+  write_atomically,
+  FileIndex,
+};
+use crate::file::ToUtf8 as _;
+
+/// The size the artifact store is trimmed to when `cache.max_size` is not set
+pub const DEFAULT_ARTIFACT_MAX_SIZE: u64 = 1 << 30;
+
+/// The outputs of a task saved for a fingerprint. The contents of the files are
+/// kept once in the object store, named by their SHA-256.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactManifest {
+  pub fingerprint: String,
+  pub outputs: Vec<String>,
+  pub entries: Vec<ArtifactEntry>,
+  /// The outputs the task saved with `save_output_as`
+  #[serde(default)]
+  pub saved_outputs: HashMap<String, String>,
+  /// The output of the commands, compressed with `compress_output`
+  #[serde(default)]
+  pub output: Option<String>,
+  pub created_at: String,
+  /// When the outputs were last saved or restored, in milliseconds since the
+  /// Unix epoch. The least recently used artifacts are evicted first.
+  pub last_used_ms: i64,
+}
+
+/// A file or directory below one of the outputs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArtifactEntry {
+  pub path: String,
+  /// The SHA-256 of the file content, not set for a directory
+  #[serde(default)]
+  pub digest: Option<String>,
+  #[serde(default)]
+  pub size: u64,
+  #[serde(default)]
+  pub executable: bool,
+}
+
+/// What a garbage collection of the artifact store removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcReport {
+  pub removed_artifacts: usize,
+  pub removed_bytes: u64,
+  pub kept_bytes: u64,
+}
+
+/// The content-addressed store of task outputs in `.mk/artifacts`
+pub struct ArtifactStore {
+  dir: PathBuf,
+}
+
+impl ArtifactStore {
+  pub fn in_dir(base_dir: &Path) -> Self {
+    Self {
+      dir: artifacts_path_in_dir(base_dir),
+    }
+  }
+
+  /// The manifest saved for the fingerprint, if any
+  pub fn load(&self, fingerprint: &str) -> anyhow::Result<Option<ArtifactManifest>> {
+    let path = self.manifest_path(fingerprint);
+    if !path.exists() {
+      return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&path).with_context(|| {
+      format!(
+        "Failed to read artifact manifest - {}",
+        path.to_utf8().unwrap_or("<non-utf8-path>")
+      )
+    })?;
+    Ok(Some(serde_json::from_str(&contents)?))
+  }
+
+  /// Save the outputs for the fingerprint, with the saved outputs and command
+  /// output of the task. Nothing is saved when one of the outputs does not exist.
+  pub fn save(
+    &self,
+    fingerprint: &str,
+    outputs: &[PathBuf],
+    saved_outputs: HashMap<String, String>,
+    output: Option<String>,
+    index: &Mutex<FileIndex>,
+  ) -> anyhow::Result<Option<ArtifactManifest>> {
+    if outputs.iter().any(|output| !output.exists()) {
+      return Ok(None);
+    }
+
+    let mut found = Vec::new();
+    for output in outputs {
+      collect_entries(output, &mut found)?;
+    }
+    let files: Vec<_> = found
+      .iter()
+      .filter(|(_, metadata)| !metadata.is_dir())
+      .map(|(path, _)| path.clone())
+      .collect();
+    let digests = FileIndex::digest_shared_files(index, &files)?;
+
+    let mut entries = Vec::with_capacity(found.len());
+    for (path, metadata) in found {
+      let digest = digests.get(&path).cloned();
+      if let Some(digest) = &digest {
+        self.store_object(&path, digest)?;
+      }
+      entries.push(ArtifactEntry {
+        path: path.to_string_lossy().into_owned(),
+        size: if digest.is_some() { metadata.len() } else { 0 },
+        executable: is_executable(&metadata),
+        digest,
+      });
+    }
+
+    let manifest = ArtifactManifest {
+      fingerprint: fingerprint.to_string(),
+      outputs: outputs
+        .iter()
+        .map(|output| output.to_string_lossy().into_owned())
+        .collect(),
+      entries,
+      saved_outputs,
+      output,
+      created_at: chrono::Utc::now().to_rfc3339(),
+      last_used_ms: chrono::Utc::now().timestamp_millis(),
+    };
+    self.write_manifest(&manifest)?;
+    Ok(Some(manifest))
+  }
+
+  /// The outputs that were deleted or changed since they were saved. `None`
+  /// when some saved content is no longer in the store.
+  pub fn changed_outputs(
+    &self,
+    manifest: &ArtifactManifest,
+    index: &Mutex<FileIndex>,
+  ) -> anyhow::Result<Option<Vec<String>>> {
+    let objects_exist = manifest
+      .entries
+      .iter()
+      .filter_map(|entry| entry.digest.as_deref())
+      .all(|digest| self.object_path(digest).exists());
+    if !objects_exist {
+      return Ok(None);
+    }
+
+    let mut changed = Vec::new();
+    for output in &manifest.outputs {
+      if !outputs_match(Path::new(output), &output_entries(manifest, output), index)? {
+        changed.push(output.clone());
+      }
+    }
+    Ok(Some(changed))
+  }
+
+  /// Put back the outputs that were deleted or changed since they were saved,
+  /// returning how many were restored. `None` when some saved content is no
+  /// longer in the store.
+  pub fn restore(
+    &self,
+    manifest: &mut ArtifactManifest,
+    index: &Mutex<FileIndex>,
+  ) -> anyhow::Result<Option<usize>> {
+    let Some(changed) = self.changed_outputs(manifest, index)? else {
+      return Ok(None);
+    };
+
+    for output in &changed {
+      remove_path(Path::new(output))?;
+      for entry in output_entries(manifest, output) {
+        self.materialize(entry)?;
+      }
+    }
+
+    manifest.last_used_ms = chrono::Utc::now().timestamp_millis();
+    self.write_manifest(manifest)?;
+    Ok(Some(changed.len()))
+  }
+
+  /// Evict the least recently used artifacts until the store fits in
+  /// `max_size`, then remove the contents no artifact refers to. Hold the
+  /// cache lock, so contents saved for a manifest that is not written yet are
+  /// kept.
+  pub fn gc(&self, max_size: u64) -> anyhow::Result<GcReport> {
+    let mut report = GcReport::default();
+    let mut manifests = self.load_manifests()?;
+    manifests.sort_by_key(|(_, manifest)| Reverse(manifest.last_used_ms));
+
+    let mut kept_objects = HashSet::new();
+    let mut evicting = false;
+    for (path, manifest) in manifests {
+      let mut new_objects = HashMap::new();
+      for entry in &manifest.entries {
+        if let Some(digest) = entry
+          .digest
+          .as_deref()
+          .filter(|digest| !kept_objects.contains(*digest))
+        {
+          new_objects.insert(digest.to_string(), entry.size);
+        }
+      }
+
+      let added: u64 = new_objects.values().sum();
+      evicting = evicting || report.kept_bytes + added > max_size;
+      if evicting {
+        remove_stale_file(&path)?;
+        report.removed_artifacts += 1;
+      } else {
+        report.kept_bytes += added;
+        kept_objects.extend(new_objects.into_keys());
+      }
+    }
+
+    let objects_dir = self.dir.join("objects");
+    if objects_dir.exists() {
+      for shard in sorted_entries(&objects_dir)? {
+        if !shard.is_dir() {
+          continue;
+        }
+        for object in sorted_entries(&shard)? {
+          let name = object.file_name().map(|name| name.to_string_lossy().into_owned());
+          let name = name.unwrap_or_default();
+          // Objects that are still being written start with a dot.
+          if name.starts_with('.') || kept_objects.contains(&name) {
+            continue;
+          }
+          let size = match fs::metadata(&object) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+          };
+          remove_stale_file(&object)?;
+          report.removed_bytes += size;
+        }
+      }
+    }
+
+    Ok(report)
+  }
+
+  fn load_manifests(&self) -> anyhow::Result<Vec<(PathBuf, ArtifactManifest)>> {
+    let manifests_dir = self.dir.join("manifests");
+    if !manifests_dir.exists() {
+      return Ok(Vec::new());
+    }
+
+    let mut manifests = Vec::new();
+    for path in sorted_entries(&manifests_dir)? {
+      if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+        continue;
+      }
+      let contents = fs::read_to_string(&path)?;
+      match serde_json::from_str(&contents) {
+        Ok(manifest) => manifests.push((path, manifest)),
+        Err(e) => {
+          log::debug!(
+            "Removing unreadable artifact manifest {} - {}",
+            path.to_string_lossy(),
+            e
+          );
+          fs::remove_file(&path)?;
+        },
+      }
+    }
+    Ok(manifests)
+  }
+
+  fn write_manifest(&self, manifest: &ArtifactManifest) -> anyhow::Result<()> {
+    let path = self.manifest_path(&manifest.fingerprint);
+    write_atomically(&path, |temp_path| {
+      fs::write(temp_path, serde_json::to_string_pretty(manifest)?)?;
+      Ok(())
+    })
+    .with_context(|| {
+      format!(
+        "Failed to write artifact manifest - {}",
+        path.to_utf8().unwrap_or("<non-utf8-path>")
+      )
+    })
+  }
+
+  fn store_object(&self, source: &Path, digest: &str) -> anyhow::Result<()> {
+    let path = self.object_path(digest);
+    if path.exists() {
+      return Ok(());
+    }
+
+    write_atomically(&path, |temp_path| {
+      fs::copy(source, temp_path)?;
+      Ok(())
+    })
+    .with_context(|| format!("Failed to save output - {}", source.to_string_lossy()))
+  }
+
+  fn materialize(&self, entry: &ArtifactEntry) -> anyhow::Result<()> {
+    let path = Path::new(&entry.path);
+    let Some(digest) = &entry.digest else {
+      fs::create_dir_all(path)?;
+      return Ok(());
+    };
+
+    write_atomically(path, |temp_path| {
+      fs::copy(self.object_path(digest), temp_path)?;
+      set_executable(temp_path, entry.executable)
+    })
+    .with_context(|| format!("Failed to restore output - {}", entry.path))
+  }
+
+  fn manifest_path(&self, fingerprint: &str) -> PathBuf {
+    self.dir.join("manifests").join(format!("{}.json", fingerprint))
+  }
+
+  fn object_path(&self, digest: &str) -> PathBuf {
+    let shard = digest.get(..2).unwrap_or(digest);
+    self.dir.join("objects").join(shard).join(digest)
+  }
+}
+
+pub fn artifacts_path_in_dir(base_dir: &Path) -> PathBuf {
+  base_dir.join(".mk").join("artifacts")
+}
+
+/// Add the path, and every path below it when it is a directory, to `entries`
+fn collect_entries(path: &Path, entries: &mut Vec<(PathBuf, Metadata)>) -> anyhow::Result<()> {
+  let metadata =
+    fs::metadata(path).with_context(|| format!("Failed to read output - {}", path.to_string_lossy()))?;
+  let is_dir = metadata.is_dir();
+  entries.push((path.to_path_buf(), metadata));
+  if is_dir {
+    for entry in sorted_entries(path)? {
+      collect_entries(&entry, entries)?;
+    }
+  }
+  Ok(())
+}
+
+/// The saved entries of one of the outputs
+fn output_entries<'a>(manifest: &'a ArtifactManifest, output: &str) -> Vec<&'a ArtifactEntry> {
+  manifest
+    .entries
+    .iter()
+    .filter(|entry| Path::new(&entry.path).starts_with(output))
+    .collect()
+}
+
+/// Whether the output on disk has exactly the saved entries and contents
+fn outputs_match(output: &Path, saved: &[&ArtifactEntry], index: &Mutex<FileIndex>) -> anyhow::Result<bool> {
+  if !output.exists() {
+    return Ok(false);
+  }
+
+  let mut found = Vec::new();
+  collect_entries(output, &mut found)?;
+  if found.len() != saved.len() {
+    return Ok(false);
+  }
+
+  let files: Vec<_> = found
+    .iter()
+    .filter(|(_, metadata)| !metadata.is_dir())
+    .map(|(path, _)| path.clone())
+    .collect();
+  let digests = FileIndex::digest_shared_files(index, &files)?;
+
+  Ok(found.iter().zip(saved).all(|((path, metadata), entry)| {
+    path.to_string_lossy() == entry.path
+      && digests.get(path) == entry.digest.as_ref()
+      && is_executable(metadata) == entry.executable
+  }))
+}
+
+/// Remove a file, which another process may have removed already
+fn remove_stale_file(path: &Path) -> anyhow::Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+    _ => Ok(()),
+  }
+}
+
+fn remove_path(path: &Path) -> anyhow::Result<()> {
+  let Ok(metadata) = fs::symlink_metadata(path) else {
+    return Ok(());
+  };
+
+  if metadata.is_dir() {
+    fs::remove_dir_all(path)?;
+  } else {
+    fs::remove_file(path)?;
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &Metadata) -> bool {
+  use std::os::unix::fs::PermissionsExt as _;
+  !metadata.is_dir() && metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &Metadata) -> bool {
+  false
+}
+
+#[cfg(unix)]
+fn set_executable(path: &Path, executable: bool) -> anyhow::Result<()> {
+  use std::os::unix::fs::PermissionsExt as _;
+  let mode = if executable { 0o755 } else { 0o644 };
+  fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_executable(_path: &Path, _executable: bool) -> anyhow::Result<()> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_artifact_store_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let dist = temp_dir.path().join("dist");
+    fs::create_dir_all(dist.join("assets"))?;
+    fs::write(dist.join("app.js"), "console.log(1);\n")?;
+    fs::write(dist.join("assets/logo.svg"), "<svg/>\n")?;
+    let outputs = vec![dist.clone()];
+
+    let store = ArtifactStore::in_dir(temp_dir.path());
+    let index = Mutex::new(FileIndex::default());
+    let saved_outputs = HashMap::from([("version".to_string(), "1.2.0".to_string())]);
+    let manifest = store.save("abc123", &outputs, saved_outputs, None, &index)?;
+    assert_eq!(manifest.map(|manifest| manifest.entries.len()), Some(4));
+
+    // Outputs that did not change are left alone.
+    let mut manifest = store.load("abc123")?.expect("saved manifest");
+    assert_eq!(manifest.saved_outputs["version"], "1.2.0");
+    assert_eq!(store.restore(&mut manifest, &index)?, Some(0));
+
+    // Deleted and overwritten outputs are put back, extra files are removed.
+    fs::remove_file(dist.join("assets/logo.svg"))?;
+    fs::write(dist.join("app.js"), "console.log(2);\n")?;
+    fs::write(dist.join("stale.js"), "")?;
+    assert_eq!(store.restore(&mut manifest, &index)?, Some(1));
+    assert_eq!(fs::read_to_string(dist.join("app.js"))?, "console.log(1);\n");
+    assert_eq!(fs::read_to_string(dist.join("assets/logo.svg"))?, "<svg/>\n");
+    assert!(!dist.join("stale.js").exists());
+
+    fs::remove_dir_all(&dist)?;
+    assert_eq!(store.restore(&mut manifest, &index)?, Some(1));
+    assert!(dist.join("app.js").exists());
+
+    // A missing output is not saved.
+    let missing = vec![temp_dir.path().join("missing")];
+    assert!(store
+      .save("def456", &missing, HashMap::new(), None, &index)?
+      .is_none());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_artifact_store_gc_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let store = ArtifactStore::in_dir(temp_dir.path());
+    let index = Mutex::new(FileIndex::default());
+
+    let output = temp_dir.path().join("out.bin");
+    for (fingerprint, contents, last_used_ms) in [("old", "aaaa", 1), ("mid", "bbbbbb", 2), ("new", "cc", 3)]
+    {
+      fs::write(&output, contents)?;
+      let mut manifest = store
+        .save(
+          fingerprint,
+          std::slice::from_ref(&output),
+          HashMap::new(),
+          None,
+          &index,
+        )?
+        .expect("saved manifest");
+      manifest.last_used_ms = last_used_ms;
+      store.write_manifest(&manifest)?;
+    }
+
+    // The most recently used artifacts are kept while they fit.
+    let report = store.gc(8)?;
+    assert_eq!(
+      report,
+      GcReport {
+        removed_artifacts: 1,
+        removed_bytes: 4,
+        kept_bytes: 8,
+      }
+    );
+    assert!(store.load("old")?.is_none());
+    assert!(store.load("mid")?.is_some());
+
+    let report = store.gc(0)?;
+    assert_eq!(report.removed_artifacts, 2);
+    assert_eq!(report.kept_bytes, 0);
+    assert!(store.load("new")?.is_none());
+
+    // A file removed by another process in the meantime counts as removed.
+    remove_stale_file(&store.manifest_path("new"))?;
+
+    Ok(())
+  }
+}
@@ -0,0 +1,403 @@
+use std::fs::{
+  self,
+  File,
+  Metadata,
+};
+use std::io;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::sync::{
+  Mutex,
+  MutexGuard,
+};
+use std::thread;
+use std::time::{
+  Duration,
+  SystemTime,
+  UNIX_EPOCH,
+};
+
+use anyhow::Context as _;
+use hashbrown::{
+  HashMap,
+  HashSet,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use sha2::{
+  Digest as _,
+  Sha256,
+};
+
+use super::{
+  write_atomically,
+  CacheLock,
+};
+use crate::file::ToUtf8 as _;
+
+/// Files modified this recently may change again without a visible change of
+/// their modification time, so their digests are not kept.
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// The SHA-256 digests of input files, kept in `.mk/file-index.json`. A stored
+/// digest is reused while the size, modification time and inode of the file
+/// stay the same, so unchanged files are not read again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileIndex {
+  pub files: HashMap<String, IndexedFile>,
+
+  /// The files hashed since the index was loaded, merged into the file on save
+  #[serde(skip)]
+  changed: HashSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexedFile {
+  #[serde(flatten)]
+  pub stamp: FileStamp,
+  pub digest: String,
+}
+
+/// The metadata that tells whether a file changed since it was hashed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+  pub size: u64,
+  pub modified_ns: u64,
+  pub inode: u64,
+}
+
+impl FileStamp {
+  fn from_metadata(metadata: &Metadata) -> Self {
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+
+    Self {
+      size: metadata.len(),
+      modified_ns: metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos() as u64),
+      inode,
+    }
+  }
+
+  fn is_racy(&self) -> bool {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_or(0, |now| now.as_nanos() as u64);
+    now.saturating_sub(self.modified_ns) < RACY_WINDOW.as_nanos() as u64
+  }
+}
+
+impl FileIndex {
+  pub fn load_in_dir(base_dir: &Path) -> anyhow::Result<Self> {
+    let path = file_index_path_in_dir(base_dir);
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+
+    let contents = fs::read_to_string(&path).with_context(|| {
+      format!(
+        "Failed to read file index - {}",
+        path.to_utf8().unwrap_or("<non-utf8-path>")
+      )
+    })?;
+    Ok(serde_json::from_str(&contents)?)
+  }
+
+  /// Merge the digests added since the index was loaded into the index file.
+  /// The file is read and replaced under the cache lock, so digests written by
+  /// other mk processes in the meantime are kept.
+  pub fn save_in_dir(&mut self, base_dir: &Path) -> anyhow::Result<()> {
+    if self.changed.is_empty() {
+      return Ok(());
+    }
+
+    let _lock = CacheLock::acquire_in_dir(base_dir)?;
+    self.save_locked(base_dir)
+  }
+
+  /// Save an index shared between tasks. The cache lock is taken before the
+  /// index is locked, the same order as when artifacts are saved, so the two
+  /// cannot deadlock.
+  pub fn save_shared_in_dir(index: &Mutex<Self>, base_dir: &Path) -> anyhow::Result<()> {
+    if lock_shared(index)?.changed.is_empty() {
+      return Ok(());
+    }
+
+    let _lock = CacheLock::acquire_in_dir(base_dir)?;
+    lock_shared(index)?.save_locked(base_dir)
+  }
+
+  /// Merge the added digests into the index file while the cache lock is held
+  fn save_locked(&mut self, base_dir: &Path) -> anyhow::Result<()> {
+    let path = file_index_path_in_dir(base_dir);
+    // The index only saves work, an unreadable file is replaced.
+    let mut merged = Self::load_in_dir(base_dir).unwrap_or_default();
+    for key in self.changed.drain() {
+      if let Some(indexed) = self.files.get(&key) {
+        merged.files.insert(key, indexed.clone());
+      }
+    }
+
+    write_atomically(&path, |temp_path| {
+      fs::write(temp_path, serde_json::to_string(&merged)?)?;
+      Ok(())
+    })
+    .with_context(|| {
+      format!(
+        "Failed to write file index - {}",
+        path.to_utf8().unwrap_or("<non-utf8-path>")
+      )
+    })?;
+    self.files = merged.files;
+    Ok(())
+  }
+
+  /// The digests of the given files. Files whose metadata changed since they
+  /// were indexed are hashed again, spread over the available threads.
+  pub fn digest_files(&mut self, files: &[PathBuf]) -> anyhow::Result<HashMap<PathBuf, String>> {
+    let (mut digests, stale) = self.lookup(stamp_files(files)?);
+    let hashed = hash_stale_files(&stale)?;
+    self.record(stale, hashed, &mut digests);
+    Ok(digests)
+  }
+
+  /// The digests of the given files from an index shared between tasks. The
+  /// index is only locked to look up and record digests, so tasks running at the
+  /// same time hash their files in parallel.
+  pub fn digest_shared_files(
+    index: &Mutex<Self>,
+    files: &[PathBuf],
+  ) -> anyhow::Result<HashMap<PathBuf, String>> {
+    let stamps = stamp_files(files)?;
+    let (mut digests, stale) = lock_shared(index)?.lookup(stamps);
+    let hashed = hash_stale_files(&stale)?;
+    lock_shared(index)?.record(stale, hashed, &mut digests);
+    Ok(digests)
+  }
+
+  /// The indexed digests of the files whose metadata did not change, and the
+  /// files that need to be hashed
+  fn lookup(
+    &self,
+    stamps: Vec<(PathBuf, FileStamp)>,
+  ) -> (HashMap<PathBuf, String>, Vec<(PathBuf, FileStamp)>) {
+    let mut digests = HashMap::new();
+    let mut stale = Vec::new();
+    for (path, stamp) in stamps {
+      match self.files.get(path.to_string_lossy().as_ref()) {
+        Some(indexed) if indexed.stamp == stamp => {
+          digests.insert(path, indexed.digest.clone());
+        },
+        _ => stale.push((path, stamp)),
+      }
+    }
+    (digests, stale)
+  }
+
+  /// Keep the digests of the hashed files, except the ones modified too recently
+  fn record(
+    &mut self,
+    stale: Vec<(PathBuf, FileStamp)>,
+    hashed: Vec<String>,
+    digests: &mut HashMap<PathBuf, String>,
+  ) {
+    for ((path, stamp), digest) in stale.into_iter().zip(hashed) {
+      if !stamp.is_racy() {
+        let key = path.to_string_lossy().into_owned();
+        self.changed.insert(key.clone());
+        self.files.insert(
+          key,
+          IndexedFile {
+            stamp,
+            digest: digest.clone(),
+          },
+        );
+      }
+      digests.insert(path, digest);
+    }
+  }
+}
+
+pub fn file_index_path_in_dir(base_dir: &Path) -> PathBuf {
+  base_dir.join(".mk").join("file-index.json")
+}
+
+fn lock_shared(index: &Mutex<FileIndex>) -> anyhow::Result<MutexGuard<'_, FileIndex>> {
+  index
+    .lock()
+    .map_err(|e| anyhow::anyhow!("Failed to lock file index - {}", e))
+}
+
+/// The current metadata of the files
+fn stamp_files(files: &[PathBuf]) -> anyhow::Result<Vec<(PathBuf, FileStamp)>> {
+  files
+    .iter()
+    .map(|path| {
+      let metadata =
+        fs::metadata(path).with_context(|| format!("Failed to read input - {}", path.to_string_lossy()))?;
+      Ok((path.clone(), FileStamp::from_metadata(&metadata)))
+    })
+    .collect()
+}
+
+fn hash_stale_files(stale: &[(PathBuf, FileStamp)]) -> anyhow::Result<Vec<String>> {
+  let paths: Vec<_> = stale.iter().map(|(path, _)| path.as_path()).collect();
+  hash_files(&paths)
+}
+
+/// Hash the files on up to one thread per CPU, keeping their order
+fn hash_files(paths: &[&Path]) -> anyhow::Result<Vec<String>> {
+  if paths.is_empty() {
+    return Ok(Vec::new());
+  }
+
+  let threads = thread::available_parallelism().map_or(1, usize::from);
+  let chunk_size = paths.len().div_ceil(threads);
+  thread::scope(|scope| {
+    let handles: Vec<_> = paths
+      .chunks(chunk_size)
+      .map(|chunk| scope.spawn(move || chunk.iter().map(|path| hash_file(path)).collect::<Vec<_>>()))
+      .collect();
+
+    let mut digests = Vec::with_capacity(paths.len());
+    for handle in handles {
+      let chunk = handle
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to join input hashing thread"))?;
+      for digest in chunk {
+        digests.push(digest?);
+      }
+    }
+    Ok(digests)
+  })
+}
+
+fn hash_file(path: &Path) -> anyhow::Result<String> {
+  let read_error = || format!("Failed to read input - {}", path.to_string_lossy());
+  let mut file = File::open(path).with_context(read_error)?;
+  let mut hasher = Sha256::new();
+  io::copy(&mut file, &mut hasher).with_context(read_error)?;
+  Ok(hex::encode(hasher.finalize()))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_file_index_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let file = temp_dir.path().join("lib.rs");
+    fs::write(&file, "fn main() {}\n")?;
+    let files = vec![file.clone()];
+
+    let mut index = FileIndex::default();
+    let digests = index.digest_files(&files)?;
+    assert_eq!(digests[&file], hex::encode(Sha256::digest(b"fn main() {}\n")));
+    // The file was just written, so its digest is not kept yet.
+    assert!(index.files.is_empty());
+
+    // A kept digest is reused while the metadata of the file matches.
+    let stamp = FileStamp::from_metadata(&fs::metadata(&file)?);
+    index.files.insert(
+      file.to_string_lossy().into_owned(),
+      IndexedFile {
+        stamp,
+        digest: "indexed".to_string(),
+      },
+    );
+    assert_eq!(index.digest_files(&files)?[&file], "indexed");
+
+    fs::write(&file, "fn main() { }\n")?;
+    assert_eq!(
+      index.digest_files(&files)?[&file],
+      hex::encode(Sha256::digest(b"fn main() { }\n"))
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_file_index_2() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let file = temp_dir.path().join("lib.rs");
+    fs::write(&file, "fn main() {}\n")?;
+    let files = vec![file.clone()];
+
+    // A shared index reuses kept digests and hashes changed files.
+    let stamp = FileStamp::from_metadata(&fs::metadata(&file)?);
+    let index = Mutex::new(FileIndex::default());
+    let index_file = |stamp| {
+      index.lock().unwrap().files.insert(
+        file.to_string_lossy().into_owned(),
+        IndexedFile {
+          stamp,
+          digest: "indexed".to_string(),
+        },
+      );
+    };
+    index_file(stamp);
+    assert_eq!(FileIndex::digest_shared_files(&index, &files)?[&file], "indexed");
+
+    index_file(FileStamp { size: 0, ..stamp });
+    assert_eq!(
+      FileIndex::digest_shared_files(&index, &files)?[&file],
+      hex::encode(Sha256::digest(b"fn main() {}\n"))
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_file_index_3() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let base_dir = temp_dir.path();
+    let stamp = FileStamp {
+      size: 1,
+      modified_ns: 0,
+      inode: 0,
+    };
+
+    // Each index is loaded before any of them saves, like separate mk processes.
+    let mut indexes = (0..8)
+      .map(|_| FileIndex::load_in_dir(base_dir))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    std::thread::scope(|scope| {
+      let handles: Vec<_> = indexes
+        .iter_mut()
+        .enumerate()
+        .map(|(i, index)| {
+          scope.spawn(move || {
+            let file = PathBuf::from(format!("src/file-{}.rs", i));
+            index.record(
+              vec![(file, stamp)],
+              vec![format!("digest-{}", i)],
+              &mut HashMap::new(),
+            );
+            index.save_in_dir(base_dir)
+          })
+        })
+        .collect();
+      handles.into_iter().try_for_each(|handle| {
+        handle
+          .join()
+          .map_err(|_| anyhow::anyhow!("Failed to join file index thread"))?
+      })
+    })?;
+
+    let index = FileIndex::load_in_dir(base_dir)?;
+    assert_eq!(index.files.len(), 8);
+    assert_eq!(index.files["src/file-3.rs"].digest, "digest-3");
+
+    Ok(())
+  }
+}
@@ -0,0 +1,69 @@
+use std::fs::{
+  self,
+  File,
+};
+use std::io;
+use std::path::{
+  Path,
+  PathBuf,
+};
+
+use anyhow::Context as _;
+
+use crate::file::ToUtf8 as _;
+
+/// An advisory lock on `.mk/cache.lock`, held while the cache file is read and
+/// written so concurrent mk processes do not lose each other's entries. The
+/// lock is released when it is dropped. Only Unix platforms take the lock.
+pub struct CacheLock {
+  _file: File,
+}
+
+impl CacheLock {
+  /// Wait until no other process holds the lock
+  pub fn acquire_in_dir(base_dir: &Path) -> anyhow::Result<Self> {
+    let path = cache_lock_path_in_dir(base_dir);
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+
+    let file = File::options()
+      .create(true)
+      .truncate(false)
+      .write(true)
+      .open(&path)
+      .and_then(|file| lock_exclusive(&file).map(|_| file))
+      .with_context(|| {
+        format!(
+          "Failed to lock cache file - {}",
+          path.to_utf8().unwrap_or("<non-utf8-path>")
+        )
+      })?;
+    Ok(Self { _file: file })
+  }
+}
+
+pub fn cache_lock_path_in_dir(base_dir: &Path) -> PathBuf {
+  base_dir.join(".mk").join("cache.lock")
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd as _;
+
+  loop {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+      return Ok(());
+    }
+
+    let error = io::Error::last_os_error();
+    if error.kind() != io::ErrorKind::Interrupted {
+      return Err(error);
+    }
+  }
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> io::Result<()> {
+  Ok(())
+}
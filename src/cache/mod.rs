@@ -0,0 +1,715 @@
+mod artifacts;
+mod file_index;
+mod lock;
+mod output;
+mod status;
+mod timestamp;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::sync::Mutex;
+
+use anyhow::Context as _;
+use glob::glob;
+use hashbrown::{
+  HashMap,
+  HashSet,
+};
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use serde_json::Value as JsonValue;
+use sha2::{
+  Digest as _,
+  Sha256,
+};
+
+use crate::file::ToUtf8 as _;
+use crate::utils::resolve_path;
+
+pub use artifacts::*;
+pub use file_index::*;
+pub use lock::*;
+pub use output::*;
+pub use status::*;
+pub use timestamp::*;
+
+/// The version of the cache file and of the fingerprint encoding. Entries of
+/// another version were fingerprinted differently and are dropped on load.
+pub const CACHE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+  pub fingerprint: String,
+  pub outputs: Vec<String>,
+  /// The outputs the task saved with `save_output_as`, published again on a cache hit
+  #[serde(default)]
+  pub saved_outputs: HashMap<String, String>,
+  /// The parts the fingerprint was computed from, used to tell what changed
+  #[serde(default)]
+  pub components: Option<FingerprintComponents>,
+  /// The output of the commands, compressed with `compress_output`
+  #[serde(default)]
+  pub output: Option<String>,
+  pub updated_at: String,
+}
+
+/// The fingerprint of a task and the parts it was computed from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+  pub hash: String,
+  pub components: FingerprintComponents,
+}
+
+/// The parts of a fingerprint, kept with the cache entry to tell why a task is
+/// not a cache hit. Environment values are kept as digests so they are not
+/// written to disk.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FingerprintComponents {
+  /// The SHA-256 of the canonical task definition
+  pub definition: String,
+  /// The SHA-256 of each environment variable value
+  #[serde(default)]
+  pub env: BTreeMap<String, String>,
+  /// The digest of each input file, `missing` for inputs that do not exist
+  #[serde(default)]
+  pub inputs: BTreeMap<String, String>,
+  /// The digest of each env and secret file
+  #[serde(default)]
+  pub env_files: BTreeMap<String, String>,
+  #[serde(default)]
+  pub outputs: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CacheStore {
+  /// The format the entries were written with, `1` for cache files written
+  /// before the format was versioned
+  #[serde(default = "legacy_cache_version")]
+  pub version: u32,
+  pub tasks: HashMap<String, CacheEntry>,
+
+  /// The entries inserted since the store was loaded or saved
+  #[serde(skip)]
+  changed: HashSet<String>,
+}
+
+impl Default for CacheStore {
+  fn default() -> Self {
+    Self {
+      version: CACHE_FORMAT_VERSION,
+      tasks: HashMap::new(),
+      changed: HashSet::new(),
+    }
+  }
+}
+
+fn legacy_cache_version() -> u32 {
+  1
+}
+
+impl CacheStore {
+  pub fn load() -> anyhow::Result<Self> {
+    Self::load_in_dir(Path::new("."))
+  }
+
+  pub fn load_in_dir(base_dir: &Path) -> anyhow::Result<Self> {
+    let path = cache_path_in_dir(base_dir);
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+
+    let store = match serde_json::from_str::<Self>(&read_cache_file(&path)?) {
+      Ok(store) => store,
+      Err(_) => {
+        // Read the file again under the lock, it may have been replaced since.
+        let _lock = CacheLock::acquire_in_dir(base_dir)?;
+        Self::read_locked(&path)?
+      },
+    };
+    Ok(store.with_current_version())
+  }
+
+  /// Read the cache file while the cache lock is held. The entries of a corrupt
+  /// file that still parse are kept, and the file is moved aside.
+  fn read_locked(path: &Path) -> anyhow::Result<Self> {
+    if !path.exists() {
+      return Ok(Self::default());
+    }
+
+    let contents = read_cache_file(path)?;
+    let error = match serde_json::from_str::<Self>(&contents) {
+      Ok(store) => return Ok(store),
+      Err(error) => error,
+    };
+
+    let mut store = Self::default();
+    if let Ok(JsonValue::Object(fields)) = serde_json::from_str::<JsonValue>(&contents) {
+      store.version = fields
+        .get("version")
+        .and_then(JsonValue::as_u64)
+        .map_or(legacy_cache_version(), |version| version as u32);
+      if let Some(JsonValue::Object(tasks)) = fields.get("tasks") {
+        for (key, entry) in tasks {
+          if let Ok(entry) = serde_json::from_value::<CacheEntry>(entry.clone()) {
+            store.tasks.insert(key.clone(), entry);
+          }
+        }
+      }
+    }
+
+    let corrupt_path = path.with_extension("json.corrupt");
+    fs::rename(path, &corrupt_path)?;
+    log::warn!(
+      "The cache file is corrupt ({}), kept {} entries and moved it to {}",
+      error,
+      store.tasks.len(),
+      corrupt_path.to_utf8().unwrap_or("<non-utf8-path>")
+    );
+    Ok(store)
+  }
+
+  fn with_current_version(self) -> Self {
+    if self.version == CACHE_FORMAT_VERSION {
+      return self;
+    }
+
+    log::debug!(
+      "Dropping {} cache entries of format version {}",
+      self.tasks.len(),
+      self.version
+    );
+    Self::default()
+  }
+
+  /// Set the entry of a task, written by the next save
+  pub fn insert(&mut self, key: String, entry: CacheEntry) {
+    self.changed.insert(key.clone());
+    self.tasks.insert(key, entry);
+  }
+
+  pub fn save(&mut self) -> anyhow::Result<()> {
+    self.save_in_dir(Path::new("."))
+  }
+
+  /// Merge the inserted entries into the cache file. The file is read and
+  /// replaced under the cache lock, so entries written by other mk processes in
+  /// the meantime are kept, and readers never see a partly written file.
+  pub fn save_in_dir(&mut self, base_dir: &Path) -> anyhow::Result<()> {
+    if self.changed.is_empty() {
+      return Ok(());
+    }
+
+    let path = cache_path_in_dir(base_dir);
+    let _lock = CacheLock::acquire_in_dir(base_dir)?;
+    let mut merged = Self::read_locked(&path)?.with_current_version();
+    for key in self.changed.drain() {
+      if let Some(entry) = self.tasks.get(&key) {
+        merged.tasks.insert(key, entry.clone());
+      }
+    }
+
+    write_atomically(&path, |temp_path| {
+      fs::write(temp_path, serde_json::to_string_pretty(&merged)?)?;
+      Ok(())
+    })
+    .with_context(|| {
+      format!(
+        "Failed to write cache file - {}",
+        path.to_utf8().unwrap_or("<non-utf8-path>")
+      )
+    })?;
+    self.tasks = merged.tasks;
+    Ok(())
+  }
+
+  pub fn remove() -> anyhow::Result<()> {
+    Self::remove_in_dir(Path::new("."))
+  }
+
+  /// Remove the cache file, the file index and the artifact store next to it
+  pub fn remove_in_dir(base_dir: &Path) -> anyhow::Result<()> {
+    for path in [cache_path_in_dir(base_dir), file_index_path_in_dir(base_dir)] {
+      if path.exists() {
+        fs::remove_file(&path).with_context(|| {
+          format!(
+            "Failed to remove cache file - {}",
+            path.to_utf8().unwrap_or("<non-utf8-path>")
+          )
+        })?;
+      }
+    }
+
+    let artifacts_path = artifacts_path_in_dir(base_dir);
+    if artifacts_path.exists() {
+      fs::remove_dir_all(&artifacts_path).with_context(|| {
+        format!(
+          "Failed to remove artifact store - {}",
+          artifacts_path.to_utf8().unwrap_or("<non-utf8-path>")
+        )
+      })?;
+    }
+    Ok(())
+  }
+}
+
+fn read_cache_file(path: &Path) -> anyhow::Result<String> {
+  fs::read_to_string(path).with_context(|| {
+    format!(
+      "Failed to read cache file - {}",
+      path.to_utf8().unwrap_or("<non-utf8-path>")
+    )
+  })
+}
+
+pub fn cache_path() -> PathBuf {
+  cache_path_in_dir(Path::new("."))
+}
+
+pub fn cache_path_in_dir(base_dir: &Path) -> PathBuf {
+  base_dir.join(".mk").join("cache.json")
+}
+
+pub fn expand_patterns(patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+  expand_patterns_in_dir(Path::new("."), patterns)
+}
+
+pub fn expand_patterns_in_dir(base_dir: &Path, patterns: &[String]) -> anyhow::Result<Vec<PathBuf>> {
+  let mut paths = Vec::new();
+
+  for pattern in patterns {
+    let mut matched = false;
+    let resolved_pattern = resolve_path(base_dir, pattern);
+    let resolved_pattern = resolved_pattern.to_string_lossy().into_owned();
+    for entry in glob(&resolved_pattern)? {
+      matched = true;
+      let path = entry?;
+      paths.push(path);
+    }
+
+    if !matched {
+      paths.push(resolve_path(base_dir, pattern));
+    }
+  }
+
+  paths.sort();
+  paths.dedup();
+  Ok(paths)
+}
+
+/// Compute the SHA-256 fingerprint of a task, as lowercase hex.
+///
+/// The fingerprint hashes a sequence of records. Each record is its tag and
+/// its value, both prefixed with their length as a little-endian `u64`, so
+/// different records never encode to the same bytes. The records are, in
+/// order:
+///
+/// - `format`: the cache format version, e.g. `mk-cache-v2`
+/// - `task`: the task name
+/// - `definition`: the task definition as canonical JSON, see [`canonical_json`]
+/// - `output`: each declared output path
+/// - `env`: each environment variable as `KEY=VALUE`, sorted by key
+/// - `input` then `content`: each input path and the digest of its content
+/// - `env_file` then `content`: each env or secret file and the digest of its content
+///
+/// File contents are hashed through the `index`, so files that did not change
+/// since they were last hashed are not read again. The index is not locked
+/// while files are hashed.
+pub fn compute_fingerprint(
+  task_name: &str,
+  definition: &JsonValue,
+  env_vars: &[(String, String)],
+  inputs: &[PathBuf],
+  env_files: &[PathBuf],
+  outputs: &[PathBuf],
+  index: &Mutex<FileIndex>,
+) -> anyhow::Result<Fingerprint> {
+  let mut files = Vec::new();
+  for path in inputs.iter().chain(env_files) {
+    collect_files(path, &mut files)?;
+  }
+  files.sort();
+  files.dedup();
+  let digests = FileIndex::digest_shared_files(index, &files)?;
+
+  let mut hasher = FingerprintHasher::default();
+  let definition = canonical_json(definition);
+
+  hasher.record("format", format!("mk-cache-v{}", CACHE_FORMAT_VERSION).as_bytes());
+  hasher.record("task", task_name.as_bytes());
+  hasher.record("definition", definition.as_bytes());
+
+  for path in outputs {
+    hasher.record("output", path.to_string_lossy().as_bytes());
+  }
+
+  for (key, value) in env_vars {
+    hasher.record("env", format!("{}={}", key, value).as_bytes());
+  }
+
+  for path in inputs {
+    hasher.record("input", path.to_string_lossy().as_bytes());
+    hasher.record("content", content_digest(path, &digests)?.as_bytes());
+  }
+
+  for path in env_files {
+    hasher.record("env_file", path.to_string_lossy().as_bytes());
+    hasher.record("content", content_digest(path, &digests)?.as_bytes());
+  }
+
+  let components = FingerprintComponents {
+    definition: hex::encode(Sha256::digest(definition.as_bytes())),
+    env: env_vars
+      .iter()
+      .map(|(key, value)| (key.clone(), hex::encode(Sha256::digest(value.as_bytes()))))
+      .collect(),
+    inputs: file_digests(inputs, &digests)?,
+    env_files: file_digests(env_files, &digests)?,
+    outputs: outputs
+      .iter()
+      .map(|path| path.to_string_lossy().into_owned())
+      .collect(),
+  };
+
+  Ok(Fingerprint {
+    hash: hex::encode(hasher.0.finalize()),
+    components,
+  })
+}
+
+/// The digest of every file below the paths, `missing` for paths that do not exist
+fn file_digests(
+  paths: &[PathBuf],
+  digests: &HashMap<PathBuf, String>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+  let mut files = BTreeMap::new();
+  for path in paths {
+    if !path.exists() {
+      files.insert(path.to_string_lossy().into_owned(), "missing".to_string());
+      continue;
+    }
+
+    let mut found = Vec::new();
+    collect_files(path, &mut found)?;
+    for file in found {
+      files.insert(
+        file.to_string_lossy().into_owned(),
+        content_digest(&file, digests)?,
+      );
+    }
+  }
+  Ok(files)
+}
+
+/// Writes length-prefixed records into a SHA-256 hasher
+#[derive(Default)]
+struct FingerprintHasher(Sha256);
+
+impl FingerprintHasher {
+  fn record(&mut self, tag: &str, value: &[u8]) {
+    self.0.update((tag.len() as u64).to_le_bytes());
+    self.0.update(tag.as_bytes());
+    self.0.update((value.len() as u64).to_le_bytes());
+    self.0.update(value);
+  }
+}
+
+/// Write `path` through a temporary file renamed into place
+fn write_atomically(path: &Path, write: impl FnOnce(&Path) -> anyhow::Result<()>) -> anyhow::Result<()> {
+  let parent = path.parent().unwrap_or_else(|| Path::new("."));
+  fs::create_dir_all(parent)?;
+
+  let name = path.file_name().map(|name| name.to_string_lossy().into_owned());
+  let temp_path = parent.join(format!(
+    ".{}.{}.tmp",
+    name.unwrap_or_default(),
+    std::process::id()
+  ));
+  let result = write(&temp_path).and_then(|_| Ok(fs::rename(&temp_path, path)?));
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path);
+  }
+  result
+}
+
+/// Add the file, or every file below the directory, to `files`
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+  let Ok(metadata) = fs::metadata(path) else {
+    return Ok(());
+  };
+
+  if !metadata.is_dir() {
+    files.push(path.to_path_buf());
+    return Ok(());
+  }
+
+  for entry in sorted_entries(path)? {
+    collect_files(&entry, files)?;
+  }
+  Ok(())
+}
+
+fn sorted_entries(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+  let mut entries = fs::read_dir(dir)?
+    .map(|entry| Ok(entry?.path()))
+    .collect::<anyhow::Result<Vec<_>>>()?;
+  entries.sort();
+  Ok(entries)
+}
+
+/// The digest of a path: `missing`, `file:<sha256>` or, for a directory,
+/// `dir:<sha256>` over the names and digests of its entries
+fn content_digest(path: &Path, digests: &HashMap<PathBuf, String>) -> anyhow::Result<String> {
+  let Ok(metadata) = fs::metadata(path) else {
+    return Ok("missing".to_string());
+  };
+
+  if !metadata.is_dir() {
+    let digest = digests
+      .get(path)
+      .with_context(|| format!("Input was not hashed - {}", path.to_string_lossy()))?;
+    return Ok(format!("file:{}", digest));
+  }
+
+  let mut hasher = FingerprintHasher::default();
+  for entry in sorted_entries(path)? {
+    let name = entry.file_name().map(|name| name.to_string_lossy().into_owned());
+    hasher.record("name", name.unwrap_or_default().as_bytes());
+    hasher.record("content", content_digest(&entry, digests)?.as_bytes());
+  }
+  Ok(format!("dir:{}", hex::encode(hasher.0.finalize())))
+}
+
+/// Encode a value as canonical JSON: no whitespace, object keys sorted by
+/// their UTF-8 bytes, and object fields that are `null`, `[]` or `{}` left
+/// out. Leaving out empty fields keeps fingerprints stable when a new
+/// optional setting is added.
+pub fn canonical_json(value: &JsonValue) -> String {
+  let mut encoded = String::new();
+  write_canonical_json(value, &mut encoded);
+  encoded
+}
+
+fn write_canonical_json(value: &JsonValue, encoded: &mut String) {
+  match value {
+    JsonValue::Array(values) => {
+      encoded.push('[');
+      for (index, value) in values.iter().enumerate() {
+        if index > 0 {
+          encoded.push(',');
+        }
+        write_canonical_json(value, encoded);
+      }
+      encoded.push(']');
+    },
+    JsonValue::Object(fields) => {
+      let mut fields: Vec<_> = fields
+        .iter()
+        .filter(|(_, value)| !is_empty_value(value))
+        .collect();
+      fields.sort_by_key(|(key, _)| *key);
+      encoded.push('{');
+      for (index, (key, value)) in fields.into_iter().enumerate() {
+        if index > 0 {
+          encoded.push(',');
+        }
+        encoded.push_str(&JsonValue::String(key.clone()).to_string());
+        encoded.push(':');
+        write_canonical_json(value, encoded);
+      }
+      encoded.push('}');
+    },
+    value => encoded.push_str(&value.to_string()),
+  }
+}
+
+fn is_empty_value(value: &JsonValue) -> bool {
+  match value {
+    JsonValue::Null => true,
+    JsonValue::Array(values) => values.is_empty(),
+    JsonValue::Object(fields) => fields.is_empty(),
+    _ => false,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_canonical_json_1() {
+    let value = serde_json::json!({
+      "verbose": null,
+      "commands": [{ "shell": "sh", "command": "echo \"hi\"" }, null],
+      "env_file": [],
+      "labels": {},
+      "description": "build",
+    });
+    assert_eq!(
+      canonical_json(&value),
+      r#"{"commands":[{"command":"echo \"hi\"","shell":"sh"},null],"description":"build"}"#
+    );
+  }
+
+  #[test]
+  fn test_compute_fingerprint_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    std::fs::create_dir_all(temp_dir.path().join("src"))?;
+    std::fs::write(temp_dir.path().join("src/lib.rs"), "fn main() {}\n")?;
+    let inputs = vec![temp_dir.path().join("src"), temp_dir.path().join("missing.txt")];
+    let definition = serde_json::json!({ "commands": ["cargo build"] });
+    let env_vars = vec![("PROFILE".to_string(), "release".to_string())];
+    let index = Mutex::new(FileIndex::default());
+
+    // The encoding is documented and must not change without a new format version.
+    assert_eq!(
+      compute_fingerprint("build", &definition, &env_vars, &[], &[], &[], &index)?.hash,
+      "8e85b47710019685c9094d58bbc92b227018162ab591e08548faea65743049d6"
+    );
+
+    let fingerprint = compute_fingerprint("build", &definition, &env_vars, &inputs, &[], &[], &index)?;
+    let lib_path = temp_dir.path().join("src/lib.rs").to_string_lossy().into_owned();
+    let missing_path = temp_dir.path().join("missing.txt").to_string_lossy().into_owned();
+    assert_eq!(
+      fingerprint.components.inputs,
+      BTreeMap::from([
+        (
+          lib_path,
+          format!("file:{}", hex::encode(Sha256::digest(b"fn main() {}\n")))
+        ),
+        (missing_path, "missing".to_string()),
+      ])
+    );
+    assert_eq!(
+      fingerprint.components.env["PROFILE"],
+      hex::encode(Sha256::digest(b"release"))
+    );
+
+    // Fields that are not set do not change the fingerprint.
+    let with_empty_fields = serde_json::json!({ "commands": ["cargo build"], "timeout": null, "args": [] });
+    assert_eq!(
+      compute_fingerprint("build", &with_empty_fields, &env_vars, &inputs, &[], &[], &index)?,
+      fingerprint
+    );
+
+    std::fs::write(temp_dir.path().join("src/lib.rs"), "fn main() { }\n")?;
+    assert_ne!(
+      compute_fingerprint("build", &definition, &env_vars, &inputs, &[], &[], &index)?.hash,
+      fingerprint.hash
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_cache_store_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    std::fs::create_dir_all(temp_dir.path().join(".mk"))?;
+    std::fs::write(
+      cache_path_in_dir(temp_dir.path()),
+      r#"{"tasks":{"build":{"fingerprint":"00ff","outputs":[],"updated_at":"2024-01-01T00:00:00Z"}}}"#,
+    )?;
+
+    // Entries of the unversioned format are dropped.
+    let store = CacheStore::load_in_dir(temp_dir.path())?;
+    assert_eq!(store.version, CACHE_FORMAT_VERSION);
+    assert!(store.tasks.is_empty());
+
+    let mut store = CacheStore::default();
+    store.insert("build".to_string(), test_entry("00ff"));
+    store.save_in_dir(temp_dir.path())?;
+    assert_eq!(CacheStore::load_in_dir(temp_dir.path())?.tasks.len(), 1);
+
+    Ok(())
+  }
+
+  fn test_entry(fingerprint: &str) -> CacheEntry {
+    CacheEntry {
+      fingerprint: fingerprint.to_string(),
+      outputs: Vec::new(),
+      saved_outputs: HashMap::new(),
+      components: None,
+      output: None,
+      updated_at: "2024-01-01T00:00:00Z".to_string(),
+    }
+  }
+
+  #[test]
+  fn test_cache_store_2() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let base_dir = temp_dir.path();
+
+    // Each store is loaded before any of them saves, like separate mk processes.
+    let mut stores = (0..8)
+      .map(|_| CacheStore::load_in_dir(base_dir))
+      .collect::<anyhow::Result<Vec<_>>>()?;
+    std::thread::scope(|scope| {
+      let handles: Vec<_> = stores
+        .iter_mut()
+        .enumerate()
+        .map(|(i, store)| {
+          scope.spawn(move || {
+            store.insert(format!("task-{}", i), test_entry("00ff"));
+            store.save_in_dir(base_dir)
+          })
+        })
+        .collect();
+      handles.into_iter().try_for_each(|handle| {
+        handle
+          .join()
+          .map_err(|_| anyhow::anyhow!("Failed to join cache thread"))?
+      })
+    })?;
+
+    let store = CacheStore::load_in_dir(base_dir)?;
+    assert_eq!(store.tasks.len(), 8);
+
+    // An entry inserted again replaces the stored one and keeps the others.
+    let mut store = CacheStore::load_in_dir(base_dir)?;
+    store.insert("task-0".to_string(), test_entry("abcd"));
+    store.save_in_dir(base_dir)?;
+    let store = CacheStore::load_in_dir(base_dir)?;
+    assert_eq!(store.tasks.len(), 8);
+    assert_eq!(store.tasks["task-0"].fingerprint, "abcd");
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_cache_store_3() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let path = cache_path_in_dir(temp_dir.path());
+    let corrupt_path = path.with_extension("json.corrupt");
+    std::fs::create_dir_all(temp_dir.path().join(".mk"))?;
+
+    // Entries that still parse are kept.
+    std::fs::write(
+      &path,
+      format!(
+        r#"{{"version":{},"tasks":{{"build":{},"lint":{{"fingerprint":7}}}}}}"#,
+        CACHE_FORMAT_VERSION,
+        serde_json::to_string(&test_entry("00ff"))?
+      ),
+    )?;
+    let store = CacheStore::load_in_dir(temp_dir.path())?;
+    assert_eq!(store.tasks.len(), 1);
+    assert_eq!(store.tasks["build"].fingerprint, "00ff");
+    assert!(corrupt_path.exists());
+    assert!(!path.exists());
+
+    // A torn file is moved aside and the next save starts a new one.
+    std::fs::write(&path, r#"{"version":2,"tasks":{"bui"#)?;
+    let mut store = CacheStore::load_in_dir(temp_dir.path())?;
+    assert!(store.tasks.is_empty());
+    store.insert("build".to_string(), test_entry("00ff"));
+    store.save_in_dir(temp_dir.path())?;
+    assert_eq!(CacheStore::load_in_dir(temp_dir.path())?.tasks.len(), 1);
+
+    Ok(())
+  }
+}
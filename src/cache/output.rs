@@ -0,0 +1,68 @@
+use std::io::{
+  Read as _,
+  Write as _,
+};
+
+use base64::Engine as _;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+/// The output a command printed when its task last ran, replayed when the task
+/// is skipped from the cache
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommandOutput {
+  /// The command, shortened to its first line
+  pub command: String,
+  pub lines: Vec<String>,
+  /// Whether the command failed and its error was ignored
+  #[serde(default)]
+  pub failed: bool,
+}
+
+/// The output of the commands of a task, as gzip compressed JSON encoded with
+/// base64 so it can be kept in the cache file
+pub fn compress_output(outputs: &[CommandOutput]) -> anyhow::Result<String> {
+  let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+  encoder.write_all(&serde_json::to_vec(outputs)?)?;
+  Ok(base64::engine::general_purpose::STANDARD.encode(encoder.finish()?))
+}
+
+pub fn decompress_output(data: &str) -> anyhow::Result<Vec<CommandOutput>> {
+  let compressed = base64::engine::general_purpose::STANDARD.decode(data)?;
+  let mut json = Vec::new();
+  GzDecoder::new(compressed.as_slice()).read_to_end(&mut json)?;
+  Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_compress_output_1() -> anyhow::Result<()> {
+    let outputs = vec![
+      CommandOutput {
+        command: "cargo clippy".to_string(),
+        lines: vec!["warning: unused variable".to_string(); 100],
+        failed: false,
+      },
+      CommandOutput {
+        command: "cargo fmt --check".to_string(),
+        lines: Vec::new(),
+        failed: true,
+      },
+    ];
+
+    let data = compress_output(&outputs)?;
+    assert!(data.len() < serde_json::to_string(&outputs)?.len());
+    assert_eq!(decompress_output(&data)?, outputs);
+    assert!(decompress_output("not base64!").is_err());
+
+    Ok(())
+  }
+}
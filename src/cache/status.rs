@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use super::FingerprintComponents;
+
+/// Whether a task would be skipped from the cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheStatus {
+  /// The task does not set `cache.enabled`
+  Disabled,
+  /// The task is skipped and its outputs are up to date
+  Hit,
+  /// The task is skipped after the given number of outputs are restored from
+  /// the artifact store
+  Restorable(usize),
+  /// The task runs for the given reasons
+  Miss(Vec<CacheMiss>),
+}
+
+/// Why a cached task is not skipped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheMiss {
+  /// `--force` bypasses the cache
+  Forced,
+  /// The task declares no `outputs`, so it is never skipped
+  NoOutputs,
+  /// The task did not complete since the cache was cleared
+  NoEntry,
+  /// The entry was written without the parts of its fingerprint
+  NoBreakdown,
+  Definition,
+  EnvAdded(String),
+  EnvRemoved(String),
+  EnvChanged(String),
+  InputAdded(String),
+  InputRemoved(String),
+  InputModified(String),
+  /// An input was modified after the oldest output, with `cache.mode: timestamp`
+  InputNewer(String),
+  EnvFileChanged(String),
+  OutputsChanged,
+  MissingOutput(String),
+}
+
+impl CacheMiss {
+  /// Show the paths of the reason relative to `base_dir` when they are below it
+  pub fn relative_to(self, base_dir: &Path) -> Self {
+    let relative = |path: String| {
+      Path::new(&path)
+        .strip_prefix(base_dir)
+        .map(|relative| relative.to_string_lossy().into_owned())
+        .unwrap_or(path)
+    };
+
+    match self {
+      CacheMiss::InputAdded(path) => CacheMiss::InputAdded(relative(path)),
+      CacheMiss::InputRemoved(path) => CacheMiss::InputRemoved(relative(path)),
+      CacheMiss::InputModified(path) => CacheMiss::InputModified(relative(path)),
+      CacheMiss::InputNewer(path) => CacheMiss::InputNewer(relative(path)),
+      CacheMiss::EnvFileChanged(path) => CacheMiss::EnvFileChanged(relative(path)),
+      CacheMiss::MissingOutput(path) => CacheMiss::MissingOutput(relative(path)),
+      reason => reason,
+    }
+  }
+}
+
+impl fmt::Display for CacheMiss {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CacheMiss::Forced => write!(f, "the cache is bypassed with --force"),
+      CacheMiss::NoOutputs => write!(f, "the task declares no outputs"),
+      CacheMiss::NoEntry => write!(f, "the task has not run since the cache was cleared"),
+      CacheMiss::NoBreakdown => write!(f, "the fingerprint changed, the stored entry has no breakdown"),
+      CacheMiss::Definition => write!(f, "the task definition changed"),
+      CacheMiss::EnvAdded(key) => write!(f, "env var {} was added (value masked)", key),
+      CacheMiss::EnvRemoved(key) => write!(f, "env var {} was removed", key),
+      CacheMiss::EnvChanged(key) => write!(f, "env var {} changed (value masked)", key),
+      CacheMiss::InputAdded(path) => write!(f, "input file added: {}", path),
+      CacheMiss::InputRemoved(path) => write!(f, "input file removed: {}", path),
+      CacheMiss::InputModified(path) => write!(f, "input file modified: {}", path),
+      CacheMiss::InputNewer(path) => write!(f, "input file is newer than the outputs: {}", path),
+      CacheMiss::EnvFileChanged(path) => write!(f, "env file changed: {}", path),
+      CacheMiss::OutputsChanged => write!(f, "the declared outputs changed"),
+      CacheMiss::MissingOutput(path) => write!(f, "output is missing: {}", path),
+    }
+  }
+}
+
+/// What changed between the parts of the stored fingerprint and the current ones
+pub fn diff_components(stored: &FingerprintComponents, current: &FingerprintComponents) -> Vec<CacheMiss> {
+  let mut changes = Vec::new();
+
+  if stored.definition != current.definition {
+    changes.push(CacheMiss::Definition);
+  }
+
+  for (key, change) in diff_maps(&stored.env, &current.env) {
+    changes.push(match change {
+      Change::Added => CacheMiss::EnvAdded(key),
+      Change::Removed => CacheMiss::EnvRemoved(key),
+      Change::Modified => CacheMiss::EnvChanged(key),
+    });
+  }
+
+  for (path, change) in diff_maps(&stored.inputs, &current.inputs) {
+    changes.push(match change {
+      Change::Added => CacheMiss::InputAdded(path),
+      Change::Removed => CacheMiss::InputRemoved(path),
+      Change::Modified => CacheMiss::InputModified(path),
+    });
+  }
+
+  for (path, _) in diff_maps(&stored.env_files, &current.env_files) {
+    changes.push(CacheMiss::EnvFileChanged(path));
+  }
+
+  if stored.outputs != current.outputs {
+    changes.push(CacheMiss::OutputsChanged);
+  }
+
+  changes
+}
+
+enum Change {
+  Added,
+  Removed,
+  Modified,
+}
+
+/// The keys that were added, removed or changed, in key order. A value of
+/// `missing` counts as absent.
+fn diff_maps(stored: &BTreeMap<String, String>, current: &BTreeMap<String, String>) -> Vec<(String, Change)> {
+  let present =
+    |map: &BTreeMap<String, String>, key: &str| map.get(key).filter(|value| *value != "missing").cloned();
+
+  let mut keys: Vec<_> = stored.keys().chain(current.keys()).collect();
+  keys.sort();
+  keys.dedup();
+
+  keys
+    .into_iter()
+    .filter_map(|key| {
+      let change = match (present(stored, key), present(current, key)) {
+        (None, Some(_)) => Change::Added,
+        (Some(_), None) => Change::Removed,
+        (Some(before), Some(after)) if before != after => Change::Modified,
+        _ => return None,
+      };
+      Some((key.clone(), change))
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_diff_components_1() {
+    let stored = FingerprintComponents {
+      definition: "d1".to_string(),
+      env: BTreeMap::from([
+        ("PROFILE".to_string(), "a".to_string()),
+        ("TOKEN".to_string(), "b".to_string()),
+      ]),
+      inputs: BTreeMap::from([
+        ("src/lib.rs".to_string(), "file:1".to_string()),
+        ("src/old.rs".to_string(), "file:2".to_string()),
+        ("build.rs".to_string(), "missing".to_string()),
+      ]),
+      env_files: BTreeMap::from([(".env".to_string(), "file:3".to_string())]),
+      outputs: vec!["dist".to_string()],
+    };
+    assert!(diff_components(&stored, &stored).is_empty());
+
+    let current = FingerprintComponents {
+      definition: "d2".to_string(),
+      env: BTreeMap::from([
+        ("PROFILE".to_string(), "c".to_string()),
+        ("CI".to_string(), "d".to_string()),
+      ]),
+      inputs: BTreeMap::from([
+        ("src/lib.rs".to_string(), "file:4".to_string()),
+        ("build.rs".to_string(), "file:5".to_string()),
+      ]),
+      env_files: BTreeMap::from([(".env".to_string(), "file:6".to_string())]),
+      outputs: vec!["dist".to_string()],
+    };
+    assert_eq!(
+      diff_components(&stored, &current),
+      vec![
+        CacheMiss::Definition,
+        CacheMiss::EnvAdded("CI".to_string()),
+        CacheMiss::EnvChanged("PROFILE".to_string()),
+        CacheMiss::EnvRemoved("TOKEN".to_string()),
+        CacheMiss::InputAdded("build.rs".to_string()),
+        CacheMiss::InputModified("src/lib.rs".to_string()),
+        CacheMiss::InputRemoved("src/old.rs".to_string()),
+        CacheMiss::EnvFileChanged(".env".to_string()),
+      ]
+    );
+    assert_eq!(
+      CacheMiss::EnvChanged("TOKEN".to_string()).to_string(),
+      "env var TOKEN changed (value masked)"
+    );
+    assert_eq!(
+      CacheMiss::InputModified("/repo/src/lib.rs".to_string())
+        .relative_to(Path::new("/repo"))
+        .to_string(),
+      "input file modified: src/lib.rs"
+    );
+  }
+}
@@ -0,0 +1,109 @@
+use std::fs;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::time::SystemTime;
+
+use super::{
+  collect_files,
+  CacheMiss,
+};
+
+/// Why the outputs are out of date with their inputs, comparing modification
+/// times like make does. Empty when every output exists and no input was
+/// modified after the oldest output.
+pub fn compare_timestamps(inputs: &[PathBuf], outputs: &[PathBuf]) -> anyhow::Result<Vec<CacheMiss>> {
+  if outputs.is_empty() {
+    return Ok(vec![CacheMiss::NoOutputs]);
+  }
+
+  let mut misses = Vec::new();
+  let mut oldest_output: Option<SystemTime> = None;
+  for output in outputs {
+    match oldest_modified(output)? {
+      Some(modified) => oldest_output = Some(oldest_output.map_or(modified, |oldest| oldest.min(modified))),
+      None => misses.push(CacheMiss::MissingOutput(output.to_string_lossy().into_owned())),
+    }
+  }
+  let Some(oldest_output) = oldest_output.filter(|_| misses.is_empty()) else {
+    return Ok(misses);
+  };
+
+  let mut files = Vec::new();
+  for input in inputs {
+    collect_files(input, &mut files)?;
+  }
+  for file in files {
+    if fs::metadata(&file)?.modified()? > oldest_output {
+      misses.push(CacheMiss::InputNewer(file.to_string_lossy().into_owned()));
+    }
+  }
+  Ok(misses)
+}
+
+/// The modification time of a file, or of the oldest file in a directory.
+/// `None` when the path does not exist.
+fn oldest_modified(path: &Path) -> anyhow::Result<Option<SystemTime>> {
+  let Ok(metadata) = fs::metadata(path) else {
+    return Ok(None);
+  };
+
+  let mut files = Vec::new();
+  collect_files(path, &mut files)?;
+  if !metadata.is_dir() || files.is_empty() {
+    return Ok(Some(metadata.modified()?));
+  }
+
+  let mut oldest: Option<SystemTime> = None;
+  for file in files {
+    let modified = fs::metadata(&file)?.modified()?;
+    oldest = Some(oldest.map_or(modified, |oldest| oldest.min(modified)));
+  }
+  Ok(oldest)
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs::File;
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn test_compare_timestamps_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let input = temp_dir.path().join("main.c");
+    let output = temp_dir.path().join("main.o");
+    fs::write(&input, "int main() {}\n")?;
+    let inputs = vec![input.clone()];
+    let outputs = vec![output.clone()];
+
+    assert_eq!(
+      compare_timestamps(&inputs, &outputs)?,
+      vec![CacheMiss::MissingOutput(output.to_string_lossy().into_owned())]
+    );
+
+    fs::write(&output, "")?;
+    let now = SystemTime::now();
+    File::options()
+      .write(true)
+      .open(&input)?
+      .set_modified(now - Duration::from_secs(60))?;
+    File::options().write(true).open(&output)?.set_modified(now)?;
+    assert!(compare_timestamps(&inputs, &outputs)?.is_empty());
+
+    File::options()
+      .write(true)
+      .open(&input)?
+      .set_modified(now + Duration::from_secs(60))?;
+    assert_eq!(
+      compare_timestamps(&inputs, &outputs)?,
+      vec![CacheMiss::InputNewer(input.to_string_lossy().into_owned())]
+    );
+
+    assert_eq!(compare_timestamps(&inputs, &[])?, vec![CacheMiss::NoOutputs]);
+
+    Ok(())
+  }
+}
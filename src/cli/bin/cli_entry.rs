@@ -12,13 +12,23 @@ use clap::{
 };
 use clap_complete::Shell;
 use console::style;
+use indicatif::{
+  HumanBytes,
+  HumanDuration,
+};
+use mk_lib::cache::CacheStatus;
 use mk_lib::file::ToUtf8 as _;
 use mk_lib::schema::{
-  run_task_by_name,
+  run_task_invocations,
+  split_task_invocations,
+  task_cache_status,
+  watch_task_invocations,
   Task,
   TaskContext,
+  TaskInvocation,
   TaskPlan,
   TaskRoot,
+  TaskRunOutcome,
 };
 use mk_lib::version::get_version_digits;
 use once_cell::sync::Lazy;
@@ -51,6 +61,15 @@ struct Args {
   )]
   config: String,
 
+  #[arg(
+    long,
+    global = true,
+    default_value = "auto",
+    value_parser = ["auto", "tty", "plain", "none"],
+    help = "How to render task progress, `auto` uses `plain` when stdout is not a terminal"
+  )]
+  progress: String,
+
   // Waiting for the dynamic completion to be implemented
   // Tracking can be found here:
   // - https://github.com/clap-rs/clap/issues/3166
@@ -61,6 +80,20 @@ struct Args {
   #[arg(help = "The task name to run", value_hint = clap::ValueHint::Other)]
   task_name: Option<String>,
 
+  #[arg(
+    help = "Arguments passed to the task, or more tasks to run",
+    allow_hyphen_values = true,
+    requires = "task_name"
+  )]
+  task_args: Vec<String>,
+
+  #[arg(
+    last = true,
+    help = "Extra arguments forwarded to the task commands",
+    requires = "task_name"
+  )]
+  extra_args: Vec<String>,
+
   #[command(subcommand)]
   command: Option<Command>,
 }
@@ -75,11 +108,25 @@ enum Command {
     #[arg(help = "Optional output path for the created config file")]
     output: Option<String>,
   },
-  #[command(visible_aliases = ["r"], arg_required_else_help = true, about = "Run specific tasks")]
+  #[command(
+    visible_aliases = ["r"],
+    arg_required_else_help = true,
+    disable_version_flag = true,
+    about = "Run specific tasks"
+  )]
   Run {
     #[arg(required = true, help = "The task name to run", value_hint = clap::ValueHint::Other)]
     task_name: String,
 
+    #[arg(
+      help = "Arguments passed to the task, or more tasks to run",
+      allow_hyphen_values = true
+    )]
+    task_args: Vec<String>,
+
+    #[arg(last = true, help = "Extra arguments forwarded to the task commands")]
+    extra_args: Vec<String>,
+
     #[arg(long, help = "Print the resolved task plan without executing commands")]
     dry_run: bool,
 
@@ -88,6 +135,22 @@ enum Command {
 
     #[arg(long, help = "Emit newline-delimited JSON execution events")]
     json_events: bool,
+
+    #[arg(long, help = "Run independent tasks and dependencies at the same time")]
+    parallel: bool,
+
+    #[arg(short, long, help = "Run up to N tasks and dependencies at the same time")]
+    jobs: Option<usize>,
+
+    #[arg(
+      long,
+      conflicts_with = "dry_run",
+      help = "Run the tasks again when their inputs or the config file change"
+    )]
+    watch: bool,
+
+    #[arg(long, help = "Report why cached tasks are not skipped")]
+    explain: bool,
   },
   #[command(visible_aliases = ["ls"], about = "List all available tasks")]
   List {
@@ -107,11 +170,20 @@ enum Command {
     #[arg(long, help = "Show validation results in JSON format")]
     json: bool,
   },
-  #[command(about = "Show the resolved execution plan for a task")]
+  #[command(
+    disable_version_flag = true,
+    about = "Show the resolved execution plan for a task"
+  )]
   Plan {
     #[arg(required = true, help = "The task name to inspect", value_hint = clap::ValueHint::Other)]
     task_name: String,
 
+    #[arg(help = "Arguments passed to the task", allow_hyphen_values = true)]
+    task_args: Vec<String>,
+
+    #[arg(last = true, help = "Extra arguments forwarded to the task commands")]
+    extra_args: Vec<String>,
+
     #[arg(long, help = "Show the plan in JSON format")]
     json: bool,
   },
@@ -122,10 +194,42 @@ enum Command {
   Update,
   #[command(about = "Remove mk task cache metadata")]
   CleanCache,
+  #[command(arg_required_else_help = true, about = "Manage the task cache")]
+  Cache {
+    #[command(subcommand)]
+    command: CacheCommand,
+  },
   #[command(about = "Print the JSON Schema for the task configuration file")]
   Schema,
 }
 
+/// The cache subcommands
+#[derive(Debug, Subcommand)]
+enum CacheCommand {
+  #[command(about = "Trim the artifact store to its size limit, evicting the least recently used outputs")]
+  Gc {
+    #[arg(
+      long,
+      help = "The size to trim the store to, e.g. 500MB. Defaults to `cache.max_size` or 1GB"
+    )]
+    max_size: Option<String>,
+  },
+  #[command(about = "Remove the cache metadata and the artifact store")]
+  Clean,
+  #[command(about = "Show whether cached tasks are up to date and why they would run")]
+  Status {
+    #[arg(help = "The task to check, all cached tasks when omitted", value_hint = clap::ValueHint::Other)]
+    task_name: Option<String>,
+
+    #[arg(
+      help = "Arguments passed to the task",
+      allow_hyphen_values = true,
+      requires = "task_name"
+    )]
+    task_args: Vec<String>,
+  },
+}
+
 /// The CLI entry
 pub(super) struct CliEntry {
   args: Args,
@@ -201,14 +305,50 @@ impl CliEntry {
       },
       Some(Command::Run {
         task_name,
+        task_args,
+        extra_args,
         dry_run,
         force,
         json_events,
+        parallel,
+        jobs,
+        watch,
+        explain,
       }) => {
-        if *dry_run {
-          self.print_plan(task_name, false)?;
+        let task_args = Self::join_task_args(task_args, extra_args);
+        let mut invocations = split_task_invocations(&self.task_root, task_name, &task_args);
+        let dry_run = self.take_flags(&mut invocations, "dry-run") || *dry_run;
+        let force = self.take_flags(&mut invocations, "force") || *force;
+        let json_events = self.take_flags(&mut invocations, "json-events") || *json_events;
+        let parallel = self.take_flags(&mut invocations, "parallel") || *parallel;
+        let watch = self.take_flags(&mut invocations, "watch") || *watch;
+        let explain = self.take_flags(&mut invocations, "explain") || *explain;
+        let progress = self
+          .take_options(&mut invocations, "progress", None)
+          .unwrap_or_else(|| self.args.progress.clone());
+        let jobs = match self.take_options(&mut invocations, "jobs", Some('j')) {
+          Some(jobs) => Some(
+            jobs
+              .parse::<usize>()
+              .map_err(|_| anyhow::anyhow!("Invalid value '{}' for '--jobs' - expected a number", jobs))?,
+          ),
+          None => *jobs,
+        };
+        let jobs = match jobs {
+          Some(0) => anyhow::bail!("Invalid value '0' for '--jobs' - expected at least 1"),
+          Some(jobs) => jobs,
+          None if parallel => std::thread::available_parallelism().map_or(1, usize::from),
+          None => 1,
+        };
+
+        if dry_run {
+          for invocation in &invocations {
+            self.print_plan(&invocation.task_name, &invocation.args, false)?;
+          }
+        } else if watch {
+          self.watch_tasks(&invocations, force, json_events, explain, jobs, &progress)?;
         } else {
-          self.run_task(task_name, *force, *json_events)?;
+          self.run_tasks(&invocations, force, json_events, explain, jobs, &progress)?;
         }
       },
       Some(Command::List { plain, json }) => {
@@ -220,8 +360,18 @@ impl CliEntry {
       Some(Command::Validate { json }) => {
         self.validate_config(*json)?;
       },
-      Some(Command::Plan { task_name, json }) => {
-        self.print_plan(task_name, *json)?;
+      Some(Command::Plan {
+        task_name,
+        task_args,
+        extra_args,
+        json,
+      }) => {
+        let mut invocation = TaskInvocation {
+          task_name: task_name.clone(),
+          args: Self::join_task_args(task_args, extra_args),
+        };
+        let json = self.take_flag(&mut invocation, "json") || *json;
+        self.print_plan(task_name, &invocation.args, json)?;
       },
       Some(Command::Secrets(secrets)) => {
         secrets.execute()?;
@@ -229,17 +379,32 @@ impl CliEntry {
       Some(Command::Update) => {
         self.update_mk()?;
       },
-      Some(Command::CleanCache) => {
+      Some(Command::CleanCache)
+      | Some(Command::Cache {
+        command: CacheCommand::Clean,
+      }) => {
         mk_lib::cache::CacheStore::remove_in_dir(&self.task_root.cache_base_dir())?;
         println!("Cache cleared");
       },
+      Some(Command::Cache {
+        command: CacheCommand::Gc { max_size },
+      }) => {
+        self.collect_cache_garbage(max_size.as_deref())?;
+      },
+      Some(Command::Cache {
+        command: CacheCommand::Status { task_name, task_args },
+      }) => {
+        self.print_cache_status(task_name.as_deref(), task_args)?;
+      },
       Some(Command::Schema) => {
         let schema = mk_lib::generate_schema()?;
         println!("{}", schema);
       },
       None => {
         if let Some(task_name) = &self.args.task_name {
-          self.run_task(task_name, false, false)?;
+          let task_args = Self::join_task_args(&self.args.task_args, &self.args.extra_args);
+          let invocations = split_task_invocations(&self.task_root, task_name, &task_args);
+          self.run_tasks(&invocations, false, false, false, 1, &self.args.progress)?;
         } else {
           anyhow::bail!("No subcommand or task name provided. Use `--help` flag for more information.");
         }
@@ -249,6 +414,81 @@ impl CliEntry {
     Ok(())
   }
 
+  fn print_cache_status(&self, task_name: Option<&str>, task_args: &[String]) -> anyhow::Result<()> {
+    let context = TaskContext::new(self.task_root.clone());
+    let task_names = match task_name {
+      Some(task_name) => vec![task_name.to_string()],
+      None => {
+        let mut task_names: Vec<_> = self.task_root.tasks.keys().cloned().collect();
+        task_names.sort();
+        task_names
+      },
+    };
+
+    let mut printed = false;
+    for name in &task_names {
+      let status = match task_cache_status(&context, name, task_args) {
+        Result::Ok(status) => status,
+        Err(e) if task_name.is_some() => return Err(e),
+        Err(e) => {
+          println!("{}: unknown - {:#}", name, e);
+          printed = true;
+          continue;
+        },
+      };
+
+      match status {
+        CacheStatus::Disabled if task_name.is_some() => println!("{}: cache is not enabled", name),
+        CacheStatus::Disabled => continue,
+        CacheStatus::Hit => println!("{}: cached", name),
+        CacheStatus::Restorable(count) => println!(
+          "{}: cached, {} {} restored from the artifact store on the next run",
+          name,
+          count,
+          if count == 1 { "output is" } else { "outputs are" }
+        ),
+        CacheStatus::Miss(reasons) => {
+          println!("{}: not cached", name);
+          for reason in reasons {
+            println!("  - {}", reason);
+          }
+        },
+      }
+      printed = true;
+    }
+
+    if !printed {
+      println!("No tasks use the cache");
+    }
+    Ok(())
+  }
+
+  fn collect_cache_garbage(&self, max_size: Option<&str>) -> anyhow::Result<()> {
+    let max_size = match max_size {
+      Some(max_size) => mk_lib::utils::parse_size(max_size)?,
+      None => self
+        .task_root
+        .cache
+        .as_ref()
+        .and_then(|cache| cache.max_size)
+        .unwrap_or(mk_lib::cache::DEFAULT_ARTIFACT_MAX_SIZE),
+    };
+
+    let base_dir = self.task_root.cache_base_dir();
+    let artifacts = mk_lib::cache::ArtifactStore::in_dir(&base_dir);
+    let report = {
+      let _lock = mk_lib::cache::CacheLock::acquire_in_dir(&base_dir)?;
+      artifacts.gc(max_size)?
+    };
+    println!(
+      "Removed {} cached artifacts, freed {}, {} kept",
+      report.removed_artifacts,
+      HumanBytes(report.removed_bytes),
+      HumanBytes(report.kept_bytes)
+    );
+    Ok(())
+  }
+
   fn update_mk(&self) -> anyhow::Result<()> {
     println!("Checking for updates...");
     let current_version = VERSION.as_str();
@@ -307,11 +547,206 @@ impl CliEntry {
     Ok(())
   }
 
-  /// Run the specified tasks
-  fn run_task(&self, task_name: &str, force: bool, json_events: bool) -> anyhow::Result<()> {
-    assert!(!task_name.is_empty());
-    let context = TaskContext::new_with_options(self.task_root.clone(), force, json_events);
-    run_task_by_name(&context, task_name)
+  /// Run the specified tasks over a shared context, printing a summary when
+  /// more than one task was given.
+  fn run_tasks(
+    &self,
+    invocations: &[TaskInvocation],
+    force: bool,
+    json_events: bool,
+    explain: bool,
+    jobs: usize,
+    progress: &str,
+  ) -> anyhow::Result<()> {
+    assert!(!invocations.is_empty());
+    let mut context = TaskContext::new_with_options(self.task_root.clone(), force, json_events);
+    context.set_progress_mode(progress.parse()?);
+    context.set_explain(explain);
+    let mut outcomes = run_task_invocations(&context, invocations, jobs);
+    if invocations.len() == 1 {
+      return match outcomes.pop() {
+        Some(TaskRunOutcome::Failed(_, e)) => Err(e),
+        _ => Ok(()),
+      };
+    }
+
+    self.print_run_summary(&context, invocations, &outcomes)?;
+
+    let failed: Vec<_> = invocations
+      .iter()
+      .zip(&outcomes)
+      .filter(|(_, outcome)| outcome.is_failed())
+      .map(|(invocation, _)| invocation.task_name.as_str())
+      .collect();
+    if !failed.is_empty() {
+      anyhow::bail!(
+        "{} of {} tasks failed - {}",
+        failed.len(),
+        invocations.len(),
+        failed.join(", ")
+      );
+    }
+
+    Ok(())
+  }
+
+  fn watch_tasks(
+    &self,
+    invocations: &[TaskInvocation],
+    force: bool,
+    json_events: bool,
+    explain: bool,
+    jobs: usize,
+    progress: &str,
+  ) -> anyhow::Result<()> {
+    assert!(!invocations.is_empty());
+    let progress = progress.parse()?;
+    watch_task_invocations(self.task_root.clone(), invocations, jobs, &|task_root| {
+      let mut context = TaskContext::new_with_options(task_root, force, json_events);
+      context.set_progress_mode(progress);
+      context.set_explain(explain);
+      context
+    })
+  }
+
+  fn print_run_summary(
+    &self,
+    context: &TaskContext,
+    invocations: &[TaskInvocation],
+    outcomes: &[TaskRunOutcome],
+  ) -> anyhow::Result<()> {
+    if context.json_events {
+      let tasks: Vec<_> = invocations
+        .iter()
+        .zip(outcomes)
+        .map(|(invocation, outcome)| {
+          let (duration, error) = match outcome {
+            TaskRunOutcome::Succeeded(duration) => (Some(duration), None),
+            TaskRunOutcome::Failed(duration, e) => (Some(duration), Some(e.to_string())),
+            TaskRunOutcome::Skipped => (None, None),
+          };
+          serde_json::json!({
+            "task": invocation.task_name,
+            "status": outcome.status(),
+            "duration_ms": duration.map(|duration| duration.as_millis() as u64),
+            "error": error,
+          })
+        })
+        .collect();
+      return context.emit_event(&serde_json::json!({
+        "event": "run_summary",
+        "success": !outcomes.iter().any(TaskRunOutcome::is_failed),
+        "tasks": tasks,
+      }));
+    }
+
+    println!();
+    println!("{}", style("Summary:").bold().cyan());
+    for (invocation, outcome) in invocations.iter().zip(outcomes) {
+      match outcome {
+        TaskRunOutcome::Succeeded(duration) => println!(
+          "  {} {} ({})",
+          style("✓").green(),
+          invocation.task_name,
+          HumanDuration(*duration)
+        ),
+        TaskRunOutcome::Failed(duration, e) => println!(
+          "  {} {} ({}) - {}",
+          style("✗").red(),
+          invocation.task_name,
+          HumanDuration(*duration),
+          e
+        ),
+        TaskRunOutcome::Skipped => println!("  {} {} (skipped)", style("-").dim(), invocation.task_name),
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Rebuild the task argument list with the values clap collected after `--`.
+  fn join_task_args(task_args: &[String], extra_args: &[String]) -> Vec<String> {
+    let mut args = task_args.to_vec();
+    if !extra_args.is_empty() {
+      args.push("--".to_string());
+      args.extend(extra_args.iter().cloned());
+    }
+    args
+  }
+
+  /// Remove an `mk` flag that was written after the task arguments started.
+  /// Flags that the task declares as its own arguments, or that come after `--`,
+  /// are left for the task.
+  fn take_flag(&self, invocation: &mut TaskInvocation, flag: &str) -> bool {
+    if self.declares_argument(&invocation.task_name, flag) {
+      return false;
+    }
+
+    let flag = format!("--{}", flag);
+    let task_args = &mut invocation.args;
+    let end = task_args
+      .iter()
+      .position(|arg| arg == "--")
+      .unwrap_or(task_args.len());
+    let extra_args = task_args.split_off(end);
+    let count = task_args.len();
+    task_args.retain(|arg| *arg != flag);
+    let found = task_args.len() != count;
+    task_args.extend(extra_args);
+    found
+  }
+
+  /// Remove an `mk` flag from the arguments of every task.
+  fn take_flags(&self, invocations: &mut [TaskInvocation], flag: &str) -> bool {
+    let mut found = false;
+    for invocation in invocations.iter_mut() {
+      found |= self.take_flag(invocation, flag);
+    }
+    found
+  }
+
+  /// Remove an `mk` option such as `--jobs 4`, `--jobs=4` or `-j 4` from the
+  /// arguments of every task, returning the last value given.
+  fn take_options(
+    &self,
+    invocations: &mut [TaskInvocation],
+    option: &str,
+    short: Option<char>,
+  ) -> Option<String> {
+    let flag = format!("--{}", option);
+    let short = short.map(|short| format!("-{}", short));
+    let prefix = format!("--{}=", option);
+    let mut found = None;
+    for invocation in invocations.iter_mut() {
+      if self.declares_argument(&invocation.task_name, option) {
+        continue;
+      }
+
+      let task_args = &mut invocation.args;
+      let mut index = 0;
+      while index < task_args.len() && task_args[index] != "--" {
+        if let Some(value) = task_args[index].strip_prefix(&prefix) {
+          found = Some(value.to_string());
+          task_args.remove(index);
+        } else if (task_args[index] == flag || short.as_ref() == Some(&task_args[index]))
+          && index + 1 < task_args.len()
+        {
+          found = Some(task_args.remove(index + 1));
+          task_args.remove(index);
+        } else {
+          index += 1;
+        }
+      }
+    }
+    found
+  }
+
+  fn declares_argument(&self, task_name: &str, name: &str) -> bool {
+    self
+      .task_root
+      .tasks
+      .get(task_name)
+      .is_some_and(|task| task.arguments().iter().any(|argument| argument.name == name))
   }
 
   /// Build the contents of a new tasks.yaml, including a modeline and auto-detected integrations.
@@ -442,8 +877,8 @@ impl CliEntry {
     Ok(())
   }
 
-  fn print_plan(&self, task_name: &str, json: bool) -> anyhow::Result<()> {
-    let plan = self.task_root.plan_task(task_name)?;
+  fn print_plan(&self, task_name: &str, task_args: &[String], json: bool) -> anyhow::Result<()> {
+    let plan = self.task_root.plan_task_with_args(task_name, task_args)?;
     if json {
       println!("{}", serde_json::to_string_pretty(&plan)?);
     } else {
@@ -471,12 +906,55 @@ impl CliEntry {
           mk_lib::schema::PlannedExecutionMode::Parallel => "parallel",
         }
       );
+      if !step.matrix.is_empty() {
+        let values: Vec<_> = step
+          .matrix
+          .iter()
+          .map(|value| format!("{}={}", value.name, value.value))
+          .collect();
+        println!("   matrix: {}", values.join(", "));
+      }
       if !step.dependencies.is_empty() {
         println!("   depends_on: {}", step.dependencies.join(", "));
       }
+      for dependency in &step.conditional_dependencies {
+        println!(
+          "   depends_on: {} if {}",
+          dependency.name,
+          dependency.condition.summary()
+        );
+      }
+      if let Some(condition) = &step.condition {
+        println!("   if: {}", condition.summary());
+      }
+      if let Some(timeout) = &step.timeout {
+        println!("   timeout: {}", timeout);
+      }
+      for argument in &step.arguments {
+        let value = match &argument.value {
+          Some(value) => format!("= {:?}", value),
+          None if argument.required => "(required, missing)".to_string(),
+          None => "(unset)".to_string(),
+        };
+        let help = argument
+          .help
+          .as_ref()
+          .map(|help| format!(" - {}", help))
+          .unwrap_or_default();
+        println!("   arg: {} {}{}", argument.usage, value, help);
+      }
+      if !step.extra_args.is_empty() {
+        println!("   extra_args: {}", step.extra_args.join(" "));
+      }
       for command in &step.commands {
         println!("   {}", command.summary());
       }
+      for command in &step.on_failure {
+        println!("   on_failure: {}", command.summary());
+      }
+      for command in &step.finally {
+        println!("   finally: {}", command.summary());
+      }
       if let Some(reason) = &step.skipped_reason {
         println!("   skip: {}", reason);
       }
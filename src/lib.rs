@@ -20,6 +20,9 @@ pub mod defaults;
 /// The file module contains the file path handling functions
 pub mod file;
 
+/// The progress module renders task progress for terminals, CI logs and quiet runs
+pub mod progress;
+
 /// The schema module contains the data structures used to represent the tasks
 pub mod schema;
 
@@ -0,0 +1,336 @@
+use std::fmt;
+use std::io::{
+  IsTerminal as _,
+  Write as _,
+};
+use std::str::FromStr;
+use std::sync::atomic::{
+  AtomicBool,
+  Ordering,
+};
+use std::sync::{
+  Arc,
+  Mutex,
+};
+use std::thread::{
+  self,
+  JoinHandle,
+};
+use std::time::{
+  Duration,
+  Instant,
+};
+
+use indicatif::{
+  MultiProgress,
+  ProgressBar,
+  ProgressDrawTarget,
+  ProgressStyle,
+};
+
+use crate::cache::CommandOutput;
+
+/// How task progress is rendered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProgressMode {
+  /// Use `tty` when stdout is a terminal and `plain` otherwise
+  #[default]
+  Auto,
+  /// Animated spinners
+  Tty,
+  /// One line per progress event, for CI logs
+  Plain,
+  /// No progress output, command output is still shown
+  None,
+}
+
+impl FromStr for ProgressMode {
+  type Err = anyhow::Error;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value {
+      "auto" => Ok(ProgressMode::Auto),
+      "tty" => Ok(ProgressMode::Tty),
+      "plain" => Ok(ProgressMode::Plain),
+      "none" => Ok(ProgressMode::None),
+      _ => anyhow::bail!("Invalid progress mode - {}", value),
+    }
+  }
+}
+
+impl fmt::Display for ProgressMode {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProgressMode::Auto => write!(f, "auto"),
+      ProgressMode::Tty => write!(f, "tty"),
+      ProgressMode::Plain => write!(f, "plain"),
+      ProgressMode::None => write!(f, "none"),
+    }
+  }
+}
+
+/// Renders task progress and command output
+pub trait Renderer: Send + Sync {
+  /// Start a progress step of a task, e.g. running its commands
+  fn start(&self, task: &str, label: &str, total: u64) -> Box<dyn ProgressStep>;
+
+  /// Print a line of command output
+  fn println(&self, line: &str);
+
+  /// Stop drawing progress before a command takes over the terminal
+  fn suspend(&self) {}
+}
+
+/// A progress step started by a renderer
+pub trait ProgressStep: Send + Sync {
+  /// Report that the item at `position` (starting at 1) is running
+  fn advance(&self, position: u64);
+
+  /// Update the step message
+  fn set_message(&self, message: &str);
+
+  /// Finish the step. Nested tasks clear the step instead of keeping the message.
+  fn finish(&self, message: &str, clear: bool);
+}
+
+/// Create the renderer for a progress mode
+pub fn create_renderer(mode: ProgressMode) -> Arc<dyn Renderer> {
+  let mode = match mode {
+    ProgressMode::Auto if std::io::stdout().is_terminal() => ProgressMode::Tty,
+    ProgressMode::Auto => ProgressMode::Plain,
+    mode => mode,
+  };
+
+  match mode {
+    ProgressMode::Tty => Arc::new(TtyRenderer::new(MultiProgress::new())),
+    ProgressMode::Plain => Arc::new(PlainRenderer),
+    _ => Arc::new(QuietRenderer),
+  }
+}
+
+/// A renderer that draws nothing and drops command output, used for
+/// `--json-events` so stdout only carries events.
+pub fn hidden_renderer() -> Arc<dyn Renderer> {
+  Arc::new(TtyRenderer::new(MultiProgress::with_draw_target(
+    ProgressDrawTarget::hidden(),
+  )))
+}
+
+/// Wait for the threads printing command output after the command exited, so
+/// the last lines are not lost. Background processes that keep the output open
+/// are not waited for longer than a short grace period.
+pub(crate) fn join_output_threads(handles: Vec<JoinHandle<()>>) {
+  let deadline = Instant::now() + Duration::from_millis(500);
+  for handle in handles {
+    while !handle.is_finished() && Instant::now() < deadline {
+      thread::sleep(Duration::from_millis(5));
+    }
+    if handle.is_finished() {
+      let _ = handle.join();
+    }
+  }
+}
+
+/// Animated spinners drawn with indicatif
+pub struct TtyRenderer {
+  multi: MultiProgress,
+}
+
+impl TtyRenderer {
+  pub fn new(multi: MultiProgress) -> Self {
+    Self { multi }
+  }
+}
+
+impl Renderer for TtyRenderer {
+  fn start(&self, _task: &str, label: &str, total: u64) -> Box<dyn ProgressStep> {
+    // Spinners can be found here:
+    // https://github.com/sindresorhus/cli-spinners/blob/main/spinners.json
+    let style = ProgressStyle::with_template("{spinner:.green} [{prefix:.bold.dim}] {wide_msg:.cyan/blue} ")
+      .unwrap_or_else(|_| ProgressStyle::default_spinner())
+      .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏⦿");
+    let pb = self.multi.add(ProgressBar::new(total));
+    pb.set_style(style);
+    pb.set_prefix(format!("?/{}", total));
+    pb.set_message(label.to_string());
+    pb.enable_steady_tick(Duration::from_millis(80));
+    Box::new(TtyStep { pb, total })
+  }
+
+  fn println(&self, line: &str) {
+    let _ = self.multi.println(line);
+  }
+
+  fn suspend(&self) {
+    self.multi.set_draw_target(ProgressDrawTarget::hidden());
+  }
+}
+
+struct TtyStep {
+  pb: ProgressBar,
+  total: u64,
+}
+
+impl ProgressStep for TtyStep {
+  fn advance(&self, position: u64) {
+    self.pb.set_prefix(format!("{}/{}", position, self.total));
+    self.pb.set_position(position);
+  }
+
+  fn set_message(&self, message: &str) {
+    self.pb.set_message(message.to_string());
+  }
+
+  fn finish(&self, message: &str, clear: bool) {
+    if clear {
+      self.pb.finish_and_clear();
+    } else {
+      self.pb.finish_with_message(message.to_string());
+    }
+  }
+}
+
+/// One line per progress event on stderr, command output on stdout
+pub struct PlainRenderer;
+
+impl Renderer for PlainRenderer {
+  fn start(&self, task: &str, label: &str, total: u64) -> Box<dyn ProgressStep> {
+    Box::new(PlainStep {
+      task: task.to_string(),
+      label: label.trim_end_matches('.').to_string(),
+      total,
+    })
+  }
+
+  fn println(&self, line: &str) {
+    let _ = writeln!(std::io::stdout().lock(), "{}", line);
+  }
+}
+
+struct PlainStep {
+  task: String,
+  label: String,
+  total: u64,
+}
+
+impl ProgressStep for PlainStep {
+  fn advance(&self, position: u64) {
+    eprintln!("[{}] {} ({}/{})", self.task, self.label, position, self.total);
+  }
+
+  fn set_message(&self, _message: &str) {}
+
+  fn finish(&self, message: &str, _clear: bool) {
+    eprintln!("[{}] {}", self.task, message);
+  }
+}
+
+/// No progress output, command output on stdout
+pub struct QuietRenderer;
+
+impl Renderer for QuietRenderer {
+  fn start(&self, _task: &str, _label: &str, _total: u64) -> Box<dyn ProgressStep> {
+    Box::new(QuietStep)
+  }
+
+  fn println(&self, line: &str) {
+    let _ = writeln!(std::io::stdout().lock(), "{}", line);
+  }
+}
+
+struct QuietStep;
+
+impl ProgressStep for QuietStep {
+  fn advance(&self, _position: u64) {}
+
+  fn set_message(&self, _message: &str) {}
+
+  fn finish(&self, _message: &str, _clear: bool) {}
+}
+
+/// Forwards command output to another renderer and keeps a copy of it, so the
+/// output of a cached task can be replayed when the task is skipped
+pub struct RecordingRenderer {
+  inner: Arc<dyn Renderer>,
+  lines: Mutex<Vec<String>>,
+  failed: AtomicBool,
+}
+
+impl RecordingRenderer {
+  pub fn new(inner: Arc<dyn Renderer>) -> Self {
+    Self {
+      inner,
+      lines: Mutex::new(Vec::new()),
+      failed: AtomicBool::new(false),
+    }
+  }
+
+  /// Note that the command failed and its error was ignored
+  pub fn mark_failed(&self) {
+    self.failed.store(true, Ordering::Relaxed);
+  }
+
+  /// The output recorded for the command so far
+  pub fn take_output(&self, command: &str) -> CommandOutput {
+    let lines = self
+      .lines
+      .lock()
+      .map(|mut lines| std::mem::take(&mut *lines))
+      .unwrap_or_default();
+    CommandOutput {
+      command: command.to_string(),
+      lines,
+      failed: self.failed.load(Ordering::Relaxed),
+    }
+  }
+}
+
+impl Renderer for RecordingRenderer {
+  fn start(&self, task: &str, label: &str, total: u64) -> Box<dyn ProgressStep> {
+    self.inner.start(task, label, total)
+  }
+
+  fn println(&self, line: &str) {
+    if let Ok(mut lines) = self.lines.lock() {
+      lines.push(line.to_string());
+    }
+    self.inner.println(line);
+  }
+
+  fn suspend(&self) {
+    self.inner.suspend();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_progress_mode_1() -> anyhow::Result<()> {
+    assert_eq!("auto".parse::<ProgressMode>()?, ProgressMode::Auto);
+    assert_eq!("tty".parse::<ProgressMode>()?, ProgressMode::Tty);
+    assert_eq!("plain".parse::<ProgressMode>()?, ProgressMode::Plain);
+    assert_eq!("none".parse::<ProgressMode>()?, ProgressMode::None);
+    assert!("fancy".parse::<ProgressMode>().is_err());
+    assert_eq!(ProgressMode::Plain.to_string(), "plain");
+    Ok(())
+  }
+
+  #[test]
+  fn test_recording_renderer_1() {
+    let renderer = RecordingRenderer::new(hidden_renderer());
+    renderer.println("Checking mk v0.1.0");
+    renderer.println("warning: unused variable");
+    renderer.mark_failed();
+
+    let output = renderer.take_output("cargo clippy");
+    assert_eq!(output.command, "cargo clippy");
+    assert_eq!(
+      output.lines,
+      vec!["Checking mk v0.1.0", "warning: unused variable"]
+    );
+    assert!(output.failed);
+    assert!(renderer.take_output("cargo clippy").lines.is_empty());
+  }
+}
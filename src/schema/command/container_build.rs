@@ -7,25 +7,36 @@ use std::process::Command as ProcessCommand;
 use std::thread;
 
 use anyhow::Context as _;
-use git2::Repository;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 
 use crate::defaults::default_verbose;
+use crate::progress::join_output_threads;
 use crate::schema::{
   get_output_handler,
+  git_remote_origin,
+  git_revision,
+  interpolate_template_list,
+  interpolate_templates,
   is_shell_command,
-  is_template_command,
+  open_git_repository,
+  use_process_group,
+  wait_for_command,
+  CommandFailed,
   ContainerRuntime,
+  ExpressionScope,
+  RetryPolicy,
   TaskContext,
 };
 use crate::{
-  get_template_command_value,
   handle_output,
   run_shell_command,
 };
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct ContainerBuildArgs {
   /// The image name to build
   pub image_name: String,
@@ -66,11 +77,19 @@ pub struct ContainerBuildArgs {
   pub runtime: Option<ContainerRuntime>,
 }
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct ContainerBuild {
   /// The command to run in the container
   pub container_build: ContainerBuildArgs,
 
+  /// Run the build again when it fails
+  #[serde(default)]
+  pub retry: Option<RetryPolicy>,
+
+  /// Only run the build when this expression is true, e.g. `env.CI == 'true'`
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
+
   /// Show verbose output
   #[serde(default)]
   pub verbose: Option<bool>,
@@ -78,6 +97,34 @@ pub struct ContainerBuild {
 
 #[allow(dead_code)]
 impl ContainerBuild {
+  /// A copy of the build with the templates of its string fields replaced
+  pub fn resolve(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Self> {
+    let args = &self.container_build;
+    let resolve_list = |values: &Option<Vec<String>>| {
+      values
+        .as_ref()
+        .map(|values| interpolate_template_list(values, scope))
+        .transpose()
+    };
+
+    Ok(Self {
+      container_build: ContainerBuildArgs {
+        image_name: interpolate_templates(&args.image_name, scope)?,
+        context: interpolate_templates(&args.context, scope)?,
+        containerfile: args
+          .containerfile
+          .as_ref()
+          .map(|containerfile| interpolate_templates(containerfile, scope))
+          .transpose()?,
+        tags: resolve_list(&args.tags)?,
+        build_args: resolve_list(&args.build_args)?,
+        labels: resolve_list(&args.labels)?,
+        ..args.clone()
+      },
+      ..self.clone()
+    })
+  }
+
   pub fn execute(&self, context: &TaskContext) -> anyhow::Result<()> {
     assert!(!self.container_build.context.is_empty());
 
@@ -155,25 +202,40 @@ impl ContainerBuild {
     cmd.arg(&resolved_context);
 
     let cmd_str = format!("{:?}", cmd);
-    context.multi.println(cmd_str)?;
+    context.renderer.println(&cmd_str);
 
     // Inject environment variables in both container and command
     for (key, value) in context.env_vars.iter() {
       cmd.env(key, value);
     }
 
+    let timeout = context.command_timeout(None);
+    if timeout.is_some() || context.is_cancellable() {
+      use_process_group(&mut cmd);
+    }
+
     log::trace!("Running command: {:?}", cmd);
 
     let mut cmd = cmd.spawn()?;
+    let mut output_handles = Vec::new();
     if verbose {
-      handle_output!(cmd.stdout, context);
-      handle_output!(cmd.stderr, context);
+      output_handles.push(handle_output!(cmd.stdout, context));
+      output_handles.push(handle_output!(cmd.stderr, context));
     }
 
-    let status = cmd.wait()?;
-    if !status.success() {
+    let command = format!("build {}", self.container_build.image_name);
+    let status = wait_for_command(context, &mut cmd, &command, timeout.as_ref(), false)?;
+    join_output_threads(output_handles);
+    if let Some(status) = status.filter(|status| !status.success()) {
       // Note: container build failures are always fatal and do not honor task-level ignore_errors.
-      anyhow::bail!("Container build failed");
+      return Err(
+        CommandFailed {
+          command,
+          message: "Container build failed".to_string(),
+          exit_code: status.code(),
+        }
+        .into(),
+      );
     }
 
     Ok(())
@@ -186,9 +248,6 @@ impl ContainerBuild {
       let mut cmd = context.shell().proc();
       let output = run_shell_command!(tag_in, cmd, verbose);
       Ok(output)
-    } else if is_template_command(tag_in)? {
-      let output = get_template_command_value!(tag_in, context);
-      Ok(output)
     } else {
       Ok(tag_in.to_string())
     }
@@ -223,8 +282,6 @@ impl ContainerBuild {
           let value = if is_shell_command(value)? {
             let mut cmd = context.shell().proc();
             run_shell_command!(value, cmd, verbose)
-          } else if is_template_command(value)? {
-            get_template_command_value!(value, context)
           } else {
             value.to_string()
           };
@@ -238,21 +295,13 @@ impl ContainerBuild {
   }
 
   fn get_git_revision(&self, context: &TaskContext) -> anyhow::Result<String> {
-    let repo = self.open_git_repository(context)?;
-    let head = repo.head().context("Failed to get git HEAD reference")?;
-    let commit = head
-      .peel_to_commit()
-      .context("Failed to resolve git HEAD commit")?;
-    Ok(commit.id().to_string())
+    let repo = open_git_repository(&self.resolved_context(context))?;
+    git_revision(&repo)
   }
 
   fn get_git_remote_origin(&self, context: &TaskContext) -> anyhow::Result<String> {
-    let repo = self.open_git_repository(context)?;
-    let remote = repo
-      .find_remote("origin")
-      .context("Failed to find git remote origin")?;
-    let url = remote.url().context("Failed to get git remote URL")?;
-    Ok(url.to_string())
+    let repo = open_git_repository(&self.resolved_context(context))?;
+    git_remote_origin(&repo)
   }
 
   pub fn resolved_context(&self, context: &TaskContext) -> PathBuf {
@@ -266,16 +315,6 @@ impl ContainerBuild {
       .as_ref()
       .map(|containerfile| context.resolve_from_config(containerfile))
   }
-
-  fn open_git_repository(&self, context: &TaskContext) -> anyhow::Result<Repository> {
-    let resolved_context = self.resolved_context(context);
-    Repository::discover(&resolved_context).with_context(|| {
-      format!(
-        "Failed to open git repository from build context - {}",
-        resolved_context.to_string_lossy()
-      )
-    })
-  }
 }
 
 #[cfg(test)]
@@ -4,10 +4,14 @@ use std::io::{
 };
 use std::process::Command as ProcessCommand;
 use std::thread;
+use std::time::Duration;
 
 use anyhow::Context;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 
 use crate::defaults::{
   default_ignore_errors,
@@ -15,13 +19,27 @@ use crate::defaults::{
 };
 use crate::file::ToUtf8 as _;
 use crate::handle_output;
+use crate::progress::join_output_threads;
 use crate::schema::{
   get_output_handler,
+  interpolate_template_list,
+  interpolate_templates,
+  use_process_group,
+  wait_for_command,
+  CommandFailed,
   ContainerRuntime,
+  ExpressionScope,
+  OutputFile,
+  RetryPolicy,
   TaskContext,
+  OUTPUT_FILE_ENV,
 };
+use crate::utils::deserialize_duration;
+
+/// Where the output file is mounted inside the container
+const CONTAINER_OUTPUT_FILE: &str = "/mk/output";
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct ContainerRun {
   /// The command to run in the container
   pub container_command: Vec<String>,
@@ -37,6 +55,19 @@ pub struct ContainerRun {
   #[serde(default)]
   pub runtime: Option<ContainerRuntime>,
 
+  /// Stop the container after this long, e.g. `30s`, `5m` or `1h30m`
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_duration")]
+  pub timeout: Option<Duration>,
+
+  /// Run the command again when it fails
+  #[serde(default)]
+  pub retry: Option<RetryPolicy>,
+
+  /// Only run the command when this expression is true, e.g. `env.CI == 'true'`
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
+
   /// Ignore errors if the command fails
   #[serde(default)]
   pub ignore_errors: Option<bool>,
@@ -47,6 +78,16 @@ pub struct ContainerRun {
 }
 
 impl ContainerRun {
+  /// A copy of the command with the templates of its string fields replaced
+  pub fn resolve(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Self> {
+    Ok(Self {
+      container_command: interpolate_template_list(&self.container_command, scope)?,
+      image: interpolate_templates(&self.image, scope)?,
+      mounted_paths: interpolate_template_list(&self.mounted_paths, scope)?,
+      ..self.clone()
+    })
+  }
+
   pub fn execute(&self, context: &TaskContext) -> anyhow::Result<()> {
     assert!(!self.image.is_empty());
     assert!(!self.container_command.is_empty());
@@ -71,6 +112,16 @@ impl ContainerRun {
       cmd.arg("-v").arg(mounted_path);
     }
 
+    let output_file = OutputFile::create()?;
+    cmd.arg("-v").arg(format!(
+      "{}:{}:z",
+      output_file.path().to_utf8()?,
+      CONTAINER_OUTPUT_FILE
+    ));
+    cmd
+      .arg("-e")
+      .arg(format!("{}={}", OUTPUT_FILE_ENV, CONTAINER_OUTPUT_FILE));
+
     // Inject environment variables in both container and command
     for (key, value) in context.env_vars.iter() {
       cmd.env(key, value);
@@ -79,17 +130,30 @@ impl ContainerRun {
 
     cmd.arg(&self.image).args(&self.container_command);
 
+    let timeout = context.command_timeout(self.timeout);
+    if timeout.is_some() || context.is_cancellable() {
+      use_process_group(&mut cmd);
+    }
+
     log::trace!("Running command: {:?}", cmd);
 
     let mut cmd = cmd.spawn()?;
+    let mut output_handles = Vec::new();
     if verbose {
-      handle_output!(cmd.stdout, context);
-      handle_output!(cmd.stderr, context);
+      output_handles.push(handle_output!(cmd.stdout, context));
+      output_handles.push(handle_output!(cmd.stderr, context));
     }
 
-    let status = cmd.wait()?;
-    if !status.success() && !ignore_errors {
-      anyhow::bail!("Command failed - {}", self.container_command.join(" "));
+    let command = self.container_command.join(" ");
+    let status = wait_for_command(context, &mut cmd, &command, timeout.as_ref(), ignore_errors)?;
+    join_output_threads(output_handles);
+    if let Some(status) = status.filter(|status| !status.success() && !ignore_errors) {
+      return Err(CommandFailed::new(&command, status.code()).into());
+    }
+    if status.is_some_and(|status| status.success()) {
+      output_file.publish(context)?;
+    } else {
+      context.note_ignored_failure();
     }
 
     Ok(())
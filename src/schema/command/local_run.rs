@@ -9,36 +9,60 @@ use std::process::{
   Stdio,
 };
 use std::thread;
-#[cfg(unix)]
-use std::time::Duration;
+use std::time::{
+  Duration,
+  Instant,
+};
 
 use anyhow::Context as _;
 #[cfg(unix)]
 use console::Term;
-use indicatif::ProgressDrawTarget;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 
 #[cfg(unix)]
 use std::os::fd::AsRawFd as _;
-#[cfg(unix)]
-use std::os::unix::process::CommandExt as _;
 
 use crate::defaults::{
   default_ignore_errors,
   default_verbose,
 };
 use crate::handle_output;
-#[cfg(unix)]
-use crate::schema::ExecutionInterrupted;
+use crate::progress::join_output_threads;
 use crate::schema::{
   get_output_handler,
-  interpolate_template_string,
+  interpolate_templates,
+  kill_process_group,
+  report_timeout,
+  terminate_process_group,
+  use_process_group,
+  wait_for_exit,
+  Cancellation,
+  CommandFailed,
+  CommandTimeout,
+  ExpressionScope,
+  OutputFile,
+  RetryPolicy,
+  SaveOutput,
   Shell,
   TaskContext,
+  OUTPUT_FILE_ENV,
+  TIMEOUT_GRACE_PERIOD,
+};
+#[cfg(unix)]
+use crate::schema::{
+  ExecutionInterrupted,
+  RunCancelled,
+};
+use crate::utils::{
+  deserialize_duration,
+  shell_join,
 };
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct LocalRun {
   /// The command to run
   pub command: String,
@@ -65,13 +89,31 @@ pub struct LocalRun {
   #[serde(default)]
   pub retrigger: Option<bool>,
 
+  /// Stop the command after this long, e.g. `30s`, `5m` or `1h30m`
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_duration")]
+  pub timeout: Option<Duration>,
+
+  /// Run the command again when it fails
+  #[serde(default)]
+  pub retry: Option<RetryPolicy>,
+
+  /// Only run the command when this expression is true, e.g. `env.CI == 'true'`
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
+
   /// Ignore errors if the command fails
   #[serde(default)]
   pub ignore_errors: Option<bool>,
 
-  /// Save the command stdout to a task-scoped output name
+  /// Save the command stdout to a task-scoped output name, optionally parsed
+  /// as `json`, `yaml` or `dotenv`
   #[serde(default)]
-  pub save_output_as: Option<String>,
+  pub save_output_as: Option<SaveOutput>,
+
+  /// Append the arguments passed after `--` to the command, shell-quoted
+  #[serde(default)]
+  pub append_args: Option<bool>,
 
   /// Show verbose output
   #[serde(default)]
@@ -79,10 +121,32 @@ pub struct LocalRun {
 }
 
 impl LocalRun {
+  /// A copy of the command with the templates of its string fields replaced
+  pub fn resolve(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Self> {
+    Ok(Self {
+      command: interpolate_templates(&self.command, scope)?,
+      test: self
+        .test
+        .as_ref()
+        .map(|test| interpolate_templates(test, scope))
+        .transpose()?,
+      work_dir: self
+        .work_dir
+        .as_ref()
+        .map(|work_dir| interpolate_templates(work_dir, scope))
+        .transpose()?,
+      ..self.clone()
+    })
+  }
+
   pub fn execute(&self, context: &TaskContext) -> anyhow::Result<()> {
     assert!(!self.command.is_empty());
 
-    let command = interpolate_template_string(&self.command, context)?;
+    let mut command = self.command.clone();
+    if self.append_args.unwrap_or(false) && !context.extra_args.is_empty() {
+      command.push(' ');
+      command.push_str(&shell_join(&context.extra_args)?);
+    }
     let interactive = self.interactive_enabled();
     let retrigger = self.retrigger_enabled();
     if interactive && retrigger {
@@ -100,20 +164,45 @@ impl LocalRun {
       return Ok(());
     }
 
+    let output_file = OutputFile::create()?;
     if retrigger {
-      return self.execute_with_retrigger(context, &command, ignore_errors, capture_output, verbose);
+      return self.execute_with_retrigger(
+        context,
+        &command,
+        &output_file,
+        ignore_errors,
+        capture_output,
+        verbose,
+      );
     }
 
+    let timeout = context.command_timeout(self.timeout);
     let (status, captured_stdout) = self
-      .spawn_command(context, &command, capture_output, verbose, interactive)?
-      .wait_for_completion()?;
-    self.finish_execution(context, &command, status, captured_stdout, ignore_errors)
+      .spawn_command(
+        context,
+        &command,
+        &output_file,
+        capture_output,
+        verbose,
+        interactive,
+      )?
+      .wait_for_completion(timeout.as_ref(), context.cancellation.as_ref())?;
+    self.finish_execution(
+      context,
+      &command,
+      &output_file,
+      status,
+      timeout,
+      captured_stdout,
+      ignore_errors,
+    )
   }
 
   fn spawn_command(
     &self,
     context: &TaskContext,
     command: &str,
+    output_file: &OutputFile,
     capture_output: bool,
     verbose: bool,
     interactive: bool,
@@ -129,14 +218,14 @@ impl LocalRun {
     if capture_output {
       cmd.stdout(Stdio::piped());
       if interactive {
-        context.multi.set_draw_target(ProgressDrawTarget::hidden());
+        context.renderer.suspend();
         cmd.stdin(Stdio::inherit()).stderr(Stdio::inherit());
       } else {
         cmd.stderr(get_output_handler(verbose));
       }
     } else if verbose {
       if interactive {
-        context.multi.set_draw_target(ProgressDrawTarget::hidden());
+        context.renderer.suspend();
 
         cmd
           .stdin(Stdio::inherit())
@@ -153,34 +242,29 @@ impl LocalRun {
       cmd.current_dir(work_dir);
     }
 
-    #[cfg(unix)]
-    if self.retrigger_enabled() && !interactive {
-      unsafe {
-        cmd.pre_exec(|| {
-          if libc::setpgid(0, 0) != 0 {
-            return Err(std::io::Error::last_os_error());
-          }
-          Ok(())
-        });
-      }
+    // Interactive commands stay in the foreground process group of the terminal.
+    let has_timeout = self.timeout.is_some() || context.deadline.is_some();
+    if (self.retrigger_enabled() || has_timeout || context.is_cancellable()) && !interactive {
+      use_process_group(&mut cmd);
     }
 
     // Inject environment variables
     for (key, value) in context.env_vars.iter() {
       cmd.env(key, value);
     }
+    cmd.env(OUTPUT_FILE_ENV, output_file.path());
 
     let mut child = cmd.spawn()?;
     let stdout_handle = if capture_output {
       let stdout = child.stdout.take().context("Failed to open stdout")?;
-      let multi = context.multi.clone();
+      let renderer = context.renderer.clone();
       Some(thread::spawn(move || -> anyhow::Result<String> {
         let reader = BufReader::new(stdout);
         let mut output = String::new();
         for line in reader.lines() {
           let line = line?;
           if verbose {
-            let _ = multi.println(line.clone());
+            renderer.println(&line);
           }
           output.push_str(&line);
           output.push('\n');
@@ -191,32 +275,49 @@ impl LocalRun {
       None
     };
 
+    let mut output_handles = Vec::new();
     if verbose && !interactive && !capture_output {
-      handle_output!(child.stdout, context);
-      handle_output!(child.stderr, context);
+      output_handles.push(handle_output!(child.stdout, context));
+      output_handles.push(handle_output!(child.stderr, context));
     } else if verbose && !interactive && capture_output {
-      handle_output!(child.stderr, context);
+      output_handles.push(handle_output!(child.stderr, context));
     }
 
-    Ok(SpawnedLocalCommand { child, stdout_handle })
+    Ok(SpawnedLocalCommand {
+      child,
+      stdout_handle,
+      output_handles,
+    })
   }
 
+  #[allow(clippy::too_many_arguments)]
   fn finish_execution(
     &self,
     context: &TaskContext,
     command: &str,
-    status: ExitStatus,
+    output_file: &OutputFile,
+    status: Option<ExitStatus>,
+    timeout: Option<CommandTimeout>,
     captured_stdout: Option<String>,
     ignore_errors: bool,
   ) -> anyhow::Result<()> {
+    let Some(status) = status else {
+      let timeout = timeout.context("Command stopped without a timeout")?;
+      return report_timeout(context, command, &timeout, ignore_errors);
+    };
+
     if !status.success() && !ignore_errors {
-      anyhow::bail!("Command failed - {}", command);
+      return Err(CommandFailed::new(command, status.code()).into());
     }
 
     if status.success() {
-      if let (Some(output_name), Some(output_value)) = (&self.save_output_as, captured_stdout) {
-        context.insert_task_output(output_name.clone(), output_value)?;
+      if let (Some(save_output), Some(output_value)) = (&self.save_output_as, captured_stdout) {
+        let output_value = save_output.parse(command, output_value)?;
+        context.insert_task_output(save_output.name(), output_value)?;
       }
+      output_file.publish(context)?;
+    } else {
+      context.note_ignored_failure();
     }
 
     Ok(())
@@ -226,6 +327,7 @@ impl LocalRun {
     &self,
     context: &TaskContext,
     command: &str,
+    output_file: &OutputFile,
     ignore_errors: bool,
     capture_output: bool,
     verbose: bool,
@@ -234,6 +336,7 @@ impl LocalRun {
       return self.execute_without_retrigger(
         context,
         command,
+        output_file,
         ignore_errors,
         capture_output,
         verbose,
@@ -246,6 +349,7 @@ impl LocalRun {
       return self.execute_without_retrigger(
         context,
         command,
+        output_file,
         ignore_errors,
         capture_output,
         verbose,
@@ -261,16 +365,29 @@ impl LocalRun {
       drain_retrigger_input()?;
 
       loop {
-        let spawned = self.spawn_command(context, command, capture_output, verbose, false)?;
-        match spawned.wait_for_completion_or_retrigger() {
+        let timeout = context.command_timeout(self.timeout);
+        let spawned = self.spawn_command(context, command, output_file, capture_output, verbose, false)?;
+        match spawned.wait_for_completion_or_retrigger(timeout.as_ref(), context.cancellation.as_ref()) {
           Ok(CommandOutcome::Completed {
             status,
             captured_stdout,
           }) => {
-            return self.finish_execution(context, command, status, captured_stdout, ignore_errors);
+            return self.finish_execution(
+              context,
+              command,
+              output_file,
+              Some(status),
+              timeout,
+              captured_stdout,
+              ignore_errors,
+            );
+          },
+          Ok(CommandOutcome::TimedOut) => {
+            return self.finish_execution(context, command, output_file, None, timeout, None, ignore_errors);
           },
           Ok(CommandOutcome::RestartRequested) => {
             let _ = term.write_line("Restarting command...");
+            output_file.reset()?;
           },
           Ok(CommandOutcome::Interrupted) => {
             return Err(ExecutionInterrupted.into());
@@ -281,22 +398,33 @@ impl LocalRun {
     }
   }
 
+  #[allow(clippy::too_many_arguments)]
   fn execute_without_retrigger(
     &self,
     context: &TaskContext,
     command: &str,
+    output_file: &OutputFile,
     ignore_errors: bool,
     capture_output: bool,
     verbose: bool,
     reason: &str,
   ) -> anyhow::Result<()> {
     if !context.json_events {
-      let _ = context.multi.println(reason);
+      context.renderer.println(reason);
     }
+    let timeout = context.command_timeout(self.timeout);
     let (status, captured_stdout) = self
-      .spawn_command(context, command, capture_output, verbose, false)?
-      .wait_for_completion()?;
-    self.finish_execution(context, command, status, captured_stdout, ignore_errors)
+      .spawn_command(context, command, output_file, capture_output, verbose, false)?
+      .wait_for_completion(timeout.as_ref(), context.cancellation.as_ref())?;
+    self.finish_execution(
+      context,
+      command,
+      output_file,
+      status,
+      timeout,
+      captured_stdout,
+      ignore_errors,
+    )
   }
 
   /// Check if the local run task is parallel safe
@@ -320,25 +448,26 @@ impl LocalRun {
     let stderr = get_output_handler(verbose);
 
     if let Some(test) = &self.test {
-      let test = interpolate_template_string(test, context)?;
       let mut cmd = self
         .shell
         .as_ref()
         .map(|shell| shell.proc())
         .unwrap_or_else(|| context.shell().proc());
-      cmd.arg(&test).stdout(stdout).stderr(stderr);
+      cmd.arg(test).stdout(stdout).stderr(stderr);
 
       if let Some(work_dir) = self.resolved_work_dir(context) {
         cmd.current_dir(work_dir);
       }
 
       let mut cmd = cmd.spawn()?;
+      let mut output_handles = Vec::new();
       if verbose {
-        handle_output!(cmd.stdout, context);
-        handle_output!(cmd.stderr, context);
+        output_handles.push(handle_output!(cmd.stdout, context));
+        output_handles.push(handle_output!(cmd.stderr, context));
       }
 
       let status = cmd.wait()?;
+      join_output_threads(output_handles);
 
       log::trace!("Test status: {:?}", status.success());
       if !status.success() {
@@ -371,16 +500,32 @@ impl LocalRun {
 struct SpawnedLocalCommand {
   child: Child,
   stdout_handle: Option<thread::JoinHandle<anyhow::Result<String>>>,
+  output_handles: Vec<thread::JoinHandle<()>>,
 }
 
 impl SpawnedLocalCommand {
-  fn wait_for_completion(mut self) -> anyhow::Result<(ExitStatus, Option<String>)> {
-    let status = self.child.wait()?;
+  /// Wait for the command to exit. The status is `None` if it timed out and was terminated.
+  fn wait_for_completion(
+    mut self,
+    timeout: Option<&CommandTimeout>,
+    cancellation: Option<&Cancellation>,
+  ) -> anyhow::Result<(Option<ExitStatus>, Option<String>)> {
+    let status = match wait_for_exit(&mut self.child, timeout.map(|timeout| timeout.at), cancellation) {
+      Ok(status) => status,
+      Err(e) => {
+        let _ = self.join_stdout_handle();
+        return Err(e);
+      },
+    };
+    if status.is_none() {
+      terminate_process_group(&mut self.child, TIMEOUT_GRACE_PERIOD)?;
+    }
     let captured_stdout = self.join_stdout_handle()?;
     Ok((status, captured_stdout))
   }
 
   fn join_stdout_handle(&mut self) -> anyhow::Result<Option<String>> {
+    join_output_threads(std::mem::take(&mut self.output_handles));
     self
       .stdout_handle
       .take()
@@ -393,7 +538,11 @@ impl SpawnedLocalCommand {
   }
 
   #[cfg(unix)]
-  fn wait_for_completion_or_retrigger(mut self) -> anyhow::Result<CommandOutcome> {
+  fn wait_for_completion_or_retrigger(
+    mut self,
+    timeout: Option<&CommandTimeout>,
+    cancellation: Option<&Cancellation>,
+  ) -> anyhow::Result<CommandOutcome> {
     loop {
       if let Some(status) = self.child.try_wait()? {
         let captured_stdout = self.join_stdout_handle()?;
@@ -403,6 +552,19 @@ impl SpawnedLocalCommand {
         });
       }
 
+      if cancellation.is_some_and(|cancellation| cancellation.is_cancelled()) {
+        self.kill_for_restart()?;
+        let _ = self.child.wait()?;
+        let _ = self.join_stdout_handle()?;
+        return Err(RunCancelled.into());
+      }
+
+      if timeout.is_some_and(|timeout| Instant::now() >= timeout.at) {
+        terminate_process_group(&mut self.child, TIMEOUT_GRACE_PERIOD)?;
+        let _ = self.join_stdout_handle()?;
+        return Ok(CommandOutcome::TimedOut);
+      }
+
       match read_control_byte(Duration::from_millis(100))? {
         Some(b'R' | b'r') => {
           self.kill_for_restart()?;
@@ -425,23 +587,7 @@ impl SpawnedLocalCommand {
 
   #[cfg(unix)]
   fn kill_for_restart(&mut self) -> anyhow::Result<()> {
-    let pid = self.child.id() as i32;
-    let kill_result = unsafe { libc::killpg(pid, libc::SIGKILL) };
-    if kill_result == 0 {
-      return Ok(());
-    }
-
-    let error = std::io::Error::last_os_error();
-    let raw_error = error.raw_os_error();
-    if raw_error == Some(libc::ESRCH) || raw_error == Some(libc::EPERM) {
-      match self.child.kill() {
-        Ok(()) => return Ok(()),
-        Err(child_error) if child_error.kind() == std::io::ErrorKind::InvalidInput => return Ok(()),
-        Err(child_error) => return Err(child_error.into()),
-      }
-    }
-
-    Err(error.into())
+    kill_process_group(&mut self.child)
   }
 }
 
@@ -453,6 +599,7 @@ enum CommandOutcome {
   },
   RestartRequested,
   Interrupted,
+  TimedOut,
 }
 
 #[cfg(unix)]
@@ -5,23 +5,42 @@ use std::io::{
 use std::thread;
 
 use crate::handle_output;
-use crate::schema::get_output_handler;
+use crate::progress::join_output_threads;
+use crate::schema::{
+  evaluate_condition,
+  get_output_handler,
+  interpolate_templates,
+  use_process_group,
+  wait_for_command,
+};
 use anyhow::Context;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 
-use super::TaskContext;
+use super::{
+  ExpressionScope,
+  TaskContext,
+};
 
 mod container_build;
 mod container_run;
 mod container_runtime;
 mod local_run;
+mod output_file;
+mod retry;
+mod save_output;
 mod task_run;
 
 pub use container_runtime::ContainerRuntime;
 pub use local_run::LocalRun;
+pub use output_file::*;
+pub use retry::*;
+pub use save_output::*;
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(untagged)]
 /// A single command entry in a task. Can be a shell string or a structured runner object.
 pub enum CommandRunner {
@@ -33,19 +52,50 @@ pub enum CommandRunner {
 }
 
 impl CommandRunner {
+  /// A copy of the command with the templates of its string fields replaced.
+  /// Task names are kept as they are, so the task graph is known up front.
+  pub fn resolve(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Self> {
+    let command = match self {
+      CommandRunner::ContainerBuild(container_build) => {
+        CommandRunner::ContainerBuild(container_build.resolve(scope)?)
+      },
+      CommandRunner::ContainerRun(container_run) => {
+        CommandRunner::ContainerRun(container_run.resolve(scope)?)
+      },
+      CommandRunner::LocalRun(local_run) => CommandRunner::LocalRun(local_run.resolve(scope)?),
+      CommandRunner::TaskRun(task_run) => CommandRunner::TaskRun(task_run.clone()),
+      CommandRunner::CommandRun(command) => CommandRunner::CommandRun(interpolate_templates(command, scope)?),
+    };
+    Ok(command)
+  }
+
   pub fn execute(&self, context: &TaskContext) -> anyhow::Result<()> {
+    if let Some(condition) = self.condition() {
+      if !evaluate_condition(condition, context)? {
+        context.emit_event(&serde_json::json!({
+          "event": "command_skipped",
+          "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
+          "kind": self.kind(),
+          "reason": "condition_false",
+        }))?;
+        return Ok(());
+      }
+    }
+
+    let command = self.resolve(context)?;
+    command.run(context)
+  }
+
+  fn run(&self, context: &TaskContext) -> anyhow::Result<()> {
     context.emit_event(&serde_json::json!({
       "event": "command_started",
       "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
       "kind": self.kind(),
     }))?;
 
-    let result = match self {
-      CommandRunner::ContainerBuild(container_build) => container_build.execute(context),
-      CommandRunner::ContainerRun(container_run) => container_run.execute(context),
-      CommandRunner::LocalRun(local_run) => local_run.execute(context),
-      CommandRunner::TaskRun(task_run) => task_run.execute(context),
-      CommandRunner::CommandRun(command) => self.execute_command(context, command),
+    let result = match self.retry() {
+      Some(retry) => self.execute_with_retry(context, retry),
+      None => self.execute_once(context),
     };
 
     context.emit_event(&serde_json::json!({
@@ -58,6 +108,77 @@ impl CommandRunner {
     result
   }
 
+  fn execute_once(&self, context: &TaskContext) -> anyhow::Result<()> {
+    match self {
+      CommandRunner::ContainerBuild(container_build) => container_build.execute(context),
+      CommandRunner::ContainerRun(container_run) => container_run.execute(context),
+      CommandRunner::LocalRun(local_run) => local_run.execute(context),
+      CommandRunner::TaskRun(task_run) => task_run.execute(context),
+      CommandRunner::CommandRun(command) => self.execute_command(context, command),
+    }
+  }
+
+  fn execute_with_retry(&self, context: &TaskContext, retry: &RetryPolicy) -> anyhow::Result<()> {
+    let attempts = retry.attempts();
+    let mut attempt = 1;
+    loop {
+      let error = match self.execute_once(context) {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+      };
+
+      if !retry.should_retry(&error) {
+        return Err(error);
+      }
+      if attempt >= attempts {
+        let message = format!("Failed after {} attempts - {}", attempts, error);
+        return Err(error.context(message));
+      }
+
+      attempt += 1;
+      let delay = retry.delay_before(attempt);
+      context.emit_event(&serde_json::json!({
+        "event": "command_retry",
+        "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
+        "kind": self.kind(),
+        "attempt": attempt,
+        "attempts": attempts,
+        "delay_ms": delay.as_millis() as u64,
+        "error": error.to_string(),
+      }))?;
+      log::debug!(
+        "Retrying {} command ({}/{}) - {}",
+        self.kind(),
+        attempt,
+        attempts,
+        error
+      );
+      thread::sleep(delay);
+    }
+  }
+
+  /// The retry policy of the command, if it has one
+  pub fn retry(&self) -> Option<&RetryPolicy> {
+    match self {
+      CommandRunner::ContainerBuild(container_build) => container_build.retry.as_ref(),
+      CommandRunner::ContainerRun(container_run) => container_run.retry.as_ref(),
+      CommandRunner::LocalRun(local_run) => local_run.retry.as_ref(),
+      CommandRunner::TaskRun(task_run) => task_run.retry.as_ref(),
+      CommandRunner::CommandRun(_) => None,
+    }
+  }
+
+  /// The `if` expression of the command, if it has one
+  pub fn condition(&self) -> Option<&str> {
+    match self {
+      CommandRunner::ContainerBuild(container_build) => container_build.condition.as_deref(),
+      CommandRunner::ContainerRun(container_run) => container_run.condition.as_deref(),
+      CommandRunner::LocalRun(local_run) => local_run.condition.as_deref(),
+      CommandRunner::TaskRun(task_run) => task_run.condition.as_deref(),
+      CommandRunner::CommandRun(_) => None,
+    }
+  }
+
   fn execute_command(&self, context: &TaskContext, command: &str) -> anyhow::Result<()> {
     assert!(!command.is_empty());
 
@@ -71,25 +192,53 @@ impl CommandRunner {
     let mut cmd = shell.proc();
     cmd.arg(command).stdout(stdout).stderr(stderr);
 
+    let timeout = context.command_timeout(None);
+    if timeout.is_some() || context.is_cancellable() {
+      use_process_group(&mut cmd);
+    }
+
     // Inject environment variables
     for (key, value) in context.env_vars.iter() {
       cmd.env(key, value);
     }
+    let output_file = OutputFile::create()?;
+    cmd.env(OUTPUT_FILE_ENV, output_file.path());
 
     let mut cmd = cmd.spawn()?;
+    let mut output_handles = Vec::new();
     if verbose {
-      handle_output!(cmd.stdout, context);
-      handle_output!(cmd.stderr, context);
+      output_handles.push(handle_output!(cmd.stdout, context));
+      output_handles.push(handle_output!(cmd.stderr, context));
     }
 
-    let status = cmd.wait()?;
-    if !status.success() && !ignore_errors {
-      anyhow::bail!("Command failed - {}", command);
+    let status = wait_for_command(context, &mut cmd, command, timeout.as_ref(), ignore_errors)?;
+    join_output_threads(output_handles);
+    if let Some(status) = status.filter(|status| !status.success() && !ignore_errors) {
+      return Err(CommandFailed::new(command, status.code()).into());
+    }
+    if status.is_some_and(|status| status.success()) {
+      output_file.publish(context)?;
+    } else {
+      context.note_ignored_failure();
     }
 
     Ok(())
   }
 
+  /// The first line of the command, shown when its output is replayed
+  pub fn label(&self) -> String {
+    let label = match self {
+      CommandRunner::ContainerBuild(container_build) => {
+        format!("container build {}", container_build.container_build.image_name)
+      },
+      CommandRunner::ContainerRun(container_run) => container_run.container_command.join(" "),
+      CommandRunner::LocalRun(local_run) => local_run.command.clone(),
+      CommandRunner::TaskRun(task_run) => format!("task {}", task_run.task),
+      CommandRunner::CommandRun(command) => command.clone(),
+    };
+    label.lines().next().unwrap_or_default().trim().to_string()
+  }
+
   fn kind(&self) -> &'static str {
     match self {
       CommandRunner::ContainerBuild(_) => "container_build",
@@ -228,4 +377,51 @@ mod test {
       Ok(())
     }
   }
+
+  #[test]
+  fn test_command_7() -> anyhow::Result<()> {
+    let yaml = "
+      container_build:
+        image_name: ghcr.io/${{ env.OWNER }}/app
+        context: ${{ env.CONTEXT || '.' }}
+        containerfile: docker/${{ env.FILE }}
+        tags:
+          - ${{ env.TAG }}
+        build_args:
+          - VERSION=${{ env.TAG }}
+    ";
+    let command = serde_yaml::from_str::<CommandRunner>(yaml)?;
+    let mut context = TaskContext::empty();
+    context.extend_env_vars([
+      ("OWNER".to_string(), "acme".to_string()),
+      ("FILE".to_string(), "Containerfile".to_string()),
+      ("TAG".to_string(), "v1".to_string()),
+    ]);
+
+    let CommandRunner::ContainerBuild(container_build) = command.resolve(&context)? else {
+      panic!("Expected CommandRunner::ContainerBuild");
+    };
+    let args = container_build.container_build;
+    assert_eq!(args.image_name, "ghcr.io/acme/app");
+    assert_eq!(args.context, ".");
+    assert_eq!(args.containerfile.as_deref(), Some("docker/Containerfile"));
+    assert_eq!(args.tags, Some(vec!["v1".to_string()]));
+    assert_eq!(args.build_args, Some(vec!["VERSION=v1".to_string()]));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_command_8() -> anyhow::Result<()> {
+    let command = serde_yaml::from_str::<CommandRunner>(r#""cargo build --profile ${{ env.PROFILE }}""#)?;
+    let mut context = TaskContext::empty();
+    context.extend_env_vars([("PROFILE".to_string(), "release".to_string())]);
+
+    let CommandRunner::CommandRun(command) = command.resolve(&context)? else {
+      panic!("Expected CommandRunner::CommandRun");
+    };
+    assert_eq!(command, "cargo build --profile release");
+
+    Ok(())
+  }
 }
@@ -0,0 +1,149 @@
+use std::fs::{
+  self,
+  OpenOptions,
+};
+use std::path::{
+  Path,
+  PathBuf,
+};
+
+use anyhow::Context as _;
+
+use crate::schema::TaskContext;
+
+/// The environment variable pointing commands to their output file
+pub const OUTPUT_FILE_ENV: &str = "MK_OUTPUT";
+
+/// A per-command file that commands write `NAME=VALUE` lines, or multiline
+/// `NAME<<DELIMITER` blocks, to publish task outputs. The file is removed when
+/// dropped.
+pub struct OutputFile {
+  path: PathBuf,
+}
+
+impl OutputFile {
+  pub fn create() -> anyhow::Result<Self> {
+    let path = std::env::temp_dir().join(format!(
+      "mk-output-{}-{:016x}",
+      std::process::id(),
+      rand::random::<u64>()
+    ));
+    OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .open(&path)
+      .with_context(|| format!("Failed to create output file - {}", path.to_string_lossy()))?;
+    Ok(Self { path })
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Discard the outputs written so far, e.g. before restarting the command
+  pub fn reset(&self) -> anyhow::Result<()> {
+    fs::write(&self.path, "")
+      .with_context(|| format!("Failed to reset output file - {}", self.path.to_string_lossy()))
+  }
+
+  /// Read the outputs written by the command and save them as task outputs
+  pub fn publish(&self, context: &TaskContext) -> anyhow::Result<()> {
+    let contents = fs::read_to_string(&self.path)
+      .with_context(|| format!("Failed to read output file - {}", self.path.to_string_lossy()))?;
+    for (name, value) in parse_output_file(&contents)? {
+      context.insert_task_output(name, value)?;
+    }
+    Ok(())
+  }
+}
+
+impl Drop for OutputFile {
+  fn drop(&mut self) {
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+/// Parse the `NAME=VALUE` lines and `NAME<<DELIMITER` blocks of an output file
+pub fn parse_output_file(contents: &str) -> anyhow::Result<Vec<(String, String)>> {
+  let mut outputs = Vec::new();
+  let mut lines = contents.lines();
+  while let Some(line) = lines.next() {
+    if line.trim().is_empty() {
+      continue;
+    }
+
+    let equals = line.find('=');
+    let heredoc = line.find("<<");
+    let (name, value) = match (equals, heredoc) {
+      (_, Some(heredoc)) if equals.map_or(true, |equals| heredoc < equals) => {
+        let name = &line[..heredoc];
+        let delimiter = &line[heredoc + 2..];
+        if delimiter.is_empty() {
+          anyhow::bail!(
+            "Invalid {} line - missing delimiter for '{}'",
+            OUTPUT_FILE_ENV,
+            name
+          );
+        }
+        let mut value_lines = Vec::new();
+        loop {
+          match lines.next() {
+            Some(value_line) if value_line == delimiter => break,
+            Some(value_line) => value_lines.push(value_line),
+            None => anyhow::bail!(
+              "Invalid {} value - '{}' is missing its closing delimiter '{}'",
+              OUTPUT_FILE_ENV,
+              name,
+              delimiter
+            ),
+          }
+        }
+        (name, value_lines.join("\n"))
+      },
+      (Some(equals), _) => (&line[..equals], line[equals + 1..].to_string()),
+      (None, _) => anyhow::bail!(
+        "Invalid {} line - expected NAME=VALUE or NAME<<DELIMITER, got '{}'",
+        OUTPUT_FILE_ENV,
+        line
+      ),
+    };
+
+    if name.is_empty() {
+      anyhow::bail!(
+        "Invalid {} line - missing output name in '{}'",
+        OUTPUT_FILE_ENV,
+        line
+      );
+    }
+    outputs.push((name.to_string(), value));
+  }
+  Ok(outputs)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_output_file_1() -> anyhow::Result<()> {
+    let contents =
+      "version=1.2.3\n\nurl=https://example.com/?a=b\nnotes<<EOF\nfirst line\nsecond = line\nEOF\nempty=\n";
+    let outputs = parse_output_file(contents)?;
+    assert_eq!(
+      outputs,
+      vec![
+        ("version".to_string(), "1.2.3".to_string()),
+        ("url".to_string(), "https://example.com/?a=b".to_string()),
+        ("notes".to_string(), "first line\nsecond = line".to_string()),
+        ("empty".to_string(), String::new()),
+      ]
+    );
+
+    assert!(parse_output_file("version").is_err());
+    assert!(parse_output_file("=1.2.3").is_err());
+    assert!(parse_output_file("notes<<").is_err());
+    assert!(parse_output_file("notes<<EOF\nfirst line\n").is_err());
+
+    Ok(())
+  }
+}
@@ -0,0 +1,153 @@
+use std::fmt;
+use std::time::Duration;
+
+use schemars::JsonSchema;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use crate::schema::{
+  ExecutionInterrupted,
+  RunCancelled,
+};
+use crate::utils::deserialize_duration;
+
+fn default_attempts() -> u32 {
+  3
+}
+
+/// How the delay between attempts grows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryBackoff {
+  /// Wait the same delay before every attempt
+  #[default]
+  Fixed,
+  /// Double the delay after every attempt
+  Exponential,
+}
+
+/// Run a failing command again before giving up
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct RetryPolicy {
+  /// How many times the command runs at most, including the first attempt
+  #[serde(default = "default_attempts")]
+  pub attempts: u32,
+
+  /// How long to wait before the second attempt, e.g. `500ms` or `2s`
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_duration")]
+  pub delay: Option<Duration>,
+
+  /// How the delay grows between attempts
+  #[serde(default)]
+  pub backoff: RetryBackoff,
+
+  /// Only retry when the command exits with one of these codes.
+  /// Any failure is retried when empty.
+  #[serde(default)]
+  pub on_exit_codes: Vec<i32>,
+}
+
+impl RetryPolicy {
+  pub fn attempts(&self) -> u32 {
+    self.attempts.max(1)
+  }
+
+  /// The delay before the given attempt, starting at 2 for the first retry
+  pub fn delay_before(&self, attempt: u32) -> Duration {
+    let delay = self.delay.unwrap_or_default();
+    match self.backoff {
+      RetryBackoff::Fixed => delay,
+      RetryBackoff::Exponential => delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(2))),
+    }
+  }
+
+  /// Whether the error is worth another attempt
+  pub fn should_retry(&self, error: &anyhow::Error) -> bool {
+    if error
+      .chain()
+      .any(|cause| cause.is::<ExecutionInterrupted>() || cause.is::<RunCancelled>())
+    {
+      return false;
+    }
+
+    if self.on_exit_codes.is_empty() {
+      return true;
+    }
+
+    error
+      .chain()
+      .find_map(|cause| cause.downcast_ref::<CommandFailed>())
+      .and_then(|failed| failed.exit_code)
+      .is_some_and(|code| self.on_exit_codes.contains(&code))
+  }
+}
+
+/// A command that exited unsuccessfully
+#[derive(Debug)]
+pub struct CommandFailed {
+  pub command: String,
+  pub message: String,
+  pub exit_code: Option<i32>,
+}
+
+impl CommandFailed {
+  pub fn new(command: &str, exit_code: Option<i32>) -> Self {
+    Self {
+      command: command.to_string(),
+      message: format!("Command failed - {}", command),
+      exit_code,
+    }
+  }
+}
+
+impl fmt::Display for CommandFailed {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for CommandFailed {}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_retry_1() -> anyhow::Result<()> {
+    let yaml = "
+      attempts: 4
+      delay: 100ms
+      backoff: exponential
+      on_exit_codes: [75]
+    ";
+    let retry = serde_yaml::from_str::<RetryPolicy>(yaml)?;
+
+    assert_eq!(retry.attempts(), 4);
+    assert_eq!(retry.delay_before(2), Duration::from_millis(100));
+    assert_eq!(retry.delay_before(3), Duration::from_millis(200));
+    assert_eq!(retry.delay_before(4), Duration::from_millis(400));
+
+    assert!(retry.should_retry(&CommandFailed::new("curl", Some(75)).into()));
+    assert!(!retry.should_retry(&CommandFailed::new("curl", Some(1)).into()));
+    assert!(!retry.should_retry(&anyhow::anyhow!("Command timed out")));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_retry_2() -> anyhow::Result<()> {
+    let retry = serde_yaml::from_str::<RetryPolicy>("delay: 1s")?;
+
+    assert_eq!(retry.attempts(), 3);
+    assert_eq!(retry.backoff, RetryBackoff::Fixed);
+    assert_eq!(retry.delay_before(3), Duration::from_secs(1));
+    assert!(retry.should_retry(&anyhow::anyhow!("Command timed out")));
+    assert!(!retry.should_retry(&ExecutionInterrupted.into()));
+    assert!(!retry.should_retry(&RunCancelled.into()));
+
+    Ok(())
+  }
+}
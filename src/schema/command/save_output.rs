@@ -0,0 +1,242 @@
+use std::fmt;
+
+use anyhow::Context as _;
+use hashbrown::HashMap;
+use schemars::JsonSchema;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+use serde_json::Value as JsonValue;
+
+use crate::utils::parse_env_contents;
+
+/// How the stdout of a command is parsed into an output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputFormat {
+  /// Keep the stdout as it is
+  #[default]
+  Text,
+  /// Parse the stdout as JSON
+  Json,
+  /// Parse the stdout as YAML
+  Yaml,
+  /// Parse the `KEY=VALUE` lines of the stdout
+  Dotenv,
+}
+
+impl fmt::Display for OutputFormat {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      OutputFormat::Text => write!(f, "text"),
+      OutputFormat::Json => write!(f, "json"),
+      OutputFormat::Yaml => write!(f, "yaml"),
+      OutputFormat::Dotenv => write!(f, "dotenv"),
+    }
+  }
+}
+
+/// An output saved with a format, e.g. `{ name: meta, format: json }`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct SaveOutputArgs {
+  /// The name the output is saved as
+  pub name: String,
+
+  /// How the stdout is parsed. Fields of structured outputs are read with
+  /// `${{ outputs.NAME.field }}` or `${{ outputs.NAME.items[0] }}`.
+  #[serde(default)]
+  pub format: OutputFormat,
+}
+
+/// Where the stdout of a command is saved. Either the output name or an object
+/// with `name` and `format`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SaveOutput {
+  Name(String),
+  Structured(SaveOutputArgs),
+}
+
+impl SaveOutput {
+  pub fn name(&self) -> &str {
+    match self {
+      SaveOutput::Name(name) => name,
+      SaveOutput::Structured(args) => &args.name,
+    }
+  }
+
+  pub fn format(&self) -> OutputFormat {
+    match self {
+      SaveOutput::Name(_) => OutputFormat::Text,
+      SaveOutput::Structured(args) => args.format,
+    }
+  }
+
+  /// The value saved for the stdout of a command. Structured formats are
+  /// stored as JSON.
+  pub fn parse(&self, command: &str, stdout: String) -> anyhow::Result<String> {
+    let value = match self.format() {
+      OutputFormat::Text => return Ok(stdout),
+      OutputFormat::Json => serde_json::from_str::<JsonValue>(&stdout).map_err(anyhow::Error::from),
+      OutputFormat::Yaml => serde_yaml::from_str::<JsonValue>(&stdout).map_err(anyhow::Error::from),
+      OutputFormat::Dotenv => {
+        let mut entries: Vec<_> = parse_env_contents(&stdout).into_iter().collect();
+        entries.sort();
+        Ok(JsonValue::Object(
+          entries
+            .into_iter()
+            .map(|(key, value)| (key, JsonValue::String(value)))
+            .collect(),
+        ))
+      },
+    }
+    .with_context(|| {
+      format!(
+        "Failed to parse output '{}' as {} - {}",
+        self.name(),
+        self.format(),
+        command
+      )
+    })?;
+    Ok(serde_json::to_string(&value)?)
+  }
+}
+
+/// Split `meta.items[0].id` into the output name `meta` and the field path
+/// `.items[0].id`
+pub fn split_output_field(reference: &str) -> (&str, &str) {
+  let end = reference.find(['.', '[']).unwrap_or(reference.len());
+  reference.split_at(end)
+}
+
+/// The value of an output reference, reading a field of a structured output
+/// when the reference has a field path. `None` when the output or the field is
+/// not set.
+pub fn lookup_output(outputs: &HashMap<String, String>, reference: &str) -> anyhow::Result<Option<String>> {
+  if let Some(value) = outputs.get(reference) {
+    return Ok(Some(value.clone()));
+  }
+
+  let (name, path) = split_output_field(reference);
+  let Some(value) = outputs.get(name).filter(|_| !path.is_empty()) else {
+    return Ok(None);
+  };
+  let value = serde_json::from_str::<JsonValue>(value).map_err(|_| {
+    anyhow::anyhow!(
+      "Task output '{}' is not structured, save it with `format: json`, `yaml` or `dotenv` to read '{}'",
+      name,
+      reference
+    )
+  })?;
+
+  let field =
+    read_field(&value, path).with_context(|| format!("Invalid output reference '{}'", reference))?;
+  Ok(field.map(|field| match field {
+    JsonValue::String(value) => value.clone(),
+    JsonValue::Null => String::new(),
+    value => value.to_string(),
+  }))
+}
+
+/// Follow a path of `.key` and `[index]` segments
+fn read_field<'a>(value: &'a JsonValue, path: &str) -> anyhow::Result<Option<&'a JsonValue>> {
+  let mut current = value;
+  let mut rest = path;
+  while !rest.is_empty() {
+    let next = if let Some(after) = rest.strip_prefix('.') {
+      let end = after.find(['.', '[']).unwrap_or(after.len());
+      let (key, remaining) = after.split_at(end);
+      if key.is_empty() {
+        anyhow::bail!("empty field name");
+      }
+      rest = remaining;
+      current.get(key)
+    } else if let Some(after) = rest.strip_prefix('[') {
+      let (index, remaining) = after.split_once(']').context("missing ']'")?;
+      let index = index
+        .parse::<usize>()
+        .map_err(|_| anyhow::anyhow!("'{}' is not a list index", index))?;
+      rest = remaining;
+      current.get(index)
+    } else {
+      anyhow::bail!("unexpected '{}'", rest);
+    };
+    match next {
+      Some(value) => current = value,
+      None => return Ok(None),
+    }
+  }
+  Ok(Some(current))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_save_output_1() -> anyhow::Result<()> {
+    let save_output = serde_yaml::from_str::<SaveOutput>("meta")?;
+    assert_eq!(save_output, SaveOutput::Name("meta".to_string()));
+    assert_eq!(save_output.parse("echo", "{ x".to_string())?, "{ x");
+
+    let save_output = serde_yaml::from_str::<SaveOutput>("{ name: meta, format: yaml }")?;
+    assert_eq!(save_output.name(), "meta");
+    assert_eq!(save_output.format(), OutputFormat::Yaml);
+    assert_eq!(
+      save_output.parse("cat meta.yaml", "version: 1.2.0\nitems:\n  - id: a\n".to_string())?,
+      r#"{"items":[{"id":"a"}],"version":"1.2.0"}"#
+    );
+
+    let save_output = serde_yaml::from_str::<SaveOutput>("{ name: env, format: dotenv }")?;
+    assert_eq!(
+      save_output.parse("cat .env", "# comment\nB=2\nA=1\n".to_string())?,
+      r#"{"A":"1","B":"2"}"#
+    );
+
+    let save_output = serde_yaml::from_str::<SaveOutput>("{ name: meta, format: json }")?;
+    let error = save_output
+      .parse("echo '{'", "{".to_string())
+      .expect_err("invalid json");
+    assert_eq!(
+      error.to_string(),
+      "Failed to parse output 'meta' as json - echo '{'"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_lookup_output_1() -> anyhow::Result<()> {
+    let outputs = HashMap::from([
+      (
+        "meta".to_string(),
+        r#"{"version":"1.2.0","items":[{"id":7}],"draft":false,"notes":null}"#.to_string(),
+      ),
+      ("tag".to_string(), "v1".to_string()),
+    ]);
+
+    assert_eq!(lookup_output(&outputs, "tag")?, Some("v1".to_string()));
+    assert_eq!(
+      lookup_output(&outputs, "meta.version")?,
+      Some("1.2.0".to_string())
+    );
+    assert_eq!(
+      lookup_output(&outputs, "meta.items[0].id")?,
+      Some("7".to_string())
+    );
+    assert_eq!(
+      lookup_output(&outputs, "meta.items[0]")?,
+      Some(r#"{"id":7}"#.to_string())
+    );
+    assert_eq!(lookup_output(&outputs, "meta.draft")?, Some("false".to_string()));
+    assert_eq!(lookup_output(&outputs, "meta.notes")?, Some(String::new()));
+    assert_eq!(lookup_output(&outputs, "meta.missing")?, None);
+    assert_eq!(lookup_output(&outputs, "meta.items[3]")?, None);
+    assert_eq!(lookup_output(&outputs, "other.version")?, None);
+    assert!(lookup_output(&outputs, "meta.items[x]").is_err());
+    assert!(lookup_output(&outputs, "tag.value").is_err());
+
+    Ok(())
+  }
+}
@@ -1,5 +1,8 @@
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 
 use crate::defaults::{
   default_ignore_errors,
@@ -7,14 +10,23 @@ use crate::defaults::{
 };
 use crate::schema::{
   run_task_by_name,
+  RetryPolicy,
   TaskContext,
 };
 
-#[derive(Debug, Deserialize, Clone, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct TaskRun {
   /// The name of the task to run
   pub task: String,
 
+  /// Run the task again when it fails
+  #[serde(default)]
+  pub retry: Option<RetryPolicy>,
+
+  /// Only run the task when this expression is true, e.g. `env.CI == 'true'`
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
+
   /// Ignore errors if the task commands fail
   #[serde(default)]
   pub ignore_errors: Option<bool>,
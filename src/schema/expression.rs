@@ -0,0 +1,887 @@
+use std::fmt::Write as _;
+use std::path::PathBuf;
+use std::{
+  fmt,
+  fs,
+};
+
+use chrono::format::{
+  Item,
+  StrftimeItems,
+};
+use chrono::Local;
+
+use sha2::{
+  Digest as _,
+  Sha256,
+};
+
+use crate::cache::expand_patterns_in_dir;
+use crate::secrets::load_secret_value;
+use crate::utils::resolve_path;
+
+use super::{
+  git_value,
+  TaskContext,
+  GIT_FIELDS,
+};
+
+/// The namespaces an expression can read values from
+const NAMESPACES: [&str; 10] = [
+  "env", "secrets", "outputs", "tasks", "args", "matrix", "failure", "git", "mk", "time",
+];
+
+/// The fields of the built-in `mk` namespace
+const MK_FIELDS: [&str; 6] = [
+  "task_name",
+  "config_dir",
+  "invocation_dir",
+  "version",
+  "os",
+  "arch",
+];
+
+/// The format of `time.now`, e.g. `2024-05-01T12:30:00+0200`
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%z";
+
+/// The functions an expression can call
+const FUNCTIONS: [&str; 10] = [
+  "exists",
+  "hashFiles",
+  "lower",
+  "upper",
+  "trim",
+  "replace",
+  "split",
+  "join",
+  "format",
+  "time.now",
+];
+
+/// Where an expression reads its values from
+pub trait ExpressionScope {
+  /// The value of `namespace.name`, `None` when it is not set
+  fn lookup(&self, namespace: &str, name: &str) -> anyhow::Result<Option<String>>;
+
+  /// The directory relative paths of `exists()` and `hashFiles()` are resolved from
+  fn base_dir(&self) -> PathBuf;
+}
+
+/// A value that is only known once the task runs, e.g. the output of a command
+#[derive(Debug)]
+pub struct UnknownUntilRun(pub String);
+
+impl fmt::Display for UnknownUntilRun {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "'{}' is only known when the task runs", self.0)
+  }
+}
+
+impl std::error::Error for UnknownUntilRun {}
+
+/// A parsed expression such as `os == 'linux' && exists('Cargo.lock')` or
+/// `lower(env.TAG || 'latest')`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+  Literal(Value),
+  Variable(String),
+  Call(String, Vec<Expression>),
+  Not(Box<Expression>),
+  Equal(Box<Expression>, Box<Expression>),
+  NotEqual(Box<Expression>, Box<Expression>),
+  And(Box<Expression>, Box<Expression>),
+  Or(Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+  String(String),
+  Bool(bool),
+  List(Vec<String>),
+  /// A variable that is not set, with the error reported when it is used as a string
+  Missing(String),
+}
+
+impl Value {
+  /// `false`, the empty string, an empty list and values that are not set are
+  /// false, everything else is true
+  pub fn is_truthy(&self) -> bool {
+    match self {
+      Value::String(s) => !s.is_empty(),
+      Value::Bool(b) => *b,
+      Value::List(items) => !items.is_empty(),
+      Value::Missing(_) => false,
+    }
+  }
+
+  /// The value as a string. Fails for values that are not set.
+  pub fn into_string(self) -> anyhow::Result<String> {
+    match self {
+      Value::String(s) => Ok(s),
+      Value::Bool(b) => Ok(b.to_string()),
+      Value::List(items) => Ok(items.join(",")),
+      Value::Missing(message) => Err(anyhow::anyhow!(message)),
+    }
+  }
+
+  /// The value compared by `==` and `!=`. Values that are not set are empty.
+  fn comparable(self) -> String {
+    match self {
+      Value::Missing(_) => String::new(),
+      value => value.into_string().unwrap_or_default(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+  String(String),
+  Ident(String),
+  LParen,
+  RParen,
+  Comma,
+  Not,
+  Equal,
+  NotEqual,
+  And,
+  Or,
+}
+
+impl Expression {
+  pub fn parse(input: &str) -> anyhow::Result<Self> {
+    let tokens = tokenize(input).map_err(|e| anyhow::anyhow!("Invalid expression '{}' - {}", input, e))?;
+    let mut parser = Parser { tokens, position: 0 };
+    let expression = parser
+      .parse_or()
+      .and_then(|expression| match parser.peek() {
+        Some(token) => anyhow::bail!("unexpected {}", describe(token)),
+        None => Ok(expression),
+      })
+      .map_err(|e| anyhow::anyhow!("Invalid expression '{}' - {}", input, e))?;
+    expression
+      .check()
+      .map_err(|e| anyhow::anyhow!("Invalid expression '{}' - {}", input, e))?;
+    Ok(expression)
+  }
+
+  /// Whether the expression is true in the given scope
+  pub fn evaluate(&self, scope: &dyn ExpressionScope) -> anyhow::Result<bool> {
+    Ok(self.value(scope)?.is_truthy())
+  }
+
+  /// The variables the expression reads, e.g. `env.TAG`
+  pub fn variables(&self) -> Vec<&str> {
+    match self {
+      Expression::Literal(_) => Vec::new(),
+      Expression::Variable(name) => vec![name.as_str()],
+      Expression::Call(_, args) => args.iter().flat_map(Expression::variables).collect(),
+      Expression::Not(inner) => inner.variables(),
+      Expression::Equal(left, right)
+      | Expression::NotEqual(left, right)
+      | Expression::And(left, right)
+      | Expression::Or(left, right) => {
+        let mut variables = left.variables();
+        variables.extend(right.variables());
+        variables
+      },
+    }
+  }
+
+  pub fn value(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Value> {
+    match self {
+      Expression::Literal(value) => Ok(value.clone()),
+      Expression::Variable(name) => resolve_variable(name, scope),
+      Expression::Call(name, args) => {
+        let args = args
+          .iter()
+          .map(|arg| arg.value(scope))
+          .collect::<anyhow::Result<Vec<_>>>()?;
+        call_function(name, args, scope)
+      },
+      Expression::Not(inner) => Ok(Value::Bool(!inner.evaluate(scope)?)),
+      Expression::Equal(left, right) => Ok(Value::Bool(
+        left.value(scope)?.comparable() == right.value(scope)?.comparable(),
+      )),
+      Expression::NotEqual(left, right) => Ok(Value::Bool(
+        left.value(scope)?.comparable() != right.value(scope)?.comparable(),
+      )),
+      // `&&` and `||` return one of their operands, so `env.TAG || 'latest'`
+      // falls back to `latest` when `TAG` is empty or not set.
+      Expression::And(left, right) => {
+        let left = left.value(scope)?;
+        if left.is_truthy() {
+          right.value(scope)
+        } else {
+          Ok(left)
+        }
+      },
+      Expression::Or(left, right) => {
+        let left = left.value(scope)?;
+        if left.is_truthy() {
+          Ok(left)
+        } else {
+          right.value(scope)
+        }
+      },
+    }
+  }
+
+  /// Check the variables and functions without evaluating anything
+  fn check(&self) -> anyhow::Result<()> {
+    match self {
+      Expression::Literal(_) => Ok(()),
+      Expression::Variable(name) => {
+        if name == "os" || name == "arch" {
+          return Ok(());
+        }
+        match name.split_once('.') {
+          Some(("git", key)) if !GIT_FIELDS.contains(&key) => {
+            anyhow::bail!("unknown git field '{}', use {}", key, GIT_FIELDS.join(", "))
+          },
+          Some(("mk", key)) if !MK_FIELDS.contains(&key) => {
+            anyhow::bail!("unknown mk field '{}', use {}", key, MK_FIELDS.join(", "))
+          },
+          Some(("tasks", key)) if split_task_output_reference(key).is_none() => {
+            anyhow::bail!("invalid task output '{}', use tasks.NAME.outputs.OUTPUT", name)
+          },
+          Some(("time", key)) if key != "now" => {
+            anyhow::bail!("unknown time field '{}', use time.now or time.now('%Y%m%d')", key)
+          },
+          Some((namespace, key)) if NAMESPACES.contains(&namespace) && !key.is_empty() => Ok(()),
+          Some((namespace, _)) if !NAMESPACES.contains(&namespace) => {
+            anyhow::bail!("unknown namespace '{}', use {}", namespace, NAMESPACES.join(", "))
+          },
+          _ => anyhow::bail!(
+            "unknown variable '{}', use os, arch or a namespace like env.NAME",
+            name
+          ),
+        }
+      },
+      Expression::Call(name, args) => {
+        let (min, max) = match name.as_str() {
+          "exists" | "lower" | "upper" | "trim" => (1, 1),
+          "time.now" => (0, 1),
+          "split" => (2, 2),
+          "replace" => (3, 3),
+          "join" => (1, 2),
+          "format" | "hashFiles" => (1, usize::MAX),
+          _ => anyhow::bail!("unknown function '{}', use {}", name, FUNCTIONS.join(", ")),
+        };
+        if args.len() < min || args.len() > max {
+          anyhow::bail!("{}() does not take {} arguments", name, args.len());
+        }
+        args.iter().try_for_each(Expression::check)
+      },
+      Expression::Not(inner) => inner.check(),
+      Expression::Equal(left, right)
+      | Expression::NotEqual(left, right)
+      | Expression::And(left, right)
+      | Expression::Or(left, right) => {
+        left.check()?;
+        right.check()
+      },
+    }
+  }
+}
+
+/// Split `NAME.outputs.OUTPUT` of a `tasks.NAME.outputs.OUTPUT` reference
+pub fn split_task_output_reference(reference: &str) -> Option<(&str, &str)> {
+  reference
+    .rsplit_once(".outputs.")
+    .filter(|(task_name, output_name)| !task_name.is_empty() && !output_name.is_empty())
+}
+
+/// Parse and evaluate an `if:` expression
+pub fn evaluate_condition(condition: &str, scope: &dyn ExpressionScope) -> anyhow::Result<bool> {
+  Expression::parse(condition)?.evaluate(scope).map_err(|e| {
+    let message = format!("Failed to evaluate condition '{}' - {}", condition, e);
+    e.context(message)
+  })
+}
+
+/// Parse and evaluate the expression of a `${{ ... }}` template
+pub fn evaluate_template(expression: &str, scope: &dyn ExpressionScope) -> anyhow::Result<String> {
+  Expression::parse(expression)?.value(scope)?.into_string()
+}
+
+fn resolve_variable(name: &str, scope: &dyn ExpressionScope) -> anyhow::Result<Value> {
+  match name {
+    "os" => Ok(Value::String(std::env::consts::OS.to_string())),
+    "arch" => Ok(Value::String(std::env::consts::ARCH.to_string())),
+    _ => {
+      let (namespace, key) = name.split_once('.').expect("variables are checked when parsing");
+      let value = match namespace {
+        "git" => match git_value(&scope.base_dir(), key) {
+          Ok(value) => value,
+          Err(e) => {
+            let message = format!("Git value '{}' is not available - {}", key, e);
+            return Ok(Value::Missing(message));
+          },
+        },
+        "mk" => match key {
+          "config_dir" => Some(scope.base_dir().to_string_lossy().into_owned()),
+          "invocation_dir" => Some(std::env::current_dir()?.to_string_lossy().into_owned()),
+          "version" => Some(env!("CARGO_PKG_VERSION").to_string()),
+          "os" => Some(std::env::consts::OS.to_string()),
+          "arch" => Some(std::env::consts::ARCH.to_string()),
+          _ => scope.lookup(namespace, key)?,
+        },
+        "time" => Some(format_now(DEFAULT_TIME_FORMAT)?),
+        _ => scope.lookup(namespace, key)?,
+      };
+      match value {
+        Some(value) => Ok(Value::String(value)),
+        None => Ok(Value::Missing(missing_message(namespace, key))),
+      }
+    },
+  }
+}
+
+/// The local time in a chrono `strftime` format
+fn format_now(format: &str) -> anyhow::Result<String> {
+  let items: Vec<Item> = StrftimeItems::new(format).collect();
+  if items.iter().any(|item| matches!(item, Item::Error)) {
+    anyhow::bail!("Invalid time format '{}'", format);
+  }
+
+  let mut result = String::new();
+  write!(result, "{}", Local::now().format_with_items(items.into_iter()))
+    .map_err(|_| anyhow::anyhow!("Invalid time format '{}'", format))?;
+  Ok(result)
+}
+
+fn missing_message(namespace: &str, name: &str) -> String {
+  match namespace {
+    "env" => format!("Environment variable '{}' is not defined", name),
+    "outputs" => format!(
+      "Task output '{}' is not available. Ensure the task that produces it runs before this one.",
+      name
+    ),
+    "args" => format!(
+      "Task argument '{}' is not defined. Declare it in the task `args` section.",
+      name
+    ),
+    "matrix" => format!(
+      "Matrix value '{}' is not defined. Declare it in the task `matrix` section.",
+      name
+    ),
+    "tasks" => match split_task_output_reference(name) {
+      Some((task_name, output_name)) => format!(
+        "Output '{}' of task '{}' is not available. Ensure the task depends on '{}' and saves the output.",
+        output_name, task_name, task_name
+      ),
+      None => format!("'tasks.{}' is not defined", name),
+    },
+    "git" => format!("Git value '{}' is not available for this repository", name),
+    "mk" => format!("'mk.{}' is only set while a task runs", name),
+    _ => format!("'{}.{}' is not defined", namespace, name),
+  }
+}
+
+fn call_function(name: &str, args: Vec<Value>, scope: &dyn ExpressionScope) -> anyhow::Result<Value> {
+  let mut args = args.into_iter();
+  let mut next_string = || -> anyhow::Result<String> {
+    args
+      .next()
+      .map(Value::into_string)
+      .unwrap_or_else(|| Ok(String::new()))
+  };
+
+  let value = match name {
+    "exists" => Value::Bool(resolve_path(&scope.base_dir(), &next_string()?).exists()),
+    "lower" => Value::String(next_string()?.to_lowercase()),
+    "upper" => Value::String(next_string()?.to_uppercase()),
+    "trim" => Value::String(next_string()?.trim().to_string()),
+    "replace" => {
+      let (value, from, to) = (next_string()?, next_string()?, next_string()?);
+      Value::String(value.replace(&from, &to))
+    },
+    "split" => {
+      let (value, separator) = (next_string()?, next_string()?);
+      if value.is_empty() {
+        Value::List(Vec::new())
+      } else {
+        Value::List(value.split(separator.as_str()).map(str::to_string).collect())
+      }
+    },
+    "join" => {
+      let items = match args.next() {
+        Some(Value::List(items)) => items,
+        Some(value) => vec![value.into_string()?],
+        None => Vec::new(),
+      };
+      let separator = match args.next() {
+        Some(value) => value.into_string()?,
+        None => ",".to_string(),
+      };
+      Value::String(items.join(&separator))
+    },
+    "format" => {
+      let template = next_string()?;
+      let values = args.map(Value::into_string).collect::<anyhow::Result<Vec<_>>>()?;
+      Value::String(format_template(&template, &values)?)
+    },
+    "time.now" => {
+      let format = match args.next() {
+        Some(value) => value.into_string()?,
+        None => DEFAULT_TIME_FORMAT.to_string(),
+      };
+      Value::String(format_now(&format)?)
+    },
+    "hashFiles" => {
+      let patterns = args.map(Value::into_string).collect::<anyhow::Result<Vec<_>>>()?;
+      Value::String(hash_files(&scope.base_dir(), &patterns)?)
+    },
+    _ => unreachable!("functions are checked when parsing"),
+  };
+  Ok(value)
+}
+
+/// Replace `{0}`, `{1}`, ... with the values. `{{` and `}}` are literal braces.
+fn format_template(template: &str, values: &[String]) -> anyhow::Result<String> {
+  let mut result = String::with_capacity(template.len());
+  let mut chars = template.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '{' if chars.peek() == Some(&'{') => {
+        chars.next();
+        result.push('{');
+      },
+      '}' if chars.peek() == Some(&'}') => {
+        chars.next();
+        result.push('}');
+      },
+      '{' => {
+        let mut index = String::new();
+        loop {
+          match chars.next() {
+            Some('}') => break,
+            Some(c) => index.push(c),
+            None => anyhow::bail!("Invalid format string '{}' - unclosed '{{'", template),
+          }
+        }
+        let value = index
+          .trim()
+          .parse::<usize>()
+          .ok()
+          .and_then(|index| values.get(index))
+          .ok_or_else(|| {
+            anyhow::anyhow!(
+              "Invalid format string '{}' - no value for {{{}}}",
+              template,
+              index
+            )
+          })?;
+        result.push_str(value);
+      },
+      c => result.push(c),
+    }
+  }
+  Ok(result)
+}
+
+/// The SHA-256 of the files matching the patterns, empty when nothing matches
+fn hash_files(base_dir: &std::path::Path, patterns: &[String]) -> anyhow::Result<String> {
+  let files: Vec<_> = expand_patterns_in_dir(base_dir, patterns)?
+    .into_iter()
+    .filter(|path| path.is_file())
+    .collect();
+  if files.is_empty() {
+    return Ok(String::new());
+  }
+
+  let mut hasher = Sha256::new();
+  for file in &files {
+    let contents = fs::read(file)
+      .map_err(|e| anyhow::anyhow!("Failed to read file - {} - {}", file.to_string_lossy(), e))?;
+    hasher.update(Sha256::digest(&contents));
+  }
+  Ok(hex::encode(hasher.finalize()))
+}
+
+impl ExpressionScope for TaskContext {
+  fn lookup(&self, namespace: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let value = match namespace {
+      "env" => self
+        .env_vars
+        .get(name)
+        .cloned()
+        .or_else(|| std::env::var(name).ok()),
+      "secrets" => Some(load_secret_value(
+        name,
+        &self.task_root.config_base_dir(),
+        self.secret_vault_location.as_deref(),
+        self.secret_keys_location.as_deref(),
+        self.secret_key_name.as_deref(),
+        self.secret_gpg_key_id.as_deref(),
+      )?),
+      "outputs" => self.get_task_output(name)?,
+      "args" => self.task_arguments.get(name).cloned(),
+      "matrix" => self.matrix_values.get(name).cloned(),
+      "tasks" => match split_task_output_reference(name) {
+        Some((task_name, output_name)) => self.get_published_output(task_name, output_name)?,
+        None => None,
+      },
+      "mk" => match name {
+        "task_name" => self.current_task_name.clone(),
+        _ => None,
+      },
+      "failure" => {
+        // Empty unless a command of the task failed.
+        let failure = self.failure.clone().unwrap_or_default();
+        match name {
+          "command" => Some(failure.command),
+          "exit_code" => Some(failure.exit_code.map(|code| code.to_string()).unwrap_or_default()),
+          _ => anyhow::bail!(
+            "Unknown failure field '{}'. Use failure.command or failure.exit_code.",
+            name
+          ),
+        }
+      },
+      _ => anyhow::bail!("Unknown namespace '{}'", namespace),
+    };
+    Ok(value)
+  }
+
+  fn base_dir(&self) -> PathBuf {
+    self.task_root.config_base_dir()
+  }
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  position: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position)
+  }
+
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.position).cloned();
+    self.position += 1;
+    token
+  }
+
+  fn expect(&mut self, expected: Token) -> anyhow::Result<()> {
+    match self.next() {
+      Some(token) if token == expected => Ok(()),
+      Some(token) => anyhow::bail!("expected {}, found {}", describe(&expected), describe(&token)),
+      None => anyhow::bail!("expected {}, found the end", describe(&expected)),
+    }
+  }
+
+  fn parse_or(&mut self) -> anyhow::Result<Expression> {
+    let mut left = self.parse_and()?;
+    while self.peek() == Some(&Token::Or) {
+      self.next();
+      left = Expression::Or(Box::new(left), Box::new(self.parse_and()?));
+    }
+    Ok(left)
+  }
+
+  fn parse_and(&mut self) -> anyhow::Result<Expression> {
+    let mut left = self.parse_unary()?;
+    while self.peek() == Some(&Token::And) {
+      self.next();
+      left = Expression::And(Box::new(left), Box::new(self.parse_unary()?));
+    }
+    Ok(left)
+  }
+
+  fn parse_unary(&mut self) -> anyhow::Result<Expression> {
+    if self.peek() == Some(&Token::Not) {
+      self.next();
+      return Ok(Expression::Not(Box::new(self.parse_unary()?)));
+    }
+    self.parse_comparison()
+  }
+
+  fn parse_comparison(&mut self) -> anyhow::Result<Expression> {
+    let left = self.parse_primary()?;
+    match self.peek() {
+      Some(Token::Equal) => {
+        self.next();
+        Ok(Expression::Equal(Box::new(left), Box::new(self.parse_primary()?)))
+      },
+      Some(Token::NotEqual) => {
+        self.next();
+        Ok(Expression::NotEqual(
+          Box::new(left),
+          Box::new(self.parse_primary()?),
+        ))
+      },
+      _ => Ok(left),
+    }
+  }
+
+  fn parse_primary(&mut self) -> anyhow::Result<Expression> {
+    match self.next() {
+      Some(Token::LParen) => {
+        let expression = self.parse_or()?;
+        self.expect(Token::RParen)?;
+        Ok(expression)
+      },
+      Some(Token::String(value)) => Ok(Expression::Literal(Value::String(value))),
+      Some(Token::Ident(name)) if name == "true" => Ok(Expression::Literal(Value::Bool(true))),
+      Some(Token::Ident(name)) if name == "false" => Ok(Expression::Literal(Value::Bool(false))),
+      Some(Token::Ident(name)) if self.peek() == Some(&Token::LParen) => {
+        self.next();
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::RParen) {
+          loop {
+            args.push(self.parse_or()?);
+            if self.peek() != Some(&Token::Comma) {
+              break;
+            }
+            self.next();
+          }
+        }
+        self.expect(Token::RParen)?;
+        Ok(Expression::Call(name, args))
+      },
+      Some(Token::Ident(name)) if name.chars().all(|c| c.is_ascii_digit() || c == '.') => {
+        Ok(Expression::Literal(Value::String(name)))
+      },
+      Some(Token::Ident(name)) => Ok(Expression::Variable(name)),
+      Some(token) => anyhow::bail!("unexpected {}", describe(&token)),
+      None => anyhow::bail!("unexpected end of expression"),
+    }
+  }
+}
+
+fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+  let mut tokens = Vec::new();
+  let mut chars = input.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    let token = match c {
+      c if c.is_whitespace() => continue,
+      '(' => Token::LParen,
+      ')' => Token::RParen,
+      ',' => Token::Comma,
+      '!' if chars.peek() == Some(&'=') => {
+        chars.next();
+        Token::NotEqual
+      },
+      '!' => Token::Not,
+      '=' if chars.peek() == Some(&'=') => {
+        chars.next();
+        Token::Equal
+      },
+      '&' if chars.peek() == Some(&'&') => {
+        chars.next();
+        Token::And
+      },
+      '|' if chars.peek() == Some(&'|') => {
+        chars.next();
+        Token::Or
+      },
+      '\'' | '"' => {
+        let mut value = String::new();
+        loop {
+          match chars.next() {
+            Some(next) if next == c => break,
+            Some(next) => value.push(next),
+            None => anyhow::bail!("unterminated string"),
+          }
+        }
+        Token::String(value)
+      },
+      c if c.is_ascii_alphanumeric() || c == '_' => {
+        let mut name = c.to_string();
+        while let Some(&next) = chars.peek() {
+          if !(next.is_ascii_alphanumeric() || matches!(next, '_' | '.' | '-' | '/' | '[' | ']')) {
+            break;
+          }
+          name.push(next);
+          chars.next();
+        }
+        Token::Ident(name)
+      },
+      c => anyhow::bail!("unexpected character '{}'", c),
+    };
+    tokens.push(token);
+  }
+
+  Ok(tokens)
+}
+
+fn describe(token: &Token) -> String {
+  match token {
+    Token::String(value) => format!("string '{}'", value),
+    Token::Ident(name) => format!("'{}'", name),
+    Token::LParen => "'('".to_string(),
+    Token::RParen => "')'".to_string(),
+    Token::Comma => "','".to_string(),
+    Token::Not => "'!'".to_string(),
+    Token::Equal => "'=='".to_string(),
+    Token::NotEqual => "'!='".to_string(),
+    Token::And => "'&&'".to_string(),
+    Token::Or => "'||'".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use hashbrown::HashMap;
+
+  use super::*;
+
+  struct TestScope(HashMap<String, String>);
+
+  impl ExpressionScope for TestScope {
+    fn lookup(&self, namespace: &str, name: &str) -> anyhow::Result<Option<String>> {
+      if namespace == "outputs" {
+        return Err(UnknownUntilRun(format!("outputs.{}", name)).into());
+      }
+      Ok(self.0.get(&format!("{}.{}", namespace, name)).cloned())
+    }
+
+    fn base_dir(&self) -> PathBuf {
+      PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    }
+  }
+
+  fn scope() -> TestScope {
+    TestScope(HashMap::from([
+      ("env.CI".to_string(), "true".to_string()),
+      ("args.channel".to_string(), "beta".to_string()),
+    ]))
+  }
+
+  #[test]
+  fn test_expression_1() -> anyhow::Result<()> {
+    let scope = scope();
+    let os_check = format!("os == '{}'", std::env::consts::OS);
+
+    assert!(evaluate_condition(&os_check, &scope)?);
+    assert!(evaluate_condition(
+      "env.CI == 'true' && exists('Cargo.toml')",
+      &scope
+    )?);
+    assert!(evaluate_condition("!exists('missing.lock') || env.CI", &scope)?);
+    assert!(evaluate_condition(
+      "(args.channel != 'stable') && !env.MISSING",
+      &scope
+    )?);
+    assert!(!evaluate_condition("env.MISSING", &scope)?);
+    assert!(!evaluate_condition(
+      "false || args.channel == \"stable\"",
+      &scope
+    )?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_expression_2() {
+    let scope = scope();
+
+    assert!(Expression::parse("os ==").is_err());
+    assert!(Expression::parse("env.CI = 'true'").is_err());
+    assert!(Expression::parse("'unterminated").is_err());
+    assert!(Expression::parse("(os == 'linux'").is_err());
+    assert!(Expression::parse("secret.token == ''").is_err());
+    assert!(Expression::parse("platform == 'linux'").is_err());
+    assert!(Expression::parse("glob('*.rs')").is_err());
+
+    let error = evaluate_condition("outputs.version != ''", &scope).expect_err("outputs are unknown");
+    assert!(error.chain().any(|cause| cause.is::<UnknownUntilRun>()));
+    assert!(!evaluate_condition("false && outputs.version != ''", &scope).expect("short circuit"));
+  }
+
+  #[test]
+  fn test_expression_3() -> anyhow::Result<()> {
+    let scope = scope();
+
+    assert_eq!(evaluate_template("env.TAG || 'latest'", &scope)?, "latest");
+    assert_eq!(evaluate_template("args.channel || 'stable'", &scope)?, "beta");
+    assert_eq!(evaluate_template("env.CI && args.channel", &scope)?, "beta");
+    assert_eq!(evaluate_template("upper(args.channel)", &scope)?, "BETA");
+    assert_eq!(evaluate_template("lower('MiXeD')", &scope)?, "mixed");
+    assert_eq!(evaluate_template("trim('  v1  ')", &scope)?, "v1");
+    assert_eq!(
+      evaluate_template("replace('refs/heads/main', 'refs/heads/', '')", &scope)?,
+      "main"
+    );
+    assert_eq!(
+      evaluate_template("join(split('a,b,c', ','), '-')", &scope)?,
+      "a-b-c"
+    );
+    assert_eq!(evaluate_template("split('a b', ' ')", &scope)?, "a,b");
+    assert_eq!(
+      evaluate_template("format('{0}-{1} {{literal}}', 'app', args.channel)", &scope)?,
+      "app-beta {literal}"
+    );
+    assert_eq!(evaluate_template("env.CI == 'true'", &scope)?, "true");
+
+    let error = evaluate_template("env.TAG", &scope).expect_err("TAG is not set");
+    assert_eq!(error.to_string(), "Environment variable 'TAG' is not defined");
+    assert!(evaluate_template("lower(args.missing)", &scope).is_err());
+    assert!(evaluate_template("format('{1}', 'a')", &scope).is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_expression_4() -> anyhow::Result<()> {
+    let scope = scope();
+
+    let hash = evaluate_template("hashFiles('Cargo.toml', 'src/schema/*.rs')", &scope)?;
+    assert_eq!(hash.len(), 64);
+    assert_eq!(
+      hash,
+      evaluate_template("hashFiles('src/schema/*.rs', 'Cargo.toml')", &scope)?
+    );
+    assert_ne!(hash, evaluate_template("hashFiles('Cargo.toml')", &scope)?);
+    assert_eq!(evaluate_template("hashFiles('missing/**')", &scope)?, "");
+
+    assert!(Expression::parse("hashFiles()").is_err());
+    assert!(Expression::parse("lower('a', 'b')").is_err());
+    assert!(Expression::parse("sha256('a')").is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_expression_5() -> anyhow::Result<()> {
+    let scope = scope();
+
+    assert_eq!(
+      evaluate_template("mk.version", &scope)?,
+      env!("CARGO_PKG_VERSION")
+    );
+    assert_eq!(evaluate_template("mk.os", &scope)?, std::env::consts::OS);
+    assert_eq!(
+      evaluate_template("mk.config_dir", &scope)?,
+      env!("CARGO_MANIFEST_DIR")
+    );
+    assert_eq!(evaluate_template("mk.task_name || 'none'", &scope)?, "none");
+    assert_eq!(evaluate_template("time.now('%Y')", &scope)?.len(), 4);
+    assert!(evaluate_template("time.now", &scope)?.contains('T'));
+    assert!(evaluate_template("time.now('%Q')", &scope).is_err());
+    assert!(evaluate_template("git.dirty == 'true' || git.dirty == 'false'", &scope).is_ok());
+
+    assert!(Expression::parse("git.author").is_err());
+    assert!(Expression::parse("mk.user").is_err());
+    assert!(Expression::parse("time.today").is_err());
+    assert!(Expression::parse("time.now('%Y', 'UTC')").is_err());
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_expression_6() -> anyhow::Result<()> {
+    let mut context = TaskContext::empty();
+    context.set_current_task_name("build");
+
+    assert_eq!(evaluate_template("mk.task_name", &context)?, "build");
+    assert_eq!(context.lookup("mk", "task_name")?.as_deref(), Some("build"));
+    assert_eq!(context.lookup("mk", "user")?, None);
+    assert!(evaluate_template("mk.user", &context).is_err());
+
+    Ok(())
+  }
+}
@@ -0,0 +1,142 @@
+use std::path::Path;
+
+use anyhow::Context as _;
+use git2::{
+  Repository,
+  StatusOptions,
+};
+
+/// The fields of the `git` template namespace
+pub const GIT_FIELDS: [&str; 6] = ["sha", "short_sha", "branch", "tag", "dirty", "remote_url"];
+
+/// Open the git repository containing `dir`
+pub fn open_git_repository(dir: &Path) -> anyhow::Result<Repository> {
+  Repository::discover(dir)
+    .with_context(|| format!("Failed to open git repository - {}", dir.to_string_lossy()))
+}
+
+/// The value of `git.FIELD` for the repository containing `dir`. `None` when
+/// the repository has no such value, e.g. `branch` on a detached HEAD or `tag`
+/// when no tag points at HEAD.
+pub fn git_value(dir: &Path, field: &str) -> anyhow::Result<Option<String>> {
+  let repo = open_git_repository(dir)?;
+  let value = match field {
+    "sha" => Some(git_revision(&repo)?),
+    "short_sha" => {
+      let commit = repo
+        .head()
+        .context("Failed to get git HEAD reference")?
+        .peel_to_commit()
+        .context("Failed to resolve git HEAD commit")?;
+      let short_id = commit
+        .as_object()
+        .short_id()
+        .context("Failed to shorten git HEAD commit id")?;
+      short_id.as_str().map(str::to_string)
+    },
+    "branch" => {
+      let head = repo.head().context("Failed to get git HEAD reference")?;
+      if head.is_branch() {
+        head.shorthand().map(str::to_string)
+      } else {
+        None
+      }
+    },
+    "tag" => git_tag(&repo)?,
+    "dirty" => {
+      let mut options = StatusOptions::new();
+      options.include_untracked(false).include_ignored(false);
+      let statuses = repo
+        .statuses(Some(&mut options))
+        .context("Failed to get git status")?;
+      Some((!statuses.is_empty()).to_string())
+    },
+    "remote_url" => repo
+      .find_remote("origin")
+      .ok()
+      .and_then(|remote| remote.url().map(str::to_string)),
+    _ => anyhow::bail!(
+      "Unknown git field '{}'. Use one of {}.",
+      field,
+      GIT_FIELDS.join(", ")
+    ),
+  };
+  Ok(value)
+}
+
+/// The commit id of HEAD
+pub fn git_revision(repo: &Repository) -> anyhow::Result<String> {
+  let head = repo.head().context("Failed to get git HEAD reference")?;
+  let commit = head
+    .peel_to_commit()
+    .context("Failed to resolve git HEAD commit")?;
+  Ok(commit.id().to_string())
+}
+
+/// The URL of the `origin` remote
+pub fn git_remote_origin(repo: &Repository) -> anyhow::Result<String> {
+  let remote = repo
+    .find_remote("origin")
+    .context("Failed to find git remote origin")?;
+  let url = remote.url().context("Failed to get git remote URL")?;
+  Ok(url.to_string())
+}
+
+/// The first tag, by name, that points at HEAD
+fn git_tag(repo: &Repository) -> anyhow::Result<Option<String>> {
+  let head = repo
+    .head()
+    .context("Failed to get git HEAD reference")?
+    .peel_to_commit()
+    .context("Failed to resolve git HEAD commit")?
+    .id();
+  let mut names: Vec<String> = repo
+    .tag_names(None)
+    .context("Failed to list git tags")?
+    .iter()
+    .flatten()
+    .map(str::to_string)
+    .collect();
+  names.sort();
+
+  for name in names {
+    let Ok(reference) = repo.find_reference(&format!("refs/tags/{}", name)) else {
+      continue;
+    };
+    if reference.peel_to_commit().is_ok_and(|commit| commit.id() == head) {
+      return Ok(Some(name));
+    }
+  }
+  Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_git_value_1() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    assert!(git_value(temp_dir.path(), "sha").is_err());
+
+    let repo = Repository::init(temp_dir.path())?;
+    let signature = git2::Signature::now("mk", "mk@example.com")?;
+    let tree_id = repo.index()?.write_tree()?;
+    let tree = repo.find_tree(tree_id)?;
+    let commit_id = repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])?;
+    let commit = repo.find_commit(commit_id)?;
+    repo.tag_lightweight("v1.0.0", commit.as_object(), false)?;
+
+    let sha = git_value(temp_dir.path(), "sha")?.expect("sha");
+    assert_eq!(sha, commit_id.to_string());
+    let short_sha = git_value(temp_dir.path(), "short_sha")?.expect("short sha");
+    assert!(sha.starts_with(&short_sha));
+    assert!(git_value(temp_dir.path(), "branch")?.is_some());
+    assert_eq!(git_value(temp_dir.path(), "tag")?.as_deref(), Some("v1.0.0"));
+    assert_eq!(git_value(temp_dir.path(), "dirty")?.as_deref(), Some("false"));
+    assert_eq!(git_value(temp_dir.path(), "remote_url")?, None);
+    assert!(git_value(temp_dir.path(), "author").is_err());
+
+    Ok(())
+  }
+}
@@ -1,15 +1,22 @@
 mod command;
+mod expression;
+mod git_info;
 mod include;
 mod plan;
 mod precondition;
+mod scheduler;
 mod shell;
 mod task;
+mod task_argument;
 mod task_context;
 mod task_dependency;
+mod task_matrix;
 mod task_root;
+mod timeout;
 mod use_cargo;
 mod use_npm;
 mod validation;
+mod watch;
 
 use std::collections::HashSet;
 use std::fmt;
@@ -18,12 +25,15 @@ use std::sync::{
   Arc,
   Mutex,
 };
+use std::time::Duration;
 
 use once_cell::sync::Lazy;
 use regex::Regex;
 
 pub type ActiveTasks = Arc<Mutex<HashSet<String>>>;
 pub type CompletedTasks = Arc<Mutex<HashSet<String>>>;
+/// The saved outputs of finished tasks, by task name
+pub type PublishedOutputs = Arc<Mutex<hashbrown::HashMap<String, hashbrown::HashMap<String, String>>>>;
 
 #[derive(Debug)]
 pub struct ExecutionInterrupted;
@@ -36,25 +46,70 @@ impl fmt::Display for ExecutionInterrupted {
 
 impl std::error::Error for ExecutionInterrupted {}
 
+/// A run that was stopped before it finished, e.g. to restart it in watch mode
+#[derive(Debug)]
+pub struct RunCancelled;
+
+impl fmt::Display for RunCancelled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Run cancelled")
+  }
+}
+
+impl std::error::Error for RunCancelled {}
+
+/// A command that ran past its `timeout` or the `timeout` of its task
+#[derive(Debug)]
+pub struct CommandTimedOut {
+  pub command: String,
+  pub timeout: Duration,
+  pub task: Option<String>,
+}
+
+impl fmt::Display for CommandTimedOut {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let timeout = format_duration(self.timeout);
+    match &self.task {
+      Some(task) => write!(
+        f,
+        "Task '{}' timed out after {} - {}",
+        task, timeout, self.command
+      ),
+      None => write!(f, "Command timed out after {} - {}", timeout, self.command),
+    }
+  }
+}
+
+impl std::error::Error for CommandTimedOut {}
+
 pub use command::*;
+pub use expression::*;
+pub use git_info::*;
 pub use include::*;
 pub use plan::*;
 pub use precondition::*;
+pub use scheduler::*;
 pub use shell::*;
 pub use task::*;
+pub use task_argument::*;
 pub use task_context::*;
 pub use task_dependency::*;
+pub use task_matrix::*;
 pub use task_root::*;
+pub use timeout::*;
 pub use use_cargo::*;
 pub use use_npm::*;
 pub use validation::*;
+pub use watch::*;
 
-use crate::secrets::load_secret_value;
+use crate::utils::format_duration;
 
 static TEMPLATE_COMMAND_RE: Lazy<Regex> =
   Lazy::new(|| Regex::new(r"^\$\{\{.+\}\}$").expect("valid template regex"));
 static TEMPLATE_EXPR_RE: Lazy<Regex> =
   Lazy::new(|| Regex::new(r"\$\{\{\s*(.+?)\s*\}\}").expect("valid template expression regex"));
+static SHELL_VARIABLE_RE: Lazy<Regex> =
+  Lazy::new(|| Regex::new(r"\$\{?([A-Z_][A-Z0-9_]*)").expect("valid shell variable regex"));
 
 pub fn is_shell_command(value: &str) -> anyhow::Result<bool> {
   let re = Regex::new(r"^\$\(.+\)$")?;
@@ -71,37 +126,16 @@ pub fn resolve_template_command_value(value: &str, context: &TaskContext) -> any
 }
 
 pub fn resolve_template_expression(value: &str, context: &TaskContext) -> anyhow::Result<String> {
-  if value.starts_with("env.") {
-    let value = value.trim_start_matches("env.");
-    let value = context
-      .env_vars
-      .get(value)
-      .ok_or_else(|| anyhow::anyhow!("Environment variable '{}' is not defined", value))?;
-    Ok(value.to_string())
-  } else if value.starts_with("secrets.") {
-    let path = value.trim_start_matches("secrets.");
-    load_secret_value(
-      path,
-      &context.task_root.config_base_dir(),
-      context.secret_vault_location.as_deref(),
-      context.secret_keys_location.as_deref(),
-      context.secret_key_name.as_deref(),
-      context.secret_gpg_key_id.as_deref(),
-    )
-  } else if value.starts_with("outputs.") {
-    let name = value.trim_start_matches("outputs.");
-    context.get_task_output(name)?.ok_or_else(|| {
-      anyhow::anyhow!(
-        "Task output '{}' is not available. Ensure the task that produces it runs before this one.",
-        name
-      )
-    })
-  } else {
-    Ok(value.to_string())
-  }
+  evaluate_template(value, context)
 }
 
 pub fn interpolate_template_string(value: &str, context: &TaskContext) -> anyhow::Result<String> {
+  interpolate_templates(value, context)
+}
+
+/// Replace the `${{ ... }}` templates in a value. Templates whose value is only
+/// known when the task runs are left as they are.
+pub fn interpolate_templates(value: &str, scope: &dyn ExpressionScope) -> anyhow::Result<String> {
   let mut result = String::with_capacity(value.len());
   let mut last_end = 0usize;
   for captures in TEMPLATE_EXPR_RE.captures_iter(value) {
@@ -112,20 +146,65 @@ pub fn interpolate_template_string(value: &str, context: &TaskContext) -> anyhow
       continue;
     };
     result.push_str(&value[last_end..full_match.start()]);
-    result.push_str(&resolve_template_expression(expr.as_str().trim(), context)?);
+    match evaluate_template(expr.as_str().trim(), scope) {
+      Ok(resolved) => result.push_str(&resolved),
+      Err(e) if e.chain().any(|cause| cause.is::<UnknownUntilRun>()) => result.push_str(full_match.as_str()),
+      Err(e) => return Err(e),
+    }
     last_end = full_match.end();
   }
   result.push_str(&value[last_end..]);
   Ok(result)
 }
 
-pub fn extract_output_references(value: &str) -> Vec<String> {
+/// Replace the templates in every value of a list
+pub fn interpolate_template_list(
+  values: &[String],
+  scope: &dyn ExpressionScope,
+) -> anyhow::Result<Vec<String>> {
+  values
+    .iter()
+    .map(|value| interpolate_templates(value, scope))
+    .collect()
+}
+
+/// The expressions of the `${{ ... }}` templates in a value
+pub fn extract_template_expressions(value: &str) -> Vec<&str> {
   TEMPLATE_EXPR_RE
     .captures_iter(value)
     .filter_map(|captures| captures.get(1))
     .map(|expr| expr.as_str().trim())
-    .filter_map(|expr| expr.strip_prefix("outputs."))
-    .map(str::to_string)
+    .collect()
+}
+
+pub fn contains_template_expression(value: &str) -> bool {
+  TEMPLATE_EXPR_RE.is_match(value)
+}
+
+/// The names a value reads from a namespace, e.g. `version` for
+/// `${{ outputs.version || 'dev' }}` and the `outputs` namespace
+fn extract_namespace_references(value: &str, namespace: &str) -> Vec<String> {
+  extract_template_expressions(value)
+    .into_iter()
+    .filter_map(|expr| Expression::parse(expr).ok())
+    .flat_map(|expr| {
+      expr
+        .variables()
+        .into_iter()
+        .filter_map(|variable| variable.split_once('.'))
+        .filter(|(prefix, _)| *prefix == namespace)
+        .map(|(_, name)| name.to_string())
+        .collect::<Vec<_>>()
+    })
+    .collect()
+}
+
+/// The outputs read with `${{ outputs.NAME }}`, without the field path of
+/// structured outputs
+pub fn extract_output_references(value: &str) -> Vec<String> {
+  extract_namespace_references(value, "outputs")
+    .iter()
+    .map(|reference| split_output_field(reference).0.to_string())
     .collect()
 }
 
@@ -133,6 +212,47 @@ pub fn contains_output_reference(value: &str) -> bool {
   !extract_output_references(value).is_empty()
 }
 
+/// The tasks and outputs read with `${{ tasks.NAME.outputs.OUTPUT }}`
+pub fn extract_task_output_references(value: &str) -> Vec<(String, String)> {
+  extract_namespace_references(value, "tasks")
+    .iter()
+    .filter_map(|reference| split_task_output_reference(reference))
+    .map(|(task_name, output_name)| {
+      let (output_name, _) = split_output_field(output_name);
+      (task_name.to_string(), output_name.to_string())
+    })
+    .collect()
+}
+
+pub fn contains_task_output_reference(value: &str) -> bool {
+  !extract_task_output_references(value).is_empty()
+}
+
+pub fn extract_argument_references(value: &str) -> Vec<String> {
+  extract_namespace_references(value, "args")
+}
+
+pub fn contains_argument_reference(value: &str) -> bool {
+  !extract_argument_references(value).is_empty()
+}
+
+pub fn extract_matrix_references(value: &str) -> Vec<String> {
+  extract_namespace_references(value, "matrix")
+}
+
+pub fn contains_matrix_reference(value: &str) -> bool {
+  !extract_matrix_references(value).is_empty()
+}
+
+/// The upper case variables a command reads with `$NAME` or `${NAME}`
+pub fn extract_shell_variables(value: &str) -> Vec<&str> {
+  SHELL_VARIABLE_RE
+    .captures_iter(value)
+    .filter_map(|captures| captures.get(1))
+    .map(|name| name.as_str())
+    .collect()
+}
+
 pub fn get_output_handler(verbose: bool) -> Stdio {
   if verbose {
     Stdio::piped()
@@ -159,6 +279,22 @@ mod test {
     Ok(())
   }
 
+  #[test]
+  fn test_interpolate_template_string_resolves_arguments() -> anyhow::Result<()> {
+    let root = Arc::new(TaskRoot::default());
+    let mut context = TaskContext::empty_with_root(root);
+    context.set_task_arguments(hashbrown::HashMap::from([(
+      "version".to_string(),
+      "1.2.3".to_string(),
+    )]));
+    assert_eq!(
+      interpolate_template_string("release v${{ args.version }}", &context)?,
+      "release v1.2.3"
+    );
+    assert!(interpolate_template_string("${{ args.missing }}", &context).is_err());
+    Ok(())
+  }
+
   #[test]
   fn test_extract_output_references_finds_all_output_templates() {
     assert_eq!(
@@ -1,15 +1,29 @@
 use std::collections::HashSet;
+use std::path::PathBuf;
+
+use hashbrown::HashMap;
 
 use serde::Serialize;
 
 use crate::defaults::default_shell;
+use crate::utils::format_duration;
 
 use super::{
+  evaluate_condition,
+  interpolate_templates,
+  is_shell_command,
+  matrix_base_name,
+  parse_task_arguments,
+  split_extra_arguments,
   CommandRunner,
+  ExpressionScope,
+  MatrixValues,
   Shell,
   Task,
   TaskArgs,
+  TaskArgumentType,
   TaskRoot,
+  UnknownUntilRun,
 };
 
 #[derive(Debug, Serialize)]
@@ -23,13 +37,38 @@ pub struct PlannedTask {
   pub name: String,
   pub description: Option<String>,
   pub commands: Vec<PlannedCommand>,
+  pub on_failure: Vec<PlannedCommand>,
+  pub finally: Vec<PlannedCommand>,
   pub dependencies: Vec<String>,
+  pub conditional_dependencies: Vec<PlannedDependency>,
+  pub arguments: Vec<PlannedArgument>,
+  pub matrix: Vec<PlannedMatrixValue>,
+  pub extra_args: Vec<String>,
   pub base_dir: String,
   pub execution_mode: PlannedExecutionMode,
   pub max_parallel: Option<usize>,
+  pub timeout: Option<String>,
+  pub condition: Option<PlannedCondition>,
   pub skipped_reason: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct PlannedArgument {
+  pub name: String,
+  #[serde(rename = "type")]
+  pub kind: TaskArgumentType,
+  pub usage: String,
+  pub required: bool,
+  pub help: Option<String>,
+  pub value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedMatrixValue {
+  pub name: String,
+  pub value: String,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum PlannedExecutionMode {
@@ -50,12 +89,17 @@ pub enum PlannedCommand {
     work_dir: Option<String>,
     interactive: bool,
     retrigger: bool,
+    append_args: bool,
+    timeout: Option<String>,
+    condition: Option<PlannedCondition>,
   },
   ContainerRun {
     runtime: String,
     image: String,
     command: Vec<String>,
     mounted_paths: Vec<String>,
+    timeout: Option<String>,
+    condition: Option<PlannedCondition>,
   },
   ContainerBuild {
     runtime: String,
@@ -65,14 +109,68 @@ pub enum PlannedCommand {
     tags: Vec<String>,
     build_args: Vec<String>,
     labels: Vec<String>,
+    condition: Option<PlannedCondition>,
   },
   TaskRun {
     task: String,
+    condition: Option<PlannedCondition>,
   },
 }
 
+/// An `if:` expression and its value when the plan was made. The value is
+/// `None` when the expression reads something only known when the task runs.
+#[derive(Debug, Serialize)]
+pub struct PlannedCondition {
+  pub expression: String,
+  pub value: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlannedDependency {
+  pub name: String,
+  pub condition: PlannedCondition,
+}
+
+impl PlannedCondition {
+  pub fn summary(&self) -> String {
+    match self.value {
+      Some(value) => format!("{} ({})", self.expression, value),
+      None => format!("{} (evaluated at run time)", self.expression),
+    }
+  }
+}
+
 impl PlannedCommand {
   pub fn summary(&self) -> String {
+    let summary = self.describe();
+    match self.condition() {
+      Some(condition) => format!("{} if {}", summary, condition.summary()),
+      None => summary,
+    }
+  }
+
+  pub fn condition(&self) -> Option<&PlannedCondition> {
+    match self {
+      PlannedCommand::CommandRun { .. } => None,
+      PlannedCommand::LocalRun { condition, .. }
+      | PlannedCommand::ContainerRun { condition, .. }
+      | PlannedCommand::ContainerBuild { condition, .. }
+      | PlannedCommand::TaskRun { condition, .. } => condition.as_ref(),
+    }
+  }
+
+  fn with_condition(mut self, value: Option<PlannedCondition>) -> Self {
+    match &mut self {
+      PlannedCommand::CommandRun { .. } => {},
+      PlannedCommand::LocalRun { condition, .. }
+      | PlannedCommand::ContainerRun { condition, .. }
+      | PlannedCommand::ContainerBuild { condition, .. }
+      | PlannedCommand::TaskRun { condition, .. } => *condition = value,
+    }
+    self
+  }
+
+  fn describe(&self) -> String {
     match self {
       PlannedCommand::CommandRun { command, .. } => format!("command: {}", command),
       PlannedCommand::LocalRun { command, .. } => format!("local: {}", command),
@@ -82,14 +180,24 @@ impl PlannedCommand {
       PlannedCommand::ContainerBuild {
         image_name, context, ..
       } => format!("container_build: {} ({})", image_name, context),
-      PlannedCommand::TaskRun { task } => format!("task: {}", task),
+      PlannedCommand::TaskRun { task, .. } => format!("task: {}", task),
     }
   }
 }
 
 impl TaskRoot {
   pub fn plan_task(&self, task_name: &str) -> anyhow::Result<TaskPlan> {
-    let mut planner = Planner::default();
+    self.plan_task_with_args(task_name, &[])
+  }
+
+  /// Plan a task, resolving the given command line arguments against the root task.
+  /// Missing required arguments are shown without a value instead of failing.
+  pub fn plan_task_with_args(&self, task_name: &str, args: &[String]) -> anyhow::Result<TaskPlan> {
+    let mut planner = Planner {
+      root_task: task_name.to_string(),
+      root_args: args.to_vec(),
+      ..Default::default()
+    };
     planner.visit_task(self, task_name)?;
     Ok(TaskPlan {
       root_task: task_name.to_string(),
@@ -100,6 +208,8 @@ impl TaskRoot {
 
 #[derive(Default)]
 struct Planner {
+  root_task: String,
+  root_args: Vec<String>,
   steps: Vec<PlannedTask>,
   visiting: HashSet<String>,
   visited: HashSet<String>,
@@ -127,20 +237,94 @@ impl Planner {
         name: task_name.to_string(),
         description: None,
         commands: vec![PlannedCommand::CommandRun {
-          command: command.clone(),
+          command: interpolate_templates(
+            command,
+            &PlanScope::new(root, task_name, &TaskArgs::default(), &HashMap::new()),
+          )?,
           shell: default_shell().cmd(),
         }],
+        on_failure: Vec::new(),
+        finally: Vec::new(),
         dependencies: Vec::new(),
+        conditional_dependencies: Vec::new(),
+        arguments: Vec::new(),
+        matrix: Vec::new(),
+        extra_args: Vec::new(),
         base_dir: root.config_base_dir().to_string_lossy().into_owned(),
         execution_mode: PlannedExecutionMode::Sequential,
         max_parallel: None,
+        timeout: None,
+        condition: None,
         skipped_reason: None,
       },
       Task::Task(task) => {
-        for dependency in &task.depends_on {
-          self.visit_task(root, dependency.resolve_name())?;
+        let is_root_task = task_name == self.root_task
+          || (!task.matrix_values.is_empty() && matrix_base_name(task_name) == Some(self.root_task.as_str()));
+        let (args, extra_args) = if is_root_task {
+          split_extra_arguments(&self.root_args)
+        } else {
+          (&[][..], &[][..])
+        };
+        let values = parse_task_arguments(task_name, &task.args, args, false)?;
+        let extra_args = extra_args.to_vec();
+        let scope = PlanScope::new(root, task_name, task, &values);
+
+        // A matrix task runs its instances, which evaluate the condition themselves.
+        let condition = match &task.condition {
+          Some(condition) if task.matrix_instances.is_empty() => Some(plan_condition(condition, &scope)?),
+          _ => None,
+        };
+        let skipped = condition
+          .as_ref()
+          .is_some_and(|condition| condition.value == Some(false));
+
+        // Dependencies with a condition that is only known at run time are left
+        // to the task, which runs them once the condition is true.
+        let mut dependencies = Vec::new();
+        let mut conditional_dependencies = Vec::new();
+        if !skipped {
+          for dependency in &task.depends_on {
+            let name = dependency.resolve_name();
+            if let Some(condition) = dependency.condition() {
+              let condition = plan_condition(condition, &scope)?;
+              let value = condition.value;
+              conditional_dependencies.push(PlannedDependency {
+                name: name.to_string(),
+                condition,
+              });
+              if value != Some(true) {
+                continue;
+              }
+            }
+            self.visit_task(root, name)?;
+            dependencies.push(name.to_string());
+          }
+
+          // A matrix task runs its instances, which take the arguments of the task.
+          for instance in &task.matrix_instances {
+            self.visit_task(root, instance)?;
+            dependencies.push(instance.clone());
+          }
         }
 
+        // A matrix task has no commands of its own, its instances run them.
+        let plan_commands = |commands: &[CommandRunner]| -> anyhow::Result<Vec<PlannedCommand>> {
+          if !task.matrix_instances.is_empty() {
+            return Ok(Vec::new());
+          }
+          commands
+            .iter()
+            .map(|command| {
+              let condition = command
+                .condition()
+                .map(|condition| plan_condition(condition, &scope))
+                .transpose()?;
+              let command = command.resolve(&scope)?;
+              Ok(PlannedCommand::from_task_command(root, task, &command).with_condition(condition))
+            })
+            .collect()
+        };
+
         PlannedTask {
           name: task_name.to_string(),
           description: if task.description.is_empty() {
@@ -148,16 +332,32 @@ impl Planner {
           } else {
             Some(task.description.clone())
           },
-          commands: task
-            .commands
+          commands: plan_commands(&task.commands)?,
+          on_failure: plan_commands(&task.on_failure)?,
+          finally: plan_commands(&task.finally)?,
+          dependencies,
+          conditional_dependencies,
+          arguments: task
+            .args
             .iter()
-            .map(|command| PlannedCommand::from_task_command(root, task, command))
+            .map(|argument| PlannedArgument {
+              name: argument.name.clone(),
+              kind: argument.kind.clone(),
+              usage: argument.usage(),
+              required: argument.required,
+              help: argument.help.clone(),
+              value: values.get(&argument.name).cloned(),
+            })
             .collect(),
-          dependencies: task
-            .depends_on
+          matrix: task
+            .matrix_values
             .iter()
-            .map(|dependency| dependency.resolve_name().to_string())
+            .map(|(name, value)| PlannedMatrixValue {
+              name: name.clone(),
+              value: value.clone(),
+            })
             .collect(),
+          extra_args,
           base_dir: task.task_base_dir_from_root(root).to_string_lossy().into_owned(),
           execution_mode: if task.is_parallel() {
             PlannedExecutionMode::Parallel
@@ -169,7 +369,9 @@ impl Planner {
           } else {
             None
           },
-          skipped_reason: None,
+          timeout: task.timeout.map(format_duration),
+          condition,
+          skipped_reason: skipped.then(|| "condition_false".to_string()),
         }
       },
     };
@@ -203,6 +405,9 @@ impl PlannedCommand {
           .map(|work_dir| root.resolve_from_config(work_dir).to_string_lossy().into_owned()),
         interactive: local_run.interactive_enabled(),
         retrigger: local_run.retrigger_enabled(),
+        append_args: local_run.append_args.unwrap_or(false),
+        timeout: local_run.timeout.map(format_duration),
+        condition: None,
       },
       CommandRunner::ContainerRun(container_run) => PlannedCommand::ContainerRun {
         runtime: container_run
@@ -218,6 +423,8 @@ impl PlannedCommand {
           .iter()
           .map(|mounted_path| resolve_plan_mount_spec(root, mounted_path))
           .collect(),
+        timeout: container_run.timeout.map(format_duration),
+        condition: None,
       },
       CommandRunner::ContainerBuild(container_build) => PlannedCommand::ContainerBuild {
         runtime: container_build
@@ -253,14 +460,124 @@ impl PlannedCommand {
           .clone()
           .unwrap_or_default(),
         labels: container_build.container_build.labels.clone().unwrap_or_default(),
+        condition: None,
       },
       CommandRunner::TaskRun(task_run) => PlannedCommand::TaskRun {
         task: task_run.task.clone(),
+        condition: None,
       },
     }
   }
 }
 
+/// Resolves `if:` expressions and templates while planning. Outputs and environment values
+/// computed by commands are only known when the task runs.
+struct PlanScope<'a> {
+  root: &'a TaskRoot,
+  task_name: &'a str,
+  environment: HashMap<String, Option<String>>,
+  loads_env_files: bool,
+  arguments: &'a HashMap<String, String>,
+  required_arguments: Vec<&'a str>,
+  matrix: &'a MatrixValues,
+}
+
+impl<'a> PlanScope<'a> {
+  fn new(
+    root: &'a TaskRoot,
+    task_name: &'a str,
+    task: &'a TaskArgs,
+    arguments: &'a HashMap<String, String>,
+  ) -> Self {
+    let environment = root
+      .environment
+      .iter()
+      .chain(&task.environment)
+      .map(|(key, value)| {
+        let is_static = !value.contains("${{") && !is_shell_command(value).unwrap_or(true);
+        (key.clone(), is_static.then(|| value.clone()))
+      })
+      .collect();
+
+    let loads_env_files = !root.env_file.is_empty()
+      || !root.secrets_path.is_empty()
+      || !task.env_file.is_empty()
+      || !task.secrets_path.is_empty();
+
+    Self {
+      root,
+      task_name,
+      environment,
+      loads_env_files,
+      arguments,
+      required_arguments: task
+        .args
+        .iter()
+        .filter(|argument| argument.required)
+        .map(|argument| argument.name.as_str())
+        .collect(),
+      matrix: &task.matrix_values,
+    }
+  }
+}
+
+impl ExpressionScope for PlanScope<'_> {
+  fn lookup(&self, namespace: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let value = match namespace {
+      "env" => match self.environment.get(name) {
+        Some(Some(value)) => Some(value.clone()),
+        Some(None) => return Err(UnknownUntilRun(format!("env.{}", name)).into()),
+        // Env files and secrets are only read when the task runs.
+        None => match std::env::var(name) {
+          Ok(value) => Some(value),
+          Err(_) if self.loads_env_files => return Err(UnknownUntilRun(format!("env.{}", name)).into()),
+          Err(_) => None,
+        },
+      },
+      // Plans never read secrets, and outputs and failures only exist once
+      // the tasks run.
+      "secrets" | "outputs" | "tasks" | "failure" => {
+        return Err(UnknownUntilRun(format!("{}.{}", namespace, name)).into())
+      },
+      // Plans do not require the arguments a run needs.
+      "args" => match self.arguments.get(name) {
+        Some(value) => Some(value.clone()),
+        None if self.required_arguments.contains(&name) => {
+          return Err(UnknownUntilRun(format!("args.{}", name)).into())
+        },
+        None => None,
+      },
+      "matrix" => self
+        .matrix
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.clone()),
+      "mk" => match name {
+        "task_name" => Some(self.task_name.to_string()),
+        _ => None,
+      },
+      _ => anyhow::bail!("Unknown namespace '{}'", namespace),
+    };
+    Ok(value)
+  }
+
+  fn base_dir(&self) -> PathBuf {
+    self.root.config_base_dir()
+  }
+}
+
+fn plan_condition(expression: &str, scope: &PlanScope) -> anyhow::Result<PlannedCondition> {
+  let value = match evaluate_condition(expression, scope) {
+    Ok(value) => Some(value),
+    Err(e) if e.chain().any(|cause| cause.is::<UnknownUntilRun>()) => None,
+    Err(e) => return Err(e),
+  };
+  Ok(PlannedCondition {
+    expression: expression.to_string(),
+    value,
+  })
+}
+
 fn effective_shell(task: &TaskArgs, command_shell: Option<&Shell>) -> Shell {
   command_shell
     .cloned()
@@ -357,4 +674,32 @@ mod tests {
 
     Ok(())
   }
+
+  #[test]
+  fn test_plan_task_resolves_arguments() -> anyhow::Result<()> {
+    let yaml = "
+      tasks:
+        release:
+          args:
+            - name: version
+              required: true
+            - name: channel
+              type: enum
+              choices: [stable, beta]
+              default: stable
+          commands:
+            - command: echo ${{ args.version }}
+    ";
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let plan = task_root.plan_task("release")?;
+    assert_eq!(plan.steps[0].arguments[0].value, None);
+    assert_eq!(plan.steps[0].arguments[1].value.as_deref(), Some("stable"));
+
+    let plan = task_root.plan_task_with_args("release", &["--version".into(), "1.2.3".into()])?;
+    assert_eq!(plan.steps[0].arguments[0].value.as_deref(), Some("1.2.3"));
+    assert_eq!(plan.steps[0].arguments[0].usage, "--version <string>");
+
+    Ok(())
+  }
 }
@@ -1,23 +1,34 @@
 use anyhow::Context as _;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 use std::io::{
   BufRead as _,
   BufReader,
 };
 use std::thread;
+use std::time::Duration;
 
 use super::{
+  interpolate_templates,
+  use_process_group,
+  wait_for_command,
+  CommandFailed,
+  ExpressionScope,
   Shell,
   TaskContext,
 };
 use crate::defaults::default_verbose;
 use crate::handle_output;
+use crate::progress::join_output_threads;
 use crate::schema::get_output_handler;
+use crate::utils::deserialize_duration;
 
 /// This struct represents a precondition that must be met before a task can be
 /// executed.
-#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct Precondition {
   /// The command to run
   pub command: String,
@@ -34,12 +45,34 @@ pub struct Precondition {
   #[serde(default)]
   pub work_dir: Option<String>,
 
+  /// Fail the precondition if the command runs longer than this, e.g. `30s` or `5m`
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_duration")]
+  pub timeout: Option<Duration>,
+
   /// Show verbose output
   #[serde(default)]
   pub verbose: Option<bool>,
 }
 
 impl Precondition {
+  /// A copy of the precondition with the templates of its string fields replaced
+  pub fn resolve(&self, scope: &dyn ExpressionScope) -> anyhow::Result<Self> {
+    let resolve = |value: &Option<String>| {
+      value
+        .as_ref()
+        .map(|value| interpolate_templates(value, scope))
+        .transpose()
+    };
+
+    Ok(Self {
+      command: interpolate_templates(&self.command, scope)?,
+      message: resolve(&self.message)?,
+      work_dir: resolve(&self.work_dir)?,
+      ..self.clone()
+    })
+  }
+
   pub fn execute(&self, context: &TaskContext) -> anyhow::Result<()> {
     assert!(!self.command.is_empty());
 
@@ -65,20 +98,31 @@ impl Precondition {
       cmd.env(key, value);
     }
 
+    let timeout = context.command_timeout(self.timeout);
+    if timeout.is_some() || context.is_cancellable() {
+      use_process_group(&mut cmd);
+    }
+
     let mut cmd = cmd.spawn()?;
 
+    let mut output_handles = Vec::new();
     if verbose {
-      handle_output!(cmd.stdout, context);
-      handle_output!(cmd.stderr, context);
+      output_handles.push(handle_output!(cmd.stdout, context));
+      output_handles.push(handle_output!(cmd.stderr, context));
     }
 
-    let status = cmd.wait()?;
-    if !status.success() {
-      if let Some(message) = &self.message {
-        anyhow::bail!("Precondition failed - {}", message);
-      } else {
-        anyhow::bail!("Precondition failed - {}", self.command);
-      }
+    let status = wait_for_command(context, &mut cmd, &self.command, timeout.as_ref(), false)?;
+    join_output_threads(output_handles);
+    if let Some(status) = status.filter(|status| !status.success()) {
+      let message = self.message.as_ref().unwrap_or(&self.command);
+      return Err(
+        CommandFailed {
+          command: self.command.clone(),
+          message: format!("Precondition failed - {}", message),
+          exit_code: status.code(),
+        }
+        .into(),
+      );
     }
 
     Ok(())
@@ -0,0 +1,620 @@
+use std::collections::VecDeque;
+use std::sync::{
+  Arc,
+  Condvar,
+  Mutex,
+};
+use std::thread;
+use std::time::{
+  Duration,
+  Instant,
+};
+
+use hashbrown::{
+  HashMap,
+  HashSet,
+};
+
+use super::{
+  parse_task_arguments,
+  run_task_by_name_with_args,
+  run_task_in_prepared_context,
+  split_extra_arguments,
+  task_dependency_context,
+  PlannedTask,
+  TaskContext,
+  TaskInvocation,
+  TaskRunOutcome,
+};
+
+/// A task in the execution graph
+struct GraphNode {
+  task_name: String,
+  args: Vec<String>,
+  dependencies: Vec<usize>,
+  dependents: Vec<usize>,
+  /// The task the node runs as a dependency of, the first one to reach it in
+  /// the order tasks run their dependencies one after another
+  parent: Option<usize>,
+}
+
+/// The shared state of the workers running the graph
+struct GraphState {
+  ready: VecDeque<usize>,
+  pending: Vec<usize>,
+  running: usize,
+  failed: bool,
+  outcomes: Vec<Option<TaskRunOutcome>>,
+}
+
+/// Run the given tasks and their `depends_on` graph with up to `jobs` tasks at
+/// the same time. A task starts once all of its dependencies completed, so
+/// independent subtrees run concurrently and shared dependencies run once.
+///
+/// The graph is built from the task plans, so cycles and unknown tasks are
+/// reported before anything runs. A dependency runs from the context of the
+/// task that depends on it, with the environment, env files, secrets and vault
+/// settings of that task, as with `--jobs 1`. After a failure no further task
+/// is started.
+pub fn run_task_graph(
+  context: &TaskContext,
+  invocations: &[TaskInvocation],
+  jobs: usize,
+) -> Vec<TaskRunOutcome> {
+  let nodes = match build_graph(context, invocations) {
+    Ok(nodes) => nodes,
+    Err((failed, e)) => {
+      let mut error = Some(e);
+      return (0..invocations.len())
+        .map(|index| match error.take().filter(|_| index == failed) {
+          Some(e) => TaskRunOutcome::Failed(Duration::ZERO, e),
+          None => TaskRunOutcome::Skipped,
+        })
+        .collect();
+    },
+  };
+
+  let state = Mutex::new(GraphState {
+    ready: nodes
+      .iter()
+      .enumerate()
+      .filter(|(_, node)| node.dependencies.is_empty())
+      .map(|(index, _)| index)
+      .collect(),
+    pending: nodes.iter().map(|node| node.dependencies.len()).collect(),
+    running: 0,
+    failed: false,
+    outcomes: nodes.iter().map(|_| None).collect(),
+  });
+  let changed = Condvar::new();
+  let prepared: Vec<_> = nodes.iter().map(|_| Mutex::new(None)).collect();
+
+  thread::scope(|scope| {
+    for _ in 0..jobs.clamp(1, nodes.len().max(1)) {
+      scope.spawn(|| run_worker(context, &nodes, &prepared, &state, &changed));
+    }
+  });
+
+  let mut outcomes = state.into_inner().unwrap_or_else(|e| e.into_inner()).outcomes;
+  let failures: Vec<_> = (0..invocations.len())
+    .map(|index| failed_dependency(&nodes, &outcomes, index))
+    .collect();
+  invocations
+    .iter()
+    .zip(failures)
+    .enumerate()
+    .map(
+      |(index, (invocation, failure))| match (outcomes[index].take(), failure) {
+        (Some(outcome), _) => outcome,
+        (None, Some((name, message))) => TaskRunOutcome::Failed(
+          Duration::ZERO,
+          anyhow::anyhow!(
+            "Dependency '{}' of task '{}' failed - {}",
+            name,
+            invocation.task_name,
+            message
+          ),
+        ),
+        (None, None) => TaskRunOutcome::Skipped,
+      },
+    )
+    .collect()
+}
+
+/// Build the graph nodes. The first nodes are the given tasks, in order.
+/// On error, returns the index of the task that could not be planned.
+fn build_graph(
+  context: &TaskContext,
+  invocations: &[TaskInvocation],
+) -> Result<Vec<GraphNode>, (usize, anyhow::Error)> {
+  let mut nodes: Vec<GraphNode> = Vec::new();
+  let mut indices: HashMap<String, usize> = HashMap::new();
+
+  for (index, invocation) in invocations.iter().enumerate() {
+    // Report argument errors before any dependency runs.
+    let task = context
+      .task_root
+      .tasks
+      .get(&invocation.task_name)
+      .ok_or_else(|| {
+        (
+          index,
+          anyhow::anyhow!(
+            "Task '{}' not found. Run 'mk list' to see available tasks.",
+            invocation.task_name
+          ),
+        )
+      })?;
+    let (args, _) = split_extra_arguments(&invocation.args);
+    parse_task_arguments(&invocation.task_name, task.arguments(), args, true).map_err(|e| (index, e))?;
+
+    if !indices.contains_key(&invocation.task_name) {
+      indices.insert(invocation.task_name.clone(), nodes.len());
+    }
+    nodes.push(GraphNode {
+      task_name: invocation.task_name.clone(),
+      args: invocation.args.clone(),
+      dependencies: Vec::new(),
+      dependents: Vec::new(),
+      parent: None,
+    });
+  }
+
+  let mut edges = Vec::new();
+  for (index, invocation) in invocations.iter().enumerate() {
+    let plan = context
+      .task_root
+      .plan_task(&invocation.task_name)
+      .map_err(|e| (index, e))?;
+    // Matrix instances are run by their matrix task, in its execution mode.
+    let instances: HashSet<String> = plan
+      .steps
+      .iter()
+      .filter(|step| !step.matrix.is_empty() && step.name != invocation.task_name)
+      .map(|step| step.name.clone())
+      .collect();
+    let steps: HashMap<&str, &PlannedTask> =
+      plan.steps.iter().map(|step| (step.name.as_str(), step)).collect();
+
+    // Only unconditional dependencies are scheduled. A dependency with an `if`
+    // is run by its task once the condition is evaluated in the task context.
+    let mut stack = vec![invocation.task_name.as_str()];
+    let mut seen = HashSet::new();
+    while let Some(name) = stack.pop() {
+      let Some(step) = steps.get(name).filter(|_| seen.insert(name)) else {
+        continue;
+      };
+      let index = *indices.entry(step.name.clone()).or_insert_with(|| {
+        nodes.push(GraphNode {
+          task_name: step.name.clone(),
+          args: Vec::new(),
+          dependencies: Vec::new(),
+          dependents: Vec::new(),
+          parent: None,
+        });
+        nodes.len() - 1
+      });
+      for dependency in static_dependencies(step) {
+        if !instances.contains(dependency) {
+          edges.push((index, dependency.to_string()));
+          stack.push(dependency);
+        }
+      }
+    }
+  }
+
+  for (index, dependency) in edges {
+    let dependency = indices[&dependency];
+    if !nodes[index].dependencies.contains(&dependency) {
+      nodes[index].dependencies.push(dependency);
+      nodes[dependency].dependents.push(index);
+    }
+  }
+
+  // Walk the dependencies in the order they run one after another, so a shared
+  // dependency runs from the context of the first task that reaches it.
+  let mut visited = vec![false; nodes.len()];
+  for invocation in invocations {
+    let first = indices[&invocation.task_name];
+    if !std::mem::replace(&mut visited[first], true) {
+      let mut stack = vec![(first, 0)];
+      while let Some((index, position)) = stack.pop() {
+        let Some(&dependency) = nodes[index].dependencies.get(position) else {
+          continue;
+        };
+        stack.push((index, position + 1));
+        if !std::mem::replace(&mut visited[dependency], true) {
+          nodes[dependency].parent = Some(index);
+          stack.push((dependency, 0));
+        }
+      }
+    }
+  }
+
+  // A task given twice runs once, the later entry waits for the first one.
+  for (index, invocation) in invocations.iter().enumerate() {
+    let first = indices[&invocation.task_name];
+    if first != index {
+      nodes[index].dependencies.push(first);
+      nodes[first].dependents.push(index);
+    }
+  }
+
+  Ok(nodes)
+}
+
+/// The context a node runs from: the context its parent runs its dependencies
+/// from, prepared once and shared by the dependencies of the parent. `None`
+/// for the root context.
+fn node_context(
+  context: &TaskContext,
+  nodes: &[GraphNode],
+  prepared: &[Mutex<Option<Arc<TaskContext>>>],
+  index: usize,
+) -> anyhow::Result<Option<Arc<TaskContext>>> {
+  let Some(parent) = nodes[index].parent else {
+    return Ok(None);
+  };
+
+  let mut slot = prepared[parent].lock().unwrap_or_else(|e| e.into_inner());
+  if let Some(parent_context) = slot.as_ref() {
+    return Ok(Some(parent_context.clone()));
+  }
+
+  let outer = node_context(context, nodes, prepared, parent)?;
+  let node = &nodes[parent];
+  let parent_context = Arc::new(task_dependency_context(
+    outer.as_deref().unwrap_or(context),
+    &node.task_name,
+    &node.args,
+  )?);
+  *slot = Some(parent_context.clone());
+  Ok(Some(parent_context))
+}
+
+/// The dependencies of a planned task that do not have an `if` condition
+fn static_dependencies(step: &PlannedTask) -> impl Iterator<Item = &str> {
+  step.dependencies.iter().map(String::as_str).filter(|dependency| {
+    !step
+      .conditional_dependencies
+      .iter()
+      .any(|conditional| conditional.name == *dependency)
+  })
+}
+
+fn run_worker(
+  context: &TaskContext,
+  nodes: &[GraphNode],
+  prepared: &[Mutex<Option<Arc<TaskContext>>>],
+  state: &Mutex<GraphState>,
+  changed: &Condvar,
+) {
+  loop {
+    let index = {
+      let mut guard = state.lock().unwrap_or_else(|e| e.into_inner());
+      loop {
+        if !guard.failed {
+          if let Some(index) = guard.ready.pop_front() {
+            guard.running += 1;
+            break Some(index);
+          }
+        }
+
+        if guard.running == 0 {
+          break None;
+        }
+
+        guard = changed.wait(guard).unwrap_or_else(|e| e.into_inner());
+      }
+    };
+
+    let Some(index) = index else {
+      changed.notify_all();
+      return;
+    };
+
+    let node = &nodes[index];
+    let started = Instant::now();
+    let result = node_context(context, nodes, prepared, index).and_then(|node_context| {
+      let outer = node_context.as_deref().unwrap_or(context);
+      // A task whose dependencies ran reuses the context prepared for them.
+      let prepared_context = prepared[index].lock().unwrap_or_else(|e| e.into_inner()).clone();
+      match prepared_context {
+        Some(prepared_context) => run_task_in_prepared_context(outer, &prepared_context, &node.task_name),
+        None => run_task_by_name_with_args(outer, &node.task_name, &node.args),
+      }
+    });
+
+    let mut guard = state.lock().unwrap_or_else(|e| e.into_inner());
+    guard.running -= 1;
+    guard.outcomes[index] = Some(match result {
+      Ok(()) => {
+        for &dependent in &node.dependents {
+          guard.pending[dependent] -= 1;
+          if guard.pending[dependent] == 0 {
+            guard.ready.push_back(dependent);
+          }
+        }
+        TaskRunOutcome::Succeeded(started.elapsed())
+      },
+      Err(e) => {
+        guard.failed = true;
+        TaskRunOutcome::Failed(started.elapsed(), e)
+      },
+    });
+    changed.notify_all();
+  }
+}
+
+/// Find a failed task among the transitive dependencies of a task that never ran.
+fn failed_dependency(
+  nodes: &[GraphNode],
+  outcomes: &[Option<TaskRunOutcome>],
+  index: usize,
+) -> Option<(String, String)> {
+  if outcomes[index].is_some() {
+    return None;
+  }
+
+  let mut stack = nodes[index].dependencies.clone();
+  let mut seen = vec![false; nodes.len()];
+  while let Some(index) = stack.pop() {
+    if std::mem::replace(&mut seen[index], true) {
+      continue;
+    }
+
+    match &outcomes[index] {
+      Some(TaskRunOutcome::Failed(_, e)) => return Some((nodes[index].task_name.clone(), e.to_string())),
+      Some(_) => {},
+      None => stack.extend(nodes[index].dependencies.iter().copied()),
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+
+  use super::*;
+  use crate::schema::TaskRoot;
+
+  fn invocation(task_name: &str) -> TaskInvocation {
+    TaskInvocation {
+      task_name: task_name.to_string(),
+      args: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_scheduler_1_diamond_runs_shared_dependency_once() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let log = temp_dir.path().join("log.txt");
+    let root_yaml = format!(
+      "
+      tasks:
+        base:
+          commands:
+            - command: echo base >> {0}
+              verbose: false
+        left:
+          depends_on: [base]
+          commands:
+            - command: sleep 0.3; echo left >> {0}
+              verbose: false
+        right:
+          depends_on: [base]
+          commands:
+            - command: sleep 0.3; echo right >> {0}
+              verbose: false
+        top:
+          depends_on: [left, right]
+          commands:
+            - command: echo top >> {0}
+              verbose: false
+    ",
+      log.to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_graph(&context, &[invocation("top")], 4);
+    assert_eq!(outcomes[0].status(), "succeeded");
+
+    let lines: Vec<_> = std::fs::read_to_string(&log)?.lines().map(String::from).collect();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(lines[0], "base");
+    assert_eq!(lines[3], "top");
+    assert!(context.is_task_completed("left")?);
+    assert!(context.is_task_completed("right")?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_scheduler_2_reports_cycles_before_running() -> anyhow::Result<()> {
+    let root_yaml = "
+      tasks:
+        a:
+          depends_on: [b]
+          commands:
+            - command: echo a
+              verbose: false
+        b:
+          depends_on: [a]
+          commands:
+            - command: echo b
+              verbose: false
+    ";
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let mut outcomes = run_task_graph(&context, &[invocation("a")], 2);
+    match outcomes.pop() {
+      Some(TaskRunOutcome::Failed(_, e)) => assert_eq!(e.to_string(), "Circular dependency detected - a"),
+      outcome => panic!("Expected a failed outcome, got {:?}", outcome),
+    }
+    assert!(!context.is_task_completed("b")?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_scheduler_3_failed_dependency_fails_dependents() -> anyhow::Result<()> {
+    let root_yaml = "
+      tasks:
+        broken:
+          commands:
+            - command: exit 2
+              verbose: false
+        ok:
+          commands:
+            - command: echo ok
+              verbose: false
+        top:
+          depends_on: [broken, ok]
+          commands:
+            - command: echo top
+              verbose: false
+    ";
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let mut outcomes = run_task_graph(&context, &[invocation("top")], 2);
+    match outcomes.pop() {
+      Some(TaskRunOutcome::Failed(_, e)) => {
+        assert!(e
+          .to_string()
+          .starts_with("Dependency 'broken' of task 'top' failed"))
+      },
+      outcome => panic!("Expected a failed outcome, got {:?}", outcome),
+    }
+    assert!(!context.is_task_completed("top")?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_scheduler_4_dependencies_inherit_dependent_context() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let log = temp_dir.path().join("log.txt");
+    let root_yaml = format!(
+      "
+      environment:
+        STAGE: root
+      tasks:
+        base:
+          commands:
+            - command: echo base $STAGE $PROFILE >> {0}
+              verbose: false
+        build:
+          environment:
+            PROFILE: release
+          depends_on: [base]
+          commands:
+            - command: echo build $STAGE $PROFILE >> {0}
+              verbose: false
+        top:
+          environment:
+            STAGE: top
+          depends_on: [build]
+          commands:
+            - command: echo top $STAGE >> {0}
+              verbose: false
+    ",
+      log.to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_graph(&context, &[invocation("top")], 4);
+    assert_eq!(outcomes[0].status(), "succeeded");
+
+    // The same environment as when the dependencies run one after another.
+    assert_eq!(
+      std::fs::read_to_string(&log)?,
+      "base top release\nbuild top release\ntop top\n"
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_scheduler_5_conditional_dependencies_use_task_context() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let log = temp_dir.path().join("log.txt");
+    let env_file = temp_dir.path().join("deploy.env");
+    std::fs::write(&env_file, "MK_SCHEDULER_TEST_STAGE=prod\n")?;
+    // The plan sees this value, the task overrides it with its env file.
+    std::env::set_var("MK_SCHEDULER_TEST_STAGE", "dev");
+
+    let root_yaml = format!(
+      "
+      tasks:
+        seed:
+          commands:
+            - command: echo seed >> {0}
+              verbose: false
+        build:
+          commands:
+            - command: echo build >> {0}
+              verbose: false
+        deploy:
+          env_file:
+            - {1}
+          depends_on:
+            - build
+            - name: seed
+              if: env.MK_SCHEDULER_TEST_STAGE != 'prod'
+          commands:
+            - command: echo deploy >> {0}
+              verbose: false
+    ",
+      log.to_string_lossy(),
+      env_file.to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_graph(&context, &[invocation("deploy")], 4);
+    assert_eq!(outcomes[0].status(), "succeeded");
+
+    assert_eq!(std::fs::read_to_string(&log)?, "build\ndeploy\n");
+    assert!(!context.is_task_completed("seed")?);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_scheduler_6_tasks_are_prepared_once() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let log = temp_dir.path().join("log.txt");
+    let root_yaml = format!(
+      "
+      tasks:
+        base:
+          commands:
+            - command: echo base $VERSION >> {0}
+              verbose: false
+        top:
+          environment:
+            VERSION: $(echo prepared >> {0} && echo v1)
+          depends_on: [base]
+          commands:
+            - command: echo top $VERSION >> {0}
+              verbose: false
+    ",
+      log.to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_graph(&context, &[invocation("top")], 4);
+    assert_eq!(outcomes[0].status(), "succeeded");
+
+    // The env command of the task runs once, for its dependencies and itself.
+    assert_eq!(std::fs::read_to_string(&log)?, "prepared\nbase v1\ntop v1\n");
+
+    Ok(())
+  }
+}
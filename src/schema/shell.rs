@@ -1,8 +1,11 @@
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{
+  Deserialize,
+  Serialize,
+};
 use std::process::Command as ProcessCommand;
 
-#[derive(Debug, Default, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq, Eq, JsonSchema)]
 /// Shell command with optional flags.
 pub struct ShellArgs {
   /// The shell command to run
@@ -12,7 +15,7 @@ pub struct ShellArgs {
   pub args: Option<Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(untagged)]
 /// The shell to use. Either a string name (e.g. "bash") or an object with `command` and optional `args`.
 pub enum Shell {
@@ -1,10 +1,5 @@
 use hashbrown::HashMap;
-use indicatif::{
-  HumanDuration,
-  ProgressBar,
-  ProgressStyle,
-};
-use rand::Rng as _;
+use indicatif::HumanDuration;
 use schemars::JsonSchema;
 use serde::{
   Deserialize,
@@ -12,11 +7,17 @@ use serde::{
 };
 
 use std::io::BufRead as _;
+use std::sync::atomic::{
+  AtomicBool,
+  AtomicUsize,
+  Ordering,
+};
 use std::sync::mpsc::{
   channel,
   Receiver,
   Sender,
 };
+use std::sync::Mutex;
 use std::thread;
 use std::time::{
   Duration,
@@ -25,27 +26,51 @@ use std::time::{
 
 use super::{
   contains_output_reference,
+  contains_task_output_reference,
+  contains_template_expression,
+  evaluate_condition,
   extract_output_references,
   interpolate_template_string,
   is_shell_command,
+  run_task_with_arguments,
   CommandRunner,
+  MatrixValues,
   Precondition,
+  RunCancelled,
   Shell,
+  TaskArgument,
   TaskContext,
   TaskDependency,
+  TaskFailure,
+  TaskMatrix,
 };
 use crate::cache::{
+  canonical_json,
+  compare_timestamps,
+  compress_output,
   compute_fingerprint,
+  decompress_output,
+  diff_components,
   expand_patterns_in_dir,
+  ArtifactStore,
   CacheEntry,
+  CacheLock,
+  CacheMiss,
+  CacheStatus,
+  CommandOutput,
+  FileIndex,
+  Fingerprint,
+  DEFAULT_ARTIFACT_MAX_SIZE,
 };
 use crate::defaults::default_verbose;
 use crate::run_shell_command;
 use crate::secrets::load_secret_env;
 use crate::utils::{
+  deserialize_duration,
   deserialize_environment,
   load_env_files_in_dir,
   resolve_path,
+  shell_join,
 };
 
 fn default_cache_enabled() -> bool {
@@ -75,20 +100,130 @@ pub struct TaskExecution {
   pub fail_fast: bool,
 }
 
+/// How a cached task decides it can be skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+  /// Skip the task when its fingerprint matches and restore its `outputs`
+  #[default]
+  Outputs,
+  /// Skip the task when it succeeded with the same fingerprint, for tasks
+  /// without outputs such as lint and test tasks
+  Stamp,
+  /// Skip the task when no input was modified after its oldest output, like
+  /// make. Inputs are not hashed.
+  Timestamp,
+}
+
+/// Which command output a cached task prints when it is skipped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayOutput {
+  /// The output of every command
+  #[default]
+  Full,
+  /// The output of the commands that failed with their errors ignored
+  ErrorsOnly,
+  /// No output, only the skipped task event
+  None,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct TaskCache {
   #[serde(default = "default_cache_enabled")]
   pub enabled: bool,
+
+  /// How the task decides it can be skipped. Defaults to `outputs`.
+  #[serde(default)]
+  pub mode: Option<CacheMode>,
+
+  /// Which command output is printed again when the task is skipped. Defaults
+  /// to `full`.
+  #[serde(default)]
+  pub replay_output: Option<ReplayOutput>,
+
+  /// The environment variables that are part of the fingerprint, e.g.
+  /// `[RUSTFLAGS, CARGO_*]`. Variables that mk does not set are read from the
+  /// environment mk runs in. Defaults to every variable mk sets for the task.
+  #[serde(default)]
+  pub env: Vec<String>,
+
+  /// Environment variables left out of the fingerprint, e.g. `[BUILD_TIME]`
+  #[serde(default)]
+  pub exclude_env: Vec<String>,
+}
+
+impl TaskCache {
+  /// The environment variables of the fingerprint, sorted by name. `env_vars`
+  /// are the variables mk sets for the task and `process_env` the environment
+  /// mk runs in.
+  pub fn select_env(
+    &self,
+    env_vars: &HashMap<String, String>,
+    process_env: &HashMap<String, String>,
+  ) -> Vec<(String, String)> {
+    let mut selected: Vec<_> = if self.env.is_empty() {
+      env_vars
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+    } else {
+      // Variables set by mk take precedence over the outer environment.
+      process_env
+        .iter()
+        .filter(|(key, _)| !env_vars.contains_key(*key))
+        .chain(env_vars)
+        .filter(|(key, _)| matches_env_pattern(&self.env, key))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+    };
+    selected.retain(|(key, _)| !self.excludes_env(key));
+    selected.sort();
+    selected
+  }
+
+  /// Whether the variable is part of the fingerprint when it is set
+  pub fn covers_env(&self, name: &str) -> bool {
+    (self.env.is_empty() || matches_env_pattern(&self.env, name)) && !self.excludes_env(name)
+  }
+
+  /// Whether the variable is left out with `exclude_env`
+  pub fn excludes_env(&self, name: &str) -> bool {
+    matches_env_pattern(&self.exclude_env, name)
+  }
+}
+
+/// Whether the name matches one of the names or `*` patterns
+fn matches_env_pattern(patterns: &[String], name: &str) -> bool {
+  patterns.iter().any(|pattern| {
+    pattern == name
+      || glob::Pattern::new(pattern)
+        .map(|pattern| pattern.matches(name))
+        .unwrap_or(false)
+  })
 }
 
 /// This struct represents a task that can be executed. A task can contain multiple
 /// commands that are executed sequentially. A task can also have preconditions that
 /// must be met before the task can be executed.
-#[derive(Debug, Default, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
 pub struct TaskArgs {
   /// The commands to run
   pub commands: Vec<CommandRunner>,
 
+  /// The commands to run when a precondition or command of the task failed
+  #[serde(default)]
+  pub on_failure: Vec<CommandRunner>,
+
+  /// The commands to run after the task commands, whether they succeeded or not
+  #[serde(default)]
+  pub finally: Vec<CommandRunner>,
+
+  /// Only run the task when this expression is true, e.g. `os == 'linux'`.
+  /// Unlike preconditions, a false condition skips the task instead of failing it.
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
+
   /// The preconditions that must be met before the task can be executed
   #[serde(default)]
   pub preconditions: Vec<Precondition>,
@@ -106,6 +241,10 @@ pub struct TaskArgs {
   #[serde(default)]
   pub description: String,
 
+  /// The typed arguments the task accepts from the command line
+  #[serde(default)]
+  pub args: Vec<TaskArgument>,
+
   /// The environment variables to set before running the task
   #[schemars(with = "std::collections::HashMap<String, String>")]
   #[serde(default, deserialize_with = "deserialize_environment")]
@@ -148,6 +287,23 @@ pub struct TaskArgs {
   #[serde(default)]
   pub execution: Option<TaskExecution>,
 
+  /// Run the task once for every combination of the values, e.g.
+  /// `{ target: [x86_64, aarch64], profile: [dev, release] }`.
+  /// `include` and `exclude` entries add or remove combinations.
+  #[schemars(with = "Option<std::collections::HashMap<String, Vec<serde_json::Value>>>")]
+  #[serde(default)]
+  pub matrix: Option<TaskMatrix>,
+
+  /// The values of the matrix combination this task was expanded from
+  #[schemars(skip)]
+  #[serde(skip)]
+  pub matrix_values: MatrixValues,
+
+  /// The tasks expanded from the matrix of this task
+  #[schemars(skip)]
+  #[serde(skip)]
+  pub matrix_instances: Vec<String>,
+
   /// Task caching configuration
   #[serde(default)]
   pub cache: Option<TaskCache>,
@@ -160,6 +316,11 @@ pub struct TaskArgs {
   #[serde(default)]
   pub outputs: Vec<String>,
 
+  /// Stop the task if its preconditions and commands run longer than this, e.g. `10m`
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_duration")]
+  pub timeout: Option<Duration>,
+
   /// Ignore errors if the task fails
   #[serde(default)]
   pub ignore_errors: Option<bool>,
@@ -182,9 +343,18 @@ pub struct CommandResult {
   index: usize,
   success: bool,
   message: String,
+  output: Option<CommandOutput>,
 }
 
 impl Task {
+  /// The typed arguments accepted by the task
+  pub fn arguments(&self) -> &[TaskArgument] {
+    match self {
+      Task::String(_) => &[],
+      Task::Task(args) => &args.args,
+    }
+  }
+
   pub fn run(&self, context: &mut TaskContext) -> anyhow::Result<()> {
     match self {
       Task::String(command) => self.execute(context, command),
@@ -192,6 +362,14 @@ impl Task {
     }
   }
 
+  /// Run the task in a context its settings and environment were applied to
+  pub fn run_prepared(&self, context: &mut TaskContext) -> anyhow::Result<()> {
+    match self {
+      Task::String(command) => self.execute(context, command),
+      Task::Task(args) => args.run_prepared(context),
+    }
+  }
+
   fn execute(&self, context: &mut TaskContext, command: &str) -> anyhow::Result<()> {
     assert!(!command.is_empty());
 
@@ -205,14 +383,124 @@ impl Task {
 
 impl TaskArgs {
   pub fn run(&self, context: &mut TaskContext) -> anyhow::Result<()> {
+    self.run_in_context(context, true)
+  }
+
+  /// Run the task in a context `prepare_dependency_context` was applied to
+  pub fn run_prepared(&self, context: &mut TaskContext) -> anyhow::Result<()> {
+    self.run_in_context(context, false)
+  }
+
+  fn run_in_context(&self, context: &mut TaskContext, prepare: bool) -> anyhow::Result<()> {
+    if !self.matrix_instances.is_empty() {
+      return self.run_matrix_instances(context);
+    }
+
     assert!(!self.commands.is_empty());
 
     // Validate parallel execution requirements early
     self.validate_parallel_commands()?;
 
     let started = Instant::now();
-    let tick_interval = Duration::from_millis(80);
+    if prepare {
+      self.prepare_context(context)?;
+    }
 
+    if let Some(condition) = &self.condition {
+      if !evaluate_condition(condition, context)? {
+        context.emit_event(&serde_json::json!({
+          "event": "task_skipped",
+          "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
+          "reason": "condition_false",
+        }))?;
+        return Ok(());
+      }
+    }
+
+    let task_name = context
+      .current_task_name
+      .clone()
+      .unwrap_or_else(|| "<task>".to_string());
+
+    if !self.depends_on.is_empty() {
+      let step = context.renderer.start(
+        &task_name,
+        "Running task dependencies...",
+        self.depends_on.len() as u64,
+      );
+      for (i, dependency) in self.depends_on.iter().enumerate() {
+        step.advance(i as u64 + 1);
+        dependency.run(context)?;
+      }
+
+      let message = format!("Dependencies completed in {}.", HumanDuration(started.elapsed()));
+      step.finish(&message, context.is_nested);
+    }
+
+    // Values read from the outputs of other tasks are known once the dependencies ran.
+    let dependency_env = self.load_task_output_env(context)?;
+    context.extend_env_vars(dependency_env);
+
+    // The fingerprint covers inputs the dependencies generated.
+    let fingerprint = self.cache_fingerprint(context)?;
+    let hash = fingerprint.as_ref().map(|fingerprint| fingerprint.hash.as_str());
+    if let Some(entry) = self.find_cache_hit(context, hash)? {
+      self.replay_output(context, &task_name, entry.output.as_deref());
+      // Dependents still read the outputs the task saved when it last ran.
+      context.set_published_outputs(&task_name, entry.saved_outputs)?;
+      context.emit_event(&serde_json::json!({
+        "event": "task_skipped",
+        "task": task_name,
+        "reason": "cache_hit",
+      }))?;
+      return Ok(());
+    }
+
+    if context.explain && self.cache_enabled() {
+      let reasons = self.explain_cache_miss(context, fingerprint.as_ref())?;
+      context.emit_event(&serde_json::json!({
+        "event": "cache_miss",
+        "task": task_name,
+        "reasons": reasons.iter().map(ToString::to_string).collect::<Vec<_>>(),
+      }))?;
+      if !context.json_events {
+        eprintln!("Task '{}' is not cached:", task_name);
+        for reason in &reasons {
+          eprintln!("  - {}", reason);
+        }
+      }
+    }
+
+    // The task timeout starts once its dependencies completed.
+    let outer_deadline = context.deadline.clone();
+    if let Some(timeout) = self.timeout {
+      context.set_task_timeout(&task_name, timeout);
+    }
+
+    // Arguments after `--` are only forwarded to this task, not its dependencies.
+    if !context.extra_args.is_empty() {
+      let extra_args = shell_join(&context.extra_args)?;
+      context.extend_env_vars([("MK_ARGS".to_string(), extra_args)]);
+    }
+
+    let mut command_output = Vec::new();
+    let result = self
+      .run_commands(context, &task_name, started)
+      .map(|output| command_output = output);
+    // Cleanup commands are not bound by the task timeout.
+    context.deadline = outer_deadline;
+    self.run_cleanup(context, result)?;
+
+    context.publish_task_outputs(&task_name)?;
+    if self.cache_enabled() {
+      self.update_cache(context, fingerprint, command_output)?;
+    }
+
+    Ok(())
+  }
+
+  /// Apply the settings and environment of the task to the context
+  fn prepare_context(&self, context: &mut TaskContext) -> anyhow::Result<()> {
     if let Some(shell) = &self.shell {
       context.set_shell(shell);
     }
@@ -225,7 +513,9 @@ impl TaskArgs {
       context.set_verbose(*verbose);
     }
 
-    if !context.is_nested {
+    context.set_matrix_values(self.matrix_values.iter().cloned().collect());
+
+    if context.is_root_task() {
       if let Some(vault_location) = &context.task_root.vault_location {
         context.set_secret_vault_location(vault_location.clone());
       }
@@ -260,9 +550,14 @@ impl TaskArgs {
     }
 
     // Load environment variables from root and task environments and env files.
-    if !context.is_nested {
+    if context.is_root_task() {
       let config_base_dir = self.config_base_dir(context);
-      let root_env = context.task_root.environment.clone();
+      let mut root_env = context.task_root.environment.clone();
+      for value in root_env.values_mut() {
+        if contains_template_expression(value) {
+          *value = interpolate_template_string(value, context)?;
+        }
+      }
       let root_env_files = load_env_files_in_dir(&context.task_root.env_file, &config_base_dir)?;
       let root_secret_env = load_secret_env(
         &context.task_root.secrets_path,
@@ -286,94 +581,184 @@ impl TaskArgs {
     context.extend_env_vars(additional_env);
     context.extend_env_vars(secret_env);
 
-    if self.should_skip_from_cache(context)? {
-      context.emit_event(&serde_json::json!({
-        "event": "task_skipped",
-        "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
-        "reason": "cache_hit",
-      }))?;
-      return Ok(());
-    }
+    Ok(())
+  }
 
-    let mut rng = rand::thread_rng();
-    // Spinners can be found here:
-    // https://github.com/sindresorhus/cli-spinners/blob/main/spinners.json
-    let pb_style =
-      ProgressStyle::with_template("{spinner:.green} [{prefix:.bold.dim}] {wide_msg:.cyan/blue} ")?
-        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏⦿");
+  /// Run the preconditions and commands of the task. Returns the output of the
+  /// commands when the task replays it on cache hits.
+  fn run_commands(
+    &self,
+    context: &mut TaskContext,
+    task_name: &str,
+    started: Instant,
+  ) -> anyhow::Result<Vec<CommandOutput>> {
+    if !self.preconditions.is_empty() {
+      let step = context.renderer.start(
+        task_name,
+        "Running task precondition...",
+        self.preconditions.len() as u64,
+      );
+      for (i, precondition) in self.preconditions.iter().enumerate() {
+        step.advance(i as u64 + 1);
+        precondition.resolve(context)?.execute(context)?;
+      }
 
-    let depends_on_pb = context.multi.add(ProgressBar::new(self.depends_on.len() as u64));
+      let message = format!("Preconditions completed in {}.", HumanDuration(started.elapsed()));
+      step.finish(&message, context.is_nested);
+    }
 
-    if !self.depends_on.is_empty() {
-      depends_on_pb.set_style(pb_style.clone());
-      depends_on_pb.set_message("Running task dependencies...");
-      depends_on_pb.enable_steady_tick(tick_interval);
-      for (i, dependency) in self.depends_on.iter().enumerate() {
-        thread::sleep(Duration::from_millis(rng.gen_range(40..300)));
-        depends_on_pb.set_prefix(format!("{}/{}", i + 1, self.depends_on.len()));
-        dependency.run(context)?;
-        depends_on_pb.inc(1);
-      }
+    if self.is_parallel() {
+      return self.execute_commands_parallel(context);
+    }
 
-      let message = format!("Dependencies completed in {}.", HumanDuration(started.elapsed()));
-      if context.is_nested {
-        depends_on_pb.finish_and_clear();
-      } else {
-        depends_on_pb.finish_with_message(message);
-      }
+    let record = self.records_output();
+    let mut command_output = Vec::new();
+    let step = context
+      .renderer
+      .start(task_name, "Running task command...", self.commands.len() as u64);
+    for (i, command) in self.commands.iter().enumerate() {
+      step.advance(i as u64 + 1);
+      context.check_cancelled()?;
+      self.refresh_output_env(context)?;
+      let (result, output) = execute_recorded(context, command, record);
+      command_output.extend(output);
+      result?;
     }
 
-    let precondition_pb = context
-      .multi
-      .add(ProgressBar::new(self.preconditions.len() as u64));
+    let message = format!("Commands completed in {}.", HumanDuration(started.elapsed()));
+    step.finish(&message, context.is_nested);
 
-    if !self.preconditions.is_empty() {
-      precondition_pb.set_style(pb_style.clone());
-      precondition_pb.set_message("Running task precondition...");
-      precondition_pb.enable_steady_tick(tick_interval);
-      for (i, precondition) in self.preconditions.iter().enumerate() {
-        thread::sleep(Duration::from_millis(rng.gen_range(40..300)));
-        precondition_pb.set_prefix(format!("{}/{}", i + 1, self.preconditions.len()));
-        precondition.execute(context)?;
-        precondition_pb.inc(1);
-      }
+    Ok(command_output)
+  }
 
-      let message = format!("Preconditions completed in {}.", HumanDuration(started.elapsed()));
-      if context.is_nested {
-        precondition_pb.finish_and_clear();
-      } else {
-        precondition_pb.finish_with_message(message);
+  /// Run the `on_failure` commands if the task failed, then the `finally` commands.
+  /// The error of the task wins over errors of the cleanup commands.
+  fn run_cleanup(&self, context: &mut TaskContext, result: anyhow::Result<()>) -> anyhow::Result<()> {
+    if self.on_failure.is_empty() && self.finally.is_empty() {
+      return result;
+    }
+
+    // Cleanup commands still run when the run was cancelled, a cancelled run
+    // is not a failure though.
+    let cancellation = context.cancellation.take();
+    let mut cleanup_error = None;
+    let failure = result
+      .as_ref()
+      .err()
+      .filter(|e| !e.chain().any(|cause| cause.is::<RunCancelled>()));
+    if let Some(e) = failure {
+      context.set_failure(TaskFailure::from_error(e));
+      for command in &self.on_failure {
+        if let Err(e) = command.execute(context) {
+          cleanup_error.get_or_insert(e);
+        }
       }
     }
 
-    if self.is_parallel() {
-      self.execute_commands_parallel(context)?;
-    } else {
-      let command_pb = context.multi.add(ProgressBar::new(self.commands.len() as u64));
-      command_pb.set_style(pb_style);
-      command_pb.set_message("Running task command...");
-      command_pb.enable_steady_tick(tick_interval);
-      for (i, command) in self.commands.iter().enumerate() {
-        thread::sleep(Duration::from_millis(rng.gen_range(100..400)));
-        command_pb.set_prefix(format!("{}/{}", i + 1, self.commands.len()));
-        self.refresh_output_env(context)?;
-        command.execute(context)?;
-        command_pb.inc(1);
+    for command in &self.finally {
+      if let Err(e) = command.execute(context) {
+        cleanup_error.get_or_insert(e);
       }
+    }
+    context.cancellation = cancellation;
+
+    match (result, cleanup_error) {
+      (Err(e), Some(cleanup_error)) => {
+        context.emit_event(&serde_json::json!({
+          "event": "cleanup_failed",
+          "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
+          "error": cleanup_error.to_string(),
+        }))?;
+        if !context.json_events {
+          eprintln!("Cleanup failed - {}", cleanup_error);
+        }
+        Err(e)
+      },
+      (Err(e), None) => Err(e),
+      (Ok(()), Some(cleanup_error)) => Err(cleanup_error),
+      (Ok(()), None) => Ok(()),
+    }
+  }
 
-      let message = format!("Commands completed in {}.", HumanDuration(started.elapsed()));
-      if context.is_nested {
-        command_pb.finish_and_clear();
-      } else {
-        command_pb.finish_with_message(message);
+  /// Run the tasks expanded from the matrix with the arguments given to this task.
+  /// The execution mode of the task decides whether they run one after another.
+  fn run_matrix_instances(&self, context: &TaskContext) -> anyhow::Result<()> {
+    let instances = &self.matrix_instances;
+
+    if !self.is_parallel() {
+      for instance in instances {
+        run_task_with_arguments(
+          context,
+          instance,
+          context.task_arguments.clone(),
+          &context.extra_args,
+        )?;
       }
+      return Ok(());
     }
 
-    self.update_cache(context)?;
+    let max_parallel = self.max_parallel().min(instances.len()).max(1);
+    let fail_fast = self.fail_fast();
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let failures = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+      for _ in 0..max_parallel {
+        let context = context.clone();
+        let (next, stop, failures) = (&next, &stop, &failures);
+        scope.spawn(move || {
+          while !stop.load(Ordering::SeqCst) {
+            let Some(instance) = instances.get(next.fetch_add(1, Ordering::SeqCst)) else {
+              break;
+            };
+            let result = run_task_with_arguments(
+              &context,
+              instance,
+              context.task_arguments.clone(),
+              &context.extra_args,
+            );
+            if let Err(e) = result {
+              if fail_fast {
+                stop.store(true, Ordering::SeqCst);
+              }
+              if let Ok(mut failures) = failures.lock() {
+                failures.push((instance.clone(), e));
+              }
+            }
+          }
+        });
+      }
+    });
+
+    let mut failures = failures
+      .into_inner()
+      .map_err(|e| anyhow::anyhow!("Failed to lock matrix failures - {}", e))?;
+    if failures.len() == 1 {
+      return Err(failures.remove(0).1);
+    }
+    if !failures.is_empty() {
+      let names: Vec<_> = instances
+        .iter()
+        .filter(|instance| failures.iter().any(|(name, _)| name == *instance))
+        .cloned()
+        .collect();
+      anyhow::bail!(
+        "{} of {} matrix instances failed - {}",
+        names.len(),
+        instances.len(),
+        names.join(", ")
+      );
+    }
 
     Ok(())
   }
 
+  /// The task commands followed by its `on_failure` and `finally` commands
+  pub fn all_commands(&self) -> impl Iterator<Item = &CommandRunner> {
+    self.commands.iter().chain(&self.on_failure).chain(&self.finally)
+  }
+
   /// Validate if the task can be run in parallel
   fn validate_parallel_commands(&self) -> anyhow::Result<()> {
     if !self.is_parallel() {
@@ -404,20 +789,20 @@ impl TaskArgs {
   }
 
   /// Execute the commands in parallel
-  fn execute_commands_parallel(&self, context: &TaskContext) -> anyhow::Result<()> {
+  fn execute_commands_parallel(&self, context: &TaskContext) -> anyhow::Result<Vec<CommandOutput>> {
     let (tx, rx): (Sender<CommandResult>, Receiver<CommandResult>) = channel();
     let mut handles = vec![];
     let command_count = self.commands.len();
     let max_parallel = self.max_parallel().min(command_count.max(1));
     let fail_fast = self.fail_fast();
-    let command_pb = context.multi.add(ProgressBar::new(command_count as u64));
-    command_pb.set_style(ProgressStyle::with_template(
-      "{spinner:.green} [{prefix:.bold.dim}] {wide_msg:.cyan/blue} ",
-    )?);
-    command_pb.set_prefix("?/?");
-    command_pb.set_message("Running task commands in parallel...");
-    command_pb.enable_steady_tick(Duration::from_millis(80));
+    let step = context.renderer.start(
+      context.current_task_name.as_deref().unwrap_or("<task>"),
+      "Running task commands in parallel...",
+      command_count as u64,
+    );
     let mut failures = Vec::new();
+    let record = self.records_output();
+    let mut command_output = vec![None; command_count];
 
     // Clone all commands upfront to avoid borrowing issues
     let commands: Vec<_> = self.commands.to_vec();
@@ -439,16 +824,19 @@ impl TaskArgs {
         let context = context.clone();
 
         let handle = thread::spawn(move || {
-          let result = match command.execute(&context) {
+          let (result, output) = execute_recorded(&context, &command, record);
+          let result = match result {
             Ok(_) => CommandResult {
               index: i,
               success: true,
               message: format!("Command {} completed successfully", i + 1),
+              output,
             },
             Err(e) => CommandResult {
               index: i,
               success: false,
               message: format!("Command {} failed: {}", i + 1, e),
+              output,
             },
           };
           tx.send(result).unwrap();
@@ -472,18 +860,20 @@ impl TaskArgs {
               stop_scheduling = true;
             }
           }
+          if let Some(mut output) = result.output {
+            output.failed |= !result.success;
+            command_output[index] = Some(output);
+          }
 
           completed += 1;
-          command_pb.set_prefix(format!("{}/{}", completed, command_count));
-          command_pb.inc(1);
-
-          command_pb.set_message(format!(
+          step.advance(completed as u64);
+          step.set_message(&format!(
             "Running task commands in parallel (completed {})",
             index + 1
           ));
         },
         Err(e) => {
-          command_pb.finish_with_message("Error receiving command results");
+          step.finish("Error receiving command results", false);
           return Err(anyhow::anyhow!("Channel error: {}", e));
         },
       }
@@ -495,27 +885,22 @@ impl TaskArgs {
     }
 
     if !failures.is_empty() {
-      command_pb.finish_with_message("Some commands failed");
+      step.finish("Some commands failed", false);
 
       // Sort failures by command index for clearer error reporting
       failures.sort();
       return Err(anyhow::anyhow!("Failed commands:\n{}", failures.join("\n")));
     }
 
-    let message = "Commands completed in parallel";
-    if context.is_nested {
-      command_pb.finish_and_clear();
-    } else {
-      command_pb.finish_with_message(message);
-    }
+    step.finish("Commands completed in parallel", context.is_nested);
 
-    Ok(())
+    Ok(command_output.into_iter().flatten().collect())
   }
 
   fn load_static_env(&self, context: &TaskContext) -> anyhow::Result<HashMap<String, String>> {
     let mut local_env: HashMap<String, String> = HashMap::new();
     for (key, value) in &self.environment {
-      if contains_output_reference(value) {
+      if contains_output_reference(value) || contains_task_output_reference(value) {
         continue;
       }
       let value = self.get_env_value(context, value)?;
@@ -525,6 +910,17 @@ impl TaskArgs {
     Ok(local_env)
   }
 
+  fn load_task_output_env(&self, context: &TaskContext) -> anyhow::Result<HashMap<String, String>> {
+    let mut local_env: HashMap<String, String> = HashMap::new();
+    for (key, value) in &self.environment {
+      if contains_task_output_reference(value) && !contains_output_reference(value) {
+        local_env.insert(key.clone(), self.get_env_value(context, value)?);
+      }
+    }
+
+    Ok(local_env)
+  }
+
   fn refresh_output_env(&self, context: &mut TaskContext) -> anyhow::Result<()> {
     let mut updated_env = HashMap::new();
 
@@ -577,7 +973,7 @@ impl TaskArgs {
         .unwrap_or_else(|| context.shell().proc());
       let output = run_shell_command!(value_in, cmd, verbose);
       Ok(output)
-    } else if super::is_template_command(value_in)? || contains_output_reference(value_in) {
+    } else if contains_template_expression(value_in) {
       Ok(interpolate_template_string(value_in, context)?)
     } else {
       Ok(value_in.to_string())
@@ -614,7 +1010,13 @@ impl TaskArgs {
       .execution
       .as_ref()
       .and_then(|execution| execution.max_parallel)
-      .unwrap_or(self.commands.len().max(1))
+      .unwrap_or_else(|| {
+        if self.matrix_instances.is_empty() {
+          self.commands.len().max(1)
+        } else {
+          self.matrix_instances.len()
+        }
+      })
   }
 
   pub(crate) fn fail_fast(&self) -> bool {
@@ -629,92 +1031,372 @@ impl TaskArgs {
     self.cache.as_ref().map(|cache| cache.enabled).unwrap_or(false)
   }
 
-  fn should_skip_from_cache(&self, context: &TaskContext) -> anyhow::Result<bool> {
-    if context.force || !self.cache_enabled() || self.outputs.is_empty() {
-      return Ok(false);
+  fn replay_mode(&self) -> ReplayOutput {
+    self
+      .cache
+      .as_ref()
+      .and_then(|cache| cache.replay_output)
+      .unwrap_or_default()
+  }
+
+  /// Whether the output of the commands is kept to replay it on cache hits
+  fn records_output(&self) -> bool {
+    self.cache_enabled() && self.replay_mode() != ReplayOutput::None
+  }
+
+  /// Print the output the commands printed when the skipped task last ran,
+  /// after a `cached` marker
+  fn replay_output(&self, context: &TaskContext, task_name: &str, output: Option<&str>) {
+    let mode = self.replay_mode();
+    if mode == ReplayOutput::None {
+      return;
     }
 
-    let resolved_outputs = self.resolve_output_paths(context)?;
-    let outputs_exist = self
-      .resolve_output_paths(context)?
+    let command_output = match output.map(decompress_output).transpose() {
+      Ok(command_output) => command_output.unwrap_or_default(),
+      Err(e) => {
+        log::debug!("Failed to read the cached output of {} - {}", task_name, e);
+        Vec::new()
+      },
+    };
+    let replayed: Vec<_> = command_output
       .iter()
-      .all(|output| output.exists());
-    if !outputs_exist {
-      return Ok(false);
+      .filter(|output| mode == ReplayOutput::Full || output.failed)
+      .collect();
+    if replayed.is_empty() {
+      context.renderer.println(&format!("[{}] cached", task_name));
+    }
+    for output in replayed {
+      context
+        .renderer
+        .println(&format!("[{}] cached: {}", task_name, output.command));
+      for line in &output.lines {
+        context.renderer.println(line);
+      }
     }
+  }
 
-    let env_vars = sorted_env_vars(&context.env_vars);
+  fn cache_mode(&self) -> CacheMode {
+    self
+      .cache
+      .as_ref()
+      .and_then(|cache| cache.mode)
+      .unwrap_or_default()
+  }
+
+  /// The fingerprint of the task when it is cached. It is computed once, after
+  /// the dependencies ran and before the commands run, and stored once the task
+  /// succeeded.
+  fn cache_fingerprint(&self, context: &TaskContext) -> anyhow::Result<Option<Fingerprint>> {
+    let fingerprinted = match self.cache_mode() {
+      CacheMode::Outputs => !self.outputs.is_empty(),
+      CacheMode::Stamp => true,
+      CacheMode::Timestamp => false,
+    };
+    if !self.cache_enabled() || !fingerprinted {
+      return Ok(None);
+    }
+
+    let process_env = std::env::vars().collect();
+    let env_vars = self
+      .cache
+      .as_ref()
+      .map(|cache| cache.select_env(&context.env_vars, &process_env))
+      .unwrap_or_default();
     let inputs = self.resolve_input_paths(context)?;
     let mut env_files = self.resolve_env_file_paths(context);
     env_files.extend(self.resolve_secret_paths(context));
     env_files.sort();
     env_files.dedup();
+    let resolved_outputs = self.resolve_output_paths(context)?;
+
     let fingerprint = compute_fingerprint(
       &context
         .current_task_name
         .clone()
         .unwrap_or_else(|| "<task>".to_string()),
-      &stable_task_debug(self),
+      &fingerprint_definition(self)?,
       &env_vars,
       &inputs,
       &env_files,
       &resolved_outputs,
+      &context.file_index,
     )?;
+    FileIndex::save_shared_in_dir(&context.file_index, &context.task_root.cache_base_dir())?;
+
+    Ok(Some(fingerprint))
+  }
+
+  /// The cache entry of the task when its inputs did not change since it last
+  /// ran. Outputs saved in the artifact store for the fingerprint are restored
+  /// when they were deleted or changed.
+  fn find_cache_hit(
+    &self,
+    context: &TaskContext,
+    fingerprint: Option<&str>,
+  ) -> anyhow::Result<Option<CacheEntry>> {
+    if context.force {
+      return Ok(None);
+    }
+    if self.cache_mode() == CacheMode::Timestamp && self.cache_enabled() {
+      return self.find_timestamp_hit(context);
+    }
+    let Some(fingerprint) = fingerprint else {
+      return Ok(None);
+    };
+
+    let artifacts = ArtifactStore::in_dir(&context.task_root.cache_base_dir());
+    if let Some(mut manifest) = artifacts.load(fingerprint)? {
+      if let Some(restored) = artifacts.restore(&mut manifest, &context.file_index)? {
+        log::debug!("Restored {} outputs from the artifact store", restored);
+        return Ok(Some(CacheEntry {
+          fingerprint: manifest.fingerprint,
+          outputs: manifest.outputs,
+          saved_outputs: manifest.saved_outputs,
+          components: None,
+          output: manifest.output,
+          updated_at: manifest.created_at,
+        }));
+      }
+    }
+
+    let outputs_exist = self
+      .resolve_output_paths(context)?
+      .iter()
+      .all(|output| output.exists());
+    if !outputs_exist {
+      return Ok(None);
+    }
 
     let store = context
       .cache_store
       .lock()
       .map_err(|e| anyhow::anyhow!("Failed to lock cache store - {}", e))?;
-    let Some(entry) = store.tasks.get(&fingerprint_task_key(context, self)) else {
-      return Ok(false);
+    let entry = store
+      .tasks
+      .get(&fingerprint_task_key(context, self)?)
+      .filter(|entry| entry.fingerprint == fingerprint)
+      .cloned();
+
+    Ok(entry)
+  }
+
+  /// The stored cache entry of a `timestamp` cached task when its outputs are
+  /// newer than its inputs. The entry keeps the outputs the task saved.
+  fn find_timestamp_hit(&self, context: &TaskContext) -> anyhow::Result<Option<CacheEntry>> {
+    if !self.timestamp_misses(context)?.is_empty() {
+      return Ok(None);
+    }
+
+    let store = context
+      .cache_store
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock cache store - {}", e))?;
+    let entry = store.tasks.get(&fingerprint_task_key(context, self)?).cloned();
+    Ok(Some(entry.unwrap_or_else(|| CacheEntry {
+      fingerprint: String::new(),
+      outputs: self.outputs.clone(),
+      saved_outputs: HashMap::new(),
+      components: None,
+      output: None,
+      updated_at: chrono::Utc::now().to_rfc3339(),
+    })))
+  }
+
+  /// Why the outputs of a `timestamp` cached task are out of date. The env and
+  /// secret files count as inputs.
+  fn timestamp_misses(&self, context: &TaskContext) -> anyhow::Result<Vec<CacheMiss>> {
+    let mut inputs = self.resolve_input_paths(context)?;
+    inputs.extend(self.resolve_env_file_paths(context));
+    inputs.extend(self.resolve_secret_paths(context));
+    compare_timestamps(&inputs, &self.resolve_output_paths(context)?)
+  }
+
+  /// Why the task is not a cache hit, comparing its stored cache entry with
+  /// the current fingerprint
+  fn explain_cache_miss(
+    &self,
+    context: &TaskContext,
+    fingerprint: Option<&Fingerprint>,
+  ) -> anyhow::Result<Vec<CacheMiss>> {
+    if context.force {
+      return Ok(vec![CacheMiss::Forced]);
+    }
+    if self.cache_mode() == CacheMode::Timestamp {
+      return Ok(self.relative_cache_misses(context, self.timestamp_misses(context)?));
+    }
+    let Some(fingerprint) = fingerprint else {
+      return Ok(vec![CacheMiss::NoOutputs]);
+    };
+
+    let store = context
+      .cache_store
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock cache store - {}", e))?;
+    let Some(entry) = store.tasks.get(&fingerprint_task_key(context, self)?) else {
+      return Ok(vec![CacheMiss::NoEntry]);
+    };
+
+    let reasons = if entry.fingerprint == fingerprint.hash {
+      self
+        .resolve_output_paths(context)?
+        .into_iter()
+        .filter(|output| !output.exists())
+        .map(|output| CacheMiss::MissingOutput(output.to_string_lossy().into_owned()))
+        .collect()
+    } else if let Some(components) = &entry.components {
+      diff_components(components, &fingerprint.components)
+    } else {
+      vec![CacheMiss::NoBreakdown]
     };
 
-    Ok(entry.fingerprint == fingerprint)
+    Ok(self.relative_cache_misses(context, reasons))
   }
 
-  fn update_cache(&self, context: &TaskContext) -> anyhow::Result<()> {
-    if !self.cache_enabled() || self.outputs.is_empty() {
+  fn relative_cache_misses(&self, context: &TaskContext, reasons: Vec<CacheMiss>) -> Vec<CacheMiss> {
+    let base_dir = context.task_root.config_base_dir();
+    reasons
+      .into_iter()
+      .map(|reason| reason.relative_to(&base_dir))
+      .collect()
+  }
+
+  /// Apply the settings and environment of the task to the context its
+  /// dependencies run from. Matrix instances are prepared by each instance.
+  pub fn prepare_dependency_context(&self, context: &mut TaskContext) -> anyhow::Result<()> {
+    if !self.matrix_instances.is_empty() {
       return Ok(());
     }
+    self.prepare_context(context)
+  }
 
-    let env_vars = sorted_env_vars(&context.env_vars);
-    let inputs = self.resolve_input_paths(context)?;
-    let mut env_files = self.resolve_env_file_paths(context);
-    env_files.extend(self.resolve_secret_paths(context));
-    env_files.sort();
-    env_files.dedup();
+  /// Whether the task would be skipped from the cache, without running it
+  pub fn cache_status(&self, context: &mut TaskContext) -> anyhow::Result<CacheStatus> {
+    if !self.cache_enabled() || !self.matrix_instances.is_empty() {
+      return Ok(CacheStatus::Disabled);
+    }
+
+    self.prepare_context(context)?;
+    if self.cache_mode() == CacheMode::Timestamp && self.find_cache_hit(context, None)?.is_some() {
+      return Ok(CacheStatus::Hit);
+    }
+
+    let fingerprint = self.cache_fingerprint(context)?;
+    if let Some(fingerprint) = fingerprint.as_ref().filter(|_| !context.force) {
+      let artifacts = ArtifactStore::in_dir(&context.task_root.cache_base_dir());
+      if let Some(manifest) = artifacts.load(&fingerprint.hash)? {
+        match artifacts.changed_outputs(&manifest, &context.file_index)? {
+          Some(changed) if changed.is_empty() => return Ok(CacheStatus::Hit),
+          Some(changed) => return Ok(CacheStatus::Restorable(changed.len())),
+          None => {},
+        }
+      }
+
+      let store = context
+        .cache_store
+        .lock()
+        .map_err(|e| anyhow::anyhow!("Failed to lock cache store - {}", e))?;
+      let stored = store
+        .tasks
+        .get(&fingerprint_task_key(context, self)?)
+        .is_some_and(|entry| entry.fingerprint == fingerprint.hash);
+      let outputs_exist = self
+        .resolve_output_paths(context)?
+        .iter()
+        .all(|output| output.exists());
+      if stored && outputs_exist {
+        return Ok(CacheStatus::Hit);
+      }
+    }
+
+    Ok(CacheStatus::Miss(
+      self.explain_cache_miss(context, fingerprint.as_ref())?,
+    ))
+  }
+
+  /// Store the cache entry of the task once it succeeded, with the output of
+  /// its commands. Only tasks in the `outputs` mode save their outputs in the
+  /// artifact store.
+  fn update_cache(
+    &self,
+    context: &TaskContext,
+    fingerprint: Option<Fingerprint>,
+    command_output: Vec<CommandOutput>,
+  ) -> anyhow::Result<()> {
+    let mode = self.cache_mode();
+    if fingerprint.is_none() && mode != CacheMode::Timestamp {
+      return Ok(());
+    }
+
+    let output = if command_output.is_empty() {
+      None
+    } else {
+      Some(compress_output(&command_output)?)
+    };
+    let (hash, components) = fingerprint
+      .map(|fingerprint| (fingerprint.hash, Some(fingerprint.components)))
+      .unwrap_or_default();
     let resolved_outputs = self.resolve_output_paths(context)?;
-    let fingerprint = compute_fingerprint(
-      &context
-        .current_task_name
-        .clone()
-        .unwrap_or_else(|| "<task>".to_string()),
-      &stable_task_debug(self),
-      &env_vars,
-      &inputs,
-      &env_files,
-      &resolved_outputs,
-    )?;
-    let key = fingerprint_task_key(context, self);
+    let key = fingerprint_task_key(context, self)?;
+    let base_dir = context.task_root.cache_base_dir();
+    let saved_outputs = context
+      .task_outputs
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock task outputs - {}", e))?
+      .clone();
 
+    // Other tasks only wait for the entry to be written, not for the artifacts.
     {
       let mut store = context
         .cache_store
         .lock()
         .map_err(|e| anyhow::anyhow!("Failed to lock cache store - {}", e))?;
-      store.tasks.insert(
+      store.insert(
         key,
         CacheEntry {
-          fingerprint,
+          fingerprint: hash.clone(),
           outputs: resolved_outputs
             .iter()
             .map(|path| path.to_string_lossy().into_owned())
             .collect(),
+          saved_outputs: saved_outputs.clone(),
+          components,
+          output: output.clone(),
           updated_at: chrono::Utc::now().to_rfc3339(),
         },
       );
-      store.save_in_dir(&context.task_root.cache_base_dir())?;
+      store.save_in_dir(&base_dir)?;
+    }
+    if mode != CacheMode::Outputs {
+      return Ok(());
+    }
+
+    // Keep the outputs so a later run with the same fingerprint restores them.
+    // The contents and manifest are written under the cache lock, so a gc in
+    // another task or mk process does not remove contents before the manifest
+    // refers to them.
+    let max_size = context
+      .task_root
+      .cache
+      .as_ref()
+      .and_then(|cache| cache.max_size)
+      .unwrap_or(DEFAULT_ARTIFACT_MAX_SIZE);
+    let artifacts = ArtifactStore::in_dir(&base_dir);
+    {
+      let _lock = CacheLock::acquire_in_dir(&base_dir)?;
+      artifacts.save(
+        &hash,
+        &resolved_outputs,
+        saved_outputs,
+        output,
+        &context.file_index,
+      )?;
+      // The task succeeded, a failed gc only leaves the store larger.
+      if let Err(e) = artifacts.gc(max_size) {
+        log::warn!("Failed to trim the artifact store - {}", e);
+      }
     }
+    FileIndex::save_shared_in_dir(&context.file_index, &base_dir)?;
 
     Ok(())
   }
@@ -808,66 +1490,46 @@ impl ExecutionMode {
   }
 }
 
-fn sorted_env_vars(env_vars: &HashMap<String, String>) -> Vec<(String, String)> {
-  let mut pairs: Vec<_> = env_vars
-    .iter()
-    .map(|(key, value)| (key.clone(), value.clone()))
-    .collect();
-  pairs.sort();
-  pairs
+/// Run a command of a task. The output it printed is returned when `record` is
+/// set, so the task can replay it on cache hits.
+fn execute_recorded(
+  context: &TaskContext,
+  command: &CommandRunner,
+  record: bool,
+) -> (anyhow::Result<()>, Option<CommandOutput>) {
+  if !record {
+    return (command.execute(context), None);
+  }
+
+  let mut context = context.clone();
+  let recorder = context.record_output();
+  let result = command.execute(&context);
+  (result, Some(recorder.take_output(&command.label())))
 }
 
-fn fingerprint_task_key(context: &TaskContext, task: &TaskArgs) -> String {
-  context.current_task_name.clone().unwrap_or_else(|| {
-    if !task.description.is_empty() {
-      task.description.clone()
-    } else {
-      format!("{:?}", task.commands)
-    }
-  })
+/// The key of the cache entry of the task. A task run without a name is keyed
+/// by its description, or else by its commands as canonical JSON.
+fn fingerprint_task_key(context: &TaskContext, task: &TaskArgs) -> anyhow::Result<String> {
+  if let Some(task_name) = &context.current_task_name {
+    return Ok(task_name.clone());
+  }
+  if !task.description.is_empty() {
+    return Ok(task.description.clone());
+  }
+  Ok(canonical_json(&serde_json::to_value(&task.commands)?))
 }
 
-fn stable_task_debug(task: &TaskArgs) -> String {
-  let mut labels: Vec<_> = task
-    .labels
-    .iter()
-    .map(|(key, value)| (key.clone(), value.clone()))
-    .collect();
-  labels.sort();
-
-  let mut environment: Vec<_> = task
-    .environment
-    .iter()
-    .map(|(key, value)| (key.clone(), value.clone()))
-    .collect();
-  environment.sort();
-
-  let mut secrets_path = task.secrets_path.clone();
-  secrets_path.sort();
-
-  format!(
-    "commands={:?};preconditions={:?};depends_on={:?};labels={:?};description={:?};environment={:?};env_file={:?};secrets_path={:?};vault_location={:?};keys_location={:?};key_name={:?};shell={:?};execution_mode={:?};max_parallel={:?};fail_fast={};cache_enabled={};inputs={:?};outputs={:?};ignore_errors={:?};verbose={:?}",
-    task.commands,
-    task.preconditions,
-    task.depends_on,
-    labels,
-    task.description,
-    environment,
-    task.env_file,
-    secrets_path,
-    task.vault_location,
-    task.keys_location,
-    task.key_name,
-    task.shell,
-    task.execution_mode(),
-    task.execution.as_ref().and_then(|execution| execution.max_parallel),
-    task.fail_fast(),
-    task.cache_enabled(),
-    task.inputs,
-    task.outputs,
-    task.ignore_errors,
-    task.verbose
-  )
+/// The task definition covered by the cache fingerprint. The matrix values of
+/// a matrix instance are not part of its config, so they are added.
+fn fingerprint_definition(task: &TaskArgs) -> anyhow::Result<serde_json::Value> {
+  let mut definition = serde_json::to_value(task)?;
+  if let Some(fields) = definition.as_object_mut() {
+    fields.insert(
+      "matrix_values".to_string(),
+      serde_json::to_value(&task.matrix_values)?,
+    );
+  }
+  Ok(definition)
 }
 
 #[cfg(test)]
@@ -1399,4 +2061,85 @@ mod test {
 
     Ok(())
   }
+
+  #[test]
+  fn test_cleanup_keeps_original_error() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let log = temp_dir.path().join("log.txt");
+    let yaml = format!(
+      r#"
+          commands:
+            - command: "exit 4"
+              verbose: false
+            - command: "echo unreachable >> {0}"
+              verbose: false
+          on_failure:
+            - command: "echo 'failed ${{{{ failure.command }}}} ${{{{ failure.exit_code }}}}' >> {0}"
+              verbose: false
+          finally:
+            - command: "exit 9"
+              verbose: false
+            - command: "echo finally >> {0}"
+              verbose: false
+      "#,
+      log.to_string_lossy()
+    );
+
+    let task = serde_yaml::from_str::<Task>(&yaml)?;
+    let mut context = TaskContext::empty();
+
+    let error = task.run(&mut context).expect_err("expected the task to fail");
+    assert_eq!(error.to_string(), "Command failed - exit 4");
+    assert_eq!(std::fs::read_to_string(&log)?, "failed exit 4 4\nfinally\n");
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_cache_select_env_1() -> anyhow::Result<()> {
+    let env_vars = HashMap::from([
+      ("PROFILE".to_string(), "release".to_string()),
+      ("BUILD_TIME".to_string(), "12:00".to_string()),
+    ]);
+    let process_env = HashMap::from([
+      ("PATH".to_string(), "/usr/bin".to_string()),
+      ("CARGO_HOME".to_string(), "/cargo".to_string()),
+      ("PROFILE".to_string(), "debug".to_string()),
+    ]);
+
+    // Without a list, the variables mk sets are used.
+    let cache = serde_yaml::from_str::<TaskCache>("exclude_env: [BUILD_*]")?;
+    assert_eq!(
+      cache.select_env(&env_vars, &process_env),
+      vec![("PROFILE".to_string(), "release".to_string())]
+    );
+
+    let cache = serde_yaml::from_str::<TaskCache>("env: [PROFILE, CARGO_*, RUSTFLAGS]")?;
+    assert_eq!(
+      cache.select_env(&env_vars, &process_env),
+      vec![
+        ("CARGO_HOME".to_string(), "/cargo".to_string()),
+        ("PROFILE".to_string(), "release".to_string()),
+      ]
+    );
+    assert!(cache.covers_env("RUSTFLAGS"));
+    assert!(!cache.covers_env("PATH"));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_fingerprint_task_key_1() -> anyhow::Result<()> {
+    let mut context = TaskContext::empty();
+    let task = serde_yaml::from_str::<TaskArgs>("commands: [cargo build]")?;
+    assert_eq!(fingerprint_task_key(&context, &task)?, r#"["cargo build"]"#);
+
+    let task = serde_yaml::from_str::<TaskArgs>("{ description: Build, commands: [cargo build] }")?;
+    assert_eq!(fingerprint_task_key(&context, &task)?, "Build");
+
+    context.set_current_task_name("build");
+    assert_eq!(fingerprint_task_key(&context, &task)?, "build");
+
+    Ok(())
+  }
 }
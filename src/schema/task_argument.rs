@@ -0,0 +1,415 @@
+use std::fmt;
+
+use hashbrown::HashMap;
+use schemars::JsonSchema;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use super::TaskRoot;
+use crate::utils::deserialize_scalar;
+
+/// The value type of a task argument
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskArgumentType {
+  #[default]
+  String,
+  Int,
+  Bool,
+  Enum,
+}
+
+/// A typed parameter a task accepts from the command line.
+/// Named arguments are passed as `--name value`, positional arguments by order.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct TaskArgument {
+  /// The argument name, used as `--name` and `${{ args.name }}`
+  pub name: String,
+
+  /// The value type of the argument
+  #[serde(default, rename = "type")]
+  pub kind: TaskArgumentType,
+
+  /// The value used when the argument is not passed
+  #[schemars(with = "Option<serde_json::Value>")]
+  #[serde(default, deserialize_with = "deserialize_scalar")]
+  pub default: Option<String>,
+
+  /// Fail before running anything if the argument is not passed
+  #[serde(default)]
+  pub required: bool,
+
+  /// The help text for the argument
+  #[serde(default)]
+  pub help: Option<String>,
+
+  /// Accept the argument by position instead of `--name`
+  #[serde(default)]
+  pub positional: bool,
+
+  /// The allowed values of an `enum` argument
+  #[serde(default)]
+  pub choices: Vec<String>,
+}
+
+impl TaskArgument {
+  /// Check a raw value against the argument type and normalize it.
+  pub fn parse_value(&self, value: &str) -> anyhow::Result<String> {
+    match self.kind {
+      TaskArgumentType::String => Ok(value.to_string()),
+      TaskArgumentType::Int => value
+        .trim()
+        .parse::<i64>()
+        .map(|value| value.to_string())
+        .map_err(|_| anyhow::anyhow!("Invalid value '{}' for {} - expected an integer", value, self)),
+      TaskArgumentType::Bool => match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok("true".to_string()),
+        "false" | "no" | "0" => Ok("false".to_string()),
+        _ => anyhow::bail!("Invalid value '{}' for {} - expected true or false", value, self),
+      },
+      TaskArgumentType::Enum => {
+        if self.choices.iter().any(|choice| choice == value) {
+          Ok(value.to_string())
+        } else {
+          anyhow::bail!(
+            "Invalid value '{}' for {} - expected one of: {}",
+            value,
+            self,
+            self.choices.join(", ")
+          )
+        }
+      },
+    }
+  }
+
+  /// The usage string of the argument, e.g. `--version <string>`
+  pub fn usage(&self) -> String {
+    let kind = match self.kind {
+      TaskArgumentType::String => "string".to_string(),
+      TaskArgumentType::Int => "int".to_string(),
+      TaskArgumentType::Bool => "bool".to_string(),
+      TaskArgumentType::Enum => self.choices.join("|"),
+    };
+
+    if self.positional {
+      format!("<{}:{}>", self.name, kind)
+    } else if self.kind == TaskArgumentType::Bool {
+      format!("--{}", self.name)
+    } else {
+      format!("--{} <{}>", self.name, kind)
+    }
+  }
+}
+
+impl fmt::Display for TaskArgument {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    if self.positional {
+      write!(f, "argument '{}'", self.name)
+    } else {
+      write!(f, "argument '--{}'", self.name)
+    }
+  }
+}
+
+/// The name under which the arguments after `--` are exposed, e.g. `${{ args.rest }}`
+pub const REST_ARGUMENT_NAME: &str = "rest";
+
+/// Split command line values at the first `--` into task arguments and the
+/// extra arguments forwarded to the task commands.
+pub fn split_extra_arguments(values: &[String]) -> (&[String], &[String]) {
+  match values.iter().position(|value| value == "--") {
+    Some(index) => (&values[..index], &values[index + 1..]),
+    None => (values, &[]),
+  }
+}
+
+/// A task named on the command line together with the values passed to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInvocation {
+  pub task_name: String,
+  pub args: Vec<String>,
+}
+
+/// Split command line values such as `lint test --version 1.2.3 build` into task
+/// invocations. A value that names a task starts a new invocation unless it is the
+/// value of a `--name` option of the current task. Values after `--` are kept with
+/// the last task.
+pub fn split_task_invocations(
+  task_root: &TaskRoot,
+  task_name: &str,
+  values: &[String],
+) -> Vec<TaskInvocation> {
+  let (values, extra_args) = split_extra_arguments(values);
+  let mut invocations = vec![TaskInvocation {
+    task_name: task_name.to_string(),
+    args: Vec::new(),
+  }];
+
+  let mut iter = values.iter();
+  while let Some(value) = iter.next() {
+    let current = invocations.last_mut().expect("at least one invocation");
+    let definitions = task_root
+      .tasks
+      .get(&current.task_name)
+      .map(|task| task.arguments())
+      .unwrap_or_default();
+
+    let takes_value = value.strip_prefix("--").is_some_and(|option| {
+      definitions.iter().any(|definition| {
+        !definition.positional && definition.kind != TaskArgumentType::Bool && definition.name == option
+      })
+    });
+
+    if takes_value {
+      current.args.push(value.clone());
+      current.args.extend(iter.next().cloned());
+    } else if task_root.tasks.contains_key(value) {
+      invocations.push(TaskInvocation {
+        task_name: value.clone(),
+        args: Vec::new(),
+      });
+    } else {
+      current.args.push(value.clone());
+    }
+  }
+
+  if !extra_args.is_empty() {
+    let last = invocations.last_mut().expect("at least one invocation");
+    last.args.push("--".to_string());
+    last.args.extend(extra_args.iter().cloned());
+  }
+
+  invocations
+}
+
+/// Parse command line values against the task argument definitions.
+///
+/// Returns the resolved value of every declared argument. Arguments that were not
+/// passed fall back to their default; optional arguments without a default resolve
+/// to an empty string (or `false` for bool arguments). When `require` is false,
+/// missing required arguments are left out instead of failing.
+pub fn parse_task_arguments(
+  task_name: &str,
+  definitions: &[TaskArgument],
+  values: &[String],
+  require: bool,
+) -> anyhow::Result<HashMap<String, String>> {
+  if definitions.is_empty() {
+    if let Some(value) = values.first() {
+      anyhow::bail!("Task '{}' does not accept arguments, got '{}'", task_name, value);
+    }
+    return Ok(HashMap::new());
+  }
+
+  let mut parsed = HashMap::new();
+  let mut positionals = definitions.iter().filter(|definition| definition.positional);
+  let mut iter = values.iter();
+  while let Some(value) = iter.next() {
+    if let Some(option) = value.strip_prefix("--").filter(|option| !option.is_empty()) {
+      let (name, inline_value) = match option.split_once('=') {
+        Some((name, inline_value)) => (name, Some(inline_value)),
+        None => (option, None),
+      };
+
+      let definition = definitions
+        .iter()
+        .find(|definition| !definition.positional && definition.name == name)
+        .ok_or_else(|| {
+          anyhow::anyhow!(
+            "Unknown argument '--{}' for task '{}'. Run 'mk plan {}' to see accepted arguments.",
+            name,
+            task_name,
+            task_name
+          )
+        })?;
+
+      let raw = match inline_value {
+        Some(inline_value) => inline_value.to_string(),
+        None if definition.kind == TaskArgumentType::Bool => "true".to_string(),
+        None => iter
+          .next()
+          .cloned()
+          .ok_or_else(|| anyhow::anyhow!("Missing value for {} of task '{}'", definition, task_name))?,
+      };
+
+      parsed.insert(definition.name.clone(), definition.parse_value(&raw)?);
+    } else {
+      let definition = positionals.next().ok_or_else(|| {
+        anyhow::anyhow!(
+          "Unexpected argument '{}' for task '{}'. Run 'mk plan {}' to see accepted arguments.",
+          value,
+          task_name,
+          task_name
+        )
+      })?;
+
+      parsed.insert(definition.name.clone(), definition.parse_value(value)?);
+    }
+  }
+
+  for definition in definitions {
+    if parsed.contains_key(&definition.name) {
+      continue;
+    }
+
+    if let Some(default) = &definition.default {
+      parsed.insert(definition.name.clone(), definition.parse_value(default)?);
+    } else if definition.required {
+      if require {
+        let help = definition
+          .help
+          .as_ref()
+          .map(|help| format!(" ({})", help))
+          .unwrap_or_default();
+        anyhow::bail!("Missing required {} for task '{}'{}", definition, task_name, help);
+      }
+    } else if definition.kind == TaskArgumentType::Bool {
+      parsed.insert(definition.name.clone(), "false".to_string());
+    } else {
+      parsed.insert(definition.name.clone(), String::new());
+    }
+  }
+
+  Ok(parsed)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn definitions() -> anyhow::Result<Vec<TaskArgument>> {
+    let yaml = "
+      - name: version
+        required: true
+        help: Version to release
+      - name: count
+        type: int
+        default: 3
+      - name: dry
+        type: bool
+      - name: channel
+        type: enum
+        choices: [stable, beta]
+        default: stable
+      - name: target
+        positional: true
+    ";
+    Ok(serde_yaml::from_str::<Vec<TaskArgument>>(yaml)?)
+  }
+
+  fn to_values(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+  }
+
+  #[test]
+  fn test_task_argument_1() -> anyhow::Result<()> {
+    let definitions = definitions()?;
+    let parsed = parse_task_arguments(
+      "release",
+      &definitions,
+      &to_values(&["--version", "1.2.3", "--dry", "--channel=beta", "x86_64"]),
+      true,
+    )?;
+
+    assert_eq!(parsed.get("version").map(String::as_str), Some("1.2.3"));
+    assert_eq!(parsed.get("count").map(String::as_str), Some("3"));
+    assert_eq!(parsed.get("dry").map(String::as_str), Some("true"));
+    assert_eq!(parsed.get("channel").map(String::as_str), Some("beta"));
+    assert_eq!(parsed.get("target").map(String::as_str), Some("x86_64"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_argument_2() -> anyhow::Result<()> {
+    let definitions = definitions()?;
+    let error = parse_task_arguments("release", &definitions, &[], true).unwrap_err();
+    assert_eq!(
+      error.to_string(),
+      "Missing required argument '--version' for task 'release' (Version to release)"
+    );
+
+    let parsed = parse_task_arguments("release", &definitions, &[], false)?;
+    assert!(!parsed.contains_key("version"));
+    assert_eq!(parsed.get("dry").map(String::as_str), Some("false"));
+    assert_eq!(parsed.get("target").map(String::as_str), Some(""));
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_argument_3() -> anyhow::Result<()> {
+    let definitions = definitions()?;
+
+    let error =
+      parse_task_arguments("release", &definitions, &to_values(&["--count", "many"]), true).unwrap_err();
+    assert!(error.to_string().contains("expected an integer"));
+
+    let error = parse_task_arguments(
+      "release",
+      &definitions,
+      &to_values(&["--channel", "nightly"]),
+      true,
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("expected one of: stable, beta"));
+
+    let error =
+      parse_task_arguments("release", &definitions, &to_values(&["--unknown", "1"]), true).unwrap_err();
+    assert!(error.to_string().contains("Unknown argument '--unknown'"));
+
+    let error = parse_task_arguments("build", &[], &to_values(&["--version", "1"]), true).unwrap_err();
+    assert!(error.to_string().contains("does not accept arguments"));
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_argument_4() {
+    let values = to_values(&["--version", "1.2.3", "--", "-p", "foo", "--"]);
+    let (args, extra_args) = split_extra_arguments(&values);
+    assert_eq!(args, to_values(&["--version", "1.2.3"]).as_slice());
+    assert_eq!(extra_args, to_values(&["-p", "foo", "--"]).as_slice());
+
+    let values = to_values(&["--dry"]);
+    let (args, extra_args) = split_extra_arguments(&values);
+    assert_eq!(args, values.as_slice());
+    assert!(extra_args.is_empty());
+  }
+
+  #[test]
+  fn test_task_argument_5() -> anyhow::Result<()> {
+    let yaml = "
+      tasks:
+        lint: echo lint
+        test: echo test
+        release:
+          args:
+            - name: target
+              default: test
+            - name: dry
+              type: bool
+          commands:
+            - echo ${{ args.target }}
+    ";
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+
+    let values = to_values(&[
+      "test", "release", "--target", "lint", "--dry", "lint", "--", "-p", "foo",
+    ]);
+    let invocations = split_task_invocations(&task_root, "lint", &values);
+    let invocations: Vec<_> = invocations
+      .iter()
+      .map(|invocation| (invocation.task_name.as_str(), invocation.args.clone()))
+      .collect();
+    assert_eq!(
+      invocations,
+      vec![
+        ("lint", vec![]),
+        ("test", vec![]),
+        ("release", to_values(&["--target", "lint", "--dry"])),
+        ("lint", to_values(&["--", "-p", "foo"])),
+      ]
+    );
+    Ok(())
+  }
+}
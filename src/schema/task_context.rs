@@ -2,31 +2,78 @@ use std::collections::HashSet;
 use std::path::PathBuf;
 use std::sync::{
   Arc,
+  Condvar,
   Mutex,
 };
+use std::time::{
+  Duration,
+  Instant,
+};
 
 use hashbrown::HashMap;
-use indicatif::{
-  MultiProgress,
-  ProgressDrawTarget,
-};
 use serde::Serialize;
 
-use crate::cache::CacheStore;
+use crate::cache::{
+  CacheStore,
+  FileIndex,
+};
 use crate::defaults::{
   default_ignore_errors,
   default_shell,
   default_verbose,
 };
+use crate::progress::{
+  create_renderer,
+  hidden_renderer,
+  ProgressMode,
+  RecordingRenderer,
+  Renderer,
+};
 
 use super::{
+  lookup_output,
   ActiveTasks,
+  Cancellation,
+  CommandFailed,
+  CommandTimedOut,
+  CommandTimeout,
   CompletedTasks,
   ContainerRuntime,
+  PublishedOutputs,
+  RunCancelled,
   Shell,
+  TaskDeadline,
   TaskRoot,
 };
 
+/// The command that made a task fail, exposed to its `on_failure` and `finally`
+/// commands as `${{ failure.command }}` and `${{ failure.exit_code }}`
+#[derive(Debug, Clone, Default)]
+pub struct TaskFailure {
+  pub command: String,
+  pub exit_code: Option<i32>,
+}
+
+impl TaskFailure {
+  pub fn from_error(error: &anyhow::Error) -> Self {
+    for cause in error.chain() {
+      if let Some(failed) = cause.downcast_ref::<CommandFailed>() {
+        return Self {
+          command: failed.command.clone(),
+          exit_code: failed.exit_code,
+        };
+      }
+      if let Some(timed_out) = cause.downcast_ref::<CommandTimedOut>() {
+        return Self {
+          command: timed_out.command.clone(),
+          exit_code: None,
+        };
+      }
+    }
+    Self::default()
+  }
+}
+
 /// Used to pass information to tasks
 /// This use arc to allow for sharing of data between tasks
 /// and allow parallel runs of tasks
@@ -35,9 +82,18 @@ pub struct TaskContext {
   pub task_root: Arc<TaskRoot>,
   pub active_tasks: ActiveTasks,
   pub completed_tasks: CompletedTasks,
-  pub multi: Arc<MultiProgress>,
+  pub task_finished: Arc<Condvar>,
+  pub task_stack: Vec<String>,
+  pub renderer: Arc<dyn Renderer>,
+  /// Keeps the output of the running command when its task replays it on
+  /// cache hits
+  pub output_recorder: Option<Arc<RecordingRenderer>>,
   pub env_vars: HashMap<String, String>,
   pub task_outputs: Arc<Mutex<HashMap<String, String>>>,
+  pub published_outputs: PublishedOutputs,
+  pub task_arguments: HashMap<String, String>,
+  pub matrix_values: HashMap<String, String>,
+  pub extra_args: Vec<String>,
   pub secret_vault_location: Option<String>,
   pub secret_keys_location: Option<String>,
   pub secret_key_name: Option<String>,
@@ -48,21 +104,32 @@ pub struct TaskContext {
   pub verbose: Option<bool>,
   pub force: bool,
   pub json_events: bool,
+  pub explain: bool,
   pub is_nested: bool,
   pub cache_store: Arc<Mutex<CacheStore>>,
+  pub file_index: Arc<Mutex<FileIndex>>,
   pub current_task_name: Option<String>,
+  pub deadline: Option<TaskDeadline>,
+  pub failure: Option<TaskFailure>,
+  pub cancellation: Option<Cancellation>,
 }
 
 impl TaskContext {
   pub fn empty() -> Self {
-    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
     Self {
       task_root: Arc::new(TaskRoot::default()),
       active_tasks: Arc::new(Mutex::new(HashSet::new())),
       completed_tasks: Arc::new(Mutex::new(HashSet::new())),
-      multi: Arc::new(mp),
+      task_finished: Arc::new(Condvar::new()),
+      task_stack: Vec::new(),
+      renderer: hidden_renderer(),
+      output_recorder: None,
       env_vars: HashMap::new(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: Arc::new(Mutex::new(HashMap::new())),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: None,
       secret_keys_location: None,
       secret_key_name: None,
@@ -73,21 +140,32 @@ impl TaskContext {
       verbose: None,
       force: false,
       json_events: false,
+      explain: false,
       is_nested: false,
       cache_store: Arc::new(Mutex::new(CacheStore::default())),
+      file_index: Arc::new(Mutex::new(FileIndex::default())),
       current_task_name: None,
+      deadline: None,
+      failure: None,
+      cancellation: None,
     }
   }
 
   pub fn empty_with_root(task_root: Arc<TaskRoot>) -> Self {
-    let mp = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
     Self {
       task_root: task_root.clone(),
       active_tasks: Arc::new(Mutex::new(HashSet::new())),
       completed_tasks: Arc::new(Mutex::new(HashSet::new())),
-      multi: Arc::new(mp),
+      task_finished: Arc::new(Condvar::new()),
+      task_stack: Vec::new(),
+      renderer: hidden_renderer(),
+      output_recorder: None,
       env_vars: HashMap::new(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: Arc::new(Mutex::new(HashMap::new())),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: None,
       secret_keys_location: None,
       secret_key_name: None,
@@ -98,21 +176,34 @@ impl TaskContext {
       verbose: None,
       force: false,
       json_events: false,
+      explain: false,
       is_nested: false,
       cache_store: Arc::new(Mutex::new(CacheStore::default())),
+      file_index: Arc::new(Mutex::new(FileIndex::default())),
       current_task_name: None,
+      deadline: None,
+      failure: None,
+      cancellation: None,
     }
   }
 
   pub fn new(task_root: Arc<TaskRoot>) -> Self {
-    let cache_store = CacheStore::load_in_dir(&task_root.cache_base_dir()).unwrap_or_default();
+    let cache_store = load_cache_store(&task_root);
+    let file_index = FileIndex::load_in_dir(&task_root.cache_base_dir()).unwrap_or_default();
     Self {
       task_root: task_root.clone(),
       active_tasks: Arc::new(Mutex::new(HashSet::new())),
       completed_tasks: Arc::new(Mutex::new(HashSet::new())),
-      multi: Arc::new(MultiProgress::new()),
+      task_finished: Arc::new(Condvar::new()),
+      task_stack: Vec::new(),
+      renderer: create_renderer(ProgressMode::Auto),
+      output_recorder: None,
       env_vars: HashMap::new(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: Arc::new(Mutex::new(HashMap::new())),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: task_root.vault_location.clone(),
       secret_keys_location: task_root.keys_location.clone(),
       secret_key_name: task_root.key_name.clone(),
@@ -123,26 +214,39 @@ impl TaskContext {
       verbose: None,
       force: false,
       json_events: false,
+      explain: false,
       is_nested: false,
       cache_store: Arc::new(Mutex::new(cache_store)),
+      file_index: Arc::new(Mutex::new(file_index)),
       current_task_name: None,
+      deadline: None,
+      failure: None,
+      cancellation: None,
     }
   }
 
   pub fn new_with_options(task_root: Arc<TaskRoot>, force: bool, json_events: bool) -> Self {
-    let cache_store = CacheStore::load_in_dir(&task_root.cache_base_dir()).unwrap_or_default();
-    let multi = if json_events {
-      Arc::new(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()))
+    let cache_store = load_cache_store(&task_root);
+    let file_index = FileIndex::load_in_dir(&task_root.cache_base_dir()).unwrap_or_default();
+    let renderer = if json_events {
+      hidden_renderer()
     } else {
-      Arc::new(MultiProgress::new())
+      create_renderer(ProgressMode::Auto)
     };
     Self {
       task_root: task_root.clone(),
       active_tasks: Arc::new(Mutex::new(HashSet::new())),
       completed_tasks: Arc::new(Mutex::new(HashSet::new())),
-      multi,
+      task_finished: Arc::new(Condvar::new()),
+      task_stack: Vec::new(),
+      renderer,
+      output_recorder: None,
       env_vars: HashMap::new(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: Arc::new(Mutex::new(HashMap::new())),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: task_root.vault_location.clone(),
       secret_keys_location: task_root.keys_location.clone(),
       secret_key_name: task_root.key_name.clone(),
@@ -153,9 +257,14 @@ impl TaskContext {
       verbose: None,
       force,
       json_events,
+      explain: false,
       is_nested: false,
       cache_store: Arc::new(Mutex::new(cache_store)),
+      file_index: Arc::new(Mutex::new(file_index)),
       current_task_name: None,
+      deadline: None,
+      failure: None,
+      cancellation: None,
     }
   }
 
@@ -164,9 +273,16 @@ impl TaskContext {
       task_root: context.task_root.clone(),
       active_tasks: context.active_tasks.clone(),
       completed_tasks: context.completed_tasks.clone(),
-      multi: context.multi.clone(),
+      task_finished: context.task_finished.clone(),
+      task_stack: context.task_stack.clone(),
+      renderer: context.renderer.clone(),
+      output_recorder: context.output_recorder.clone(),
       env_vars: context.env_vars.clone(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: context.published_outputs.clone(),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: context.secret_vault_location.clone(),
       secret_keys_location: context.secret_keys_location.clone(),
       secret_key_name: context.secret_key_name.clone(),
@@ -177,9 +293,14 @@ impl TaskContext {
       verbose: context.verbose,
       force: context.force,
       json_events: context.json_events,
+      explain: context.explain,
       is_nested: true,
       cache_store: context.cache_store.clone(),
+      file_index: context.file_index.clone(),
       current_task_name: context.current_task_name.clone(),
+      deadline: context.deadline.clone(),
+      failure: context.failure.clone(),
+      cancellation: context.cancellation.clone(),
     }
   }
 
@@ -188,9 +309,16 @@ impl TaskContext {
       task_root: context.task_root.clone(),
       active_tasks: context.active_tasks.clone(),
       completed_tasks: context.completed_tasks.clone(),
-      multi: context.multi.clone(),
+      task_finished: context.task_finished.clone(),
+      task_stack: context.task_stack.clone(),
+      renderer: context.renderer.clone(),
+      output_recorder: context.output_recorder.clone(),
       env_vars: context.env_vars.clone(),
       task_outputs: Arc::new(Mutex::new(HashMap::new())),
+      published_outputs: context.published_outputs.clone(),
+      task_arguments: HashMap::new(),
+      matrix_values: HashMap::new(),
+      extra_args: Vec::new(),
       secret_vault_location: context.secret_vault_location.clone(),
       secret_keys_location: context.secret_keys_location.clone(),
       secret_key_name: context.secret_key_name.clone(),
@@ -201,9 +329,14 @@ impl TaskContext {
       verbose: Some(verbose),
       force: context.force,
       json_events: context.json_events,
+      explain: context.explain,
       is_nested: true,
       cache_store: context.cache_store.clone(),
+      file_index: context.file_index.clone(),
       current_task_name: context.current_task_name.clone(),
+      deadline: context.deadline.clone(),
+      failure: context.failure.clone(),
+      cancellation: context.cancellation.clone(),
     }
   }
 
@@ -247,6 +380,70 @@ impl TaskContext {
     self.verbose = Some(verbose);
   }
 
+  /// Report why cached tasks are not skipped
+  pub fn set_explain(&mut self, explain: bool) {
+    self.explain = explain;
+  }
+
+  /// Record the output of the command run with this context, forwarding it to
+  /// the current renderer
+  pub fn record_output(&mut self) -> Arc<RecordingRenderer> {
+    let recorder = Arc::new(RecordingRenderer::new(self.renderer.clone()));
+    self.renderer = recorder.clone();
+    self.output_recorder = Some(recorder.clone());
+    recorder
+  }
+
+  /// Note that a command failed and its error was ignored, so its output is
+  /// replayed with `cache.replay_output: errors_only`
+  pub fn note_ignored_failure(&self) {
+    if let Some(recorder) = &self.output_recorder {
+      recorder.mark_failed();
+    }
+  }
+
+  /// Render progress with the given mode. `--json-events` keeps stdout for events only.
+  pub fn set_progress_mode(&mut self, mode: ProgressMode) {
+    if !self.json_events {
+      self.renderer = create_renderer(mode);
+    }
+  }
+
+  /// Limit how long the rest of the task may run. A deadline inherited from an
+  /// outer task is kept when it is earlier.
+  pub fn set_task_timeout(&mut self, task_name: &str, timeout: Duration) {
+    let at = Instant::now() + timeout;
+    if self.deadline.as_ref().is_some_and(|deadline| deadline.at <= at) {
+      return;
+    }
+    self.deadline = Some(TaskDeadline {
+      task: task_name.to_string(),
+      at,
+      timeout,
+    });
+  }
+
+  /// The time limit of a command with the given `timeout`, bounded by the task deadline
+  pub fn command_timeout(&self, timeout: Option<Duration>) -> Option<CommandTimeout> {
+    CommandTimeout::resolve(timeout, self.deadline.as_ref())
+  }
+
+  pub fn set_failure(&mut self, failure: TaskFailure) {
+    self.failure = Some(failure);
+  }
+
+  pub fn set_task_arguments(&mut self, arguments: HashMap<String, String>) {
+    self.task_arguments = arguments;
+  }
+
+  pub fn set_matrix_values(&mut self, values: HashMap<String, String>) {
+    self.matrix_values = values;
+  }
+
+  pub fn set_extra_args(&mut self, extra_args: Vec<String>) {
+    self.extra_args = extra_args;
+  }
+
   pub fn insert_task_output(&self, name: impl Into<String>, value: impl Into<String>) -> anyhow::Result<()> {
     let name = name.into();
     let mut outputs = self
@@ -265,7 +462,42 @@ impl TaskContext {
       .task_outputs
       .lock()
       .map_err(|e| anyhow::anyhow!("Failed to lock task outputs - {}", e))?;
-    Ok(outputs.get(name).cloned())
+    lookup_output(&outputs, name)
+  }
+
+  /// Publish the saved outputs of the current task, so tasks that depend on
+  /// it can read them as `${{ tasks.NAME.outputs.OUTPUT }}`
+  pub fn publish_task_outputs(&self, task_name: &str) -> anyhow::Result<()> {
+    let outputs = self
+      .task_outputs
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock task outputs - {}", e))?
+      .clone();
+    self.set_published_outputs(task_name, outputs)
+  }
+
+  pub fn set_published_outputs(
+    &self,
+    task_name: &str,
+    outputs: HashMap<String, String>,
+  ) -> anyhow::Result<()> {
+    let mut published = self
+      .published_outputs
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock published outputs - {}", e))?;
+    published.insert(task_name.to_string(), outputs);
+    Ok(())
+  }
+
+  pub fn get_published_output(&self, task_name: &str, name: &str) -> anyhow::Result<Option<String>> {
+    let published = self
+      .published_outputs
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock published outputs - {}", e))?;
+    match published.get(task_name) {
+      Some(outputs) => lookup_output(outputs, name),
+      None => Ok(None),
+    }
   }
 
   pub fn has_task_output(&self, name: &str) -> anyhow::Result<bool> {
@@ -313,12 +545,45 @@ impl TaskContext {
     Ok(())
   }
 
+  /// Mark the task as active unless it already ran. When another thread is running
+  /// the task, wait for it to finish first. Returns false if the task completed.
+  pub fn begin_task(&self, task_name: &str) -> anyhow::Result<bool> {
+    let mut active = self
+      .active_tasks
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock active tasks - {}", e))?;
+    while active.contains(task_name) {
+      active = self
+        .task_finished
+        .wait(active)
+        .map_err(|e| anyhow::anyhow!("Failed to lock active tasks - {}", e))?;
+    }
+
+    if self.is_task_completed(task_name)? {
+      return Ok(false);
+    }
+
+    active.insert(task_name.to_string());
+    Ok(true)
+  }
+
   pub fn unmark_task_active(&self, task_name: &str) -> anyhow::Result<()> {
     let mut active = self
       .active_tasks
       .lock()
       .map_err(|e| anyhow::anyhow!("Failed to lock active tasks - {}", e))?;
     active.remove(task_name);
+    self.task_finished.notify_all();
+    Ok(())
+  }
+
+  /// Forget that the task ran, so the next run of this context runs it again
+  pub fn unmark_task_complete(&self, task_name: &str) -> anyhow::Result<()> {
+    let mut completed = self
+      .completed_tasks
+      .lock()
+      .map_err(|e| anyhow::anyhow!("Failed to lock completed tasks - {}", e))?;
+    completed.remove(task_name);
     Ok(())
   }
 
@@ -331,6 +596,28 @@ impl TaskContext {
     Ok(())
   }
 
+  /// Make the commands of the run stoppable with the given cancellation
+  pub fn set_cancellation(&mut self, cancellation: Cancellation) {
+    self.cancellation = Some(cancellation);
+  }
+
+  /// Whether commands run in their own process group so they can be cancelled
+  pub fn is_cancellable(&self) -> bool {
+    self.cancellation.is_some()
+  }
+
+  /// Fail with `RunCancelled` once the run was cancelled
+  pub fn check_cancelled(&self) -> anyhow::Result<()> {
+    if self
+      .cancellation
+      .as_ref()
+      .is_some_and(|cancellation| cancellation.is_cancelled())
+    {
+      return Err(RunCancelled.into());
+    }
+    Ok(())
+  }
+
   pub fn emit_event<T: Serialize>(&self, value: &T) -> anyhow::Result<()> {
     if self.json_events {
       println!("{}", serde_json::to_string(value)?);
@@ -342,11 +629,36 @@ impl TaskContext {
     self.current_task_name = Some(task_name.to_string());
   }
 
+  /// Record that the task runs within the current dependency chain.
+  pub fn push_task_stack(&mut self, task_name: &str) {
+    self.task_stack.push(task_name.to_string());
+  }
+
+  /// Whether this is the context of a task invoked directly rather than as a
+  /// dependency or a `task` command. Its dependencies inherit what it loads.
+  pub fn is_root_task(&self) -> bool {
+    self.task_stack.len() <= 1
+  }
+
+  /// Check if the task is already running further up the current dependency chain.
+  pub fn is_task_in_stack(&self, task_name: &str) -> bool {
+    self.task_stack.iter().any(|name| name == task_name)
+  }
+
   pub fn resolve_from_config(&self, value: &str) -> PathBuf {
     self.task_root.resolve_from_config(value)
   }
 }
 
+/// The cache entries of the project. Tasks run without them when the cache file
+/// cannot be read.
+fn load_cache_store(task_root: &TaskRoot) -> CacheStore {
+  CacheStore::load_in_dir(&task_root.cache_base_dir()).unwrap_or_else(|e| {
+    eprintln!("Failed to load the cache, running without it - {:#}", e);
+    CacheStore::default()
+  })
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
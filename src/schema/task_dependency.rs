@@ -1,18 +1,42 @@
-use schemars::JsonSchema;
-use serde::Deserialize;
+use std::time::{
+  Duration,
+  Instant,
+};
 
-use super::TaskContext;
+use hashbrown::HashMap;
+use schemars::JsonSchema;
+use serde::{
+  Deserialize,
+  Serialize,
+};
+
+use super::{
+  evaluate_condition,
+  parse_task_arguments,
+  run_task_graph,
+  split_extra_arguments,
+  Task,
+  TaskContext,
+  TaskInvocation,
+  REST_ARGUMENT_NAME,
+};
+use crate::cache::CacheStatus;
+use crate::utils::shell_join;
 
 /// This struct represents a task dependency. A task can depend on other tasks.
 /// If a task depends on another task, the dependent task must be executed before
 /// the dependent task.
-#[derive(Debug, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct TaskDependencyArgs {
   /// The name of the task to depend on
   pub name: String,
+
+  /// Only run the dependency when this expression is true, e.g. `env.CI != 'true'`
+  #[serde(default, rename = "if")]
+  pub condition: Option<String>,
 }
 
-#[derive(Debug, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 #[serde(untagged)]
 /// A task dependency: either a task name string or an object with a `name` field.
 pub enum TaskDependency {
@@ -22,9 +46,28 @@ pub enum TaskDependency {
 
 impl TaskDependency {
   pub fn run(&self, context: &TaskContext) -> anyhow::Result<()> {
+    if let Some(condition) = self.condition() {
+      if !evaluate_condition(condition, context)? {
+        context.emit_event(&serde_json::json!({
+          "event": "task_skipped",
+          "task": self.resolve_name(),
+          "reason": "condition_false",
+        }))?;
+        return Ok(());
+      }
+    }
+
     run_task_by_name(context, self.resolve_name())
   }
 
+  /// The `if` expression of the dependency, if it has one
+  pub fn condition(&self) -> Option<&str> {
+    match self {
+      TaskDependency::String(_) => None,
+      TaskDependency::TaskDependency(args) => args.condition.as_deref(),
+    }
+  }
+
   pub fn resolve_name(&self) -> &str {
     match self {
       TaskDependency::String(name) => name,
@@ -40,42 +83,111 @@ impl TaskDependencyArgs {
 }
 
 pub fn run_task_by_name(context: &TaskContext, task_name: &str) -> anyhow::Result<()> {
+  run_task_by_name_with_args(context, task_name, &[])
+}
+
+/// Run a task by name, parsing `args` against the typed arguments the task declares.
+/// Values after `--` are forwarded to the task commands instead of being parsed.
+/// Argument errors are reported before any dependency runs.
+pub fn run_task_by_name_with_args(
+  context: &TaskContext,
+  task_name: &str,
+  args: &[String],
+) -> anyhow::Result<()> {
   assert!(!task_name.is_empty());
+  context.check_cancelled()?;
 
   if context.is_task_completed(task_name)? {
     log::trace!("Skipping completed task: {}", task_name);
     return Ok(());
   }
 
-  if context.is_task_active(task_name)? {
-    anyhow::bail!("Circular dependency detected - {}", task_name);
-  }
-
-  let task = context.task_root.tasks.get(task_name).ok_or_else(|| {
-    anyhow::anyhow!(
-      "Task '{}' not found. Run 'mk list' to see available tasks.",
-      task_name
-    )
-  })?;
+  let task = find_task(context, task_name)?;
+  let (args, extra_args) = split_extra_arguments(args);
+  let mut arguments = parse_task_arguments(task_name, task.arguments(), args, true)?;
+  arguments.insert(REST_ARGUMENT_NAME.to_string(), shell_join(extra_args)?);
 
-  log::trace!("Task: {:?}", task);
+  run_task_with_arguments(context, task_name, arguments, extra_args)
+}
 
-  context.mark_task_active(task_name)?;
+/// Run a task by name with arguments that were already parsed, e.g. a matrix
+/// instance receiving the arguments of the task it was expanded from.
+pub fn run_task_with_arguments(
+  context: &TaskContext,
+  task_name: &str,
+  arguments: HashMap<String, String>,
+  extra_args: &[String],
+) -> anyhow::Result<()> {
+  assert!(!task_name.is_empty());
 
-  let result = {
+  run_task_once(context, task_name, |task| {
     let mut child_context = TaskContext::from_context(context);
     child_context.set_current_task_name(task_name);
+    child_context.push_task_stack(task_name);
+    child_context.set_task_arguments(arguments);
+    child_context.set_extra_args(extra_args.to_vec());
     child_context.emit_event(&serde_json::json!({
       "event": "task_started",
       "task": task_name,
     }))?;
     task.run(&mut child_context)
-  };
+  })
+}
 
-  context.unmark_task_active(task_name)?;
+/// Run a task in the context `task_dependency_context` prepared for its
+/// dependencies, so its settings and environment are not applied twice
+pub fn run_task_in_prepared_context(
+  context: &TaskContext,
+  prepared_context: &TaskContext,
+  task_name: &str,
+) -> anyhow::Result<()> {
+  assert!(!task_name.is_empty());
+  context.check_cancelled()?;
 
+  run_task_once(context, task_name, |task| {
+    let mut child_context = prepared_context.clone();
+    child_context.emit_event(&serde_json::json!({
+      "event": "task_started",
+      "task": task_name,
+    }))?;
+    task.run_prepared(&mut child_context)
+  })
+}
+
+/// Run a task unless it already completed, marking it active while it runs
+fn run_task_once(
+  context: &TaskContext,
+  task_name: &str,
+  run: impl FnOnce(&Task) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+  if context.is_task_completed(task_name)? {
+    log::trace!("Skipping completed task: {}", task_name);
+    return Ok(());
+  }
+
+  if context.is_task_in_stack(task_name) {
+    anyhow::bail!("Circular dependency detected - {}", task_name);
+  }
+
+  let task = find_task(context, task_name)?;
+
+  log::trace!("Task: {:?}", task);
+
+  // Another root running in parallel may be running the same task, wait for it.
+  if !context.begin_task(task_name)? {
+    log::trace!("Skipping completed task: {}", task_name);
+    return Ok(());
+  }
+
+  let result = run(task);
+
+  // Mark the task complete before releasing it so waiting roots skip it.
   if result.is_ok() {
     context.mark_task_complete(task_name)?;
+  }
+  context.unmark_task_active(task_name)?;
+
+  if result.is_ok() {
     context.emit_event(&serde_json::json!({
       "event": "task_finished",
       "task": task_name,
@@ -92,6 +204,116 @@ pub fn run_task_by_name(context: &TaskContext, task_name: &str) -> anyhow::Resul
   result
 }
 
+/// Whether a task would be skipped from the cache when run directly with the
+/// arguments, without running it or its dependencies
+pub fn task_cache_status(
+  context: &TaskContext,
+  task_name: &str,
+  args: &[String],
+) -> anyhow::Result<CacheStatus> {
+  let (task, mut child_context) = task_child_context(context, task_name, args)?;
+  let Task::Task(task_args) = task else {
+    return Ok(CacheStatus::Disabled);
+  };
+
+  task_args.cache_status(&mut child_context)
+}
+
+/// The context the dependencies of a task run from, with the settings and
+/// environment of the task applied as when the task runs them itself
+pub fn task_dependency_context(
+  context: &TaskContext,
+  task_name: &str,
+  args: &[String],
+) -> anyhow::Result<TaskContext> {
+  let (task, mut child_context) = task_child_context(context, task_name, args)?;
+  if let Task::Task(task_args) = task {
+    task_args.prepare_dependency_context(&mut child_context)?;
+  }
+  Ok(child_context)
+}
+
+/// A child context for the task, with the arguments parsed against the task
+fn task_child_context<'a>(
+  context: &'a TaskContext,
+  task_name: &str,
+  args: &[String],
+) -> anyhow::Result<(&'a Task, TaskContext)> {
+  let task = find_task(context, task_name)?;
+  let (args, extra_args) = split_extra_arguments(args);
+  let mut arguments = parse_task_arguments(task_name, task.arguments(), args, true)?;
+  arguments.insert(REST_ARGUMENT_NAME.to_string(), shell_join(extra_args)?);
+
+  let mut child_context = TaskContext::from_context(context);
+  child_context.set_current_task_name(task_name);
+  child_context.push_task_stack(task_name);
+  child_context.set_task_arguments(arguments);
+  child_context.set_extra_args(extra_args.to_vec());
+  Ok((task, child_context))
+}
+
+fn find_task<'a>(context: &'a TaskContext, task_name: &str) -> anyhow::Result<&'a Task> {
+  context.task_root.tasks.get(task_name).ok_or_else(|| {
+    anyhow::anyhow!(
+      "Task '{}' not found. Run 'mk list' to see available tasks.",
+      task_name
+    )
+  })
+}
+
+/// The outcome of a task named on the command line.
+#[derive(Debug)]
+pub enum TaskRunOutcome {
+  Succeeded(Duration),
+  Failed(Duration, anyhow::Error),
+  Skipped,
+}
+
+impl TaskRunOutcome {
+  pub fn status(&self) -> &'static str {
+    match self {
+      TaskRunOutcome::Succeeded(_) => "succeeded",
+      TaskRunOutcome::Failed(..) => "failed",
+      TaskRunOutcome::Skipped => "skipped",
+    }
+  }
+
+  pub fn is_failed(&self) -> bool {
+    matches!(self, TaskRunOutcome::Failed(..))
+  }
+}
+
+/// Run several tasks over the same context so shared dependencies run once.
+/// With `jobs` above one, the tasks and their dependencies are run by the graph
+/// scheduler. Otherwise they run one after another, stopping at the first
+/// failure, and the remaining tasks are reported as skipped.
+pub fn run_task_invocations(
+  context: &TaskContext,
+  invocations: &[TaskInvocation],
+  jobs: usize,
+) -> Vec<TaskRunOutcome> {
+  if jobs > 1 {
+    return run_task_graph(context, invocations, jobs);
+  }
+
+  let mut outcomes = Vec::new();
+  for invocation in invocations {
+    if outcomes.iter().any(TaskRunOutcome::is_failed) {
+      outcomes.push(TaskRunOutcome::Skipped);
+      continue;
+    }
+
+    let started = Instant::now();
+    outcomes.push(
+      match run_task_by_name_with_args(context, &invocation.task_name, &invocation.args) {
+        Ok(()) => TaskRunOutcome::Succeeded(started.elapsed()),
+        Err(e) => TaskRunOutcome::Failed(started.elapsed(), e),
+      },
+    );
+  }
+  outcomes
+}
+
 #[cfg(test)]
 mod test {
   use hashbrown::HashMap;
@@ -221,4 +443,103 @@ mod test {
 
     Ok(())
   }
+
+  #[test]
+  fn test_task_dependency_7_missing_required_argument_fails_before_dependencies() -> anyhow::Result<()> {
+    let root_yaml = "
+      tasks:
+        release:
+          args:
+            - name: version
+              required: true
+          commands:
+            - command: echo ${{ args.version }}
+              verbose: false
+          depends_on:
+            - build
+        build:
+          commands:
+            - command: echo build
+              verbose: false
+    ";
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let result = run_task_by_name(&context, "release");
+    assert!(result.is_err());
+    assert!(!context.is_task_completed("build")?);
+
+    let result = run_task_by_name_with_args(&context, "release", &["--version".into(), "1.2.3".into()]);
+    assert!(result.is_ok());
+    assert!(context.is_task_completed("build")?);
+
+    Ok(())
+  }
+
+  fn invocation(task_name: &str) -> TaskInvocation {
+    TaskInvocation {
+      task_name: task_name.to_string(),
+      args: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_task_dependency_8_roots_share_dependencies() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let counter = temp_dir.path().join("counter.txt");
+    let root_yaml = format!(
+      "
+      tasks:
+        lint:
+          commands:
+            - command: sleep 0.2
+              verbose: false
+          depends_on:
+            - setup
+        test:
+          commands:
+            - command: echo test
+              verbose: false
+          depends_on:
+            - setup
+        setup:
+          commands:
+            - command: echo setup >> {}
+              verbose: false
+    ",
+      counter.to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_invocations(&context, &[invocation("lint"), invocation("test")], 2);
+    assert!(outcomes.iter().all(|outcome| outcome.status() == "succeeded"));
+    assert_eq!(std::fs::read_to_string(&counter)?.lines().count(), 1);
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_dependency_9_sequential_roots_stop_at_first_failure() -> anyhow::Result<()> {
+    let root_yaml = "
+      tasks:
+        lint:
+          commands:
+            - command: exit 1
+              verbose: false
+        test:
+          commands:
+            - command: echo test
+              verbose: false
+    ";
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(root_yaml)?);
+    let context = TaskContext::empty_with_root(root);
+    let outcomes = run_task_invocations(&context, &[invocation("lint"), invocation("test")], 1);
+    assert!(outcomes[0].is_failed());
+    assert_eq!(outcomes[1].status(), "skipped");
+    assert!(!context.is_task_completed("test")?);
+
+    Ok(())
+  }
 }
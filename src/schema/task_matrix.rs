@@ -0,0 +1,357 @@
+use std::fmt;
+
+use serde::de::{
+  self,
+  MapAccess,
+  Visitor,
+};
+use serde::{
+  Deserialize,
+  Deserializer,
+  Serialize,
+};
+use serde_json::Value as JsonValue;
+
+/// A set of matrix values, in the order they were declared
+pub type MatrixValues = Vec<(String, String)>;
+
+/// The `matrix` of a task. Every combination of the dimension values becomes
+/// its own task named like `build[target=x86_64,profile=dev]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TaskMatrix {
+  /// The dimensions and their values, in the order they were declared
+  pub dimensions: Vec<(String, Vec<String>)>,
+
+  /// Extra combinations, or extra values for the combinations they match
+  pub include: Vec<MatrixValues>,
+
+  /// Combinations to leave out
+  pub exclude: Vec<MatrixValues>,
+}
+
+/// One expanded combination of a matrix
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixInstance {
+  /// The task name of the combination, e.g. `build[target=x86_64,profile=dev]`
+  pub name: String,
+
+  /// The values exposed as `${{ matrix.NAME }}`
+  pub values: MatrixValues,
+}
+
+impl TaskMatrix {
+  /// Expand the matrix into one instance per combination
+  pub fn expand(&self, task_name: &str) -> anyhow::Result<Vec<MatrixInstance>> {
+    for (name, values) in &self.dimensions {
+      if values.is_empty() {
+        anyhow::bail!(
+          "Invalid matrix for task '{}' - dimension '{}' has no values",
+          task_name,
+          name
+        );
+      }
+    }
+
+    for entry in &self.exclude {
+      if let Some((key, _)) = entry.iter().find(|(key, _)| !self.has_dimension(key)) {
+        anyhow::bail!(
+          "Invalid matrix for task '{}' - exclude uses unknown dimension '{}'",
+          task_name,
+          key
+        );
+      }
+    }
+
+    // Each combination keeps the values that name it apart from the values
+    // `include` adds to it.
+    let mut combinations: Vec<(MatrixValues, MatrixValues)> = Vec::new();
+    if !self.dimensions.is_empty() {
+      combinations.push((Vec::new(), Vec::new()));
+    }
+    for (name, values) in &self.dimensions {
+      combinations = combinations
+        .into_iter()
+        .flat_map(|(named, _)| {
+          values.iter().map(move |value| {
+            let mut named = named.clone();
+            named.push((name.clone(), value.clone()));
+            (named.clone(), named)
+          })
+        })
+        .collect();
+    }
+
+    combinations.retain(|(named, _)| !self.exclude.iter().any(|entry| matches_entry(named, entry)));
+
+    for entry in &self.include {
+      let dimension_values: MatrixValues = entry
+        .iter()
+        .filter(|(key, _)| self.has_dimension(key))
+        .cloned()
+        .collect();
+      let extra_values: MatrixValues = entry
+        .iter()
+        .filter(|(key, _)| !self.has_dimension(key))
+        .cloned()
+        .collect();
+
+      let mut matched = false;
+      if !dimension_values.is_empty() || !extra_values.is_empty() {
+        for (named, values) in combinations.iter_mut() {
+          if matches_entry(named, &dimension_values) && !extra_values.is_empty() {
+            matched = true;
+            for (key, value) in &extra_values {
+              if !values.iter().any(|(existing, _)| existing == key) {
+                values.push((key.clone(), value.clone()));
+              }
+            }
+          } else if dimension_values.len() == self.dimensions.len()
+            && extra_values.is_empty()
+            && matches_entry(named, &dimension_values)
+          {
+            matched = true;
+          }
+        }
+      }
+
+      if !matched {
+        combinations.push((entry.clone(), entry.clone()));
+      }
+    }
+
+    if combinations.is_empty() {
+      anyhow::bail!(
+        "Invalid matrix for task '{}' - no combinations are left",
+        task_name
+      );
+    }
+
+    let mut instances: Vec<MatrixInstance> = Vec::new();
+    for (named, values) in combinations {
+      let name = instance_name(task_name, &named);
+      if instances.iter().any(|instance| instance.name == name) {
+        anyhow::bail!(
+          "Invalid matrix for task '{}' - duplicate combination '{}'",
+          task_name,
+          name
+        );
+      }
+      instances.push(MatrixInstance { name, values });
+    }
+
+    Ok(instances)
+  }
+
+  fn has_dimension(&self, key: &str) -> bool {
+    self.dimensions.iter().any(|(name, _)| name == key)
+  }
+}
+
+/// The task name of a matrix combination, e.g. `build[target=x86_64,profile=dev]`
+pub fn instance_name(task_name: &str, values: &MatrixValues) -> String {
+  let values = values
+    .iter()
+    .map(|(key, value)| format!("{}={}", key, value))
+    .collect::<Vec<_>>()
+    .join(",");
+  format!("{}[{}]", task_name, values)
+}
+
+/// The task name a matrix instance was expanded from, e.g. `build` for
+/// `build[target=x86_64]`
+pub fn matrix_base_name(task_name: &str) -> Option<&str> {
+  task_name
+    .strip_suffix(']')
+    .and_then(|name| name.split_once('['))
+    .map(|(base, _)| base)
+}
+
+fn matches_entry(values: &MatrixValues, entry: &MatrixValues) -> bool {
+  entry.iter().all(|(key, value)| {
+    values
+      .iter()
+      .any(|(name, existing)| name == key && existing == value)
+  })
+}
+
+fn scalar_to_string<E: de::Error>(value: JsonValue) -> Result<String, E> {
+  match value {
+    JsonValue::String(s) => Ok(s),
+    JsonValue::Number(n) => Ok(n.to_string()),
+    JsonValue::Bool(b) => Ok(b.to_string()),
+    _ => Err(de::Error::custom(
+      "expected a string, number, or boolean matrix value",
+    )),
+  }
+}
+
+/// A map of matrix values that keeps the declared order
+struct OrderedValues(MatrixValues);
+
+impl<'de> Deserialize<'de> for OrderedValues {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    struct ValuesVisitor;
+
+    impl<'de> Visitor<'de> for ValuesVisitor {
+      type Value = OrderedValues;
+
+      fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of matrix values")
+      }
+
+      fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+      where
+        M: MapAccess<'de>,
+      {
+        let mut values = Vec::new();
+        while let Some((key, value)) = map.next_entry::<String, JsonValue>()? {
+          values.push((key, scalar_to_string(value)?));
+        }
+        Ok(OrderedValues(values))
+      }
+    }
+
+    deserializer.deserialize_map(ValuesVisitor)
+  }
+}
+
+impl<'de> Deserialize<'de> for TaskMatrix {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: Deserializer<'de>,
+  {
+    struct MatrixVisitor;
+
+    impl<'de> Visitor<'de> for MatrixVisitor {
+      type Value = TaskMatrix;
+
+      fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map of matrix dimensions")
+      }
+
+      fn visit_map<M>(self, mut map: M) -> Result<Self::Value, M::Error>
+      where
+        M: MapAccess<'de>,
+      {
+        let mut matrix = TaskMatrix::default();
+        while let Some(key) = map.next_key::<String>()? {
+          match key.as_str() {
+            "include" => {
+              let entries: Vec<OrderedValues> = map.next_value()?;
+              matrix.include = entries.into_iter().map(|entry| entry.0).collect();
+            },
+            "exclude" => {
+              let entries: Vec<OrderedValues> = map.next_value()?;
+              matrix.exclude = entries.into_iter().map(|entry| entry.0).collect();
+            },
+            _ => {
+              let values: Vec<JsonValue> = map.next_value()?;
+              let values = values
+                .into_iter()
+                .map(scalar_to_string)
+                .collect::<Result<Vec<_>, _>>()?;
+              matrix.dimensions.push((key, values));
+            },
+          }
+        }
+        Ok(matrix)
+      }
+    }
+
+    deserializer.deserialize_map(MatrixVisitor)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn values(pairs: &[(&str, &str)]) -> MatrixValues {
+    pairs
+      .iter()
+      .map(|(key, value)| (key.to_string(), value.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn test_task_matrix_1() -> anyhow::Result<()> {
+    let yaml = "
+      target: [x86_64, aarch64]
+      profile: [dev, release]
+    ";
+    let matrix = serde_yaml::from_str::<TaskMatrix>(yaml)?;
+    let names: Vec<_> = matrix
+      .expand("build")?
+      .into_iter()
+      .map(|instance| instance.name)
+      .collect();
+
+    assert_eq!(
+      names,
+      vec![
+        "build[target=x86_64,profile=dev]",
+        "build[target=x86_64,profile=release]",
+        "build[target=aarch64,profile=dev]",
+        "build[target=aarch64,profile=release]",
+      ]
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_matrix_2() -> anyhow::Result<()> {
+    let yaml = "
+      os: [linux, macos]
+      version: [1, 2]
+      exclude:
+        - os: macos
+          version: 1
+      include:
+        - os: linux
+          experimental: true
+        - os: windows
+          version: 2
+    ";
+    let matrix = serde_yaml::from_str::<TaskMatrix>(yaml)?;
+    let instances = matrix.expand("test")?;
+
+    assert_eq!(instances.len(), 4);
+    assert_eq!(instances[0].name, "test[os=linux,version=1]");
+    assert_eq!(
+      instances[0].values,
+      values(&[("os", "linux"), ("version", "1"), ("experimental", "true")])
+    );
+    assert_eq!(instances[2].name, "test[os=macos,version=2]");
+    assert_eq!(instances[2].values, values(&[("os", "macos"), ("version", "2")]));
+    assert_eq!(instances[3].name, "test[os=windows,version=2]");
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_task_matrix_3() -> anyhow::Result<()> {
+    let matrix = serde_yaml::from_str::<TaskMatrix>("target: []")?;
+    assert!(matrix.expand("build").is_err());
+
+    let matrix = serde_yaml::from_str::<TaskMatrix>(
+      "
+      target: [x86_64]
+      exclude:
+        - arch: arm
+      ",
+    )?;
+    assert!(matrix.expand("build").is_err());
+
+    assert_eq!(
+      matrix_base_name("build[target=x86_64,profile=dev]"),
+      Some("build")
+    );
+    assert_eq!(matrix_base_name("build"), None);
+
+    Ok(())
+  }
+}
@@ -27,10 +27,11 @@ use super::{
 use crate::file::ToUtf8 as _;
 use crate::utils::{
   deserialize_environment,
+  deserialize_size,
   resolve_path,
 };
 
-const MK_COMMANDS: [&str; 11] = [
+const MK_COMMANDS: [&str; 12] = [
   "run",
   "list",
   "completion",
@@ -41,9 +42,20 @@ const MK_COMMANDS: [&str; 11] = [
   "validate",
   "plan",
   "clean-cache",
+  "cache",
   "schema",
 ];
 
+/// Settings of the task cache shared by all tasks
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct CacheSettings {
+  /// The size the artifact store in `.mk/artifacts` is trimmed to after a
+  /// task saved its outputs, e.g. `500MB` or `2GB`. Defaults to `1GB`.
+  #[schemars(with = "Option<String>")]
+  #[serde(default, deserialize_with = "deserialize_size")]
+  pub max_size: Option<u64>,
+}
+
 /// This struct represents the root of the task schema. It contains all the tasks
 /// that can be executed.
 #[derive(Debug, Default, Deserialize, JsonSchema)]
@@ -95,6 +107,10 @@ pub struct TaskRoot {
   #[serde(default)]
   pub container_runtime: Option<ContainerRuntime>,
 
+  /// Settings of the task cache
+  #[serde(default)]
+  pub cache: Option<CacheSettings>,
+
   /// Includes additional files to be merged into the current file
   #[serde(default)]
   pub include: Option<Vec<Include>>,
@@ -145,6 +161,7 @@ impl TaskRoot {
       use_npm: None,
       use_cargo: None,
       container_runtime: None,
+      cache: None,
       include: None,
       extends: None,
       source_path: None,
@@ -166,6 +183,48 @@ impl TaskRoot {
   pub fn resolve_from_config(&self, value: &str) -> PathBuf {
     resolve_path(&self.config_base_dir(), value)
   }
+
+  /// Add a task for every combination of each task `matrix`. The task with the
+  /// matrix runs all of its instances.
+  pub fn expand_matrices(&mut self) -> anyhow::Result<()> {
+    // Drop the instances of an earlier expansion, e.g. from an extended file.
+    self
+      .tasks
+      .retain(|_, task| !matches!(task, Task::Task(task) if !task.matrix_values.is_empty()));
+
+    let mut instances = Vec::new();
+    for (task_name, task) in self.tasks.iter_mut() {
+      let Task::Task(task) = task else {
+        continue;
+      };
+      let Some(matrix) = &task.matrix else {
+        task.matrix_instances.clear();
+        continue;
+      };
+
+      let expanded = matrix.expand(task_name)?;
+      task.matrix_instances = expanded.iter().map(|instance| instance.name.clone()).collect();
+      for instance in expanded {
+        let mut instance_task = task.as_ref().clone();
+        instance_task.matrix = None;
+        instance_task.matrix_instances = Vec::new();
+        instance_task.matrix_values = instance.values;
+        // The execution mode of the matrix task applies across its instances.
+        instance_task.parallel = None;
+        instance_task.execution = None;
+        instances.push((instance.name, Task::Task(Box::new(instance_task))));
+      }
+    }
+
+    for (instance_name, task) in instances {
+      if self.tasks.contains_key(&instance_name) {
+        anyhow::bail!("Invalid matrix - task '{}' is already defined", instance_name);
+      }
+      self.tasks.insert(instance_name, task);
+    }
+
+    Ok(())
+  }
 }
 
 fn normalize_task_file_path(file: &str) -> anyhow::Result<PathBuf> {
@@ -289,6 +348,8 @@ fn process_task_sources(root: &mut TaskRoot) -> anyhow::Result<()> {
     root.tasks.extend(renamed_cargo_tasks);
   }
 
+  root.expand_matrices()?;
+
   Ok(())
 }
 
@@ -311,6 +372,7 @@ fn apply_extends(file: &Path, stack: &mut Vec<PathBuf>, mut root: TaskRoot) -> a
   base.use_npm = root.use_npm.or(base.use_npm);
   base.use_cargo = root.use_cargo.or(base.use_cargo);
   base.container_runtime = root.container_runtime.or(base.container_runtime);
+  base.cache = root.cache.or(base.cache);
   base.include = root.include.or(base.include);
   base.extends = None;
   base.source_path = root.source_path.or(base.source_path);
@@ -0,0 +1,317 @@
+use std::process::{
+  Child,
+  Command as ProcessCommand,
+  ExitStatus,
+};
+use std::sync::atomic::{
+  AtomicBool,
+  Ordering,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{
+  Duration,
+  Instant,
+};
+
+#[cfg(unix)]
+use std::os::unix::process::CommandExt as _;
+
+use super::{
+  CommandTimedOut,
+  RunCancelled,
+  TaskContext,
+};
+
+/// How long a timed out command gets to exit after SIGTERM before it is killed
+pub const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// The deadline of the running task, set from the task `timeout`
+#[derive(Debug, Clone)]
+pub struct TaskDeadline {
+  pub task: String,
+  pub at: Instant,
+  pub timeout: Duration,
+}
+
+/// The time limit of a single command. `task` is set when the limit comes from
+/// the task timeout rather than the command timeout.
+#[derive(Debug, Clone)]
+pub struct CommandTimeout {
+  pub at: Instant,
+  pub timeout: Duration,
+  pub task: Option<String>,
+}
+
+impl CommandTimeout {
+  /// The earliest of the command timeout and the deadline of the running task
+  pub fn resolve(timeout: Option<Duration>, deadline: Option<&TaskDeadline>) -> Option<Self> {
+    let own = timeout.map(|timeout| CommandTimeout {
+      at: Instant::now() + timeout,
+      timeout,
+      task: None,
+    });
+    let task = deadline.map(|deadline| CommandTimeout {
+      at: deadline.at,
+      timeout: deadline.timeout,
+      task: Some(deadline.task.clone()),
+    });
+
+    match (own, task) {
+      (Some(own), Some(task)) if task.at < own.at => Some(task),
+      (Some(own), _) => Some(own),
+      (None, task) => task,
+    }
+  }
+
+  pub fn error(&self, command: &str) -> CommandTimedOut {
+    CommandTimedOut {
+      command: command.to_string(),
+      timeout: self.timeout,
+      task: self.task.clone(),
+    }
+  }
+}
+
+/// Stops the commands of a run from another thread. Commands of a cancellable
+/// run are started in their own process group so everything they started can
+/// be killed.
+#[derive(Debug, Clone, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::SeqCst)
+  }
+}
+
+/// Run the command in its own process group so a timeout or restart can
+/// terminate everything it started.
+#[cfg(unix)]
+pub(crate) fn use_process_group(cmd: &mut ProcessCommand) {
+  unsafe {
+    cmd.pre_exec(|| {
+      if libc::setpgid(0, 0) != 0 {
+        return Err(std::io::Error::last_os_error());
+      }
+      Ok(())
+    });
+  }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn use_process_group(_cmd: &mut ProcessCommand) {}
+
+/// Send a signal to the process group of the child. Falls back to killing the
+/// child when it does not lead its own group.
+#[cfg(unix)]
+pub(crate) fn signal_process_group(child: &mut Child, signal: libc::c_int) -> anyhow::Result<()> {
+  let pid = child.id() as i32;
+  let kill_result = unsafe { libc::killpg(pid, signal) };
+  if kill_result == 0 {
+    return Ok(());
+  }
+
+  let error = std::io::Error::last_os_error();
+  let raw_error = error.raw_os_error();
+  if raw_error == Some(libc::ESRCH) || raw_error == Some(libc::EPERM) {
+    match child.kill() {
+      Ok(()) => return Ok(()),
+      Err(child_error) if child_error.kind() == std::io::ErrorKind::InvalidInput => return Ok(()),
+      Err(child_error) => return Err(child_error.into()),
+    }
+  }
+
+  Err(error.into())
+}
+
+/// Kill the process group of the child right away, e.g. to restart the command
+pub(crate) fn kill_process_group(child: &mut Child) -> anyhow::Result<()> {
+  #[cfg(unix)]
+  {
+    signal_process_group(child, libc::SIGKILL)
+  }
+
+  #[cfg(not(unix))]
+  {
+    match child.kill() {
+      Err(e) if e.kind() != std::io::ErrorKind::InvalidInput => Err(e.into()),
+      _ => Ok(()),
+    }
+  }
+}
+
+/// Ask the process group of the child to stop with SIGTERM, then SIGKILL it
+/// once the grace period is over.
+pub(crate) fn terminate_process_group(
+  child: &mut Child,
+  grace_period: Duration,
+) -> anyhow::Result<ExitStatus> {
+  #[cfg(unix)]
+  {
+    signal_process_group(child, libc::SIGTERM)?;
+    if let Some(status) = wait_until(child, Instant::now() + grace_period)? {
+      // The leader exited, make sure nothing it started is left behind.
+      let _ = unsafe { libc::killpg(child.id() as i32, libc::SIGKILL) };
+      return Ok(status);
+    }
+    signal_process_group(child, libc::SIGKILL)?;
+  }
+
+  #[cfg(not(unix))]
+  {
+    let _ = grace_period;
+    let _ = child.kill();
+  }
+
+  Ok(child.wait()?)
+}
+
+/// Wait for the child to exit. Returns `None` if the deadline passed first.
+pub(crate) fn wait_until(child: &mut Child, deadline: Instant) -> anyhow::Result<Option<ExitStatus>> {
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(Some(status));
+    }
+
+    let now = Instant::now();
+    if now >= deadline {
+      return Ok(None);
+    }
+    thread::sleep((deadline - now).min(Duration::from_millis(20)));
+  }
+}
+
+/// Wait for the child to exit. Returns `None` if the deadline passed first.
+/// When the run is cancelled, the process group of the child is killed and
+/// the wait fails with `RunCancelled`.
+pub(crate) fn wait_for_exit(
+  child: &mut Child,
+  deadline: Option<Instant>,
+  cancellation: Option<&Cancellation>,
+) -> anyhow::Result<Option<ExitStatus>> {
+  let Some(cancellation) = cancellation else {
+    return match deadline {
+      Some(deadline) => wait_until(child, deadline),
+      None => Ok(Some(child.wait()?)),
+    };
+  };
+
+  loop {
+    if let Some(status) = child.try_wait()? {
+      return Ok(Some(status));
+    }
+
+    if cancellation.is_cancelled() {
+      kill_process_group(child)?;
+      child.wait()?;
+      return Err(RunCancelled.into());
+    }
+
+    let now = Instant::now();
+    if deadline.is_some_and(|deadline| now >= deadline) {
+      return Ok(None);
+    }
+    thread::sleep(Duration::from_millis(20));
+  }
+}
+
+/// Wait for a spawned command, terminating its process group when it runs past
+/// its time limit. Returns `None` if the command timed out and errors are ignored.
+pub fn wait_for_command(
+  context: &TaskContext,
+  child: &mut Child,
+  command: &str,
+  timeout: Option<&CommandTimeout>,
+  ignore_errors: bool,
+) -> anyhow::Result<Option<ExitStatus>> {
+  let deadline = timeout.map(|timeout| timeout.at);
+  let status = wait_for_exit(child, deadline, context.cancellation.as_ref())?;
+  match (status, timeout) {
+    (Some(status), _) => Ok(Some(status)),
+    (None, Some(timeout)) => {
+      terminate_process_group(child, TIMEOUT_GRACE_PERIOD)?;
+      report_timeout(context, command, timeout, ignore_errors)?;
+      Ok(None)
+    },
+    (None, None) => unreachable!("commands without a deadline are waited for until they exit"),
+  }
+}
+
+/// Emit the `command_timed_out` event and fail unless errors are ignored
+pub fn report_timeout(
+  context: &TaskContext,
+  command: &str,
+  timeout: &CommandTimeout,
+  ignore_errors: bool,
+) -> anyhow::Result<()> {
+  context.emit_event(&serde_json::json!({
+    "event": "command_timed_out",
+    "task": context.current_task_name.clone().unwrap_or_else(|| "<task>".to_string()),
+    "command": command,
+    "timeout_ms": timeout.timeout.as_millis() as u64,
+    "task_timeout": timeout.task.is_some(),
+  }))?;
+
+  if ignore_errors {
+    context.note_ignored_failure();
+    return Ok(());
+  }
+  Err(timeout.error(command).into())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_timeout_1_prefers_earliest_limit() {
+    let deadline = TaskDeadline {
+      task: "build".to_string(),
+      at: Instant::now() + Duration::from_secs(1),
+      timeout: Duration::from_secs(1),
+    };
+
+    let timeout = CommandTimeout::resolve(Some(Duration::from_secs(60)), Some(&deadline)).expect("timeout");
+    assert_eq!(timeout.task.as_deref(), Some("build"));
+
+    let timeout = CommandTimeout::resolve(Some(Duration::from_millis(10)), Some(&deadline)).expect("timeout");
+    assert_eq!(timeout.task, None);
+    assert_eq!(timeout.timeout, Duration::from_millis(10));
+
+    assert!(CommandTimeout::resolve(None, None).is_none());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_timeout_2_terminates_process_group() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    let marker = temp_dir.path().join("marker");
+    let mut cmd = ProcessCommand::new("sh");
+    cmd.arg("-c").arg(format!(
+      "(sleep 1; touch {}) & sleep 30",
+      marker.to_string_lossy()
+    ));
+    use_process_group(&mut cmd);
+    let mut child = cmd.spawn()?;
+
+    let started = Instant::now();
+    let context = TaskContext::empty();
+    let timeout = CommandTimeout::resolve(Some(Duration::from_millis(100)), None);
+    let error = wait_for_command(&context, &mut child, "sleep", timeout.as_ref(), false)
+      .expect_err("expected the command to time out");
+    assert!(error.downcast_ref::<CommandTimedOut>().is_some());
+    assert_eq!(error.to_string(), "Command timed out after 100ms - sleep");
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    // The background job was in the same group and got terminated too.
+    thread::sleep(Duration::from_millis(1500));
+    assert!(!marker.exists());
+
+    Ok(())
+  }
+}
@@ -92,8 +92,12 @@ impl UseCargoArgs {
               .map(|work_dir| work_dir.to_string_lossy().into_owned()),
             interactive: Some(true),
             retrigger: None,
+            timeout: None,
+            retry: None,
+            condition: None,
             ignore_errors: None,
             save_output_as: None,
+            append_args: Some(true),
             verbose: None,
           })],
           ..Default::default()
@@ -111,7 +111,12 @@ impl UseNpmArgs {
       .unwrap_or_default()
       .into_iter()
       .map(|(k, _)| {
-        let command = format!("{package_manager} run {k}");
+        // npm only forwards arguments to the script after a `--` separator
+        let command = if package_manager == "npm" {
+          format!("{package_manager} run {k} --")
+        } else {
+          format!("{package_manager} run {k}")
+        };
         let task = Task::Task(Box::new(TaskArgs {
           commands: vec![CommandRunner::LocalRun(LocalRun {
             command,
@@ -122,8 +127,12 @@ impl UseNpmArgs {
               .map(|work_dir| work_dir.to_string_lossy().into_owned()),
             interactive: Some(true),
             retrigger: None,
+            timeout: None,
+            retry: None,
+            condition: None,
             ignore_errors: None,
             save_output_as: None,
+            append_args: Some(true),
             verbose: None,
           })],
           ..Default::default()
@@ -5,14 +5,27 @@ use serde::Serialize;
 
 use super::{
   contains_output_reference,
+  contains_template_expression,
+  extract_argument_references,
+  extract_matrix_references,
   extract_output_references,
+  extract_shell_variables,
+  extract_task_output_references,
+  extract_template_expressions,
+  CacheMode,
   CommandRunner,
   ContainerRuntime,
+  Expression,
   Include,
+  SaveOutput,
   Task,
+  TaskArgumentType,
+  TaskCache,
   TaskRoot,
   UseCargo,
   UseNpm,
+  OUTPUT_FILE_ENV,
+  REST_ARGUMENT_NAME,
 };
 
 #[derive(Debug, Clone, Serialize)]
@@ -90,6 +103,10 @@ impl TaskRoot {
       self.validate_includes(includes, report);
     }
 
+    for value in self.environment.values() {
+      validate_templates(None, "environment", value, report);
+    }
+
     self.validate_runtime(
       None,
       Some("container_runtime"),
@@ -104,6 +121,17 @@ impl TaskRoot {
         if command.trim().is_empty() {
           report.push_error(Some(task_name), Some("commands"), "Command must not be empty");
         }
+        validate_templates(Some(task_name), "commands", command, report);
+        for argument_name in extract_argument_references(command) {
+          if argument_name == REST_ARGUMENT_NAME {
+            continue;
+          }
+          report.push_error(
+            Some(task_name),
+            Some("commands"),
+            format!("Unknown task argument reference: {}", argument_name),
+          );
+        }
       },
       Task::Task(task) => {
         if task.commands.is_empty() {
@@ -137,7 +165,8 @@ impl TaskRoot {
           }
         }
 
-        if task.is_parallel() {
+        // The execution mode of a matrix task applies across its instances.
+        if task.is_parallel() && task.matrix.is_none() {
           for command in &task.commands {
             match command {
               CommandRunner::LocalRun(local_run) if local_run.is_parallel_safe() => {},
@@ -185,19 +214,60 @@ impl TaskRoot {
           }
         }
 
-        if task.cache.as_ref().map(|cache| cache.enabled).unwrap_or(false) && task.outputs.is_empty() {
+        let needs_outputs = task
+          .cache
+          .as_ref()
+          .is_some_and(|cache| cache.enabled && cache.mode != Some(CacheMode::Stamp));
+        if needs_outputs && task.outputs.is_empty() {
           report.push_warning(
             Some(task_name),
             Some("outputs"),
-            "Task cache is enabled without declared outputs; cache hits will not be possible",
+            "Task cache is enabled without declared outputs; cache hits need `cache.mode: stamp`",
           );
         }
+        if let Some(cache) = task
+          .cache
+          .as_ref()
+          .filter(|cache| cache.enabled && !cache.env.is_empty())
+        {
+          self.validate_cache_env(task_name, task, cache, report);
+        }
+
+        if let Some(condition) = &task.condition {
+          validate_condition(task_name, "if", condition, report);
+        }
+        for dependency in &task.depends_on {
+          if let Some(condition) = dependency.condition() {
+            validate_condition(task_name, "depends_on", condition, report);
+          }
+        }
 
-        for command in &task.commands {
+        for value in task.environment.values() {
+          validate_templates(Some(task_name), "environment", value, report);
+        }
+        for precondition in &task.preconditions {
+          let values = [
+            Some(&precondition.command),
+            precondition.message.as_ref(),
+            precondition.work_dir.as_ref(),
+          ];
+          for value in values.into_iter().flatten() {
+            validate_templates(Some(task_name), "preconditions", value, report);
+          }
+        }
+        for command in task.all_commands() {
+          if let Some(condition) = command.condition() {
+            validate_condition(task_name, "commands", condition, report);
+          }
+          for value in command_templates(command) {
+            validate_templates(Some(task_name), "commands", value, report);
+          }
           self.validate_command(task_name, command, report);
         }
 
         self.validate_command_outputs(task_name, task, report);
+        self.validate_arguments(task_name, task, report);
+        self.validate_matrix_references(task_name, task, report);
       },
     }
   }
@@ -221,12 +291,18 @@ impl TaskRoot {
           report.push_error(Some(task_name), Some("command"), "Command must not be empty");
         }
         if let Some(save_output_as) = &local_run.save_output_as {
-          if save_output_as.trim().is_empty() {
+          if save_output_as.name().trim().is_empty() {
             report.push_error(
               Some(task_name),
               Some("save_output_as"),
               "save_output_as must not be empty",
             );
+          } else if save_output_as.name().contains(['.', '[']) {
+            report.push_error(
+              Some(task_name),
+              Some("save_output_as"),
+              "save_output_as must not contain '.' or '[', they start the field path of structured outputs",
+            );
           }
         }
         if local_run.interactive_enabled() && local_run.retrigger_enabled() {
@@ -302,6 +378,14 @@ impl TaskRoot {
         }
       },
     }
+
+    if command.retry().is_some_and(|retry| retry.attempts == 0) {
+      report.push_error(
+        Some(task_name),
+        Some("retry.attempts"),
+        "retry attempts must be at least 1",
+      );
+    }
   }
 
   fn validate_command_outputs(&self, task_name: &str, task: &super::TaskArgs, report: &mut ValidationReport) {
@@ -309,16 +393,18 @@ impl TaskRoot {
       .commands
       .iter()
       .filter_map(|command| match command {
-        CommandRunner::LocalRun(local_run) => local_run.save_output_as.as_ref(),
+        CommandRunner::LocalRun(local_run) => local_run.save_output_as.as_ref().map(SaveOutput::name),
         _ => None,
       })
       .map(|name| name.trim().to_string())
       .filter(|name| !name.is_empty())
       .collect::<HashSet<_>>();
 
+    // Outputs written to the output file are only known once the command ran
+    let writes_output_file = task.commands.iter().any(command_writes_output_file);
     for value in task.environment.values() {
       for output_name in extract_output_references(value) {
-        if !declared_outputs.contains(&output_name) {
+        if !declared_outputs.contains(&output_name) && !writes_output_file {
           report.push_error(
             Some(task_name),
             Some("environment"),
@@ -361,7 +447,7 @@ impl TaskRoot {
           }
 
           if let Some(save_output_as) = &local_run.save_output_as {
-            let save_output_as = save_output_as.trim().to_string();
+            let save_output_as = save_output_as.name().trim().to_string();
             if !save_output_as.is_empty() && !produced_outputs.insert(save_output_as.clone()) {
               report.push_error(
                 Some(task_name),
@@ -387,6 +473,278 @@ impl TaskRoot {
           }
         },
       }
+      // Later commands may read any output written to the output file
+      if command_writes_output_file(command) {
+        break;
+      }
+    }
+
+    self.validate_task_output_references(task_name, task, report);
+  }
+
+  /// Outputs of other tasks can only be read once those tasks ran, so they must
+  /// be dependencies of the task, directly or through other dependencies.
+  fn validate_task_output_references(
+    &self,
+    task_name: &str,
+    task: &super::TaskArgs,
+    report: &mut ValidationReport,
+  ) {
+    let mut references: Vec<(&str, (String, String))> = Vec::new();
+    for value in task.environment.values() {
+      references.extend(
+        extract_task_output_references(value)
+          .into_iter()
+          .map(|reference| ("environment", reference)),
+      );
+    }
+    for command in task.all_commands() {
+      for value in command_templates(command) {
+        references.extend(
+          extract_task_output_references(value)
+            .into_iter()
+            .map(|reference| ("commands", reference)),
+        );
+      }
+    }
+    if references.is_empty() {
+      return;
+    }
+
+    let mut dependencies = HashSet::new();
+    self.collect_dependencies(task_name, &mut dependencies);
+
+    for (field, (producer, output_name)) in references {
+      let reference = format!("tasks.{}.outputs.{}", producer, output_name);
+      let Some(Task::Task(producer_task)) = self.tasks.get(&producer) else {
+        report.push_error(
+          Some(task_name),
+          Some(field),
+          format!("Unknown task in output reference: {}", reference),
+        );
+        continue;
+      };
+
+      if !dependencies.contains(&producer) {
+        report.push_error(
+          Some(task_name),
+          Some(field),
+          format!("Task output reference must come from a dependency: {}", reference),
+        );
+      }
+
+      let saves_output = producer_task.all_commands().any(|command| match command {
+        CommandRunner::LocalRun(local_run) => local_run
+          .save_output_as
+          .as_ref()
+          .is_some_and(|save_output| save_output.name().trim() == output_name),
+        _ => false,
+      }) || producer_task.all_commands().any(command_writes_output_file);
+      if !saves_output {
+        report.push_error(
+          Some(task_name),
+          Some(field),
+          format!("Unknown task output reference: {}", reference),
+        );
+      }
+    }
+  }
+
+  /// The tasks a task depends on, directly or through other dependencies
+  fn collect_dependencies(&self, task_name: &str, dependencies: &mut HashSet<String>) {
+    let Some(Task::Task(task)) = self.tasks.get(task_name) else {
+      return;
+    };
+    for dependency in &task.depends_on {
+      let name = dependency.resolve_name();
+      if dependencies.insert(name.to_string()) {
+        self.collect_dependencies(name, dependencies);
+      }
+    }
+  }
+
+  /// Warn about the variables the commands read that are left out of the
+  /// fingerprint by `cache.env`
+  fn validate_cache_env(
+    &self,
+    task_name: &str,
+    task: &super::TaskArgs,
+    cache: &TaskCache,
+    report: &mut ValidationReport,
+  ) {
+    let mut reported = HashSet::new();
+    for command in &task.commands {
+      let scripts = match command {
+        CommandRunner::CommandRun(command) => vec![command.as_str()],
+        CommandRunner::LocalRun(local_run) => std::iter::once(local_run.command.as_str())
+          .chain(local_run.test.as_deref())
+          .collect(),
+        _ => continue,
+      };
+
+      for name in scripts.into_iter().flat_map(extract_shell_variables) {
+        // Literal task variables are part of the task definition
+        let is_literal = task
+          .environment
+          .get(name)
+          .is_some_and(|value| !contains_template_expression(value));
+        if name.starts_with("MK_") || is_literal || cache.covers_env(name) || cache.excludes_env(name) {
+          continue;
+        }
+        if reported.insert(name) {
+          report.push_warning(
+            Some(task_name),
+            Some("cache.env"),
+            format!(
+              "Command reads ${} which is not listed in cache.env; changing it does not invalidate the cache",
+              name
+            ),
+          );
+        }
+      }
+    }
+  }
+
+  fn validate_arguments(&self, task_name: &str, task: &super::TaskArgs, report: &mut ValidationReport) {
+    let mut declared_arguments = HashSet::new();
+    for argument in &task.args {
+      if argument.name.trim().is_empty() {
+        report.push_error(Some(task_name), Some("args"), "Argument name must not be empty");
+        continue;
+      }
+
+      if !is_valid_argument_name(&argument.name) {
+        report.push_error(
+          Some(task_name),
+          Some("args"),
+          format!(
+            "Invalid argument name: {}. Use letters, digits, `-` and `_`",
+            argument.name
+          ),
+        );
+      }
+
+      if argument.name == REST_ARGUMENT_NAME {
+        report.push_error(
+          Some(task_name),
+          Some("args"),
+          format!(
+            "Argument name is reserved for values passed after `--`: {}",
+            argument.name
+          ),
+        );
+      }
+
+      if !declared_arguments.insert(argument.name.clone()) {
+        report.push_error(
+          Some(task_name),
+          Some("args"),
+          format!("Duplicate argument name: {}", argument.name),
+        );
+      }
+
+      if argument.kind == TaskArgumentType::Enum && argument.choices.is_empty() {
+        report.push_error(
+          Some(task_name),
+          Some("args"),
+          format!("Enum argument must declare choices: {}", argument.name),
+        );
+      } else if argument.kind != TaskArgumentType::Enum && !argument.choices.is_empty() {
+        report.push_warning(
+          Some(task_name),
+          Some("args"),
+          format!("Choices are ignored for non-enum argument: {}", argument.name),
+        );
+      }
+
+      if let Some(default) = &argument.default {
+        if let Err(e) = argument.parse_value(default) {
+          report.push_error(Some(task_name), Some("args"), format!("Invalid default - {}", e));
+        }
+
+        if argument.required {
+          report.push_warning(
+            Some(task_name),
+            Some("args"),
+            format!(
+              "Required argument has a default value and can never be missing: {}",
+              argument.name
+            ),
+          );
+        }
+      }
+    }
+
+    let mut references = Vec::new();
+    for value in task.environment.values() {
+      references.extend(
+        extract_argument_references(value)
+          .into_iter()
+          .map(|name| ("environment", name)),
+      );
+    }
+    for command in task.all_commands() {
+      match command {
+        CommandRunner::LocalRun(local_run) => {
+          references.extend(
+            extract_argument_references(&local_run.command)
+              .into_iter()
+              .map(|name| ("command", name)),
+          );
+          if let Some(test) = &local_run.test {
+            references.extend(
+              extract_argument_references(test)
+                .into_iter()
+                .map(|name| ("test", name)),
+            );
+          }
+        },
+        CommandRunner::CommandRun(command) => {
+          references.extend(
+            extract_argument_references(command)
+              .into_iter()
+              .map(|name| ("command", name)),
+          );
+        },
+        CommandRunner::ContainerRun(_) | CommandRunner::ContainerBuild(_) | CommandRunner::TaskRun(_) => {},
+      }
+    }
+
+    for (field, argument_name) in references {
+      if argument_name != REST_ARGUMENT_NAME && !declared_arguments.contains(&argument_name) {
+        report.push_error(
+          Some(task_name),
+          Some(field),
+          format!("Unknown task argument reference: {}", argument_name),
+        );
+      }
+    }
+  }
+
+  fn validate_matrix_references(
+    &self,
+    task_name: &str,
+    task: &super::TaskArgs,
+    report: &mut ValidationReport,
+  ) {
+    // The instances of a matrix task are validated with their own values.
+    if task.matrix.is_some() {
+      return;
+    }
+
+    let mut values: Vec<&str> = task.environment.values().map(String::as_str).collect();
+    for command in task.all_commands() {
+      values.extend(command_templates(command));
+    }
+
+    for name in values.into_iter().flat_map(extract_matrix_references) {
+      if !task.matrix_values.iter().any(|(key, _)| *key == name) {
+        report.push_error(
+          Some(task_name),
+          Some("matrix"),
+          format!("Unknown matrix value reference: {}", name),
+        );
+      }
     }
   }
 
@@ -510,7 +868,7 @@ impl TaskRoot {
         self.detect_cycle(dependency.resolve_name(), visiting, visited, report);
       }
 
-      for command in &task.commands {
+      for command in task.all_commands() {
         if let CommandRunner::TaskRun(task_run) = command {
           self.detect_cycle(&task_run.task, visiting, visited, report);
         }
@@ -522,6 +880,52 @@ impl TaskRoot {
   }
 }
 
+fn validate_condition(task_name: &str, field: &str, condition: &str, report: &mut ValidationReport) {
+  if let Err(e) = Expression::parse(condition) {
+    report.push_error(Some(task_name), Some(field), e.to_string());
+  }
+}
+
+fn validate_templates(task_name: Option<&str>, field: &str, value: &str, report: &mut ValidationReport) {
+  for expression in extract_template_expressions(value) {
+    if let Err(e) = Expression::parse(expression) {
+      report.push_error(task_name, Some(field), e.to_string());
+    }
+  }
+}
+
+/// The string fields of a command that are interpolated before it runs
+fn command_templates(command: &CommandRunner) -> Vec<&str> {
+  match command {
+    CommandRunner::CommandRun(command) => vec![command.as_str()],
+    CommandRunner::LocalRun(local_run) => std::iter::once(local_run.command.as_str())
+      .chain(local_run.test.as_deref())
+      .chain(local_run.work_dir.as_deref())
+      .collect(),
+    CommandRunner::ContainerRun(container_run) => std::iter::once(container_run.image.as_str())
+      .chain(container_run.container_command.iter().map(String::as_str))
+      .chain(container_run.mounted_paths.iter().map(String::as_str))
+      .collect(),
+    CommandRunner::ContainerBuild(container_build) => {
+      let args = &container_build.container_build;
+      [args.image_name.as_str(), args.context.as_str()]
+        .into_iter()
+        .chain(args.containerfile.as_deref())
+        .chain(
+          args
+            .tags
+            .iter()
+            .chain(&args.build_args)
+            .chain(&args.labels)
+            .flatten()
+            .map(String::as_str),
+        )
+        .collect()
+    },
+    CommandRunner::TaskRun(_) => Vec::new(),
+  }
+}
+
 fn command_uses_task_outputs(command: &CommandRunner) -> bool {
   match command {
     CommandRunner::LocalRun(local_run) => {
@@ -537,6 +941,24 @@ fn command_uses_task_outputs(command: &CommandRunner) -> bool {
   }
 }
 
+/// Whether the command mentions the output file, so it may publish outputs
+/// that are not declared with `save_output_as`
+fn command_writes_output_file(command: &CommandRunner) -> bool {
+  match command {
+    CommandRunner::ContainerBuild(_) | CommandRunner::TaskRun(_) => false,
+    _ => command_templates(command)
+      .iter()
+      .any(|value| value.contains(OUTPUT_FILE_ENV)),
+  }
+}
+
+fn is_valid_argument_name(name: &str) -> bool {
+  name.chars().next().is_some_and(|c| c.is_ascii_alphanumeric())
+    && name
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 fn has_default_containerfile(context_path: &Path) -> bool {
   context_path.join("Dockerfile").is_file() || context_path.join("Containerfile").is_file()
 }
@@ -566,4 +988,280 @@ mod tests {
 
     Ok(())
   }
+
+  #[test]
+  fn test_validate_retry_requires_an_attempt() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        install:
+          commands:
+            - command: "npm ci"
+              retry:
+                attempts: 0
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+
+    assert!(report.issues.iter().any(|issue| {
+      issue.field.as_deref() == Some("retry.attempts") && issue.message == "retry attempts must be at least 1"
+    }));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_if_conditions() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        deploy:
+          if: "secret.token != ''"
+          depends_on:
+            - name: build
+              if: "env.CI =="
+          commands:
+            - command: ./deploy.sh
+              if: "exists('deploy.sh')"
+        build:
+          commands:
+            - command: cargo build
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+
+    assert!(report.issues.iter().any(|issue| {
+      issue.field.as_deref() == Some("if")
+        && issue.message
+          == "Invalid expression 'secret.token != ''' - unknown namespace 'secret', use env, secrets, outputs, tasks, args, matrix, failure, git, mk, time"
+    }));
+    assert!(report.issues.iter().any(|issue| {
+      issue.field.as_deref() == Some("depends_on")
+        && issue.message.starts_with("Invalid expression 'env.CI =='")
+    }));
+    assert!(!report
+      .issues
+      .iter()
+      .any(|issue| issue.field.as_deref() == Some("commands")));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_template_expressions() -> anyhow::Result<()> {
+    let yaml = r#"
+      environment:
+        TAG: "${{ env.TAG || 'latest' }}"
+      tasks:
+        release:
+          environment:
+            VERSION: "${{ enz.VERSION }}"
+          commands:
+            - command: "echo ${{ lower(env.TAG) }} ${{ upper(env.TAG, 'x') }}"
+            - container_build:
+                image_name: app
+                context: .
+                tags:
+                  - "${{ format('{0}-{1}', env.TAG) }}"
+                  - "${{ env.TAG ||| 'x' }}"
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+
+    assert!(!report.issues.iter().any(|issue| issue.task.is_none()));
+    assert!(report.issues.iter().any(|issue| {
+      issue.field.as_deref() == Some("environment")
+        && issue.message
+          == "Invalid expression 'enz.VERSION' - unknown namespace 'enz', use env, secrets, outputs, tasks, args, matrix, failure, git, mk, time"
+    }));
+    let command_issues: Vec<_> = report
+      .issues
+      .iter()
+      .filter(|issue| issue.field.as_deref() == Some("commands"))
+      .map(|issue| issue.message.as_str())
+      .collect();
+    assert_eq!(
+      command_issues,
+      vec![
+        "Invalid expression 'upper(env.TAG, 'x')' - upper() does not take 2 arguments",
+        "Invalid expression 'env.TAG ||| 'x'' - unexpected character '|'",
+      ]
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_task_output_references() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        version:
+          commands:
+            - command: git describe --tags
+              save_output_as: semver
+        package:
+          depends_on:
+            - version
+          commands:
+            - command: echo package
+        release:
+          depends_on:
+            - package
+          environment:
+            TAG: "v${{ tasks.version.outputs.semver }}"
+          commands:
+            - command: "echo ${{ tasks.version.outputs.sha }} ${{ tasks.docs.outputs.url }}"
+        lint:
+          commands:
+            - command: "echo ${{ tasks.version.outputs.semver || 'dev' }}"
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+
+    let mut messages: Vec<_> = report
+      .issues
+      .iter()
+      .map(|issue| format!("{}: {}", issue.task.as_deref().unwrap_or_default(), issue.message))
+      .collect();
+    messages.sort();
+    assert_eq!(
+      messages,
+      vec![
+        "lint: Task output reference must come from a dependency: tasks.version.outputs.semver",
+        "release: Unknown task in output reference: tasks.docs.outputs.url",
+        "release: Unknown task output reference: tasks.version.outputs.sha",
+      ]
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_matrix_references() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        build:
+          matrix:
+            target: [x86_64, aarch64]
+          commands:
+            - command: "cargo build --target ${{ matrix.target }} --profile ${{ matrix.profile }}"
+    "#;
+
+    let mut task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    task_root.expand_matrices()?;
+    let report = task_root.validate();
+
+    let issues: Vec<_> = report
+      .issues
+      .iter()
+      .filter(|issue| issue.field.as_deref() == Some("matrix"))
+      .collect();
+    assert_eq!(issues.len(), 2);
+    assert!(issues
+      .iter()
+      .all(|issue| issue.message == "Unknown matrix value reference: profile"));
+    assert!(issues
+      .iter()
+      .any(|issue| issue.task.as_deref() == Some("build[target=aarch64]")));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_task_arguments() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        release:
+          args:
+            - name: version
+              required: true
+            - name: channel
+              type: enum
+            - name: count
+              type: int
+              default: many
+            - name: version
+          commands:
+            - command: "echo ${{ args.version }} ${{ args.missing }}"
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+    let messages = report
+      .issues
+      .iter()
+      .map(|issue| issue.message.as_str())
+      .collect::<Vec<_>>();
+
+    assert!(messages.contains(&"Duplicate argument name: version"));
+    assert!(messages.contains(&"Enum argument must declare choices: channel"));
+    assert!(messages.contains(&"Unknown task argument reference: missing"));
+    assert!(messages
+      .iter()
+      .any(|message| message.starts_with("Invalid default - Invalid value 'many'")));
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_cache_env_references() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        build:
+          environment:
+            PROFILE: release
+          cache:
+            enabled: true
+            env: [CARGO_*]
+            exclude_env: [BUILD_TIME]
+          outputs:
+            - target
+          commands:
+            - command: "cargo build --profile $PROFILE $RUSTFLAGS ${CARGO_HOME} $BUILD_TIME $HOME"
+            - "echo $RUSTFLAGS > $MK_OUTPUT"
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+    let messages = report
+      .issues
+      .iter()
+      .filter(|issue| issue.field.as_deref() == Some("cache.env"))
+      .map(|issue| issue.message.as_str())
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      messages,
+      vec![
+        "Command reads $RUSTFLAGS which is not listed in cache.env; changing it does not invalidate the cache",
+        "Command reads $HOME which is not listed in cache.env; changing it does not invalidate the cache",
+      ]
+    );
+
+    Ok(())
+  }
+
+  #[test]
+  fn test_validate_saved_outputs_require_local_commands() -> anyhow::Result<()> {
+    let yaml = r#"
+      tasks:
+        release:
+          commands:
+            - command: "git describe --tags"
+              save_output_as: version
+            - "echo ${{ outputs.version }}"
+    "#;
+
+    let task_root = serde_yaml::from_str::<TaskRoot>(yaml)?;
+    let report = task_root.validate();
+
+    assert!(report.issues.iter().any(|issue| {
+      issue.field.as_deref() == Some("command")
+        && issue.message == "Saved command outputs are only supported by local `command:` entries"
+    }));
+
+    Ok(())
+  }
 }
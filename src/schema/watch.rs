@@ -0,0 +1,416 @@
+use std::fs;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::sync::atomic::{
+  AtomicBool,
+  Ordering,
+};
+use std::sync::Arc;
+use std::thread;
+use std::time::{
+  Duration,
+  Instant,
+  SystemTime,
+};
+
+use hashbrown::{
+  HashMap,
+  HashSet,
+};
+
+use super::{
+  matrix_base_name,
+  run_task_invocations,
+  Cancellation,
+  ExecutionInterrupted,
+  RunCancelled,
+  Task,
+  TaskContext,
+  TaskInvocation,
+  TaskRoot,
+  TaskRunOutcome,
+};
+use crate::cache::expand_patterns_in_dir;
+
+/// How often the watched files are checked for changes
+pub const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long the watched files must stay unchanged before the tasks are run again
+pub const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// The modification time and size of a file, `None` when it does not exist
+type FileState = Option<(SystemTime, u64)>;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// A change seen by the watcher
+enum WatchEvent {
+  /// The inputs of these tasks changed
+  Inputs(HashSet<String>),
+  /// The config file changed
+  Config,
+  /// The user pressed Ctrl-C
+  Interrupted,
+}
+
+/// Run the given tasks, then run them again whenever the `inputs` of a task in
+/// their graph change. Only the tasks whose inputs changed and the tasks that
+/// depend on them run again. A run that is still going when a change comes in
+/// is cancelled first, killing the process groups of its commands.
+///
+/// The config file is watched too. When it changes, it is loaded again and all
+/// tasks run from a fresh context. A config that fails to load is reported and
+/// the previous tasks keep being watched.
+pub fn watch_task_invocations(
+  task_root: Arc<TaskRoot>,
+  invocations: &[TaskInvocation],
+  jobs: usize,
+  create_context: &dyn Fn(Arc<TaskRoot>) -> TaskContext,
+) -> anyhow::Result<()> {
+  install_interrupt_handler();
+
+  let mut context = create_context(task_root.clone());
+  let mut watcher = Watcher::new(task_root, invocations)?;
+  let mut run_tasks = true;
+  loop {
+    let cancellation = Cancellation::default();
+    context.set_cancellation(cancellation.clone());
+    let event = thread::scope(|scope| -> anyhow::Result<WatchEvent> {
+      let mut run = run_tasks.then(|| scope.spawn(|| run_task_invocations(&context, invocations, jobs)));
+      let mut pending: Option<(Instant, WatchEvent)> = None;
+      loop {
+        if run.as_ref().is_some_and(|run| run.is_finished()) {
+          if let Some(Ok(outcomes)) = run.take().map(|run| run.join()) {
+            report_outcomes(invocations, &outcomes);
+          }
+        }
+
+        let event = match watcher.poll() {
+          _ if INTERRUPTED.load(Ordering::SeqCst) => Some(WatchEvent::Interrupted),
+          Ok(event) => event,
+          Err(e) => {
+            cancellation.cancel();
+            return Err(e);
+          },
+        };
+        pending = match (pending.take(), event) {
+          (_, Some(WatchEvent::Interrupted)) => {
+            cancellation.cancel();
+            return Ok(WatchEvent::Interrupted);
+          },
+          (Some((_, WatchEvent::Config)), Some(_)) | (_, Some(WatchEvent::Config)) => {
+            Some((Instant::now(), WatchEvent::Config))
+          },
+          (Some((_, WatchEvent::Inputs(mut tasks))), Some(WatchEvent::Inputs(changed))) => {
+            tasks.extend(changed);
+            Some((Instant::now(), WatchEvent::Inputs(tasks)))
+          },
+          (_, Some(event)) => Some((Instant::now(), event)),
+          (pending, None) => pending,
+        };
+
+        if let Some((changed_at, _)) = &pending {
+          if changed_at.elapsed() >= WATCH_DEBOUNCE {
+            cancellation.cancel();
+            if let Some(run) = run.take() {
+              let _ = run.join();
+            }
+            return Ok(pending.take().map(|(_, event)| event).expect("pending change"));
+          }
+        }
+        thread::sleep(WATCH_POLL_INTERVAL);
+      }
+    })?;
+
+    run_tasks = true;
+    match event {
+      WatchEvent::Interrupted => return Err(ExecutionInterrupted.into()),
+      WatchEvent::Config => match watcher.reload() {
+        Ok(task_root) => {
+          eprintln!("Config changed, running the tasks again...");
+          context = create_context(task_root);
+        },
+        Err(e) => {
+          eprintln!("Failed to reload the config - {:#}", e);
+          eprintln!("Watching the previous tasks for changes...");
+          run_tasks = false;
+        },
+      },
+      WatchEvent::Inputs(tasks) => {
+        let affected = watcher.affected_tasks(tasks);
+        let mut names: Vec<_> = affected.iter().map(String::as_str).collect();
+        names.sort();
+        eprintln!("Inputs changed, running again: {}", names.join(", "));
+        for task_name in &affected {
+          context.unmark_task_complete(task_name)?;
+        }
+      },
+    }
+  }
+}
+
+/// Print the failures of a run, cancelled tasks are not reported
+fn report_outcomes(invocations: &[TaskInvocation], outcomes: &[TaskRunOutcome]) {
+  for (invocation, outcome) in invocations.iter().zip(outcomes) {
+    if let TaskRunOutcome::Failed(_, e) = outcome {
+      if !e.chain().any(|cause| cause.is::<RunCancelled>()) {
+        eprintln!("Task '{}' failed - {:#}", invocation.task_name, e);
+      }
+    }
+  }
+  eprintln!("Watching for changes...");
+}
+
+/// Tracks the inputs of the tasks in the graph and the config file
+struct Watcher {
+  task_root: Arc<TaskRoot>,
+  invocations: Vec<TaskInvocation>,
+  dependents: HashMap<String, HashSet<String>>,
+  tasks: Vec<String>,
+  inputs: HashMap<String, Vec<(PathBuf, FileState)>>,
+  config: FileState,
+}
+
+impl Watcher {
+  fn new(task_root: Arc<TaskRoot>, invocations: &[TaskInvocation]) -> anyhow::Result<Self> {
+    let mut watcher = Self {
+      task_root,
+      invocations: invocations.to_vec(),
+      dependents: HashMap::new(),
+      tasks: Vec::new(),
+      inputs: HashMap::new(),
+      config: None,
+    };
+    watcher.build_graph();
+    watcher.inputs = watcher.scan_inputs()?;
+    watcher.config = watcher.config_path().and_then(|path| file_state(&path));
+    Ok(watcher)
+  }
+
+  fn config_path(&self) -> Option<PathBuf> {
+    self.task_root.source_path.clone()
+  }
+
+  /// Collect the tasks of the plans of the invocations and who depends on whom.
+  /// Tasks that cannot be planned are only reported when they run.
+  fn build_graph(&mut self) {
+    let mut tasks = HashSet::new();
+    self.dependents.clear();
+    for invocation in &self.invocations {
+      let Ok(plan) = self.task_root.plan_task(&invocation.task_name) else {
+        continue;
+      };
+      for step in plan.steps {
+        let dependencies = step.dependencies.iter().chain(
+          step
+            .conditional_dependencies
+            .iter()
+            .map(|dependency| &dependency.name),
+        );
+        for dependency in dependencies {
+          self
+            .dependents
+            .entry(dependency.clone())
+            .or_default()
+            .insert(step.name.clone());
+        }
+        // A matrix instance is run by its matrix task.
+        if let Some(base) = matrix_base_name(&step.name) {
+          self
+            .dependents
+            .entry(step.name.clone())
+            .or_default()
+            .insert(base.to_string());
+        }
+        tasks.insert(step.name);
+      }
+    }
+    self.tasks = tasks.into_iter().collect();
+    self.tasks.sort();
+  }
+
+  /// The state of the input files of every task. Each file is only checked
+  /// once, even when several tasks list it.
+  fn scan_inputs(&self) -> anyhow::Result<HashMap<String, Vec<(PathBuf, FileState)>>> {
+    let mut states: HashMap<PathBuf, FileState> = HashMap::new();
+    let mut inputs = HashMap::new();
+    for task_name in &self.tasks {
+      let Some(Task::Task(task)) = self.task_root.tasks.get(task_name) else {
+        continue;
+      };
+      if task.inputs.is_empty() {
+        continue;
+      }
+
+      let base_dir = task.task_base_dir_from_root(&self.task_root);
+      let mut files = Vec::new();
+      for path in expand_patterns_in_dir(&base_dir, &task.inputs)? {
+        collect_files(&path, &mut files);
+      }
+      let files = files
+        .into_iter()
+        .map(|path| {
+          let state = *states.entry(path.clone()).or_insert_with(|| file_state(&path));
+          (path, state)
+        })
+        .collect();
+      inputs.insert(task_name.clone(), files);
+    }
+    Ok(inputs)
+  }
+
+  /// Check the watched files once
+  fn poll(&mut self) -> anyhow::Result<Option<WatchEvent>> {
+    let config = self.config_path().and_then(|path| file_state(&path));
+    if config != self.config {
+      self.config = config;
+      return Ok(Some(WatchEvent::Config));
+    }
+
+    let inputs = self.scan_inputs()?;
+    let changed: HashSet<String> = self
+      .tasks
+      .iter()
+      .filter(|task_name| inputs.get(*task_name) != self.inputs.get(*task_name))
+      .cloned()
+      .collect();
+    self.inputs = inputs;
+    if changed.is_empty() {
+      return Ok(None);
+    }
+    Ok(Some(WatchEvent::Inputs(changed)))
+  }
+
+  /// Load the config file again and watch the tasks of the new config
+  fn reload(&mut self) -> anyhow::Result<Arc<TaskRoot>> {
+    let path = self
+      .config_path()
+      .ok_or_else(|| anyhow::anyhow!("The tasks were not loaded from a config file"))?;
+    let task_root = Arc::new(TaskRoot::from_file(&path.to_string_lossy())?);
+    self.task_root = task_root.clone();
+    self.build_graph();
+    self.inputs = self.scan_inputs()?;
+    Ok(task_root)
+  }
+
+  /// The changed tasks and every task that depends on them
+  fn affected_tasks(&self, changed: HashSet<String>) -> HashSet<String> {
+    let mut affected = HashSet::new();
+    let mut queue: Vec<String> = changed.into_iter().collect();
+    while let Some(task_name) = queue.pop() {
+      if let Some(dependents) = self.dependents.get(&task_name) {
+        queue.extend(
+          dependents
+            .iter()
+            .filter(|name| !affected.contains(*name))
+            .cloned(),
+        );
+      }
+      affected.insert(task_name);
+    }
+    affected
+  }
+}
+
+/// Add the file, or every file below the directory, to `files`. Symlinked
+/// directories are not followed.
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) {
+  let is_dir = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_dir());
+  if !is_dir {
+    files.push(path.to_path_buf());
+    return;
+  }
+
+  let Ok(entries) = fs::read_dir(path) else {
+    return;
+  };
+  let mut children: Vec<_> = entries
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .collect();
+  children.sort();
+  for child in children {
+    collect_files(&child, files);
+  }
+}
+
+fn file_state(path: &Path) -> FileState {
+  let metadata = fs::metadata(path).ok()?;
+  Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[cfg(unix)]
+extern "C" fn handle_interrupt(_: libc::c_int) {
+  INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Commands of a watched run are in their own process groups and do not see
+/// the Ctrl-C of the terminal, so the watcher stops them itself.
+fn install_interrupt_handler() {
+  #[cfg(unix)]
+  unsafe {
+    libc::signal(libc::SIGINT, handle_interrupt as *const () as libc::sighandler_t);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn invocation(task_name: &str) -> TaskInvocation {
+    TaskInvocation {
+      task_name: task_name.to_string(),
+      args: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn test_watcher_1_reports_changed_tasks_and_dependents() -> anyhow::Result<()> {
+    let temp_dir = assert_fs::TempDir::new()?;
+    std::fs::create_dir_all(temp_dir.path().join("src/nested"))?;
+    std::fs::write(temp_dir.path().join("src/nested/lib.rs"), "a")?;
+    let root_yaml = format!(
+      "
+      tasks:
+        gen:
+          inputs: [{0}/src]
+          commands:
+            - echo gen
+        lint:
+          commands:
+            - echo lint
+        build:
+          depends_on: [gen, lint]
+          commands:
+            - echo build
+        release:
+          depends_on: [build]
+          commands:
+            - echo release
+    ",
+      temp_dir.path().to_string_lossy()
+    );
+
+    let root = Arc::new(serde_yaml::from_str::<TaskRoot>(&root_yaml)?);
+    let mut watcher = Watcher::new(root, &[invocation("release")])?;
+    assert_eq!(watcher.tasks, ["build", "gen", "lint", "release"]);
+    assert!(watcher.poll()?.is_none());
+
+    std::fs::write(temp_dir.path().join("src/nested/lib.rs"), "ab")?;
+    let Some(WatchEvent::Inputs(changed)) = watcher.poll()? else {
+      panic!("expected changed inputs");
+    };
+    assert_eq!(changed, HashSet::from(["gen".to_string()]));
+    assert!(watcher.poll()?.is_none());
+
+    let mut affected: Vec<_> = watcher.affected_tasks(changed).into_iter().collect();
+    affected.sort();
+    assert_eq!(affected, ["build", "gen", "release"]);
+
+    std::fs::write(temp_dir.path().join("src/main.rs"), "")?;
+    assert!(matches!(watcher.poll()?, Some(WatchEvent::Inputs(_))));
+
+    Ok(())
+  }
+}
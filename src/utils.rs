@@ -3,6 +3,7 @@ use std::path::{
   Path,
   PathBuf,
 };
+use std::time::Duration;
 use std::{
   fmt,
   fs,
@@ -84,6 +85,134 @@ where
   deserializer.deserialize_map(EnvironmentVisitor)
 }
 
+pub(crate) fn deserialize_scalar<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let value: Option<AnyValue> = Deserialize::deserialize(deserializer)?;
+  Ok(value.map(|value| value.to_string()))
+}
+
+/// Deserialize an optional duration such as `30s`, `5m` or `1h30m`. Plain numbers are seconds.
+pub(crate) fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let value: Option<AnyValue> = Deserialize::deserialize(deserializer)?;
+  value
+    .map(|value| parse_duration(&value.to_string()).map_err(de::Error::custom))
+    .transpose()
+}
+
+/// Parse a duration such as `500ms`, `30s`, `5m`, `2h` or `1h30m`. Plain numbers are seconds.
+pub fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+  let value = value.trim();
+  if value.is_empty() {
+    anyhow::bail!("Invalid duration - value is empty");
+  }
+
+  if let Ok(seconds) = value.parse::<u64>() {
+    return check_duration(value, Duration::from_secs(seconds));
+  }
+
+  let mut total = Duration::ZERO;
+  let mut rest = value;
+  while !rest.is_empty() {
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let amount = rest[..digits]
+      .parse::<u64>()
+      .map_err(|_| anyhow::anyhow!("Invalid duration - {}", value))?;
+    rest = &rest[digits..];
+
+    let units = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+    let duration = match &rest[..units] {
+      "ms" => Some(Duration::from_millis(amount)),
+      "s" => Some(Duration::from_secs(amount)),
+      "m" => amount.checked_mul(60).map(Duration::from_secs),
+      "h" => amount.checked_mul(60 * 60).map(Duration::from_secs),
+      _ => anyhow::bail!("Invalid duration - {}. Use units such as ms, s, m or h", value),
+    };
+    rest = &rest[units..];
+    total = duration
+      .and_then(|duration| total.checked_add(duration))
+      .ok_or_else(|| anyhow::anyhow!("Invalid duration - {} is out of range", value))?;
+  }
+
+  check_duration(value, total)
+}
+
+fn check_duration(value: &str, duration: Duration) -> anyhow::Result<Duration> {
+  if duration.is_zero() {
+    anyhow::bail!("Invalid duration - {} must be greater than zero", value);
+  }
+  Ok(duration)
+}
+
+/// Deserialize an optional size such as `500MB` or `2GB`. Plain numbers are bytes.
+pub(crate) fn deserialize_size<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let value: Option<AnyValue> = Deserialize::deserialize(deserializer)?;
+  value
+    .map(|value| parse_size(&value.to_string()).map_err(de::Error::custom))
+    .transpose()
+}
+
+/// Parse a size such as `512KB`, `500MB` or `2GB`, in powers of 1024. Plain
+/// numbers are bytes.
+pub fn parse_size(value: &str) -> anyhow::Result<u64> {
+  let trimmed = value.trim();
+  let digits = trimmed
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(trimmed.len());
+  let amount = trimmed[..digits]
+    .parse::<u64>()
+    .map_err(|_| anyhow::anyhow!("Invalid size - {}", value))?;
+
+  let multiplier: u64 = match trimmed[digits..].trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 1,
+    "K" | "KB" => 1 << 10,
+    "M" | "MB" => 1 << 20,
+    "G" | "GB" => 1 << 30,
+    "T" | "TB" => 1 << 40,
+    _ => anyhow::bail!("Invalid size - {}. Use units such as KB, MB or GB", value),
+  };
+  amount
+    .checked_mul(multiplier)
+    .ok_or_else(|| anyhow::anyhow!("Invalid size - {} is too large", value))
+}
+
+/// Format a duration the way it is written in the config, e.g. `1h30m` or `500ms`.
+pub fn format_duration(duration: Duration) -> String {
+  let millis = duration.as_millis();
+  if millis % 1000 != 0 {
+    return format!("{}ms", millis);
+  }
+
+  let seconds = duration.as_secs();
+  let mut out = String::new();
+  for (amount, unit) in [
+    (seconds / 3600, "h"),
+    (seconds / 60 % 60, "m"),
+    (seconds % 60, "s"),
+  ] {
+    if amount != 0 {
+      out.push_str(&format!("{}{}", amount, unit));
+    }
+  }
+  if out.is_empty() {
+    out.push_str("0s");
+  }
+  out
+}
+
+/// Join arguments into a single string that a POSIX shell splits back into the same arguments.
+pub(crate) fn shell_join(args: &[String]) -> anyhow::Result<String> {
+  shlex::try_join(args.iter().map(String::as_str))
+    .map_err(|e| anyhow::anyhow!("Failed to quote arguments - {}", e))
+}
+
 pub(crate) fn resolve_path(base_dir: &Path, value: &str) -> PathBuf {
   let path = Path::new(value);
   let joined = if path.is_absolute() {
@@ -147,3 +276,49 @@ pub(crate) fn parse_env_contents(contents: &str) -> HashMap<String, String> {
 
   env_vars
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_parse_duration_1() -> anyhow::Result<()> {
+    assert_eq!(parse_duration("30")?, Duration::from_secs(30));
+    assert_eq!(parse_duration("500ms")?, Duration::from_millis(500));
+    assert_eq!(parse_duration("5m")?, Duration::from_secs(300));
+    assert_eq!(parse_duration("1h30m")?, Duration::from_secs(5400));
+    assert!(parse_duration("0s").is_err());
+    assert!(parse_duration("5 minutes").is_err());
+    assert!(parse_duration("m").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_parse_duration_2() {
+    let error = parse_duration("99999999999999999h").expect_err("expected the duration to overflow");
+    assert_eq!(
+      error.to_string(),
+      "Invalid duration - 99999999999999999h is out of range"
+    );
+    assert!(parse_duration("18446744073709551615s1s").is_err());
+  }
+
+  #[test]
+  fn test_parse_size_1() -> anyhow::Result<()> {
+    assert_eq!(parse_size("1024")?, 1024);
+    assert_eq!(parse_size("512KB")?, 512 * 1024);
+    assert_eq!(parse_size("500mb")?, 500 * 1024 * 1024);
+    assert_eq!(parse_size("2 GB")?, 2 * 1024 * 1024 * 1024);
+    assert!(parse_size("GB").is_err());
+    assert!(parse_size("1.5GB").is_err());
+    assert!(parse_size("2 gigabytes").is_err());
+    Ok(())
+  }
+
+  #[test]
+  fn test_format_duration_1() {
+    assert_eq!(format_duration(Duration::from_millis(1500)), "1500ms");
+    assert_eq!(format_duration(Duration::from_secs(5400)), "1h30m");
+    assert_eq!(format_duration(Duration::from_secs(61)), "1m1s");
+  }
+}
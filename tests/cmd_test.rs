@@ -1506,3 +1506,1527 @@ fn test_mk_44_validate_rejects_parallel_saved_outputs() -> anyhow::Result<()> {
 
   Ok(())
 }
+
+#[test]
+fn test_mk_45_run_passes_typed_task_arguments() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let output_file = temp_dir.path().join("release.txt");
+  let marker_file = temp_dir.path().join("build.txt");
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "task-args.yaml",
+    &format!(
+      "
+    tasks:
+      build:
+        commands:
+          - command: touch {}
+            verbose: false
+      release:
+        args:
+          - name: version
+            required: true
+            help: Version to release
+          - name: channel
+            type: enum
+            choices: [stable, beta]
+            default: stable
+        depends_on:
+          - build
+        commands:
+          - command: printf '%s-%s' '${{{{ args.version }}}}' '${{{{ args.channel }}}}' > {}
+            verbose: false
+    ",
+      marker_file.to_utf8()?,
+      output_file.to_utf8()?
+    ),
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains(
+      "Missing required argument '--version' for task 'release' (Version to release)",
+    ));
+  assert!(!marker_file.exists());
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--version")
+    .arg("1.2.3")
+    .arg("--channel=beta")
+    .assert()
+    .success();
+  assert!(marker_file.exists());
+  assert_eq!(std::fs::read_to_string(&output_file)?, "1.2.3-beta");
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("plan")
+    .arg("release")
+    .arg("--version")
+    .arg("2.0.0")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("arg: --version <string> = \"2.0.0\""))
+    .stdout(predicates::str::contains(
+      "arg: --channel <stable|beta> = \"stable\"",
+    ));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_46_run_forwards_extra_arguments() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let appended_file = temp_dir.path().join("appended.txt");
+  let env_file = temp_dir.path().join("env.txt");
+  let rest_file = temp_dir.path().join("rest.txt");
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "extra-args.yaml",
+    &format!(
+      "
+    tasks:
+      forward:
+        commands:
+          - command: printf '[%s]' > {}
+            append_args: true
+            verbose: false
+          - command: printf '%s' \"$MK_ARGS\" > {}
+            verbose: false
+          - printf '%s' \"${{{{ args.rest }}}}\" > {}
+    ",
+      appended_file.to_utf8()?,
+      env_file.to_utf8()?,
+      rest_file.to_utf8()?
+    ),
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("forward")
+    .arg("--")
+    .arg("-p")
+    .arg("foo bar")
+    .arg("--force")
+    .assert()
+    .success();
+
+  assert_eq!(std::fs::read_to_string(&env_file)?, "-p 'foo bar' --force");
+  assert_eq!(std::fs::read_to_string(&rest_file)?, "-p 'foo bar' --force");
+
+  // The redirection comes first, so appended arguments become printf arguments.
+  let appended = std::fs::read_to_string(&appended_file)?;
+  assert_eq!(appended, "[-p][foo bar][--force]");
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_47_run_multiple_tasks_share_dependencies() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let log_file = temp_dir.path().join("log.txt");
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "multi-root.yaml",
+    &format!(
+      "
+    tasks:
+      setup:
+        commands:
+          - command: echo setup >> {0}
+            verbose: false
+      lint:
+        depends_on:
+          - setup
+        commands:
+          - command: echo lint >> {0}
+            verbose: false
+      test:
+        depends_on:
+          - setup
+        args:
+          - name: filter
+        commands:
+          - command: echo test-${{{{ args.filter }}}} >> {0}
+            verbose: false
+      broken:
+        commands:
+          - command: exit 3
+            verbose: false
+    ",
+      log_file.to_utf8()?
+    ),
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("lint")
+    .arg("test")
+    .arg("--filter")
+    .arg("lint")
+    .arg("--parallel")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("Summary:"));
+
+  let log = std::fs::read_to_string(&log_file)?;
+  assert_eq!(log.lines().filter(|line| *line == "setup").count(), 1);
+  assert!(log.lines().any(|line| line == "lint"));
+  assert!(log.lines().any(|line| line == "test-lint"));
+
+  std::fs::remove_file(&log_file)?;
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("broken")
+    .arg("lint")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("1 of 2 tasks failed - broken"));
+  assert!(!log_file.exists());
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("--json-events")
+    .arg("lint")
+    .arg("broken")
+    .assert()
+    .failure()
+    .stdout(predicates::str::contains("\"event\":\"run_summary\""))
+    .stdout(predicates::str::contains("\"status\":\"failed\""));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_48_progress_modes() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "progress.yaml",
+    "
+    tasks:
+      setup:
+        commands:
+          - echo from-setup
+      greet:
+        depends_on:
+          - setup
+        commands:
+          - command: echo hello
+          - command: echo world
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("--progress")
+    .arg("plain")
+    .arg("run")
+    .arg("greet")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("from-setup\n"))
+    .stdout(predicates::str::contains("hello\nworld\n"))
+    .stderr(predicates::str::contains("[greet] Running task command (2/2)"))
+    .stderr(predicates::str::contains("[greet] Commands completed in"));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("greet")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("from-setup\nhello\nworld\n")
+    .stderr("");
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_49_timeouts_stop_commands_and_tasks() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "timeouts.yaml",
+    "
+    tasks:
+      slow:
+        commands:
+          - command: sleep 30
+            timeout: 1s
+      tolerated:
+        commands:
+          - command: sleep 30
+            timeout: 500ms
+            ignore_errors: true
+          - command: echo after
+      bounded:
+        timeout: 1s
+        commands:
+          - echo started
+          - sleep 30
+          - echo unreachable
+    ",
+  )?;
+
+  let started = std::time::Instant::now();
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("slow")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("Command timed out after 1s - sleep 30"));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("tolerated")
+    .arg("--json-events")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(r#""event":"command_timed_out""#))
+    .stdout(predicates::str::contains(r#""timeout_ms":500"#));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("bounded")
+    .arg("--progress=none")
+    .assert()
+    .failure()
+    .stdout("started\n")
+    .stderr(predicates::str::contains(
+      "Task 'bounded' timed out after 1s - sleep 30",
+    ));
+  assert!(started.elapsed() < std::time::Duration::from_secs(20));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_50_retry_flaky_commands() -> anyhow::Result<()> {
+  use predicates::prelude::PredicateBooleanExt as _;
+
+  let temp_dir = TempDir::new()?;
+  let counter = temp_dir.path().join("attempts");
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "retry.yaml",
+    &format!(
+      "
+    tasks:
+      flaky:
+        commands:
+          - command: echo x >> {0}; test $(wc -l < {0}) -ge 3
+            retry:
+              attempts: 3
+              delay: 10ms
+              backoff: exponential
+      broken:
+        commands:
+          - command: exit 3
+            retry:
+              attempts: 2
+      unretried:
+        commands:
+          - command: exit 1
+            retry:
+              attempts: 5
+              on_exit_codes: [75]
+    ",
+      counter.to_utf8()?
+    ),
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("flaky")
+    .arg("--json-events")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      r#""attempt":2,"attempts":3,"delay_ms":10"#,
+    ))
+    .stdout(predicates::str::contains(
+      r#""attempt":3,"attempts":3,"delay_ms":20"#,
+    ))
+    .stdout(predicates::str::contains(r#""event":"command_retry""#));
+  assert_eq!(std::fs::read_to_string(&counter)?.lines().count(), 3);
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("broken")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains(
+      "Failed after 2 attempts - Command failed - exit 3",
+    ));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("unretried")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains("Command failed - exit 1"))
+    .stderr(predicates::str::contains("attempts").not());
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_51_finally_and_on_failure_cleanup() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "cleanup.yaml",
+    "
+    tasks:
+      report:
+        commands:
+          - command: echo \"cleanup after ${{ failure.command }} (code ${{ failure.exit_code }})\"
+      deploy:
+        commands:
+          - command: echo deploying
+          - command: exit 7
+        on_failure:
+          - task: report
+        finally:
+          - command: echo finally
+          - command: exit 1
+      build:
+        commands:
+          - command: echo building
+        on_failure:
+          - command: echo not-printed
+        finally:
+          - command: echo finally
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("deploy")
+    .arg("--progress=none")
+    .assert()
+    .failure()
+    .stdout("deploying\ncleanup after exit 7 (code 7)\nfinally\n")
+    .stderr(predicates::str::contains(
+      "Cleanup failed - Command failed - exit 1",
+    ))
+    .stderr(predicates::str::contains("Error: Command failed - exit 7"));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("building\nfinally\n");
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_52_matrix_expands_tasks() -> anyhow::Result<()> {
+  use predicates::prelude::PredicateBooleanExt as _;
+
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "matrix.yaml",
+    "
+    tasks:
+      build:
+        description: Build every target
+        matrix:
+          target: [x86_64, aarch64]
+          profile: [dev, release]
+          exclude:
+            - target: aarch64
+              profile: dev
+          include:
+            - target: x86_64
+              profile: release
+              flags: --locked
+        args:
+          - name: channel
+            default: stable
+        commands:
+          - command: echo \"${{ args.channel }} ${{ matrix.target }} ${{ matrix.profile }}\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("list")
+    .arg("--plain")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("build[target=x86_64,profile=dev]"))
+    .stdout(predicates::str::contains("build[target=x86_64,profile=release]"))
+    .stdout(predicates::str::contains("build[target=aarch64,profile=release]"))
+    .stdout(predicates::str::contains("build[target=aarch64,profile=dev]").not());
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("plan")
+    .arg("build")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      "3. build[target=aarch64,profile=release]\n",
+    ))
+    .stdout(predicates::str::contains(
+      "   matrix: target=x86_64, profile=release, flags=--locked\n",
+    ));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("validate")
+    .assert()
+    .success();
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .arg("--channel=nightly")
+    .assert()
+    .success()
+    .stdout("nightly x86_64 dev\nnightly x86_64 release\nnightly aarch64 release\n");
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_53_if_conditions_skip_tasks_and_commands() -> anyhow::Result<()> {
+  use predicates::prelude::PredicateBooleanExt as _;
+
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "conditions.yaml",
+    "
+    tasks:
+      prepare:
+        commands:
+          - command: echo prepare
+      deploy:
+        if: env.DEPLOY == 'yes'
+        commands:
+          - command: echo deploying
+      build:
+        environment:
+          MODE: release
+        depends_on:
+          - name: prepare
+            if: \"!exists('prepared.lock')\"
+        commands:
+          - command: echo \"mode ${{ env.MODE }}\"
+            if: env.MODE == 'release' && os != 'plan9'
+          - command: echo never
+            if: env.MODE == 'debug'
+          - task: deploy
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env_remove("DEPLOY")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("prepare\nmode release\n");
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env_remove("DEPLOY")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--json-events")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      r#"{"event":"command_skipped","kind":"local_run","reason":"condition_false","task":"build"}"#,
+    ))
+    .stdout(predicates::str::contains(
+      r#"{"event":"task_skipped","reason":"condition_false","task":"deploy"}"#,
+    ));
+
+  std::fs::write(temp_dir.path().join("prepared.lock"), "")?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("plan")
+    .arg("build")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      "   depends_on: prepare if !exists('prepared.lock') (false)\n",
+    ))
+    .stdout(predicates::str::contains(
+      "   local: echo never if env.MODE == 'debug' (false)\n",
+    ))
+    .stdout(predicates::str::contains("1. prepare").not());
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env("DEPLOY", "no")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("plan")
+    .arg("deploy")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("   if: env.DEPLOY == 'yes' (false)\n"))
+    .stdout(predicates::str::contains("   skip: condition_false\n"));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env("DEPLOY", "yes")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("mode release\ndeploying\n");
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_54_template_expressions() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::write(temp_dir.path().join("Cargo.lock"), "lock")?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "templates.yaml",
+    "
+    environment:
+      TAG: ${{ env.IMAGE_TAG || 'latest' }}
+    tasks:
+      release:
+        environment:
+          BRANCH: refs/heads/Main
+        commands:
+          - command: echo \"${{ upper(env.TAG) }} ${{ lower(replace(env.BRANCH, 'refs/heads/', '')) }}\"
+          - command: echo \"${{ format('{0}:{1}', 'app', env.TAG) }} ${{ join(split('a b', ' '), '+') }}\"
+          - command: echo \"${{ hashFiles('Cargo.lock') != '' }} ${{ hashFiles('*.missing') || 'none' }}\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env_remove("IMAGE_TAG")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("LATEST main\napp:latest a+b\ntrue none\n");
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .env("IMAGE_TAG", "v2")
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("V2 main\napp:v2 a+b\n"));
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "invalid-templates.yaml",
+    "
+    tasks:
+      release:
+        commands:
+          - command: echo ${{ secret.TOKEN }}
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("validate")
+    .assert()
+    .failure()
+    .stdout(predicates::str::contains(
+      "Invalid expression 'secret.TOKEN' - unknown namespace 'secret'",
+    ));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_55_builtin_template_namespaces() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "builtins.yaml",
+    "
+    tasks:
+      stamp:
+        commands:
+          - command: echo \"${{ mk.task_name }} ${{ mk.version }} ${{ mk.os }}\"
+          - command: echo \"${{ git.sha || 'no-git' }} ${{ git.dirty || 'unknown' }}\"
+          - command: echo \"${{ time.now('%Y') == time.now('%Y') }}\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("stamp")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout(format!(
+      "stamp {} {}\nno-git unknown\ntrue\n",
+      env!("CARGO_PKG_VERSION"),
+      std::env::consts::OS
+    ));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_56_templates_in_every_command_field() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::create_dir(temp_dir.path().join("release"))?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "fields.yaml",
+    "
+    environment:
+      RUST_VERSION: '1.80'
+      OUT: release
+    tasks:
+      build:
+        preconditions:
+          - command: test \"$(basename \"$PWD\")\" = \"${{ env.OUT }}\"
+            work_dir: ${{ env.OUT }}
+            message: ${{ env.OUT }} is not the working directory
+        commands:
+          - command: pwd
+            work_dir: ${{ env.OUT }}
+      image:
+        commands:
+          - image: rust:${{ env.RUST_VERSION }}
+            container_command:
+              - cargo
+              - build
+              - --out-dir=${{ env.OUT }}
+            mounted_paths:
+              - ${{ env.OUT }}:/out
+          - command: echo ${{ outputs.version }}
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout(predicates::str::ends_with("/release\n"));
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("plan")
+    .arg("image")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      "container_run: rust:1.80 -> cargo build --out-dir=release",
+    ))
+    .stdout(predicates::str::contains("local: echo ${{ outputs.version }}"));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_57_task_outputs_are_read_by_dependents() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::write(temp_dir.path().join("VERSION"), "1.4.0")?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "task-outputs.yaml",
+    "
+    tasks:
+      version:
+        inputs:
+          - VERSION
+        outputs:
+          - version.txt
+        cache:
+          enabled: true
+        commands:
+          - command: cat VERSION
+            save_output_as: semver
+          - command: cp VERSION version.txt && echo computed
+      release:
+        depends_on:
+          - version
+        environment:
+          TAG: v${{ tasks.version.outputs.semver }}
+        commands:
+          - command: echo \"release $TAG ${{ tasks.version.outputs.semver }}\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .current_dir(temp_dir.path())
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("1.4.0\ncomputed\nrelease v1.4.0 1.4.0\n");
+
+  // A cache hit still provides the outputs the task saved, and replays the
+  // output of its commands.
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .current_dir(temp_dir.path())
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout(
+      "[version] cached: cat VERSION\n1.4.0\n[version] cached: cp VERSION version.txt && echo computed\ncomputed\nrelease v1.4.0 1.4.0\n",
+    );
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "invalid-task-outputs.yaml",
+    "
+    tasks:
+      version:
+        commands:
+          - command: cat VERSION
+            save_output_as: semver
+      release:
+        commands:
+          - command: echo ${{ tasks.version.outputs.semver }}
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("validate")
+    .assert()
+    .failure()
+    .stdout(predicates::str::contains(
+      "Task output reference must come from a dependency: tasks.version.outputs.semver",
+    ));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_58_commands_publish_outputs_to_output_file() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "output-file.yaml",
+    "
+    tasks:
+      build:
+        commands:
+          - command: |
+              echo building
+              echo \"version=1.2.3\" >> \"$MK_OUTPUT\"
+              printf 'notes<<EOF\\nfirst\\nsecond\\nEOF\\n' >> \"$MK_OUTPUT\"
+          - command: echo \"built ${{ outputs.version }}\"
+      release:
+        depends_on:
+          - build
+        commands:
+          - command: echo \"${{ tasks.build.outputs.notes }}\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("release")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout("building\nbuilt 1.2.3\nfirst\nsecond\n");
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "invalid-output-file.yaml",
+    "
+    tasks:
+      build:
+        commands:
+          - command: echo \"version\" >> \"$MK_OUTPUT\"
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("--progress=none")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains(
+      "Invalid MK_OUTPUT line - expected NAME=VALUE or NAME<<DELIMITER, got 'version'",
+    ));
+
+  Ok(())
+}
+
+#[test]
+fn test_mk_59_structured_outputs_expose_fields() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "structured-outputs.yaml",
+    "
+    tasks:
+      meta:
+        commands:
+          - command: |
+              printf '{\"version\": \"2.0.1\", \"items\": [{\"id\": 7}]}'
+            save_output_as:
+              name: meta
+              format: json
+          - command: |
+              printf 'channel: beta\\n'
+            save_output_as:
+              name: settings
+              format: yaml
+          - command: echo \"${{ outputs.meta.version }} ${{ outputs.meta.items[0].id }} ${{ outputs.settings.channel }}\"
+      deploy:
+        depends_on:
+          - meta
+        commands:
+          - command: echo \"deploy ${{ tasks.meta.outputs.meta.version }}\"
+      broken:
+        commands:
+          - command: echo 'not json'
+            save_output_as:
+              name: meta
+              format: json
+    ",
+  )?;
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("deploy")
+    .arg("--progress=none")
+    .assert()
+    .success()
+    .stdout(
+      "{\"version\": \"2.0.1\", \"items\": [{\"id\": 7}]}\nchannel: beta\n2.0.1 7 beta\ndeploy 2.0.1\n",
+    );
+
+  let mut cmd = Command::new(cargo::cargo_bin!("mk"));
+  cmd
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("broken")
+    .arg("--progress=none")
+    .assert()
+    .failure()
+    .stderr(predicates::str::contains(
+      "Failed to parse output 'meta' as json - echo 'not json'",
+    ));
+
+  Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_mk_60_watch_reruns_tasks_affected_by_changed_inputs() -> anyhow::Result<()> {
+  use std::time::{
+    Duration,
+    Instant,
+  };
+
+  let temp_dir = TempDir::new()?;
+  let log_file = temp_dir.path().join("log.txt");
+  std::fs::write(temp_dir.path().join("gen.txt"), "a\n")?;
+  std::fs::write(temp_dir.path().join("other.txt"), "a\n")?;
+  let log = log_file.to_string_lossy();
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "watch.yaml",
+    &format!(
+      "
+    tasks:
+      gen:
+        inputs:
+          - gen.txt
+        commands:
+          - echo gen >> {log}
+      build:
+        depends_on:
+          - gen
+        commands:
+          - echo build >> {log}
+      other:
+        inputs:
+          - other.txt
+        commands:
+          - echo other >> {log}
+    "
+    ),
+  )?;
+
+  let wait_for_log = |expected: &str| -> anyhow::Result<()> {
+    let started = Instant::now();
+    while std::fs::read_to_string(&log_file).unwrap_or_default() != expected {
+      if started.elapsed() > Duration::from_secs(10) {
+        anyhow::bail!(
+          "expected log {:?}, found {:?}",
+          expected,
+          std::fs::read_to_string(&log_file).unwrap_or_default()
+        );
+      }
+      std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+  };
+
+  let mut child = std::process::Command::new(cargo::cargo_bin!("mk"))
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("run")
+    .arg("build")
+    .arg("other")
+    .arg("--watch")
+    .arg("--progress=none")
+    .stdout(std::process::Stdio::null())
+    .stderr(std::process::Stdio::null())
+    .spawn()?;
+
+  let result = (|| -> anyhow::Result<()> {
+    wait_for_log("gen\nbuild\nother\n")?;
+    std::fs::write(temp_dir.path().join("other.txt"), "b\n")?;
+    wait_for_log("gen\nbuild\nother\nother\n")?;
+    std::fs::write(temp_dir.path().join("gen.txt"), "b\n")?;
+    wait_for_log("gen\nbuild\nother\nother\ngen\nbuild\n")?;
+    Ok(())
+  })();
+
+  unsafe {
+    libc::kill(child.id() as i32, libc::SIGINT);
+  }
+  let status = child.wait()?;
+  result?;
+  assert_eq!(status.code(), Some(130));
+  Ok(())
+}
+
+#[test]
+fn test_mk_61_cache_hit_restores_outputs_from_artifact_store() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let input_file = temp_dir.path().join("input.txt");
+  let output_file = temp_dir.path().join("dist/output.txt");
+  let marker_file = temp_dir.path().join("marker.txt");
+  std::fs::write(&input_file, "hello")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "artifacts.yaml",
+    "
+    tasks:
+      build:
+        inputs:
+          - input.txt
+        outputs:
+          - dist
+        cache:
+          enabled: true
+        commands:
+          - command: mkdir -p dist && cat input.txt > dist/output.txt && echo run >> marker.txt
+            verbose: false
+    ",
+  )?;
+
+  let run_build = || -> anyhow::Result<()> {
+    Command::new(cargo::cargo_bin!("mk"))
+      .current_dir(temp_dir.path())
+      .arg("-c")
+      .arg(&config_file_path)
+      .arg("run")
+      .arg("build")
+      .arg("--progress=none")
+      .assert()
+      .success();
+    Ok(())
+  };
+
+  run_build()?;
+  std::fs::remove_dir_all(temp_dir.path().join("dist"))?;
+  run_build()?;
+  assert_eq!(std::fs::read_to_string(&output_file)?, "hello");
+  assert_eq!(std::fs::read_to_string(&marker_file)?.lines().count(), 1);
+
+  // Switching the input back restores the outputs saved for it.
+  std::fs::write(&input_file, "world")?;
+  run_build()?;
+  assert_eq!(std::fs::read_to_string(&output_file)?, "world");
+  std::fs::write(&input_file, "hello")?;
+  run_build()?;
+  assert_eq!(std::fs::read_to_string(&output_file)?, "hello");
+  assert_eq!(std::fs::read_to_string(&marker_file)?.lines().count(), 2);
+
+  Command::new(cargo::cargo_bin!("mk"))
+    .current_dir(temp_dir.path())
+    .arg("-c")
+    .arg(&config_file_path)
+    .arg("cache")
+    .arg("gc")
+    .arg("--max-size")
+    .arg("0")
+    .assert()
+    .success()
+    .stdout(predicates::str::contains(
+      "Removed 2 cached artifacts, freed 10 B",
+    ));
+  Ok(())
+}
+
+#[test]
+fn test_mk_62_cache_status_and_explain_report_changed_inputs() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::write(temp_dir.path().join("input.txt"), "hello")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "explain.yaml",
+    "
+    tasks:
+      build:
+        inputs:
+          - input.txt
+        outputs:
+          - output.txt
+        environment:
+          TOKEN: ${{ env.SECRET_TOKEN }}
+        cache:
+          enabled: true
+        commands:
+          - command: cat input.txt > output.txt
+            verbose: false
+    ",
+  )?;
+
+  let mk = |args: &[&str]| {
+    let mut command = Command::new(cargo::cargo_bin!("mk"));
+    command
+      .current_dir(temp_dir.path())
+      .env("SECRET_TOKEN", "first-secret")
+      .arg("-c")
+      .arg(&config_file_path)
+      .args(args);
+    command
+  };
+
+  mk(&["cache", "status"])
+    .assert()
+    .success()
+    .stdout(predicates::str::contains("build: not cached"))
+    .stdout(predicates::str::contains(
+      "the task has not run since the cache was cleared",
+    ));
+  mk(&["run", "build", "--progress=none"]).assert().success();
+  mk(&["cache", "status", "build"])
+    .assert()
+    .success()
+    .stdout("build: cached\n");
+
+  std::fs::write(temp_dir.path().join("input.txt"), "world")?;
+  mk(&["cache", "status", "build"])
+    .env("SECRET_TOKEN", "second-secret")
+    .assert()
+    .success()
+    .stdout(
+      "build: not cached\n  - env var TOKEN changed (value masked)\n  - input file modified: input.txt\n",
+    );
+
+  mk(&["run", "build", "--explain", "--progress=none"])
+    .assert()
+    .success()
+    .stderr(predicates::str::contains(
+      "Task 'build' is not cached:\n  - input file modified: input.txt\n",
+    ));
+
+  // Environment values are not written to the cache.
+  let cache = std::fs::read_to_string(temp_dir.path().join(".mk/cache.json"))?;
+  assert!(cache.contains("\"TOKEN\""));
+  assert!(!cache.contains("first-secret"));
+  Ok(())
+}
+
+#[test]
+fn test_mk_63_cache_env_allowlist_selects_fingerprinted_variables() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::write(temp_dir.path().join("input.txt"), "hello")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "cache_env.yaml",
+    "
+    tasks:
+      build:
+        inputs:
+          - input.txt
+        outputs:
+          - output.txt
+        environment:
+          BUILD_TIME: ${{ env.NOW }}
+        cache:
+          enabled: true
+          env: [MK_TEST_FLAGS, BUILD_*]
+          exclude_env: [BUILD_TIME]
+        commands:
+          - command: cat input.txt > output.txt
+            verbose: false
+    ",
+  )?;
+
+  let mk = |args: &[&str]| {
+    let mut command = Command::new(cargo::cargo_bin!("mk"));
+    command
+      .current_dir(temp_dir.path())
+      .env("MK_TEST_FLAGS", "-O1")
+      .env("NOW", "12:00")
+      .env("UNRELATED", "a")
+      .arg("-c")
+      .arg(&config_file_path)
+      .args(args);
+    command
+  };
+
+  mk(&["run", "build", "--progress=none"]).assert().success();
+
+  // Variables outside the allowlist or excluded from it do not bust the cache.
+  mk(&["cache", "status", "build"])
+    .env("NOW", "13:00")
+    .env("UNRELATED", "b")
+    .assert()
+    .success()
+    .stdout("build: cached\n");
+
+  mk(&["cache", "status", "build"])
+    .env("MK_TEST_FLAGS", "-O2")
+    .assert()
+    .success()
+    .stdout("build: not cached\n  - env var MK_TEST_FLAGS changed (value masked)\n");
+
+  let cache = std::fs::read_to_string(temp_dir.path().join(".mk/cache.json"))?;
+  assert!(!cache.contains("-O1"));
+  Ok(())
+}
+
+#[test]
+fn test_mk_64_stamp_and_timestamp_cache_modes_skip_unchanged_tasks() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let input_file = temp_dir.path().join("input.txt");
+  let marker_file = temp_dir.path().join("marker.txt");
+  std::fs::write(&input_file, "hello")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "modes.yaml",
+    "
+    tasks:
+      lint:
+        inputs:
+          - input.txt
+        cache:
+          mode: stamp
+        commands:
+          - command: echo lint >> marker.txt
+            verbose: false
+      build:
+        inputs:
+          - input.txt
+        outputs:
+          - output.txt
+        cache:
+          mode: timestamp
+        commands:
+          - command: cat input.txt > output.txt && echo build >> marker.txt
+            verbose: false
+    ",
+  )?;
+
+  let mk = |args: &[&str]| {
+    let mut command = Command::new(cargo::cargo_bin!("mk"));
+    command
+      .current_dir(temp_dir.path())
+      .arg("-c")
+      .arg(&config_file_path)
+      .args(args);
+    command
+  };
+  let runs = |task: &str| -> anyhow::Result<usize> {
+    let marker = std::fs::read_to_string(&marker_file)?;
+    Ok(marker.lines().filter(|line| *line == task).count())
+  };
+
+  mk(&["run", "lint", "--progress=none"]).assert().success();
+  mk(&["run", "lint", "--progress=none"]).assert().success();
+  assert_eq!(runs("lint")?, 1);
+  std::fs::write(&input_file, "world")?;
+  mk(&["run", "lint", "--progress=none"]).assert().success();
+  assert_eq!(runs("lint")?, 2);
+
+  mk(&["run", "build", "--progress=none"]).assert().success();
+  mk(&["run", "build", "--progress=none"]).assert().success();
+  assert_eq!(runs("build")?, 1);
+  mk(&["cache", "status", "build"])
+    .assert()
+    .success()
+    .stdout("build: cached\n");
+
+  // An input modified after the output makes the output out of date.
+  std::fs::File::options()
+    .write(true)
+    .open(&input_file)?
+    .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(60))?;
+  mk(&["cache", "status", "build"])
+    .assert()
+    .success()
+    .stdout("build: not cached\n  - input file is newer than the outputs: input.txt\n");
+  mk(&["run", "build", "--progress=none"]).assert().success();
+  assert_eq!(runs("build")?, 2);
+  Ok(())
+}
+
+#[test]
+fn test_mk_65_cache_hit_replays_command_output() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  std::fs::write(temp_dir.path().join("input.txt"), "hello")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "replay.yaml",
+    "
+    tasks:
+      lint:
+        inputs:
+          - input.txt
+        cache:
+          mode: stamp
+        commands:
+          - echo checked input.txt
+          - command: echo style warning && false
+            ignore_errors: true
+      test:
+        inputs:
+          - input.txt
+        cache:
+          mode: stamp
+          replay_output: errors_only
+        commands:
+          - echo all tests passed
+          - command: echo flaky test failed && false
+            ignore_errors: true
+      fmt:
+        inputs:
+          - input.txt
+        cache:
+          mode: stamp
+          replay_output: none
+        commands:
+          - echo formatted
+    ",
+  )?;
+
+  let mk = |task: &str| {
+    let mut command = Command::new(cargo::cargo_bin!("mk"));
+    command
+      .current_dir(temp_dir.path())
+      .arg("-c")
+      .arg(&config_file_path)
+      .arg("run")
+      .arg(task)
+      .arg("--progress=none");
+    command
+  };
+
+  mk("lint")
+    .assert()
+    .success()
+    .stdout("checked input.txt\nstyle warning\n");
+  mk("lint").assert().success().stdout(
+    "[lint] cached: echo checked input.txt\nchecked input.txt\n[lint] cached: echo style warning && false\nstyle warning\n",
+  );
+
+  mk("test")
+    .assert()
+    .success()
+    .stdout("all tests passed\nflaky test failed\n");
+  mk("test")
+    .assert()
+    .success()
+    .stdout("[test] cached: echo flaky test failed && false\nflaky test failed\n");
+
+  mk("fmt").assert().success().stdout("formatted\n");
+  mk("fmt").assert().success().stdout("");
+
+  // Outputs are stored compressed.
+  let cache = std::fs::read_to_string(temp_dir.path().join(".mk/cache.json"))?;
+  assert!(!cache.contains("checked input.txt"));
+  Ok(())
+}
+
+#[test]
+fn test_mk_66_cache_fingerprint_covers_inputs_generated_by_dependencies() -> anyhow::Result<()> {
+  let temp_dir = TempDir::new()?;
+  let source_file = temp_dir.path().join("source.txt");
+  std::fs::write(&source_file, "v1")?;
+
+  let config_file_path = common::setup_yaml(
+    &temp_dir,
+    "generated.yaml",
+    "
+    tasks:
+      generate:
+        commands:
+          - command: cp source.txt generated.txt
+            verbose: false
+      build:
+        depends_on:
+          - generate
+        inputs:
+          - generated.txt
+        outputs:
+          - output.txt
+        cache:
+          enabled: true
+        commands:
+          - command: cp generated.txt output.txt
+            verbose: false
+    ",
+  )?;
+
+  let mk = || {
+    let mut command = Command::new(cargo::cargo_bin!("mk"));
+    command
+      .current_dir(temp_dir.path())
+      .arg("-c")
+      .arg(&config_file_path)
+      .args(["run", "build", "--progress=none"]);
+    command
+  };
+
+  mk().assert().success();
+  mk().assert().success();
+  assert_eq!(std::fs::read_to_string(temp_dir.path().join("output.txt"))?, "v1");
+
+  // The input is regenerated by the dependency before the fingerprint is computed.
+  std::fs::write(&source_file, "v2")?;
+  mk().assert().success();
+  assert_eq!(std::fs::read_to_string(temp_dir.path().join("output.txt"))?, "v2");
+  Ok(())
+}